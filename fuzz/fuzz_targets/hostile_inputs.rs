@@ -0,0 +1,191 @@
+#![no_main]
+
+// Runs arbitrary operations with arbitrary table names, keys, field names, values and filters against a
+// SqlitePersistence on an in-memory database. Any operation may fail, none may panic. A row stored under a
+// table name with a quote in it has to load back unchanged.
+// Run with `cargo fuzz run hostile_inputs` from the repository root
+
+use std::{collections::HashMap, sync::Arc};
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use dmfg_persistence::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceSpec, PersistenceType, Query, Row, SpecError};
+use dmfg_persistence::persistence_adapter::sqlite::SqlitePersistence;
+
+// names that tend to break SQL, also used as the spec's own field names
+const FIELD_NAMES: [&str; 10] = ["key", "va\"lue", "by;tes", "int--eger", "unsigned ?", "fl'oat", ":tenant", "", "\0", "string\"; DROP TABLE x; --"];
+
+const FIELDS: [PersistenceType; 7] = [
+    PersistenceType::String("key"),
+    PersistenceType::String("va\"lue"),
+    PersistenceType::Bytes("by;tes"),
+    PersistenceType::Integer("int--eger"),
+    PersistenceType::UnsignedInteger("unsigned ?"),
+    PersistenceType::Float("fl'oat"),
+    PersistenceType::Double(":tenant")
+];
+
+#[derive(Debug, Clone, PartialEq, Arbitrary)]
+struct Entry {
+    string: String,
+    bytes: Vec<u8>,
+    integer: i64,
+    unsigned_integer: u64,
+    float: f32,
+    double: f64
+}
+
+struct HostileSpec;
+
+impl PersistenceSpec<String, Entry> for HostileSpec {
+    fn fields() -> &'static [PersistenceType] {
+        &FIELDS
+    }
+
+    fn key_field() -> &'static str {
+        "key"
+    }
+
+    fn serialize_key(key: &String) -> PersistenceData {
+        PersistenceData::String(key.clone())
+    }
+
+    fn deserialize_key(key: &PersistenceData) -> Option<String> {
+        key.to_str().map(str::to_string)
+    }
+
+    fn serialize_data(data: &Entry) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+        Ok(HashMap::from([
+            ("va\"lue", PersistenceData::String(data.string.clone())),
+            ("by;tes", PersistenceData::Bytes(data.bytes.clone())),
+            ("int--eger", PersistenceData::Integer(data.integer)),
+            ("unsigned ?", PersistenceData::UnsignedInteger(data.unsigned_integer)),
+            ("fl'oat", PersistenceData::Float(data.float)),
+            (":tenant", PersistenceData::Double(data.double))
+        ]))
+    }
+
+    fn deserialize_data(data: HashMap<&'static str, PersistenceData>) -> Result<Entry, SpecError> {
+        let mut row = Row::from(data);
+        Ok(Entry {
+            string: row.take("va\"lue")?,
+            bytes: row.take("by;tes")?,
+            integer: row.get("int--eger")?,
+            unsigned_integer: row.get("unsigned ?")?,
+            float: row.get("fl'oat")?,
+            double: row.get(":tenant")?
+        })
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+enum Value {
+    String(String),
+    Bytes(Vec<u8>),
+    Integer(i64),
+    UnsignedInteger(u64),
+    Float(f32),
+    Double(f64)
+}
+
+impl From<Value> for PersistenceData {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::String(s) => PersistenceData::String(s),
+            Value::Bytes(b) => PersistenceData::Bytes(b),
+            Value::Integer(i) => PersistenceData::Integer(i),
+            Value::UnsignedInteger(u) => PersistenceData::UnsignedInteger(u),
+            Value::Float(f) => PersistenceData::Float(f),
+            Value::Double(d) => PersistenceData::Double(d)
+        }
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+enum Filter {
+    Equals(String, Value),
+    GreaterThan(String, Value),
+    LessThan(String, Value),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>)
+}
+
+impl From<Filter> for Query {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Equals(field, value) => Query::Equals(field, value.into()),
+            Filter::GreaterThan(field, value) => Query::GreaterThan(field, value.into()),
+            Filter::LessThan(field, value) => Query::LessThan(field, value.into()),
+            Filter::Not(a) => Query::not((*a).into()),
+            Filter::And(a, b) => Query::and((*a).into(), (*b).into()),
+            Filter::Or(a, b) => Query::or((*a).into(), (*b).into())
+        }
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+enum Operation {
+    Store(String, Entry),
+    Update(String, Entry, Option<u8>),
+    Patch(String, u8, Value),
+    Load(String),
+    Contains(String),
+    Delete(String),
+    ScanRange(Option<String>, Option<String>, Option<u8>),
+    Query(Filter, u8, Option<u8>),
+    ClearWhere(Filter),
+    Parse(String),
+    RoundTrip(String, Entry),
+    Clear
+}
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    table_name: String,
+    operations: Vec<Operation>
+}
+
+fn field_name(index: u8) -> &'static str {
+    FIELD_NAMES[index as usize % FIELD_NAMES.len()]
+}
+
+fuzz_target!(|input: Input| {
+    let Ok(connection) = sqlite::Connection::open_with_full_mutex(":memory:") else {
+        return;
+    };
+    let connection = Arc::new(connection);
+    let persistence = SqlitePersistence::new(connection.clone(), &input.table_name);
+    let adapter: &dyn PersistenceAdapter<String, Entry, HostileSpec> = &persistence;
+    let queryable: &dyn PersistenceAdapterQueryable<String, Entry, HostileSpec> = &persistence;
+    adapter.initialize();
+    let quoted = SqlitePersistence::new(connection, &format!("{}\"", input.table_name));
+    let quoted: &dyn PersistenceAdapter<String, Entry, HostileSpec> = &quoted;
+    quoted.initialize();
+
+    for operation in input.operations {
+        match operation {
+            Operation::Store(key, entry) => { let _ = adapter.store(&key, &entry); },
+            Operation::Update(key, entry, only) => { let _ = adapter.update(&key, &entry, only.map(|i|[field_name(i)]).as_ref().map(|f|&f[..])); },
+            Operation::Patch(key, field, value) => { let _ = adapter.patch(&key, HashMap::from([(field_name(field), value.into())])); },
+            Operation::Load(key) => { let _ = adapter.load(&key); },
+            Operation::Contains(key) => { let _ = adapter.contains(&key); },
+            Operation::Delete(key) => { let _ = adapter.delete(&key); },
+            Operation::ScanRange(from, to, limit) => { let _ = adapter.scan_range(from.as_ref(), to.as_ref(), limit.map(usize::from)); },
+            Operation::Query(filter, start, limit) => { let _ = queryable.query(filter.into(), start.into(), limit.map(usize::from)); },
+            Operation::ClearWhere(filter) => { let _ = queryable.clear_where(filter.into()); },
+            Operation::Parse(filter) => {
+                if let Ok(query) = Query::parse(&filter) {
+                    let _ = queryable.query(query, 0, None);
+                }
+            },
+            // NaN is stored as NULL, so such a row can't load back
+            Operation::RoundTrip(key, entry) => {
+                if quoted.store(&key, &entry).is_ok() && !entry.float.is_nan() && !entry.double.is_nan() {
+                    assert_eq!(quoted.load(&key), Some(entry));
+                }
+            },
+            Operation::Clear => { let _ = adapter.clear(); }
+        }
+    }
+    let _ = adapter.scan(0, None);
+});
@@ -0,0 +1,164 @@
+// Inspects sqlite databases written through SqlitePersistence without needing the application's specs
+use std::{io::{self, Write}, path::Path, process::ExitCode, sync::Arc};
+use dmfg_persistence::persistence_adapter::{PersistenceData, PersistenceError, Query};
+use dmfg_persistence::persistence_adapter::sqlite::{RawRow, SqlitePersistence};
+
+const USAGE: &str = "usage: dmfg-persist <database> <command>
+
+commands:
+  tables                       list the tables
+  schema <table>               show a table's columns and their types
+  dump <table> [options]       write a table's rows to stdout
+      --format csv|ndjson      output format, csv by default
+      --where <filter>         only rows matching a Query filter, e.g. \"age > 18 AND name = 'bob'\"
+      --limit <n>              at most n rows
+      --tenant <tenant>        only the rows of this tenant
+  check                        run sqlite's integrity check";
+
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Persistence(PersistenceError),
+    Io(io::Error)
+}
+
+impl From<PersistenceError> for CliError {
+    fn from(error: PersistenceError) -> Self {
+        CliError::Persistence(error)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Usage(message)) => {
+            eprintln!("{message}\n\n{USAGE}");
+            ExitCode::from(2)
+        },
+        Err(CliError::Persistence(e)) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        },
+        // e.g. stdout closed by head
+        Err(CliError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(CliError::Io(e)) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), CliError> {
+    let [database, command, rest @ ..] = args.as_slice() else {
+        return Err(CliError::Usage("missing database or command".to_string()));
+    };
+    // opening would create a missing file
+    if !Path::new(database).is_file() {
+        return Err(CliError::Usage(format!("no database file at {database}")));
+    }
+    let connection = sqlite_::Connection::open_with_full_mutex(database).map_err(PersistenceError::from)?;
+    let connection = Arc::new(connection);
+    let mut out = io::stdout().lock();
+
+    match (command.as_str(), rest) {
+        ("tables", []) => {
+            for table in SqlitePersistence::new(connection, "").tables()? {
+                writeln!(out, "{table}")?;
+            }
+        },
+        ("schema", [table]) => {
+            let columns = SqlitePersistence::new(connection, table).table_columns()?;
+            if columns.is_empty() {
+                return Err(CliError::Usage(format!("no table named {table}")));
+            }
+            for (name, column_type) in columns {
+                writeln!(out, "{name}\t{column_type}")?;
+            }
+        },
+        ("dump", [table, options @ ..]) => dump(SqlitePersistence::new(connection, table), options, &mut out)?,
+        ("check", []) => {
+            let problems = SqlitePersistence::new(connection, "").integrity_check()?;
+            for problem in &problems {
+                writeln!(out, "{problem}")?;
+            }
+            if !problems.is_empty() {
+                return Err(PersistenceError::Backend { message: format!("{} problems found", problems.len()) }.into());
+            }
+            writeln!(out, "ok")?;
+        },
+        _ => return Err(CliError::Usage(format!("unknown command or wrong arguments: {}", args[1..].join(" "))))
+    }
+    Ok(())
+}
+
+fn dump(mut persistence: SqlitePersistence, options: &[String], out: &mut impl Write) -> Result<(), CliError> {
+    let (mut ndjson, mut filter, mut limit) = (false, None, None);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(||CliError::Usage(format!("{option} needs a value")))?;
+        match option.as_str() {
+            "--format" => ndjson = match value.as_str() {
+                "csv" => false,
+                "ndjson" => true,
+                _ => return Err(CliError::Usage(format!("unknown format {value}")))
+            },
+            "--where" => filter = Some(Query::parse(value).map_err(|e|CliError::Usage(e.to_string()))?),
+            "--limit" => limit = Some(value.parse().map_err(|_|CliError::Usage(format!("invalid limit {value}")))?),
+            "--tenant" => persistence = persistence.with_tenant(value),
+            _ => return Err(CliError::Usage(format!("unknown option {option}")))
+        }
+    }
+
+    let rows = persistence.raw_rows(filter.as_ref(), limit)?;
+    if ndjson {
+        for row in &rows {
+            writeln!(out, "{}", to_json(row))?;
+        }
+        return Ok(());
+    }
+    let header = persistence.table_columns()?.into_iter().map(|(name, _)|csv_field(&name)).collect::<Vec<_>>();
+    writeln!(out, "{}", header.join(","))?;
+    for row in &rows {
+        writeln!(out, "{}", row.iter().map(|(_, value)|value.as_ref().map(|v|csv_field(&to_text(v))).unwrap_or_default()).collect::<Vec<_>>().join(","))?;
+    }
+    Ok(())
+}
+
+// bytes as hex, the same way import_file reads them back
+fn to_text(value: &PersistenceData) -> String {
+    match value {
+        PersistenceData::String(s) => s.clone(),
+        PersistenceData::Bytes(b) => b.iter().map(|b|format!("{b:02x}")).collect(),
+        PersistenceData::Integer(i) => i.to_string(),
+        PersistenceData::UnsignedInteger(u) => u.to_string(),
+        PersistenceData::Float(f) => f.to_string(),
+        PersistenceData::Double(d) => d.to_string(),
+        #[cfg(feature = "decimal")]
+        PersistenceData::Decimal(d) => d.to_string()
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    match text.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", text.replace('"', "\"\"")),
+        false => text.to_string()
+    }
+}
+
+fn to_json(row: &RawRow) -> serde_json::Value {
+    row.iter().map(|(name, value)|{
+        let value = match value {
+            None => serde_json::Value::Null,
+            Some(PersistenceData::Integer(i)) => (*i).into(),
+            Some(PersistenceData::Double(d)) => (*d).into(),
+            Some(other) => to_text(other).into()
+        };
+        (name.clone(), value)
+    }).collect::<serde_json::Map<_, _>>().into()
+}
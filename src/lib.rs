@@ -3,8 +3,46 @@
 pub mod persistence_adapter {
     #[cfg(feature = "sqlite")]
     pub mod sqlite;
-
-    use std::{collections::HashMap, fmt::Display, rc::Rc};
+    #[cfg(feature = "serde")]
+    pub mod kv;
+    #[cfg(feature = "serde")]
+    pub mod event_log;
+    #[cfg(feature = "serde")]
+    pub mod config;
+    #[cfg(feature = "serde")]
+    pub mod cache;
+    #[cfg(feature = "serde")]
+    pub mod idempotency;
+    pub mod repository;
+    pub mod page;
+    pub mod access;
+    pub mod fault;
+    pub mod routed;
+    pub mod retention;
+    pub mod archiving;
+    pub mod cached_query;
+    pub mod dry_run;
+    pub mod diff;
+    pub mod key_lock;
+    pub mod supervisor;
+    pub mod layer;
+    pub mod clock;
+    pub mod keygen;
+    pub mod latency;
+    pub mod retry;
+    #[cfg(feature = "async-graphql")]
+    pub mod graphql;
+    #[cfg(feature = "axum")]
+    pub mod rest;
+    #[cfg(feature = "test-util")]
+    pub mod mock;
+    mod query_parse;
+    mod row;
+
+    pub use query_parse::QueryParseError;
+    pub use row::{FromPersistenceData, Row, RowError};
+
+    use std::{collections::HashMap, fmt::Display, sync::Arc, time::{Duration, SystemTime}};
 
     // Used for specifying data and how it should be stored
     #[allow(dead_code)]
@@ -14,7 +52,12 @@ pub mod persistence_adapter {
         Integer(&'static str),
         UnsignedInteger(&'static str),
         Float(&'static str),
-        Double(&'static str)
+        Double(&'static str),
+        #[cfg(feature = "decimal")]
+        Decimal(&'static str),
+        // a String stored as a salted argon2id hash, compare candidates with SqlitePersistence::verify
+        #[cfg(feature = "hashed")]
+        Hashed(&'static str)
     }
 
     impl PersistenceType {
@@ -26,18 +69,25 @@ pub mod persistence_adapter {
                 PersistenceType::UnsignedInteger(n) => n,
                 PersistenceType::Float(n) => n,
                 PersistenceType::Double(n) => n,
+                #[cfg(feature = "decimal")]
+                PersistenceType::Decimal(n) => n,
+                #[cfg(feature = "hashed")]
+                PersistenceType::Hashed(n) => n,
             }
         }
     }
 
     #[derive(Debug, Clone)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum PersistenceData{
         String(String),
         Bytes(Vec<u8>),
         Integer(i64),
         UnsignedInteger(u64),
         Float(f32),
-        Double(f64)
+        Double(f64),
+        #[cfg(feature = "decimal")]
+        Decimal(rust_decimal::Decimal)
     }
 
 
@@ -86,11 +136,35 @@ pub mod persistence_adapter {
             }
             None
         }
+
+        #[cfg(feature = "decimal")]
+        pub fn to_decimal(&self) -> Option<rust_decimal::Decimal> {
+            if let PersistenceData::Decimal(d) = self {
+                return Some(*d)
+            }
+            None
+        }
+
+        // by-value accessors, lets specs move strings and bytes out of a row instead of cloning them
+        pub fn into_string(self) -> Option<String> {
+            if let PersistenceData::String(s) = self {
+                return Some(s)
+            }
+            None
+        }
+
+        pub fn into_bytes(self) -> Option<Vec<u8>> {
+            if let PersistenceData::Bytes(b) = self {
+                return Some(b)
+            }
+            None
+        }
     }
 
     #[derive(Debug)]
     pub struct StoreError {
-        pub message: String
+        pub message: String,
+        pub kind: Option<PersistenceError> // the typed error the write failed with, when there is one
     }
 
     impl Display for StoreError {
@@ -101,52 +175,268 @@ pub mod persistence_adapter {
 
     impl std::error::Error for StoreError {}
 
+    // Why a spec couldn't serialize or deserialize a value, field is the field that caused it
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct SpecError {
+        pub field: String,
+        pub reason: String
+    }
+
+    impl SpecError {
+        pub fn new(field: &str, reason: &str) -> Self {
+            SpecError { field: field.to_string(), reason: reason.to_string() }
+        }
+
+        // for fields that are absent from the row or hold a different PersistenceData variant
+        pub fn missing(field: &str) -> Self {
+            SpecError::new(field, "missing or of the wrong type")
+        }
+    }
+
+    impl Display for SpecError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "field {}: {}", self.field, self.reason)
+        }
+    }
+
+    impl std::error::Error for SpecError {}
+
+    impl From<SpecError> for StoreError {
+        fn from(error: SpecError) -> Self {
+            StoreError { message: error.to_string(), kind: Some(error.into()) }
+        }
+    }
+
+    // keeps the error as kind, so callers can match on its variant
+    impl From<PersistenceError> for StoreError {
+        fn from(error: PersistenceError) -> Self {
+            StoreError { message: error.to_string(), kind: Some(error) }
+        }
+    }
+
+    impl StoreError {
+        // whether the write failed with PersistenceError::Busy and may work when retried
+        pub fn is_busy(&self) -> bool {
+            matches!(self.kind, Some(PersistenceError::Busy { .. }))
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum PersistenceError {
+        Backend { message: String },
+        Serialization { message: String },
+        FieldNotAllowed { field: String },
+        Spec { field: String, reason: String },
+        Corrupted { key: PersistenceData }, // stored checksum doesn't match the row, key is the serialized key
+        AccessDenied { key: PersistenceData }, // refused by an access::AccessPolicy, key is the serialized key
+        VersionConflict { stream: String, expected: u64, actual: u64 }, // an optimistic write found the stream at another version
+        // the backend refused a write that breaks a constraint, message is the backend's and names the columns
+        UniqueViolation { message: String },
+        NotNullViolation { message: String },
+        ForeignKeyViolation { message: String },
+        Busy { message: String }, // another connection holds the lock for longer than the busy timeout, retrying may work
+        ReadOnly { message: String } // the database or connection doesn't accept writes
+    }
+
+    impl From<SpecError> for PersistenceError {
+        fn from(error: SpecError) -> Self {
+            PersistenceError::Spec { field: error.field, reason: error.reason }
+        }
+    }
+
+    impl From<StoreError> for PersistenceError {
+        fn from(error: StoreError) -> Self {
+            error.kind.unwrap_or(PersistenceError::Backend { message: error.message })
+        }
+    }
+
+    impl Display for PersistenceError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{self:?}")
+        }
+    }
+
+    impl std::error::Error for PersistenceError {}
+
     // How data should be represented when stored
     pub trait PersistenceSpec<Key, Data>{
         fn fields()-> &'static [PersistenceType]; // all fields that should be present, including the primary key
         fn key_field() -> &'static str;
         fn serialize_key(key: &Key) -> PersistenceData;
         fn deserialize_key(key: &PersistenceData) -> Option<Key>;
-        fn serialize_data(data: &Data) -> Option<HashMap<&'static str, PersistenceData>>;
-        fn deserialize_data(data: HashMap<String, PersistenceData>) -> Option<Data>;
+        fn serialize_data(data: &Data) -> Result<HashMap<&'static str, PersistenceData>, SpecError>;
+        fn deserialize_data(data: HashMap<&'static str, PersistenceData>) -> Result<Data, SpecError>;
+        // fields whose values are encrypted at rest, see SqlitePersistence::with_encryption, which refuses to
+        // store them without a key rather than writing plaintext. They can't be used in query filters
+        fn sensitive_fields() -> &'static [&'static str] {
+            &[]
+        }
+        // The version of the layout serialize_data writes, kept with each row by adapters that store versions
+        // (see SqlitePersistence::with_versioning). Raise it when fields change meaning and add an upcaster for
+        // the old layout, so rows written by older code stay readable
+        fn version() -> u32 {
+            1
+        }
+        // upcasters()[0] turns version 1's fields into version 2's, upcasters()[1] version 2's into version 3's
+        // and so on
+        fn upcasters() -> &'static [Upcaster] {
+            &[]
+        }
+        // Brings fields stored at version up to version() before deserialize_data sees them, by running the
+        // upcasters in between. Override to handle old layouts another way
+        fn upcast(version: u32, data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), SpecError> {
+            if version > Self::version() {
+                return Err(SpecError::new(Self::key_field(), &format!("row is from version {version}, newer than {}", Self::version())));
+            }
+            let upcasters = Self::upcasters().get(version.max(1) as usize - 1..Self::version() as usize - 1)
+                .ok_or_else(||SpecError::new(Self::key_field(), &format!("no upcasters from version {version} to {}", Self::version())))?;
+            upcasters.iter().try_for_each(|upcaster|upcaster(data))
+        }
     }
 
-    // How to store and retrieve data
+    // Rewrites the fields of a row from one spec version into the next, see PersistenceSpec::upcasters
+    pub type Upcaster = fn(&mut HashMap<&'static str, PersistenceData>) -> Result<(), SpecError>;
+
+    // How to store and retrieve data. Adapters don't panic on any input: odd keys, field names, values or rows
+    // written by other tools come back as errors, or as None, false or no rows from the methods that can't
+    // return one, with the cause kept where the adapter has somewhere to keep it (e.g. SqlitePersistence's
+    // last error). Panics from the spec's own serialize and deserialize functions aren't caught
 
     pub trait PersistenceAdapter<Key, Data, Spec: PersistenceSpec<Key, Data>> {
         fn initialize(&self) -> Option<()>;
         fn load(&self, key: &Key) -> Option<Data>;
-        fn delete(&self, key: Key) -> Option<()>;
-        fn store(&self, key: Key, data: Data) -> Result<(), StoreError>;
+        fn delete(&self, key: &Key) -> Result<u64, PersistenceError>; // returns the number of deleted rows, 0 if the key wasn't present
+        fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError>;
         fn contains(&self, key: &Key) -> bool;
-        fn clear(&self);
+        fn clear(&self) -> Result<u64, PersistenceError>; // returns the number of deleted rows
         fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)>;
-        fn update(&self, key: &Key, data: Data, only_update: Option<&[&str]>) -> Result<(), StoreError>;
+        fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)>; // keys in [from, to), unbounded where None
+        // Overwrites the fields of an existing row, or only the fields named in only_update (the key field is ignored there).
+        // Never inserts: returns the number of updated rows, 0 if no row has this key. Errors if only_update names a field that isn't in the spec
+        fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError>;
+        fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError>; // sets only the given columns, they must be non-key spec fields
+        fn capabilities(&self) -> Capabilities;
+    }
+
+    // What an adapter supports beyond the PersistenceAdapter basics, for generic code that works across backends
+    #[derive(Debug, Clone, Copy, Default, PartialEq)]
+    pub struct Capabilities {
+        pub supports_query: bool, // implements PersistenceAdapterQueryable
+        pub supports_transactions: bool,
+        pub supports_ttl: bool, // rows can expire on their own
+        pub ordered_scan: bool, // scan and scan_range return rows in key order
+        pub max_blob_size: Option<u64> // largest Bytes/String value in bytes, None if unlimited
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct HealthReport {
+        pub latency: Duration, // round trip time of the probe
+        pub last_error: Option<String> // most recent error seen by the adapter, if any
+    }
+
+    // Connectivity probe, for readiness checks
+    pub trait PersistenceAdapterHealth {
+        fn health(&self) -> Result<HealthReport, PersistenceError>;
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct TableStats {
+        pub row_count: u64,
+        pub approx_size_bytes: Option<u64>, // table storage, None if the backend can't tell
+        pub index_sizes: Vec<(String, Option<u64>)>, // each index on the table with its approximate size in bytes
+        pub last_write: Option<SystemTime> // None if the backend doesn't record when the table was last written
+    }
+
+    // The spread of one column's values, for choosing a pagination strategy or filtering client side
+    #[derive(Debug, Clone, Default)]
+    pub struct FieldStats {
+        pub min: Option<PersistenceData>, // None if every value is null or the table is empty
+        pub max: Option<PersistenceData>,
+        pub distinct_estimate: u64, // distinct non-null values, backends may approximate
+        pub null_count: u64
+    }
+
+    // Size and growth numbers for dashboards, without raw SQL
+    pub trait PersistenceAdapterStats {
+        fn table_stats(&self) -> Result<TableStats, PersistenceError>;
+        fn analyze_field(&self, field: &str) -> Result<FieldStats, PersistenceError>; // FieldNotAllowed if the table has no such column
+    }
+
+    // Rows that expire on their own, each after its own ttl, unlike a table wide RetentionPolicy. An expired
+    // row reads as deleted until purge_expired removes it. Backends with native expiry (e.g. Redis) should
+    // map store_with_ttl to it and have little or nothing to purge
+    pub trait PersistenceAdapterTtl<Key, Data, Spec: PersistenceSpec<Key, Data>> {
+        fn store_with_ttl(&self, key: &Key, data: &Data, ttl: Duration) -> Result<(), StoreError>;
+        fn purge_expired(&self) -> Result<u64, PersistenceError>; // deletes the expired rows, returns how many
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum CasOutcome {
+        Stored,
+        ConditionFailed, // the row exists but didn't match the condition, nothing was written
+        Missing // there's no row with the key to compare against
+    }
+
+    // Writes that only happen while the stored row matches a condition, checked and applied in one atomic
+    // step, for optimistic concurrency and for taking over or releasing claims on a row
+    pub trait PersistenceAdapterConditional<Key, Data, Spec: PersistenceSpec<Key, Data>> {
+        fn store_if(&self, key: &Key, data: &Data, condition: Query) -> Result<CasOutcome, PersistenceError>;
+        fn delete_if(&self, key: &Key, condition: Query) -> Result<u64, PersistenceError>; // returns the number of deleted rows, 0 if there was no row or it didn't match
+    }
+
+    // Writes a row whether or not its key is stored already, replacing the stored row in one atomic step, so
+    // concurrent writers never see the key missing to update or taken to store
+    pub trait PersistenceAdapterUpsert<Key, Data, Spec: PersistenceSpec<Key, Data>> {
+        fn upsert(&self, key: &Key, data: &Data) -> Result<(), StoreError>;
     }
 
     pub trait PersistenceAdapterQueryable<Key, Data, Spec: PersistenceSpec<Key, Data>> {
         fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)>;
+        fn clear_where(&self, query: Query) -> Result<u64, PersistenceError>; // returns the number of deleted rows
     }
 
-    #[derive(Clone)]
+    #[derive(Debug, Clone)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum Query {
-        Or(Rc<Query>, Rc<Query>),
-        And(Rc<Query>, Rc<Query>),
-        Not(Rc<Query>),
+        Or(Arc<Query>, Arc<Query>),
+        And(Arc<Query>, Arc<Query>),
+        Not(Arc<Query>),
         Equals(String, PersistenceData),
         GreaterThan(String, PersistenceData),
         LessThan(String, PersistenceData)
     }
 
     impl Query {
-        fn or(a: Self, b: Self) -> Self {
-            Query::Or(Rc::new(a), Rc::new(b))
+        pub fn or(a: Self, b: Self) -> Self {
+            Query::Or(Arc::new(a), Arc::new(b))
         }
-        fn and(a: Self, b: Self) -> Self {
-            Query::And(Rc::new(a), Rc::new(b))
+        pub fn and(a: Self, b: Self) -> Self {
+            Query::And(Arc::new(a), Arc::new(b))
         }
-        fn not(a: Self) -> Self {
-            Query::Not(Rc::new(a))
+        #[allow(clippy::should_implement_trait)]
+        pub fn not(a: Self) -> Self {
+            Query::Not(Arc::new(a))
+        }
+
+        // every field name the query filters on, in the order they appear
+        pub fn field_names(&self) -> Vec<&str> {
+            match self {
+                Query::Or(a, b) | Query::And(a, b) => {
+                    let mut names = a.field_names();
+                    names.extend(b.field_names());
+                    names
+                },
+                Query::Not(a) => a.field_names(),
+                Query::Equals(name, _) | Query::GreaterThan(name, _) | Query::LessThan(name, _) => vec![name.as_str()]
+            }
+        }
+
+        // Rejects queries filtering on fields outside of allowed, use before running queries that came from untrusted input
+        pub fn validate_fields(&self, allowed: &[&str]) -> Result<(), PersistenceError> {
+            match self.field_names().into_iter().find(|name|!allowed.contains(name)) {
+                Some(name) => Err(PersistenceError::FieldNotAllowed { field: name.to_string() }),
+                None => Ok(())
+            }
         }
     }
 }
@@ -155,7 +445,7 @@ pub mod persistence_adapter {
 pub(crate) mod tests{
     use std::collections::HashMap;
 
-    use crate::persistence_adapter::{PersistenceData, PersistenceSpec, PersistenceType};
+    use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, Query, Row, SpecError};
 
     #[derive(Clone, PartialEq, Debug)]
     pub(crate) struct AllSupportedTypes {
@@ -192,8 +482,8 @@ pub(crate) mod tests{
             PersistenceData::String(key.clone())
         }
 
-        fn serialize_data(data: &AllSupportedTypes) -> Option<std::collections::HashMap<&'static str, crate::persistence_adapter::PersistenceData>> {
-            Some(HashMap::from(
+        fn serialize_data(data: &AllSupportedTypes) -> Result<std::collections::HashMap<&'static str, crate::persistence_adapter::PersistenceData>, SpecError> {
+            Ok(HashMap::from(
                 [
                     ("string", PersistenceData::String(data.string.clone())),
                     ("bytes", PersistenceData::Bytes(data.bytes.clone())),
@@ -205,15 +495,16 @@ pub(crate) mod tests{
             ))
         }
 
-        fn deserialize_data(data: std::collections::HashMap<String, crate::persistence_adapter::PersistenceData>) -> Option<AllSupportedTypes> {
-            Some(
+        fn deserialize_data(data: std::collections::HashMap<&'static str, crate::persistence_adapter::PersistenceData>) -> Result<AllSupportedTypes, SpecError> {
+            let mut row = Row::from(data);
+            Ok(
                 AllSupportedTypes{
-                    string: data.get("string").and_then(PersistenceData::to_str)?.to_string(),
-                    bytes: data.get("bytes").and_then(PersistenceData::to_bytes)?.to_vec(),
-                    integer: data.get("integer").and_then(PersistenceData::to_int)?,
-                    unsigned_integer: data.get("unsigned_integer").and_then(PersistenceData::to_unsigned_int)?,
-                    float: data.get("float").and_then(PersistenceData::to_float)?,
-                    double: data.get("double").and_then(PersistenceData::to_double)?,
+                    string: row.take("string")?,
+                    bytes: row.take("bytes")?,
+                    integer: row.get("integer")?,
+                    unsigned_integer: row.get("unsigned_integer")?,
+                    float: row.get("float")?,
+                    double: row.get("double")?,
                 }
             )
         }
@@ -227,6 +518,35 @@ pub(crate) mod tests{
          }
     }
 
+    #[cfg(any(feature = "sqlite", feature = "test-util"))]
+    impl AllSupportedTypes {
+        // a row told apart from others by integer alone
+        pub(crate) fn with_integer(integer: i64) -> Self {
+            AllSupportedTypes { string: "s".to_string(), bytes: vec![1], integer, unsigned_integer: 1, float: 1.0, double: 1.0 }
+        }
+    }
+
+    // A connection to a new database file. The file goes away with the returned TempDir, keep it alive as
+    // long as the connection
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn sqlite_connection() -> (tempdir::TempDir, std::sync::Arc<sqlite_::ConnectionWithFullMutex>) {
+        let temp_dir = tempdir::TempDir::new("sqlite_test").expect("Failed to create tempdir");
+
+        let db_connection = sqlite_::Connection::open_with_full_mutex(temp_dir.path().join("test.sqlite")).expect("Failed to open temp db");
+        (temp_dir, std::sync::Arc::new(db_connection))
+    }
+
+    // An initialized SqlitePersistence on "test_table" in a new database file, see sqlite_connection
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn sqlite_persistence() -> (tempdir::TempDir, crate::persistence_adapter::sqlite::SqlitePersistence) {
+        use crate::persistence_adapter::{sqlite::SqlitePersistence, PersistenceAdapter};
+
+        let (temp_dir, db_connection) = sqlite_connection();
+        let persistence = SqlitePersistence::new(db_connection, "test_table");
+        PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::initialize(&persistence);
+        (temp_dir, persistence)
+    }
+
     #[test]
     fn test_all_supported_types_eq() {
         let a = AllSupportedTypes{
@@ -258,4 +578,62 @@ pub(crate) mod tests{
         assert_eq!(a, b);
         assert_ne!(a, c);
     }
+
+    #[test]
+    fn test_all_supported_types_spec_round_trip() {
+        let a = AllSupportedTypes{
+            string: "ABCDEFGHIJKLMNOPQRSTUVWXYZ✔️".to_string(),
+            bytes: vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 255],
+            integer: i64::MIN,
+            unsigned_integer: u64::MAX,
+            float: 1.5,
+            double: f64::MAX
+        };
+
+        assert!(AllSupportedTypesPersistenceSpec::fields().iter().any(|f|f.get_name() == AllSupportedTypesPersistenceSpec::key_field()));
+
+        let mut serialized = AllSupportedTypesPersistenceSpec::serialize_data(&a).expect("Failed to serialize");
+        assert_eq!(AllSupportedTypesPersistenceSpec::deserialize_data(serialized.clone()), Ok(a));
+
+        serialized.insert("integer", PersistenceData::String("1".to_string()));
+        let error = AllSupportedTypesPersistenceSpec::deserialize_data(serialized).expect_err("Deserialize should fail");
+        assert_eq!(error, SpecError::new("integer", "is String, expected Integer"));
+        assert!(matches!(PersistenceError::from(error), PersistenceError::Spec { field, .. } if field == "integer"));
+    }
+
+    #[test]
+    fn test_query_validate_fields() {
+        let query = Query::or(
+            Query::Equals("string".to_string(), PersistenceData::String("hello!".to_string())),
+            Query::not(Query::LessThan("integer".to_string(), PersistenceData::Integer(10)))
+        );
+
+        assert_eq!(query.field_names(), vec!["string", "integer"]);
+        assert!(query.validate_fields(&["string", "integer", "double"]).is_ok());
+        assert!(matches!(query.validate_fields(&["string"]), Err(PersistenceError::FieldNotAllowed { field }) if field == "integer"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_query_serde_round_trip() {
+        let query = Query::and(
+            Query::Equals("bytes".to_string(), PersistenceData::Bytes(vec![0, 255])),
+            Query::not(Query::GreaterThan("unsigned_integer".to_string(), PersistenceData::UnsignedInteger(u64::MAX)))
+        );
+
+        let json = serde_json::to_string(&query).expect("Failed to serialize query");
+        let parsed: Query = serde_json::from_str(&json).expect("Failed to deserialize query");
+        assert_eq!(serde_json::to_string(&parsed).expect("Failed to serialize query"), json);
+        assert_eq!(parsed.field_names(), vec!["bytes", "unsigned_integer"]);
+    }
+
+    #[test]
+    fn test_query_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>(_: T) {}
+
+        assert_send_sync(Query::and(
+            Query::Equals("string".to_string(), PersistenceData::String("hello!".to_string())),
+            Query::not(Query::GreaterThan("integer".to_string(), PersistenceData::Integer(10)))
+        ));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+
+// Decides which rows a caller may see and change, e.g. only rows whose tenant field matches the caller's tenant
+pub trait AccessPolicy<Key, Data> {
+    type Context; // who is calling, given to PolicyEnforcedPersistence::new
+    fn can_read(&self, context: &Self::Context, key: &Key, data: &Data) -> bool;
+    fn can_write(&self, context: &Self::Context, key: &Key, data: &Data) -> bool; // called with both the stored row and the row as it will be written
+}
+
+// Wraps an adapter so every call goes through an AccessPolicy for one caller. Rows the caller can't read
+// are treated as absent: load returns None and scans and queries leave them out, so pages can come back
+// shorter than limit. Writes touching a row the caller can't write fail with PersistenceError::AccessDenied,
+// clear and clear_where only delete the rows the caller can write. Checks read the row before writing it,
+// so they aren't atomic with the write
+pub struct PolicyEnforcedPersistence<A, P, C> {
+    adapter: A,
+    policy: P,
+    context: C
+}
+
+impl<A, P, C> PolicyEnforcedPersistence<A, P, C> {
+    pub fn new(adapter: A, policy: P, context: C) -> Self {
+        PolicyEnforcedPersistence { adapter, policy, context }
+    }
+
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+
+    pub fn into_inner(self) -> A {
+        self.adapter
+    }
+
+    fn check_write<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, data: &Data) -> Result<(), PersistenceError> where P: AccessPolicy<Key, Data, Context = C> {
+        match self.policy.can_write(&self.context, key, data) {
+            true => Ok(()),
+            false => Err(PersistenceError::AccessDenied { key: Spec::serialize_key(key) })
+        }
+    }
+
+    // the stored row must be writable, if there is one
+    fn check_existing<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key) -> Result<Option<Data>, PersistenceError> where A: PersistenceAdapter<Key, Data, Spec>, P: AccessPolicy<Key, Data, Context = C> {
+        let existing = self.adapter.load(key);
+        if let Some(existing) = &existing {
+            self.check_write::<Key, Data, Spec>(key, existing)?;
+        }
+        Ok(existing)
+    }
+
+    fn readable<Key, Data>(&self, rows: Vec<(Key, Data)>) -> Vec<(Key, Data)> where P: AccessPolicy<Key, Data, Context = C> {
+        rows.into_iter().filter(|(key, data)|self.policy.can_read(&self.context, key, data)).collect()
+    }
+
+    fn delete_writable<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, rows: Vec<(Key, Data)>) -> Result<u64, PersistenceError> where A: PersistenceAdapter<Key, Data, Spec>, P: AccessPolicy<Key, Data, Context = C> {
+        let mut deleted = 0;
+        for (key, data) in rows {
+            if self.policy.can_write(&self.context, &key, &data) {
+                deleted += self.adapter.delete(&key)?;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>, P: AccessPolicy<Key, Data, Context = C>, C> PersistenceAdapter<Key, Data, Spec> for PolicyEnforcedPersistence<A, P, C> {
+    fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.adapter.load(key).filter(|data|self.policy.can_read(&self.context, key, data))
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        self.check_existing::<Key, Data, Spec>(key)?;
+        self.adapter.delete(key)
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.check_write::<Key, Data, Spec>(key, data).map_err(StoreError::from)?;
+        self.adapter.store(key, data)
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        PersistenceAdapter::<Key, Data, Spec>::load(self, key).is_some()
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        self.delete_writable::<Key, Data, Spec>(self.adapter.scan(0, None))
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.readable(self.adapter.scan(start, limit))
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.readable(self.adapter.scan_range(from, to, limit))
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        let checked = self.check_existing::<Key, Data, Spec>(key).and_then(|_|self.check_write::<Key, Data, Spec>(key, data));
+        checked.map_err(StoreError::from)?;
+        self.adapter.update(key, data, only_update)
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        let Some(existing) = self.check_existing::<Key, Data, Spec>(key)? else {
+            return Ok(0);
+        };
+        // the row as it will be after the patch, so a patch can't move a row out of the caller's reach
+        let mut patched = Spec::serialize_data(&existing)?;
+        patched.insert(Spec::key_field(), Spec::serialize_key(key));
+        patched.extend(changes.iter().map(|(field, value)|(*field, value.clone())));
+        self.check_write::<Key, Data, Spec>(key, &Spec::deserialize_data(patched)?)?;
+        self.adapter.patch(key, changes)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.adapter.capabilities()
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec>, P: AccessPolicy<Key, Data, Context = C>, C> PersistenceAdapterQueryable<Key, Data, Spec> for PolicyEnforcedPersistence<A, P, C> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.readable(self.adapter.query(query, start, limit))
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.delete_writable::<Key, Data, Spec>(self.adapter.query(query, 0, None))
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError};
+    use crate::persistence_adapter::access::{AccessPolicy, PolicyEnforcedPersistence};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    // the integer field holds the row's tenant
+    struct TenantPolicy {}
+
+    impl AccessPolicy<String, AllSupportedTypes> for TenantPolicy {
+        type Context = i64;
+
+        fn can_read(&self, tenant: &i64, _key: &String, data: &AllSupportedTypes) -> bool {
+            data.integer == *tenant
+        }
+
+        fn can_write(&self, tenant: &i64, _key: &String, data: &AllSupportedTypes) -> bool {
+            data.integer == *tenant
+        }
+    }
+
+    #[test]
+    fn test_policy_enforced() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+        let tenant = |tenant: i64|PolicyEnforcedPersistence::new(SqlitePersistence::new(db_connection.clone(), "test_table"), TenantPolicy {}, tenant);
+        let (one, two) = (tenant(1), tenant(2));
+        let adapter_one: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &one;
+        let adapter_two: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &two;
+        adapter_one.initialize();
+
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(adapter_one.store(&"a".to_string(), &entry).is_ok());
+        assert!(adapter_one.store(&"b".to_string(), &entry).is_ok());
+        assert!(adapter_two.store(&"c".to_string(), &AllSupportedTypes { integer: 2, ..entry.clone() }).is_ok());
+        assert!(adapter_two.store(&"d".to_string(), &entry).is_err_and(|e|matches!(e.kind, Some(PersistenceError::AccessDenied { .. }))));
+
+        assert_eq!(adapter_one.scan(0, None).len(), 2);
+        assert!(adapter_two.load(&"a".to_string()).is_none());
+        assert!(!adapter_two.contains(&"a".to_string()));
+        assert!(matches!(adapter_two.delete(&"a".to_string()), Err(PersistenceError::AccessDenied { .. })));
+        assert!(adapter_two.update(&"a".to_string(), &AllSupportedTypes { integer: 2, ..entry.clone() }, None).is_err_and(|e|matches!(e.kind, Some(PersistenceError::AccessDenied { .. }))));
+        assert!(matches!(adapter_one.patch(&"b".to_string(), HashMap::from([("integer", PersistenceData::Integer(2))])), Err(PersistenceError::AccessDenied { .. })));
+        assert_eq!(adapter_one.patch(&"b".to_string(), HashMap::from([("string", PersistenceData::String("patched".to_string()))])).ok(), Some(1));
+
+        assert_eq!(adapter_two.clear().ok(), Some(1));
+        assert_eq!(adapter_one.scan(0, None).len(), 2);
+    }
+}
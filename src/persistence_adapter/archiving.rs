@@ -0,0 +1,160 @@
+use std::{collections::HashMap, sync::Arc};
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+use crate::persistence_adapter::clock::{Clock, SystemClock};
+use crate::persistence_adapter::retention::RetentionPolicy;
+
+// A hot and a cold tier of the same table, e.g. a local sqlite database in front of cheaper bulk storage.
+// New rows go to hot, archive moves the rows beyond policy to cold. Reads and writes by key go to whichever
+// tier has the row, so callers don't need to know where it lives. Scans and queries only cover the hot tier,
+// cold backends often can't list or filter cheaply
+pub struct ArchivingPersistence<H, C> {
+    hot: H,
+    cold: C,
+    policy: RetentionPolicy,
+    clock: Arc<dyn Clock>
+}
+
+impl<H, C> ArchivingPersistence<H, C> {
+    pub fn new(hot: H, cold: C, policy: RetentionPolicy) -> Self {
+        ArchivingPersistence { hot, cold, policy, clock: Arc::new(SystemClock {}) }
+    }
+
+    // the clock row ages are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn hot(&self) -> &H {
+        &self.hot
+    }
+
+    pub fn cold(&self) -> &C {
+        &self.cold
+    }
+
+    // Moves the hot rows beyond the policy to the cold tier, returns the number moved. Each row is written to
+    // cold before it's deleted from hot, a row both tiers have after an interrupted run is moved again by the next
+    pub fn archive<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> Result<u64, PersistenceError> where H: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec>, C: PersistenceAdapter<Key, Data, Spec> {
+        let mut moved = 0;
+        for (key, data) in self.policy.expired(&self.hot, self.clock.now_millis())? {
+            if self.cold.update(&key, &data, None)? == 0 {
+                self.cold.store(&key, &data)?;
+            }
+            moved += self.hot.delete(&key)?;
+        }
+        Ok(moved)
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, H: PersistenceAdapter<Key, Data, Spec>, C: PersistenceAdapter<Key, Data, Spec>> PersistenceAdapter<Key, Data, Spec> for ArchivingPersistence<H, C> {
+    fn initialize(&self) -> Option<()> {
+        self.hot.initialize()?;
+        self.cold.initialize()
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.hot.load(key).or_else(||self.cold.load(key))
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        Ok(self.hot.delete(key)? + self.cold.delete(key)?)
+    }
+
+    // fails like a single table would if the key was already archived
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        if self.cold.contains(key) {
+            return Err(PersistenceError::UniqueViolation { message: "The key already exists in the cold tier".to_string() }.into());
+        }
+        self.hot.store(key, data)
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.hot.contains(key) || self.cold.contains(key)
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        Ok(self.hot.clear()? + self.cold.clear()?)
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.hot.scan(start, limit)
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.hot.scan_range(from, to, limit)
+    }
+
+    // archived rows are updated in the cold tier, where they stay
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        match self.hot.update(key, data, only_update)? {
+            0 => self.cold.update(key, data, only_update),
+            updated => Ok(updated)
+        }
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        match self.hot.patch(key, changes.clone())? {
+            0 => self.cold.patch(key, changes),
+            patched => Ok(patched)
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.hot.capabilities()
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, H: PersistenceAdapterQueryable<Key, Data, Spec>, C> PersistenceAdapterQueryable<Key, Data, Spec> for ArchivingPersistence<H, C> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.hot.query(query, start, limit)
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.hot.clear_where(query)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+    use sqlite_::Connection;
+    use tempdir::TempDir;
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::archiving::ArchivingPersistence;
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::retention::RetentionPolicy;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_archiving_persistence() {
+        let temp_dir = TempDir::new("sqlite_test").expect("Failed to create tempdir");
+
+        let open = |name: &str|SqlitePersistence::new(Arc::new(Connection::open_with_full_mutex(temp_dir.path().join(name)).expect("Failed to open temp db")), "test_table");
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(10)));
+        let tiers = ArchivingPersistence::new(open("hot.sqlite"), open("cold.sqlite"), RetentionPolicy::max_age("integer", Duration::from_secs(5))).with_clock(clock.clone());
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &tiers;
+        assert!(adapter.initialize().is_some());
+
+        // "integer" is when the row was written
+        let row = |at: i64|AllSupportedTypes::with_integer(at * 1_000);
+        for at in [1, 2, 8] {
+            assert!(adapter.store(&at.to_string(), &row(at)).is_ok());
+        }
+        assert_eq!(tiers.archive::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>().ok(), Some(2));
+        assert_eq!(adapter.scan(0, None).len(), 1);
+        assert_eq!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::scan(tiers.cold(), 0, None).len(), 2);
+
+        // archived rows are still there by key
+        assert_eq!(adapter.load(&"1".to_string()), Some(row(1)));
+        assert!(adapter.contains(&"2".to_string()));
+        assert!(adapter.store(&"1".to_string(), &row(9)).is_err());
+        let mut changed = row(1);
+        changed.string = "changed".to_string();
+        assert!(adapter.update(&"1".to_string(), &changed, None).is_ok_and(|n|n == 1));
+        assert_eq!(adapter.load(&"1".to_string()), Some(changed));
+        assert_eq!(adapter.delete(&"2".to_string()).ok(), Some(1));
+        assert!(!adapter.contains(&"2".to_string()));
+    }
+}
@@ -0,0 +1,299 @@
+use std::{collections::{HashMap, HashSet}, marker::PhantomData, sync::{Arc, Mutex}, thread, time::Duration};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterUpsert, PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, SpecError};
+use crate::persistence_adapter::clock::{duration_millis, Clock, SystemClock};
+
+const CACHE_FIELDS: [PersistenceType; 4] = [
+    PersistenceType::String("key"),
+    PersistenceType::Bytes("value"),
+    PersistenceType::Integer("expires_at"),
+    PersistenceType::Integer("accessed_at")
+];
+
+// a cached value as JSON, with the times it goes stale and was last read in unix milliseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub value: Vec<u8>,
+    pub expires_at: i64,
+    pub accessed_at: i64
+}
+
+// Built-in spec for PersistentCache
+pub struct CacheSpec {}
+
+impl PersistenceSpec<String, CacheEntry> for CacheSpec {
+    fn fields() -> &'static [PersistenceType] {
+        &CACHE_FIELDS
+    }
+
+    fn key_field() -> &'static str {
+        "key"
+    }
+
+    fn serialize_key(key: &String) -> PersistenceData {
+        PersistenceData::String(key.clone())
+    }
+
+    fn deserialize_key(key: &PersistenceData) -> Option<String> {
+        key.to_str().map(str::to_string)
+    }
+
+    fn serialize_data(data: &CacheEntry) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+        Ok(HashMap::from([
+            ("value", PersistenceData::Bytes(data.value.clone())),
+            ("expires_at", PersistenceData::Integer(data.expires_at)),
+            ("accessed_at", PersistenceData::Integer(data.accessed_at))
+        ]))
+    }
+
+    fn deserialize_data(mut data: HashMap<&'static str, PersistenceData>) -> Result<CacheEntry, SpecError> {
+        Ok(CacheEntry {
+            value: data.remove("value").and_then(PersistenceData::into_bytes).ok_or_else(||SpecError::missing("value"))?,
+            expires_at: data.get("expires_at").and_then(PersistenceData::to_int).ok_or_else(||SpecError::missing("expires_at"))?,
+            accessed_at: data.get("accessed_at").and_then(PersistenceData::to_int).ok_or_else(||SpecError::missing("accessed_at"))?
+        })
+    }
+}
+
+// A cache of serde values on top of any adapter using CacheSpec, keys are stored as their JSON. Entries are
+// fresh for the ttl, then stale for the stale_while_revalidate window: get_or_refresh still returns a stale
+// value but refreshes it on a background thread. With max_entries the least recently read entries are
+// evicted when a put goes over the limit, which scans the whole table, so keep it to caches of thousands
+// of entries
+pub struct PersistentCache<K, V, A: PersistenceAdapter<String, CacheEntry, CacheSpec> + PersistenceAdapterUpsert<String, CacheEntry, CacheSpec>> {
+    adapter: Arc<A>,
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+    max_entries: Option<usize>,
+    clock: Arc<dyn Clock>,
+    refreshing: Arc<Mutex<HashSet<String>>>, // keys with a background refresh running
+    _entry: PhantomData<fn(K) -> V>
+}
+
+impl<K: Serialize, V: Serialize + DeserializeOwned, A: PersistenceAdapter<String, CacheEntry, CacheSpec> + PersistenceAdapterUpsert<String, CacheEntry, CacheSpec>> PersistentCache<K, V, A> {
+    pub fn new(adapter: A, ttl: Duration) -> Self {
+        PersistentCache { adapter: Arc::new(adapter), ttl, stale_while_revalidate: Duration::ZERO, max_entries: None, clock: Arc::new(SystemClock {}), refreshing: Arc::new(Mutex::new(HashSet::new())), _entry: PhantomData }
+    }
+
+    pub fn with_stale_while_revalidate(mut self, window: Duration) -> Self {
+        self.stale_while_revalidate = window;
+        self
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    // the clock ttls are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    // the value if it's still fresh
+    pub fn get(&self, key: &K) -> Result<Option<V>, PersistenceError> {
+        let key = cache_key(key)?;
+        match self.adapter.load(&key) {
+            Some(entry) if entry.expires_at > self.clock.now_millis() => self.read(&key, entry).map(Some),
+            _ => Ok(None)
+        }
+    }
+
+    // inserts or replaces the value, fresh for the ttl from now
+    pub fn put(&self, key: &K, value: &V) -> Result<(), PersistenceError> {
+        put(self.adapter.as_ref(), self.clock.as_ref(), self.ttl, self.max_entries, &cache_key(key)?, value)
+    }
+
+    // returns whether there was an entry to remove
+    pub fn invalidate(&self, key: &K) -> Result<bool, PersistenceError> {
+        Ok(self.adapter.delete(&cache_key(key)?)? > 0)
+    }
+
+    // Deletes the entries past their stale window, returns the number deleted
+    pub fn purge_expired(&self) -> Result<u64, PersistenceError> {
+        let cutoff = self.clock.now_millis().saturating_sub(duration_millis(self.stale_while_revalidate));
+        let mut purged = 0;
+        for (key, _) in self.adapter.scan(0, None).into_iter().filter(|(_, entry)|entry.expires_at <= cutoff) {
+            purged += self.adapter.delete(&key)?;
+        }
+        Ok(purged)
+    }
+
+    fn read(&self, key: &String, entry: CacheEntry) -> Result<V, PersistenceError> {
+        if self.max_entries.is_some() {
+            // only eviction needs the read time, otherwise reads don't write
+            self.adapter.patch(key, HashMap::from([("accessed_at", PersistenceData::Integer(self.clock.now_millis()))]))?;
+        }
+        serde_json::from_slice(&entry.value).map_err(|e|PersistenceError::Serialization{message: e.to_string()})
+    }
+}
+
+impl<K: Serialize, V: Serialize + DeserializeOwned + Send + 'static, A: PersistenceAdapter<String, CacheEntry, CacheSpec> + PersistenceAdapterUpsert<String, CacheEntry, CacheSpec> + Send + Sync + 'static> PersistentCache<K, V, A> {
+    // The cached value if it's fresh. A stale value is returned as well and refreshed in the background, one
+    // refresh per key at a time, a failed background refresh keeps the stale value. Missing or expired values
+    // are refreshed before returning. Errors of the cache itself are converted into the refresher's error type
+    pub fn get_or_refresh<E: From<PersistenceError>>(&self, key: &K, refresher: impl FnOnce() -> Result<V, E> + Send + 'static) -> Result<V, E> {
+        let key = cache_key(key)?;
+        let now = self.clock.now_millis();
+        match self.adapter.load(&key) {
+            Some(entry) if entry.expires_at > now => return Ok(self.read(&key, entry)?),
+            Some(entry) if entry.expires_at.saturating_add(duration_millis(self.stale_while_revalidate)) > now => {
+                let value = self.read(&key, entry)?;
+                self.refresh_in_background(key, refresher);
+                return Ok(value);
+            },
+            _ => {}
+        }
+        let value = refresher()?;
+        put(self.adapter.as_ref(), self.clock.as_ref(), self.ttl, self.max_entries, &key, &value)?;
+        Ok(value)
+    }
+
+    fn refresh_in_background<E>(&self, key: String, refresher: impl FnOnce() -> Result<V, E> + Send + 'static) {
+        if !self.refreshing.lock().unwrap_or_else(|e|e.into_inner()).insert(key.clone()) {
+            return;
+        }
+        let (adapter, clock, refreshing) = (self.adapter.clone(), self.clock.clone(), self.refreshing.clone());
+        let (ttl, max_entries) = (self.ttl, self.max_entries);
+        thread::spawn(move ||{
+            if let Ok(value) = refresher() {
+                let _ = put(adapter.as_ref(), clock.as_ref(), ttl, max_entries, &key, &value);
+            }
+            refreshing.lock().unwrap_or_else(|e|e.into_inner()).remove(&key);
+        });
+    }
+}
+
+fn cache_key<K: Serialize>(key: &K) -> Result<String, PersistenceError> {
+    serde_json::to_string(key).map_err(|e|PersistenceError::Serialization{message: e.to_string()})
+}
+
+// shared with the background refresh, which doesn't hold the cache
+fn put<V: Serialize, A: PersistenceAdapter<String, CacheEntry, CacheSpec> + PersistenceAdapterUpsert<String, CacheEntry, CacheSpec>>(adapter: &A, clock: &dyn Clock, ttl: Duration, max_entries: Option<usize>, key: &String, value: &V) -> Result<(), PersistenceError> {
+    let now = clock.now_millis();
+    let entry = CacheEntry {
+        value: serde_json::to_vec(value).map_err(|e|PersistenceError::Serialization{message: e.to_string()})?,
+        expires_at: now.saturating_add(duration_millis(ttl)),
+        accessed_at: now
+    };
+    adapter.upsert(key, &entry)?;
+    let Some(max_entries) = max_entries else {
+        return Ok(());
+    };
+    let mut entries = adapter.scan(0, None);
+    if entries.len() > max_entries {
+        entries.sort_by_key(|(_, entry)|entry.accessed_at);
+        for (evicted, _) in entries.iter().take(entries.len() - max_entries) {
+            adapter.delete(evicted)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{sync::Arc, thread::{self, sleep}, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::PersistenceError;
+    use crate::persistence_adapter::cache::PersistentCache;
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::sqlite_connection;
+
+    #[test]
+    fn test_cache() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let cache = PersistentCache::<String, u32, _>::new(SqlitePersistence::new(db_connection, "cache"), Duration::from_secs(10))
+            .with_stale_while_revalidate(Duration::from_secs(5))
+            .with_max_entries(2)
+            .with_clock(clock.clone());
+        assert!(cache.initialize().is_some());
+        let key = |s: &str|s.to_string();
+
+        assert_eq!(cache.get_or_refresh(&key("a"), ||Ok::<_, PersistenceError>(1)).ok(), Some(1));
+        assert_eq!(cache.get_or_refresh(&key("a"), ||Err(PersistenceError::Backend { message: "not called".to_string() })).ok(), Some(1));
+
+        // stale: the old value now, the refreshed one once the background refresh stored it
+        clock.advance(Duration::from_secs(12));
+        assert_eq!(cache.get(&key("a")).ok(), Some(None));
+        assert_eq!(cache.get_or_refresh(&key("a"), ||Ok::<_, PersistenceError>(2)).ok(), Some(1));
+        for _ in 0..100 {
+            if cache.get(&key("a")).is_ok_and(|v|v == Some(2)) {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(cache.get(&key("a")).ok(), Some(Some(2)));
+
+        // expired past the stale window: refreshed before returning
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(cache.get_or_refresh(&key("a"), ||Ok::<_, PersistenceError>(3)).ok(), Some(3));
+
+        // b is read after c was written, so c is the least recently read when d goes over the limit
+        assert!(cache.put(&key("b"), &4).is_ok());
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.put(&key("c"), &5).is_ok());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get(&key("b")).ok(), Some(Some(4)));
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.put(&key("d"), &6).is_ok());
+        assert_eq!(cache.get(&key("c")).ok(), Some(None));
+        assert_eq!(cache.get(&key("b")).ok(), Some(Some(4)));
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(cache.purge_expired().ok(), Some(2));
+        assert!(cache.invalidate(&key("b")).is_ok_and(|removed|!removed));
+    }
+
+    #[test]
+    fn test_cache_concurrent_put() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let cache = Arc::new(PersistentCache::<String, u32, _>::new(SqlitePersistence::new(db_connection, "cache"), Duration::from_secs(10)));
+        assert!(cache.initialize().is_some());
+
+        // every writer may find the key missing, none of them may fail on the others' rows
+        for round in 0..200 {
+            let key = format!("key{round}");
+            let writers = (0..8u32).map(|i|{
+                let (cache, key) = (cache.clone(), key.clone());
+                thread::spawn(move ||cache.put(&key, &i))
+            }).collect::<Vec<_>>();
+            for writer in writers {
+                assert!(writer.join().expect("Writer panicked").is_ok());
+            }
+            assert!(cache.get(&key).is_ok_and(|v|v.is_some_and(|v|v < 8)));
+        }
+    }
+
+    #[test]
+    fn test_cache_huge_durations() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let cache = PersistentCache::<String, u32, _>::new(SqlitePersistence::new(db_connection.clone(), "forever"), Duration::MAX).with_clock(clock.clone());
+        assert!(cache.initialize().is_some());
+
+        // a ttl past the end of time keeps the entry fresh for ever rather than wrapping into the past
+        assert!(cache.put(&"a".to_string(), &1).is_ok());
+        clock.advance(Duration::from_secs(1_000_000));
+        assert_eq!(cache.get(&"a".to_string()).ok(), Some(Some(1)));
+        assert_eq!(cache.purge_expired().ok(), Some(0));
+
+        // likewise a stale window past the end of time serves the stale value and never purges it
+        let stale = PersistentCache::<String, u32, _>::new(SqlitePersistence::new(db_connection, "stale"), Duration::from_secs(10))
+            .with_stale_while_revalidate(Duration::MAX)
+            .with_clock(clock.clone());
+        assert!(stale.initialize().is_some());
+        assert!(stale.put(&"a".to_string(), &1).is_ok());
+        clock.advance(Duration::from_secs(1_000_000));
+        assert_eq!(stale.purge_expired().ok(), Some(0));
+        assert_eq!(stale.get_or_refresh(&"a".to_string(), ||Ok::<_, PersistenceError>(2)).ok(), Some(1));
+    }
+}
@@ -0,0 +1,205 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Mutex, atomic::{AtomicU64, Ordering}}};
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+
+// The same filter written in any order gets the same fingerprint: the operands of nested Ands and Ors are
+// flattened and sorted
+fn fingerprint(query: &Query) -> String {
+    fn operands<'a>(query: &'a Query, and: bool, out: &mut Vec<&'a Query>) {
+        match query {
+            Query::And(a, b) if and => { operands(a, and, out); operands(b, and, out); },
+            Query::Or(a, b) if !and => { operands(a, and, out); operands(b, and, out); },
+            other => out.push(other)
+        }
+    }
+    let joined = |and: bool, separator: &str|{
+        let mut parts = Vec::new();
+        operands(query, and, &mut parts);
+        let mut parts = parts.into_iter().map(fingerprint).collect::<Vec<_>>();
+        parts.sort();
+        format!("({})", parts.join(separator))
+    };
+    match query {
+        Query::And(_, _) => joined(true, " & "),
+        Query::Or(_, _) => joined(false, " | "),
+        Query::Not(a) => format!("!{}", fingerprint(a)),
+        Query::Equals(name, value) => format!("{name:?}={value:?}"),
+        Query::GreaterThan(name, value) => format!("{name:?}>{value:?}"),
+        Query::LessThan(name, value) => format!("{name:?}<{value:?}")
+    }
+}
+
+struct QueryCache<Key, Data> {
+    results: HashMap<String, Vec<(Key, Data)>>,
+    inserted: VecDeque<String>, // oldest first, for eviction
+    generation: u64 // bumped by every invalidation, a result read before one isn't cached after it
+}
+
+// Wraps a queryable adapter and memoizes query results, for dashboards running the same filters over and
+// over. Results are keyed by the query's fingerprint, start and limit, and every write through the wrapper
+// drops all of them. Writes that don't go through it, e.g. from another process, aren't seen: call
+// invalidate after those, or don't cache tables written elsewhere
+pub struct CachedQueryable<Key, Data, A> {
+    adapter: A,
+    max_entries: usize,
+    cache: Mutex<QueryCache<Key, Data>>,
+    hits: AtomicU64
+}
+
+impl<Key, Data, A> CachedQueryable<Key, Data, A> {
+    // keeps up to 1024 results until given another limit
+    pub fn new(adapter: A) -> Self {
+        CachedQueryable { adapter, max_entries: 1024, cache: Mutex::new(QueryCache { results: HashMap::new(), inserted: VecDeque::new(), generation: 0 }), hits: AtomicU64::new(0) }
+    }
+
+    // the oldest result is dropped to make room past max_entries
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    // drops every cached result
+    pub fn invalidate(&self) {
+        let mut cache = self.cache.lock().unwrap_or_else(|e|e.into_inner());
+        cache.results.clear();
+        cache.inserted.clear();
+        cache.generation += 1;
+    }
+
+    // how many queries were answered from the cache so far
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn into_inner(self) -> A {
+        self.adapter
+    }
+
+    // invalidates after the write, also when it failed since it may have partly gone through
+    fn write<T>(&self, write: impl FnOnce(&A) -> T) -> T {
+        let result = write(&self.adapter);
+        self.invalidate();
+        result
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> PersistenceAdapter<Key, Data, Spec> for CachedQueryable<Key, Data, A> {
+    fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.adapter.load(key)
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        self.write(|adapter|adapter.delete(key))
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.write(|adapter|adapter.store(key, data))
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.adapter.contains(key)
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        self.write(|adapter|adapter.clear())
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan(start, limit)
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan_range(from, to, limit)
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        self.write(|adapter|adapter.update(key, data, only_update))
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        self.write(|adapter|adapter.patch(key, changes))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.adapter.capabilities()
+    }
+}
+
+impl<Key: Clone, Data: Clone, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapterQueryable<Key, Data, Spec>> PersistenceAdapterQueryable<Key, Data, Spec> for CachedQueryable<Key, Data, A> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        let fingerprint = format!("{} [{start}, {limit:?}]", fingerprint(&query));
+        let generation = {
+            let cache = self.cache.lock().unwrap_or_else(|e|e.into_inner());
+            if let Some(rows) = cache.results.get(&fingerprint) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return rows.clone();
+            }
+            cache.generation
+        };
+        let rows = self.adapter.query(query, start, limit);
+        let mut cache = self.cache.lock().unwrap_or_else(|e|e.into_inner());
+        if self.max_entries == 0 || cache.generation != generation {
+            return rows;
+        }
+        if cache.results.insert(fingerprint.clone(), rows.clone()).is_none() {
+            cache.inserted.push_back(fingerprint);
+        }
+        while cache.inserted.len() > self.max_entries {
+            if let Some(oldest) = cache.inserted.pop_front() {
+                cache.results.remove(&oldest);
+            }
+        }
+        rows
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.write(|adapter|adapter.clear_where(query))
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, Query};
+    use crate::persistence_adapter::cached_query::CachedQueryable;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_cached_queryable() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let cached = CachedQueryable::new(SqlitePersistence::new(db_connection.clone(), "test_table")).with_max_entries(2);
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &cached;
+        let queryable: &dyn PersistenceAdapterQueryable<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &cached;
+        adapter.initialize();
+        let row = AllSupportedTypes::with_integer;
+        for i in 0..5 {
+            assert!(adapter.store(&i.to_string(), &row(i)).is_ok());
+        }
+
+        let above = |n|Query::GreaterThan("integer".to_string(), PersistenceData::Integer(n));
+        let string = Query::Equals("string".to_string(), PersistenceData::String("s".to_string()));
+        assert_eq!(queryable.query(Query::and(above(1), string.clone()), 0, None).len(), 3);
+        // the same filter in another order is a hit
+        assert_eq!(queryable.query(Query::and(string.clone(), above(1)), 0, None).len(), 3);
+        assert_eq!(cached.hits(), 1);
+        assert_eq!(queryable.query(Query::and(string.clone(), above(1)), 0, Some(1)).len(), 1);
+        assert_eq!(cached.hits(), 1);
+
+        // a write elsewhere isn't seen until invalidated, a write through the wrapper is
+        let keys = |rows: Vec<(String, AllSupportedTypes)>|rows.into_iter().map(|(key, _)|key).collect::<Vec<_>>();
+        assert_eq!(keys(queryable.query(above(2), 0, None)), vec!["3", "4"]);
+        let direct = SqlitePersistence::new(db_connection, "test_table");
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&direct, &"5".to_string(), &row(5)).is_ok());
+        assert_eq!(keys(queryable.query(above(2), 0, None)), vec!["3", "4"]);
+        assert_eq!(cached.hits(), 2);
+        cached.invalidate();
+        assert_eq!(keys(queryable.query(above(2), 0, None)), vec!["3", "4", "5"]);
+        assert!(adapter.delete(&"4".to_string()).is_ok());
+        assert_eq!(keys(queryable.query(above(2), 0, None)), vec!["3", "5"]);
+        assert_eq!(cached.hits(), 2);
+    }
+}
@@ -0,0 +1,70 @@
+use std::{fmt::Debug, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+// Where components that store or compare timestamps get the current time from, e.g. lock expiry and queue
+// visibility. They use SystemClock unless given another one with with_clock, tests use a ManualClock to
+// freeze time and move it forward by hand
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    // milliseconds since the unix epoch, how timestamps are stored
+    fn now_millis(&self) -> i64 {
+        self.now().duration_since(UNIX_EPOCH).map(|d|d.as_millis() as i64).unwrap_or_default()
+    }
+}
+
+// a duration in milliseconds to add to or subtract from a timestamp, clamped so that a huge duration meant
+// as "for ever" saturates instead of wrapping
+pub(crate) fn duration_millis(duration: Duration) -> i64 {
+    duration.as_millis().min(i64::MAX as u128) as i64
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// A clock that only moves when told to. Share it as an Arc<ManualClock> to keep a handle for advancing it
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock { now: Mutex::new(now) }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap_or_else(|e|e.into_inner()) = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap_or_else(|e|e.into_inner()) += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap_or_else(|e|e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::persistence_adapter::clock::{Clock, ManualClock};
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(10));
+        assert_eq!(clock.now_millis(), 10_000);
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(clock.now_millis(), 10_005);
+        clock.set(UNIX_EPOCH);
+        assert_eq!(clock.now_millis(), 0);
+    }
+}
@@ -0,0 +1,114 @@
+#[cfg(feature = "sqlite")]
+use std::{collections::BTreeMap, sync::Arc};
+use serde::Deserialize;
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceError, PersistenceSpec};
+#[cfg(feature = "sqlite")]
+use crate::persistence_adapter::sqlite::{check_pragmas, SqlitePersistence};
+
+pub type BoxedAdapter<Key, Data, Spec> = Box<dyn PersistenceAdapter<Key, Data, Spec> + Send + Sync>;
+
+// Which backend to use and how, deserializable from any serde format so it can sit in the application's
+// config file. The backend is picked by the "backend" field, e.g. in TOML:
+//   backend = "sqlite"
+//   path = "data/app.sqlite"
+//   table = "users"
+//   journal_mode = "WAL"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum AdapterConfig {
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteConfig)
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SqliteConfig {
+    pub path: String, // a file, ":memory:" or a url for SqlitePersistence::from_url
+    pub table: String,
+    pub journal_mode: Option<String>, // e.g. "WAL"
+    pub busy_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub pragmas: BTreeMap<String, String>, // any other pragmas, set in name order after journal_mode
+    #[serde(default)]
+    pub checksums: bool,
+    pub tenant: Option<String>
+}
+
+// Opens the configured backend. Doesn't initialize it, call initialize once the adapter is built
+pub fn build_adapter<Key: 'static, Data: 'static, Spec: PersistenceSpec<Key, Data> + 'static>(config: &AdapterConfig) -> Result<BoxedAdapter<Key, Data, Spec>, PersistenceError> {
+    match config {
+        #[cfg(feature = "sqlite")]
+        AdapterConfig::Sqlite(config) => Ok(Box::new(config.build()?)),
+        #[allow(unreachable_patterns)]
+        _ => Err(PersistenceError::Backend { message: "No backend enabled, build with a backend feature such as sqlite".to_string() })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteConfig {
+    pub fn build(&self) -> Result<SqlitePersistence, PersistenceError> {
+        let mut pragmas = Vec::new();
+        if let Some(journal_mode) = &self.journal_mode {
+            pragmas.push(("journal_mode", journal_mode.clone()));
+        }
+        if let Some(busy_timeout) = self.busy_timeout_ms {
+            pragmas.push(("busy_timeout", busy_timeout.to_string()));
+        }
+        pragmas.extend(self.pragmas.iter().map(|(name, value)|(name.as_str(), value.clone())));
+        let pragmas = pragmas.iter().map(|(name, value)|(*name, value.as_str())).collect::<Vec<_>>();
+        check_pragmas(&pragmas)?;
+
+        // a sqlite: url brings its own parameters, the ones above are applied after them
+        let mut persistence = match self.path.starts_with("sqlite:") {
+            true => SqlitePersistence::from_url(&self.path, &self.table)?,
+            false => {
+                let connection = sqlite_::Connection::open_with_full_mutex(&self.path)?;
+                SqlitePersistence::new(Arc::new(connection), &self.table)
+            }
+        };
+        persistence.apply_pragmas(&pragmas)?;
+        if self.checksums {
+            persistence = persistence.with_checksums();
+        }
+        if let Some(tenant) = &self.tenant {
+            persistence = persistence.with_tenant(tenant);
+        }
+        Ok(persistence)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use tempdir::TempDir;
+    use crate::persistence_adapter::config::{build_adapter, AdapterConfig};
+    use crate::tests::{AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_build_adapter() {
+        let temp_dir = TempDir::new("sqlite_test").expect("Failed to create tempdir");
+        let path = temp_dir.path().join("test.sqlite");
+
+        let config: AdapterConfig = serde_json::from_value(serde_json::json!({
+            "backend": "sqlite",
+            "path": path.to_str(),
+            "table": "test_table",
+            "journal_mode": "WAL",
+            "pragmas": { "synchronous": "NORMAL" },
+            "checksums": true
+        })).expect("Failed to parse config");
+        let adapter = build_adapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&config).expect("Failed to build adapter");
+        assert!(adapter.initialize().is_some());
+
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+        assert_eq!(adapter.load(&"a".to_string()), Some(entry));
+        assert!(path.with_extension("sqlite-wal").exists());
+
+        let injected: AdapterConfig = serde_json::from_value(serde_json::json!({
+            "backend": "sqlite", "path": ":memory:", "table": "t", "pragmas": { "synchronous": "OFF; DROP TABLE t" }
+        })).expect("Failed to parse config");
+        assert!(build_adapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&injected).is_err());
+        assert!(serde_json::from_value::<AdapterConfig>(serde_json::json!({ "backend": "redis" })).is_err());
+    }
+}
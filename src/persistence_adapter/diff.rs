@@ -0,0 +1,122 @@
+use std::{cmp::Ordering, collections::VecDeque, marker::PhantomData};
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceError, PersistenceSpec};
+
+// rows read from each adapter at a time
+const DIFF_BATCH: usize = 1_000;
+
+// How two tables differ, keys in key order
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffReport<Key> {
+    pub only_in_a: Vec<Key>,
+    pub only_in_b: Vec<Key>,
+    pub mismatched: Vec<Key>, // in both tables with different data
+    pub matching: u64 // rows the same in both
+}
+
+impl<Key> DiffReport<Key> {
+    // true if the tables hold the same rows
+    pub fn is_identical(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+// Compares the rows of a and b, e.g. a table before and after a migration or a primary and its replica.
+// Both are read in key order DIFF_BATCH rows at a time and merged, so only a batch of each is in memory.
+// Both adapters need ordered_scan, in the order of Key's Ord. Rows written during the diff may or may not
+// be seen, rows that fail to read are left out like the adapters' scans leave them out
+pub fn diff<Key: Ord + Clone, Data: PartialEq, Spec: PersistenceSpec<Key, Data>>(a: &impl PersistenceAdapter<Key, Data, Spec>, b: &impl PersistenceAdapter<Key, Data, Spec>) -> Result<DiffReport<Key>, PersistenceError> {
+    if !a.capabilities().ordered_scan || !b.capabilities().ordered_scan {
+        return Err(PersistenceError::Backend { message: "diff needs adapters that scan in key order".to_string() });
+    }
+    let mut report = DiffReport { only_in_a: Vec::new(), only_in_b: Vec::new(), mismatched: Vec::new(), matching: 0 };
+    let (mut rows_a, mut rows_b) = (Rows::new(a).peekable(), Rows::new(b).peekable());
+    loop {
+        let order = match (rows_a.peek(), rows_b.peek()) {
+            (None, None) => return Ok(report),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((key_a, _)), Some((key_b, _))) => key_a.cmp(key_b)
+        };
+        match order {
+            Ordering::Less => report.only_in_a.extend(rows_a.next().map(|(key, _)|key)),
+            Ordering::Greater => report.only_in_b.extend(rows_b.next().map(|(key, _)|key)),
+            Ordering::Equal => {
+                if let (Some((key, data_a)), Some((_, data_b))) = (rows_a.next(), rows_b.next()) {
+                    match data_a == data_b {
+                        true => report.matching += 1,
+                        false => report.mismatched.push(key)
+                    }
+                }
+            }
+        }
+    }
+}
+
+// an adapter's rows in key order, read a batch at a time with scan_range from the last key seen
+struct Rows<'a, Key, Data, Spec, A> {
+    adapter: &'a A,
+    batch: VecDeque<(Key, Data)>,
+    last: Option<Key>,
+    done: bool,
+    _spec: PhantomData<Spec>
+}
+
+impl<'a, Key, Data, Spec, A> Rows<'a, Key, Data, Spec, A> {
+    fn new(adapter: &'a A) -> Self {
+        Rows { adapter, batch: VecDeque::new(), last: None, done: false, _spec: PhantomData }
+    }
+}
+
+impl<Key: PartialEq + Clone, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> Iterator for Rows<'_, Key, Data, Spec, A> {
+    type Item = (Key, Data);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() && !self.done {
+            let rows = self.adapter.scan_range(self.last.as_ref(), None, Some(DIFF_BATCH));
+            self.done = rows.len() < DIFF_BATCH;
+            // scan_range starts at the last key, which was already returned
+            self.batch = rows.into_iter().skip_while(|(key, _)|Some(key) == self.last.as_ref()).collect();
+            self.last = self.batch.back().map(|(key, _)|key.clone()).or(self.last.take());
+        }
+        self.batch.pop_front()
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::diff::diff;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_diff() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let (a, b) = (SqlitePersistence::new(db_connection.clone(), "a"), SqlitePersistence::new(db_connection, "b"));
+        let adapter_a: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &a;
+        let adapter_b: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &b;
+        adapter_a.initialize();
+        adapter_b.initialize();
+        let row = AllSupportedTypes::with_integer;
+        // more rows than one batch, so the merge crosses batch boundaries on both sides
+        for i in 0..2_500 {
+            let key = format!("{i:05}");
+            if i % 500 != 1 {
+                assert!(adapter_a.store(&key, &row(i)).is_ok());
+            }
+            if i % 700 != 2 {
+                assert!(adapter_b.store(&key, &row(if i % 900 == 3 { -i } else { i })).is_ok());
+            }
+        }
+        assert!(adapter_b.store(&"99999".to_string(), &row(0)).is_ok());
+
+        let report = diff::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&a, &b).expect("Failed to diff");
+        assert_eq!(report.only_in_a, vec!["00002", "00702", "01402", "02102"]);
+        assert_eq!(report.only_in_b, vec!["00001", "00501", "01001", "01501", "02001", "99999"]);
+        assert_eq!(report.mismatched, vec!["00003", "00903", "01803"]);
+        assert_eq!(report.matching, 2_500 - 4 - 5 - 3);
+        assert!(!report.is_identical());
+        assert!(diff::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&a, &a).expect("Failed to diff").is_identical());
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::HashMap, sync::Mutex};
+use crate::persistence_adapter::layer::Layer;
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, Query, StoreError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOperation {
+    Initialize,
+    Store,
+    Update,
+    Patch,
+    Delete,
+    Clear,
+    ClearWhere
+}
+
+// A write DryRun validated and skipped
+#[derive(Debug, Clone)]
+pub struct PlannedWrite {
+    pub operation: WriteOperation,
+    pub key: Option<PersistenceData>, // the serialized key, for writes to a single row
+    pub affected_rows: u64 // how many rows the write would have changed as the data is now
+}
+
+// Wraps an adapter so writes are checked and recorded instead of made, for admin tools and rehearsing
+// migrations. Writes are validated against the spec the way the adapter would, fail where the adapter
+// would fail (e.g. storing an existing key) and are counted against the current data, reads go to the
+// adapter. Each write is planned on its own: a later read or write doesn't see an earlier planned one
+pub struct DryRun<A> {
+    adapter: A,
+    planned: Mutex<Vec<PlannedWrite>>
+}
+
+impl<A> DryRun<A> {
+    pub fn new(adapter: A) -> Self {
+        DryRun { adapter, planned: Mutex::new(Vec::new()) }
+    }
+
+    // the writes so far, oldest first
+    pub fn planned(&self) -> Vec<PlannedWrite> {
+        self.planned.lock().unwrap_or_else(|e|e.into_inner()).clone()
+    }
+
+    // returns the writes so far and starts over
+    pub fn take_planned(&self) -> Vec<PlannedWrite> {
+        std::mem::take(&mut *self.planned.lock().unwrap_or_else(|e|e.into_inner()))
+    }
+
+    // the total of affected_rows over the writes so far
+    pub fn affected_rows(&self) -> u64 {
+        self.planned.lock().unwrap_or_else(|e|e.into_inner()).iter().map(|w|w.affected_rows).sum()
+    }
+
+    pub fn into_inner(self) -> A {
+        self.adapter
+    }
+
+    fn plan(&self, operation: WriteOperation, key: Option<PersistenceData>, affected_rows: u64) -> u64 {
+        self.planned.lock().unwrap_or_else(|e|e.into_inner()).push(PlannedWrite { operation, key, affected_rows });
+        affected_rows
+    }
+}
+
+// wraps adapters in a DryRun
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DryRunLayer;
+
+impl<A> Layer<A> for DryRunLayer {
+    type Wrapped = DryRun<A>;
+
+    fn layer(&self, adapter: A) -> DryRun<A> {
+        DryRun::new(adapter)
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> PersistenceAdapter<Key, Data, Spec> for DryRun<A> {
+    fn initialize(&self) -> Option<()> {
+        self.plan(WriteOperation::Initialize, None, 0);
+        Some(())
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.adapter.load(key)
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        Ok(self.plan(WriteOperation::Delete, Some(Spec::serialize_key(key)), self.adapter.contains(key) as u64))
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        Spec::serialize_data(data)?;
+        if self.adapter.contains(key) {
+            return Err(PersistenceError::UniqueViolation { message: "The key already exists".to_string() }.into());
+        }
+        self.plan(WriteOperation::Store, Some(Spec::serialize_key(key)), 1);
+        Ok(())
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.adapter.contains(key)
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        Ok(self.plan(WriteOperation::Clear, None, self.adapter.scan(0, None).len() as u64))
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan(start, limit)
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan_range(from, to, limit)
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        Spec::serialize_data(data)?;
+        if let Some(unknown) = only_update.unwrap_or_default().iter().find(|name|!Spec::fields().iter().any(|f|f.get_name() == **name)) {
+            return Err(StoreError { message: format!("Unknown field {unknown}"), kind: None });
+        }
+        Ok(self.plan(WriteOperation::Update, Some(Spec::serialize_key(key)), self.adapter.contains(key) as u64))
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        if let Some(field) = changes.keys().find(|name|**name == Spec::key_field() || !Spec::fields().iter().any(|f|f.get_name() == **name)) {
+            return Err(PersistenceError::FieldNotAllowed { field: field.to_string() });
+        }
+        Ok(self.plan(WriteOperation::Patch, Some(Spec::serialize_key(key)), self.adapter.contains(key) as u64))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.adapter.capabilities()
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapterQueryable<Key, Data, Spec>> PersistenceAdapterQueryable<Key, Data, Spec> for DryRun<A> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.query(query, start, limit)
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        query.validate_fields(&Spec::fields().iter().map(PersistenceType::get_name).collect::<Vec<_>>())?;
+        Ok(self.plan(WriteOperation::ClearWhere, None, self.adapter.query(query, 0, None).len() as u64))
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, Query};
+    use crate::persistence_adapter::dry_run::{DryRun, WriteOperation};
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_dry_run() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let row = AllSupportedTypes::with_integer;
+        for i in 0..4 {
+            assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&persistence, &i.to_string(), &row(i)).is_ok());
+        }
+
+        let dry_run = DryRun::new(persistence);
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &dry_run;
+        let queryable: &dyn PersistenceAdapterQueryable<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &dry_run;
+        assert!(adapter.store(&"new".to_string(), &row(9)).is_ok());
+        assert!(adapter.store(&"0".to_string(), &row(9)).is_err());
+        assert_eq!(adapter.update(&"1".to_string(), &row(9), None).ok(), Some(1));
+        assert_eq!(adapter.delete(&"missing".to_string()).ok(), Some(0));
+        assert!(adapter.patch(&"2".to_string(), HashMap::from([("key", PersistenceData::String("x".to_string()))])).is_err());
+        assert_eq!(queryable.clear_where(Query::GreaterThan("integer".to_string(), PersistenceData::Integer(1))).ok(), Some(2));
+        assert!(queryable.clear_where(Query::Equals("nope".to_string(), PersistenceData::Integer(1))).is_err());
+        assert_eq!(adapter.clear().ok(), Some(4));
+
+        let planned = dry_run.take_planned();
+        assert_eq!(planned.iter().map(|w|w.operation).collect::<Vec<_>>(), vec![WriteOperation::Store, WriteOperation::Update, WriteOperation::Delete, WriteOperation::ClearWhere, WriteOperation::Clear]);
+        assert_eq!(planned.iter().map(|w|w.affected_rows).sum::<u64>(), 8);
+        assert_eq!(dry_run.affected_rows(), 0);
+        // nothing was written
+        assert_eq!(adapter.scan(0, None), (0..4).map(|i|(i.to_string(), row(i))).collect::<Vec<_>>());
+    }
+}
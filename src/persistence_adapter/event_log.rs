@@ -0,0 +1,212 @@
+use std::marker::PhantomData;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::persistence_adapter::{kv::KvSpec, PersistenceAdapter, PersistenceAdapterUpsert, PersistenceError, StoreError};
+
+// events are stored under "{stream}/{sequence}" with the sequence zero padded so key order is sequence order,
+// a stream's snapshot under "{stream}@snapshot", outside of the range its events are scanned from
+const SEQUENCE_DIGITS: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEvent<E> {
+    pub stream_id: String,
+    pub sequence: u64, // 1 for the first event of a stream
+    pub event: E
+}
+
+// Append-only event streams on top of any adapter using KvSpec, events are stored as JSON.
+// Appends rely on the adapter's store failing for keys that already exist, so two writers appending
+// at the same version can't both succeed
+pub struct EventLog<E, A: PersistenceAdapter<String, Vec<u8>, KvSpec> + PersistenceAdapterUpsert<String, Vec<u8>, KvSpec>> {
+    adapter: A,
+    _event: PhantomData<E>
+}
+
+impl<E: Serialize + DeserializeOwned, A: PersistenceAdapter<String, Vec<u8>, KvSpec> + PersistenceAdapterUpsert<String, Vec<u8>, KvSpec>> EventLog<E, A> {
+    pub fn new(adapter: A) -> Self {
+        EventLog { adapter, _event: PhantomData }
+    }
+
+    pub fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    // the sequence of the stream's last event, 0 for streams without events
+    pub fn version(&self, stream_id: &str) -> Result<u64, PersistenceError> {
+        Self::check_stream_id(stream_id)?;
+        let exists = |sequence: u64|self.adapter.contains(&Self::event_key(stream_id, sequence));
+        if !exists(1) {
+            return Ok(0);
+        }
+        // sequences have no gaps, so the last one is found by doubling past it and bisecting back
+        let (mut low, mut high) = (1, 2);
+        while exists(high) {
+            low = high;
+            high *= 2;
+        }
+        while high - low > 1 {
+            let middle = low + (high - low) / 2;
+            match exists(middle) {
+                true => low = middle,
+                false => high = middle
+            }
+        }
+        Ok(low)
+    }
+
+    // Appends event and returns its sequence. With expected_version the append fails with
+    // PersistenceError::VersionConflict unless the stream is still at that version, without it an append
+    // that races another one goes after it
+    pub fn append(&self, stream_id: &str, expected_version: Option<u64>, event: &E) -> Result<u64, PersistenceError> {
+        let bytes = serde_json::to_vec(event).map_err(|e|PersistenceError::Serialization { message: e.to_string() })?;
+        loop {
+            let version = self.version(stream_id)?;
+            if let Some(expected) = expected_version.filter(|expected|*expected != version) {
+                return Err(PersistenceError::VersionConflict { stream: stream_id.to_string(), expected, actual: version });
+            }
+
+            match self.adapter.store(&Self::event_key(stream_id, version + 1), &bytes) {
+                Ok(()) => return Ok(version + 1),
+                // someone else appended since version was read, other errors are returned
+                Err(StoreError { kind: Some(PersistenceError::UniqueViolation { .. }), .. }) if expected_version.is_none() => continue,
+                Err(StoreError { kind: Some(PersistenceError::UniqueViolation { .. }), .. }) => {
+                    return Err(PersistenceError::VersionConflict { stream: stream_id.to_string(), expected: version, actual: self.version(stream_id)? });
+                },
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+
+    // events of the stream from sequence from_sequence on, in order
+    pub fn read_stream(&self, stream_id: &str, from_sequence: u64, limit: Option<usize>) -> Result<Vec<RecordedEvent<E>>, PersistenceError> {
+        Self::check_stream_id(stream_id)?;
+        let from = Self::event_key(stream_id, from_sequence.max(1));
+        let to = format!("{stream_id}0"); // '0' is the character after '/'
+        self.adapter.scan_range(Some(&from), Some(&to), limit).into_iter().map(|(key, bytes)|{
+            let sequence = key.rsplit('/').next().and_then(|s|s.parse().ok())
+                .ok_or_else(||PersistenceError::Serialization { message: format!("Invalid event key {key}") })?;
+            let event = serde_json::from_slice(&bytes).map_err(|e|PersistenceError::Serialization { message: e.to_string() })?;
+            Ok(RecordedEvent { stream_id: stream_id.to_string(), sequence, event })
+        }).collect()
+    }
+
+    // Keeps state as the stream's state after the event at version, replacing any earlier snapshot.
+    // Rebuild from load_snapshot and the events read_stream returns after its version
+    pub fn save_snapshot<S: Serialize>(&self, stream_id: &str, version: u64, state: &S) -> Result<(), PersistenceError> {
+        Self::check_stream_id(stream_id)?;
+        let mut bytes = version.to_be_bytes().to_vec();
+        serde_json::to_writer(&mut bytes, state).map_err(|e|PersistenceError::Serialization { message: e.to_string() })?;
+        let key = Self::snapshot_key(stream_id);
+        self.adapter.upsert(&key, &bytes)?;
+        Ok(())
+    }
+
+    // the latest snapshot and the version it was taken at
+    pub fn load_snapshot<S: DeserializeOwned>(&self, stream_id: &str) -> Result<Option<(u64, S)>, PersistenceError> {
+        Self::check_stream_id(stream_id)?;
+        let Some(bytes) = self.adapter.load(&Self::snapshot_key(stream_id)) else {
+            return Ok(None);
+        };
+        let (version, state) = bytes.split_at_checked(8).ok_or_else(||PersistenceError::Serialization { message: format!("Invalid snapshot for {stream_id}") })?;
+        let version = u64::from_be_bytes(version.try_into().unwrap_or_default());
+        let state = serde_json::from_slice(state).map_err(|e|PersistenceError::Serialization { message: e.to_string() })?;
+        Ok(Some((version, state)))
+    }
+
+    // '/' and '@' would let one stream's keys fall into another stream's range
+    fn check_stream_id(stream_id: &str) -> Result<(), PersistenceError> {
+        match stream_id.is_empty() || stream_id.contains(['/', '@']) {
+            true => Err(PersistenceError::FieldNotAllowed { field: stream_id.to_string() }),
+            false => Ok(())
+        }
+    }
+
+    fn event_key(stream_id: &str, sequence: u64) -> String {
+        format!("{stream_id}/{sequence:0SEQUENCE_DIGITS$}")
+    }
+
+    fn snapshot_key(stream_id: &str) -> String {
+        format!("{stream_id}@snapshot")
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{sync::Arc, thread};
+    use crate::persistence_adapter::PersistenceError;
+    use crate::persistence_adapter::event_log::EventLog;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::sqlite_connection;
+
+    #[test]
+    fn test_event_log() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let log = EventLog::<i64, _>::new(SqlitePersistence::new(db_connection, "events"));
+        assert!(log.initialize().is_some());
+
+        for amount in 1..=5 {
+            assert!(log.append("account", None, &amount).is_ok());
+        }
+        assert!(log.append("other", None, &100).is_ok());
+        assert_eq!(log.version("account").ok(), Some(5));
+        assert!(matches!(log.append("account", Some(4), &6), Err(PersistenceError::VersionConflict { expected: 4, actual: 5, .. })));
+        assert_eq!(log.append("account", Some(5), &6).ok(), Some(6));
+
+        let events = log.read_stream("account", 5, None).expect("Failed to read");
+        assert_eq!(events.iter().map(|e|(e.sequence, e.event)).collect::<Vec<_>>(), vec![(5, 5), (6, 6)]);
+
+        assert!(log.save_snapshot("account", 4, &10i64).is_ok());
+        let (version, balance) = log.load_snapshot::<i64>("account").expect("Failed to load").expect("Snapshot should exist");
+        let balance = balance + log.read_stream("account", version + 1, None).expect("Failed to read").iter().map(|e|e.event).sum::<i64>();
+        assert_eq!(balance, 21);
+        assert_eq!(log.read_stream("account", 1, None).map(|events|events.len()).ok(), Some(6));
+        assert!(log.append("bad/stream", None, &1).is_err());
+    }
+
+    #[test]
+    fn test_event_log_concurrent_appends() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let log = Arc::new(EventLog::<i64, _>::new(SqlitePersistence::new(db_connection, "events")));
+        assert!(log.initialize().is_some());
+
+        // appends without an expected version all land, one after the other
+        let writers = (0..8).map(|i|{
+            let log = log.clone();
+            thread::spawn(move ||log.append("stream", None, &i))
+        }).collect::<Vec<_>>();
+        let mut sequences = writers.into_iter().map(|writer|writer.join().expect("Writer panicked").expect("Failed to append")).collect::<Vec<_>>();
+        sequences.sort();
+        assert_eq!(sequences, (1..=8).collect::<Vec<_>>());
+
+        // with one, only a single writer gets to append at that version
+        let writers = (0..8).map(|i|{
+            let log = log.clone();
+            thread::spawn(move ||log.append("stream", Some(8), &i))
+        }).collect::<Vec<_>>();
+        let results = writers.into_iter().map(|writer|writer.join().expect("Writer panicked")).collect::<Vec<_>>();
+        assert_eq!(results.iter().filter(|result|result.as_ref().is_ok_and(|sequence|*sequence == 9)).count(), 1);
+        assert!(results.iter().filter(|result|result.is_err()).all(|result|matches!(result, Err(PersistenceError::VersionConflict { .. }))));
+    }
+
+    #[test]
+    fn test_event_log_concurrent_snapshots() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let log = Arc::new(EventLog::<i64, _>::new(SqlitePersistence::new(db_connection, "events")));
+        assert!(log.initialize().is_some());
+
+        // every writer may find the stream without a snapshot, none of them may fail on the others' rows
+        for round in 0..200 {
+            let stream = format!("stream{round}");
+            let writers = (0..8u64).map(|i|{
+                let (log, stream) = (log.clone(), stream.clone());
+                thread::spawn(move ||log.save_snapshot(&stream, i, &i))
+            }).collect::<Vec<_>>();
+            for writer in writers {
+                assert!(writer.join().expect("Writer panicked").is_ok());
+            }
+            assert!(log.load_snapshot::<u64>(&stream).is_ok_and(|s|s.is_some_and(|(version, state)|version == state)));
+        }
+    }
+}
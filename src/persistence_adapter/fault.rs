@@ -0,0 +1,230 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, thread, time::{Duration, UNIX_EPOCH}};
+use crate::persistence_adapter::clock::{Clock, SystemClock};
+use crate::persistence_adapter::layer::Layer;
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+
+pub const INJECTED_FAULT: &str = "injected fault";
+
+// Wraps an adapter and makes it misbehave at random, for soak testing retries and timeouts in staging.
+// Every call is delayed by up to max_latency with probability latency_rate, calls that can fail fail with
+// PersistenceError::Backend carrying INJECTED_FAULT with probability error_rate, before reaching the adapter.
+// Reads that can't report errors (load, contains, scans, query) only get latency, an empty result would
+// look like missing data rather than a transient error
+pub struct FaultInjectingPersistence<A> {
+    adapter: A,
+    error_rate: f64,
+    latency_rate: f64,
+    max_latency: Duration,
+    random: Mutex<u64>, // xorshift state, seeded with with_seed or with_clock for reproducible runs
+    injected_errors: AtomicU64
+}
+
+impl<A> FaultInjectingPersistence<A> {
+    // injects nothing until given rates
+    pub fn new(adapter: A) -> Self {
+        FaultInjectingPersistence { adapter, error_rate: 0.0, latency_rate: 0.0, max_latency: Duration::ZERO, random: Mutex::new(1), injected_errors: AtomicU64::new(0) }
+            .with_clock(Arc::new(SystemClock {}))
+    }
+
+    // rate between 0 and 1, the share of fallible calls to fail
+    pub fn with_error_rate(mut self, rate: f64) -> Self {
+        self.error_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_latency(mut self, rate: f64, max_latency: Duration) -> Self {
+        self.latency_rate = rate.clamp(0.0, 1.0);
+        self.max_latency = max_latency;
+        self
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        *self.random.lock().unwrap_or_else(|e|e.into_inner()) = seed | 1; // xorshift gets stuck at 0
+        self
+    }
+
+    // seeds from clock's current time, the wrapped adapter's clock when it has one
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        let seed = clock.now().duration_since(UNIX_EPOCH).map(|d|d.as_nanos() as u64).unwrap_or_default();
+        self.with_seed(seed)
+    }
+
+    // how many errors were injected so far
+    pub fn injected_errors(&self) -> u64 {
+        self.injected_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn into_inner(self) -> A {
+        self.adapter
+    }
+
+    // uniform in [0, 1)
+    fn random(&self) -> f64 {
+        let mut state = self.random.lock().unwrap_or_else(|e|e.into_inner());
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn delay(&self) {
+        if self.latency_rate > 0.0 && self.random() < self.latency_rate {
+            thread::sleep(self.max_latency.mul_f64(self.random()));
+        }
+    }
+
+    fn fault(&self) -> Result<(), PersistenceError> {
+        self.delay();
+        match self.error_rate > 0.0 && self.random() < self.error_rate {
+            true => {
+                self.injected_errors.fetch_add(1, Ordering::Relaxed);
+                Err(PersistenceError::Backend { message: INJECTED_FAULT.to_string() })
+            },
+            false => Ok(())
+        }
+    }
+
+    fn store_fault(&self) -> Result<(), StoreError> {
+        self.fault().map_err(StoreError::from)
+    }
+}
+
+// wraps adapters in a FaultInjectingPersistence with these rates, seeded with seed if given
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultLayer {
+    pub error_rate: f64,
+    pub latency_rate: f64,
+    pub max_latency: Duration,
+    pub seed: Option<u64>
+}
+
+impl<A> Layer<A> for FaultLayer {
+    type Wrapped = FaultInjectingPersistence<A>;
+
+    fn layer(&self, adapter: A) -> FaultInjectingPersistence<A> {
+        let faulty = FaultInjectingPersistence::new(adapter).with_error_rate(self.error_rate).with_latency(self.latency_rate, self.max_latency);
+        match self.seed {
+            Some(seed) => faulty.with_seed(seed),
+            None => faulty
+        }
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> PersistenceAdapter<Key, Data, Spec> for FaultInjectingPersistence<A> {
+    fn initialize(&self) -> Option<()> {
+        self.fault().ok()?;
+        self.adapter.initialize()
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.delay();
+        self.adapter.load(key)
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        self.fault()?;
+        self.adapter.delete(key)
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.store_fault()?;
+        self.adapter.store(key, data)
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.delay();
+        self.adapter.contains(key)
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        self.fault()?;
+        self.adapter.clear()
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.delay();
+        self.adapter.scan(start, limit)
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.delay();
+        self.adapter.scan_range(from, to, limit)
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        self.store_fault()?;
+        self.adapter.update(key, data, only_update)
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        self.fault()?;
+        self.adapter.patch(key, changes)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.adapter.capabilities()
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapterQueryable<Key, Data, Spec>> PersistenceAdapterQueryable<Key, Data, Spec> for FaultInjectingPersistence<A> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.delay();
+        self.adapter.query(query, start, limit)
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.fault()?;
+        self.adapter.clear_where(query)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::fault::{FaultInjectingPersistence, INJECTED_FAULT};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_fault_injection() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let faulty = FaultInjectingPersistence::new(SqlitePersistence::new(db_connection, "test_table"))
+            .with_error_rate(0.5)
+            .with_latency(0.1, Duration::from_millis(1))
+            .with_seed(42);
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &faulty;
+        while adapter.initialize().is_none() {}
+
+        let entry = AllSupportedTypes::with_integer(1);
+        let mut failed = 0;
+        for i in 0..200 {
+            match adapter.store(&i.to_string(), &entry) {
+                Ok(()) => {},
+                Err(e) => {
+                    assert!(e.message.contains(INJECTED_FAULT));
+                    failed += 1;
+                }
+            }
+        }
+        assert!((50..150).contains(&failed));
+        assert_eq!(adapter.scan(0, None).len(), 200 - failed);
+        assert!(faulty.injected_errors() >= failed as u64);
+    }
+
+    #[test]
+    fn test_fault_injection_clock() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let faults = ||{
+            let (_temp_dir, persistence) = sqlite_persistence();
+            let faulty = FaultInjectingPersistence::new(persistence).with_error_rate(0.5).with_clock(clock.clone());
+            let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &faulty;
+            (0..50).map(|i|adapter.store(&i.to_string(), &AllSupportedTypes::with_integer(i)).is_err()).collect::<Vec<_>>()
+        };
+        let first = faults();
+        assert!(first.contains(&true) && first.contains(&false));
+        assert_eq!(faults(), first);
+    }
+}
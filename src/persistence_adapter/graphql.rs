@@ -0,0 +1,133 @@
+use async_graphql::{InputObject, OutputType};
+use async_graphql::connection::{Connection, CursorType, Edge};
+use crate::persistence_adapter::{page::PageToken, repository::Repository, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceError, PersistenceSpec, Query};
+
+// A filter argument for list fields, in the syntax of Query::parse, e.g. "age > 18 AND name = 'bob'"
+#[derive(Debug, Clone, Default, InputObject)]
+pub struct FilterInput {
+    pub filter: Option<String>
+}
+
+impl FilterInput {
+    // The parsed filter, None without one. Clients only get to filter on the spec's fields, sensitive ones excluded
+    pub fn to_query<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> async_graphql::Result<Option<Query>> {
+        let Some(filter) = self.filter.as_deref().filter(|f|!f.trim().is_empty()) else {
+            return Ok(None);
+        };
+        let query = Query::parse(filter)?;
+        let allowed = Spec::fields().iter().map(|f|f.get_name()).filter(|name|!Spec::sensitive_fields().contains(name)).collect::<Vec<_>>();
+        query.validate_fields(&allowed)?;
+        Ok(Some(query))
+    }
+}
+
+// cursors are handed out as the token's string form
+impl CursorType for PageToken {
+    type Error = PersistenceError;
+
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        PageToken::parse(s)
+    }
+
+    fn encode_cursor(&self) -> String {
+        self.to_string()
+    }
+}
+
+// Forward cursor pagination for a Relay connection field: up to first rows matching filter in key order,
+// starting after the edge whose cursor is after. Each edge's cursor is a PageToken of its own key, so it
+// stays on the same row when rows before it are added or removed, and only continues the filter it came
+// from. node builds the GraphQL node of a row
+pub fn connection<Key, Data, Spec, A, Node>(repository: &Repository<Key, Data, Spec, A>, filter: Option<Query>, after: Option<&str>, first: usize, node: impl Fn(Key, Data) -> Node) -> async_graphql::Result<Connection<PageToken, Node>>
+where
+    Spec: PersistenceSpec<Key, Data>,
+    A: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec>,
+    Node: OutputType
+{
+    let after = after.map(PageToken::parse).transpose()?;
+    let after_key = after.as_ref().map(|token|token.next_key(filter.as_ref()).cloned()).transpose()?;
+    if let Some(key) = &after_key {
+        Spec::deserialize_key(key).ok_or_else(||PersistenceError::Serialization { message: "Invalid key in cursor".to_string() })?;
+    }
+
+    let past_cursor = after_key.map(|key|Query::GreaterThan(Spec::key_field().to_string(), key));
+    let mut rows = match (filter.clone(), past_cursor) {
+        (Some(filter), Some(past_cursor)) => repository.query(Query::and(filter, past_cursor), 0, Some(first + 1)),
+        (Some(query), None) | (None, Some(query)) => repository.query(query, 0, Some(first + 1)),
+        (None, None) => repository.scan_range(None, None, Some(first + 1))
+    };
+    let has_next = rows.len() > first;
+    rows.truncate(first);
+
+    let mut connection = Connection::new(after.is_some(), has_next);
+    connection.edges.extend(rows.into_iter().map(|(key, data)|Edge::new(PageToken::new(filter.as_ref(), Spec::serialize_key(&key)), node(key, data))));
+    Ok(connection)
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
+    use async_graphql::connection::Connection;
+    use crate::persistence_adapter::graphql::{connection, FilterInput};
+    use crate::persistence_adapter::page::PageToken;
+    use crate::persistence_adapter::repository::Repository;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    type Repo = Repository<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, SqlitePersistence>;
+
+    #[derive(SimpleObject)]
+    struct Item {
+        key: String,
+        integer: i64
+    }
+
+    struct QueryRoot(Repo);
+
+    #[Object]
+    impl QueryRoot {
+        async fn items(&self, filter: Option<FilterInput>, after: Option<String>, first: Option<i32>) -> async_graphql::Result<Connection<PageToken, Item>> {
+            let filter = filter.unwrap_or_default().to_query::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>()?;
+            connection(&self.0, filter, after.as_deref(), first.unwrap_or(10).max(0) as usize, |key, data|Item { key, integer: data.integer })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_graphql_connection() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let repo = Repo::new(SqlitePersistence::new(db_connection, "test_table"));
+        repo.initialize();
+        for i in 0..5 {
+            let row = AllSupportedTypes::with_integer(i);
+            assert!(repo.store(&i.to_string(), &row).is_ok());
+        }
+        let schema = Schema::new(QueryRoot(repo), EmptyMutation, EmptySubscription);
+        let run = |query: String|{
+            let schema = schema.clone();
+            async move { schema.execute(query).await.into_result().map(|response|response.data.into_json().expect("Failed to convert")) }
+        };
+
+        let first = run("{ items(first: 2) { edges { cursor node { key } } pageInfo { hasNextPage } } }".to_string()).await.expect("Failed to query");
+        let edges = first["items"]["edges"].as_array().expect("Should have edges");
+        assert_eq!(edges.iter().map(|e|e["node"]["key"].as_str()).collect::<Vec<_>>(), vec![Some("0"), Some("1")]);
+        assert_eq!(first["items"]["pageInfo"]["hasNextPage"], true);
+
+        let cursor = edges[1]["cursor"].as_str().expect("Should have a cursor");
+        let rest = run(format!("{{ items(first: 5, after: \"{cursor}\") {{ nodes {{ key }} pageInfo {{ hasNextPage hasPreviousPage }} }} }}")).await.expect("Failed to query");
+        assert_eq!(rest["items"]["nodes"].as_array().map(|n|n.len()), Some(3));
+        assert_eq!(rest["items"]["pageInfo"]["hasNextPage"], false);
+        assert_eq!(rest["items"]["pageInfo"]["hasPreviousPage"], true);
+
+        let filtered = run("{ items(filter: {filter: \"integer > 1 AND integer < 4\"}, first: 1) { edges { cursor node { integer } } } }".to_string()).await.expect("Failed to query");
+        assert_eq!(filtered["items"]["edges"][0]["node"]["integer"], 2);
+        let cursor = filtered["items"]["edges"][0]["cursor"].as_str().expect("Should have a cursor");
+        let next = run(format!("{{ items(filter: {{filter: \"integer > 1 AND integer < 4\"}}, after: \"{cursor}\") {{ nodes {{ integer }} }} }}")).await.expect("Failed to query");
+        assert_eq!(next["items"]["nodes"][0]["integer"], 3);
+
+        // a cursor only continues its own filter, filters must parse and only name spec fields
+        assert!(run(format!("{{ items(after: \"{cursor}\") {{ nodes {{ key }} }} }}")).await.is_err());
+        assert!(run("{ items(filter: {filter: \"integer >\"}) { nodes { key } } }".to_string()).await.is_err());
+        assert!(run("{ items(filter: {filter: \"secret = 1\"}) { nodes { key } } }".to_string()).await.is_err());
+    }
+}
@@ -0,0 +1,308 @@
+use std::{collections::HashMap, marker::PhantomData, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::persistence_adapter::{CasOutcome, PersistenceAdapter, PersistenceAdapterConditional, PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, Query, SpecError, StoreError};
+use crate::persistence_adapter::clock::{duration_millis, Clock, SystemClock};
+
+const IDEMPOTENCY_FIELDS: [PersistenceType; 4] = [
+    PersistenceType::String("key"),
+    PersistenceType::Bytes("result"),
+    PersistenceType::Integer("completed"),
+    PersistenceType::Integer("expires_at")
+];
+
+// An idempotency key's claim while its operation runs, then its result as JSON. A claim's result holds an id
+// unique to the call that made it. expires_at is in unix milliseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdempotencyRecord {
+    pub result: Vec<u8>,
+    pub completed: bool,
+    pub expires_at: i64
+}
+
+// Built-in spec for IdempotencyStore
+pub struct IdempotencySpec {}
+
+impl PersistenceSpec<String, IdempotencyRecord> for IdempotencySpec {
+    fn fields() -> &'static [PersistenceType] {
+        &IDEMPOTENCY_FIELDS
+    }
+
+    fn key_field() -> &'static str {
+        "key"
+    }
+
+    fn serialize_key(key: &String) -> PersistenceData {
+        PersistenceData::String(key.clone())
+    }
+
+    fn deserialize_key(key: &PersistenceData) -> Option<String> {
+        key.to_str().map(str::to_string)
+    }
+
+    fn serialize_data(data: &IdempotencyRecord) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+        Ok(HashMap::from([
+            ("result", PersistenceData::Bytes(data.result.clone())),
+            ("completed", PersistenceData::Integer(data.completed as i64)),
+            ("expires_at", PersistenceData::Integer(data.expires_at))
+        ]))
+    }
+
+    fn deserialize_data(mut data: HashMap<&'static str, PersistenceData>) -> Result<IdempotencyRecord, SpecError> {
+        Ok(IdempotencyRecord {
+            result: data.remove("result").and_then(PersistenceData::into_bytes).ok_or_else(||SpecError::missing("result"))?,
+            completed: data.get("completed").and_then(PersistenceData::to_int).ok_or_else(||SpecError::missing("completed"))? != 0,
+            expires_at: data.get("expires_at").and_then(PersistenceData::to_int).ok_or_else(||SpecError::missing("expires_at"))?
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Idempotent<T> {
+    Executed(T), // op ran for this call
+    Replayed(T), // the result stored by an earlier call with the same key
+    InProgress // another call with the same key is still running op, e.g. answer 409 and let the client retry
+}
+
+// Claim attempts lost to concurrent calls before giving up and answering InProgress
+const CLAIM_ATTEMPTS: usize = 8;
+
+// Runs an operation at most once per idempotency key on top of any conditional adapter using IdempotencySpec,
+// e.g. for payment or webhook handlers that get retried. A call claims its key by inserting a pending record,
+// relying on the adapter's store failing with UniqueViolation for keys that already exist, or by replacing an expired record only
+// while it's still the one that was read, so only one of several concurrent calls runs the operation. The
+// result or the release of a failed claim is only written while the claim is still the caller's. Results are
+// stored as JSON and replayed until their ttl runs out, errors aren't stored so a failed operation can be
+// retried with the same key
+pub struct IdempotencyStore<T, A: PersistenceAdapter<String, IdempotencyRecord, IdempotencySpec> + PersistenceAdapterConditional<String, IdempotencyRecord, IdempotencySpec>> {
+    adapter: A,
+    lock_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    _result: PhantomData<fn() -> T>
+}
+
+impl<T: Serialize + DeserializeOwned, A: PersistenceAdapter<String, IdempotencyRecord, IdempotencySpec> + PersistenceAdapterConditional<String, IdempotencyRecord, IdempotencySpec>> IdempotencyStore<T, A> {
+    pub fn new(adapter: A) -> Self {
+        IdempotencyStore { adapter, lock_timeout: Duration::from_secs(300), clock: Arc::new(SystemClock {}), _result: PhantomData }
+    }
+
+    // How long a claim holds its key before another call may take it over, in case the process running the
+    // operation died. Defaults to 5 minutes, keep it longer than the operation can take
+    pub fn with_lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    // the clock ttls and claims are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    // Runs op unless key already has a result from the last ttl, which is returned instead. Errors of the
+    // store itself are converted into op's error type. If op outlives the lock timeout and another call takes
+    // the key over, op's result is returned but not stored over the other call's claim
+    pub fn execute<E: From<PersistenceError>>(&self, key: &str, ttl: Duration, op: impl FnOnce() -> Result<T, E>) -> Result<Idempotent<T>, E> {
+        let key = key.to_string();
+        let claim = IdempotencyRecord { result: claim_id(), completed: false, expires_at: self.clock.now_millis().saturating_add(duration_millis(self.lock_timeout)) };
+        if let Some(existing) = self.claim(&key, &claim)? {
+            return Ok(existing);
+        }
+
+        let value = match op() {
+            Ok(value) => value,
+            Err(e) => {
+                // op's error is the one worth returning, a claim that can't be released expires after the lock timeout
+                let _ = self.adapter.delete_if(&key, is_record(&claim));
+                return Err(e);
+            }
+        };
+        let result = serde_json::to_vec(&value).map_err(|e|PersistenceError::Serialization{message: e.to_string()})?;
+        let record = IdempotencyRecord { result, completed: true, expires_at: self.clock.now_millis().saturating_add(duration_millis(ttl)) };
+        self.adapter.store_if(&key, &record, is_record(&claim))?;
+        Ok(Idempotent::Executed(value))
+    }
+
+    // forgets key's result or claim, the next call runs op again
+    pub fn forget(&self, key: &str) -> Result<bool, PersistenceError> {
+        Ok(self.adapter.delete(&key.to_string())? > 0)
+    }
+
+    // Deletes the expired results and claims, returns the number deleted. A record claimed again since it
+    // was read isn't deleted
+    pub fn purge_expired(&self) -> Result<u64, PersistenceError> {
+        let now = self.clock.now_millis();
+        let mut purged = 0;
+        for (key, record) in self.adapter.scan(0, None).into_iter().filter(|(_, record)|record.expires_at <= now) {
+            purged += self.adapter.delete_if(&key, is_record(&record))?;
+        }
+        Ok(purged)
+    }
+
+    // Claims key for the caller, or returns the stored result or InProgress for a live record. An expired record
+    // is only replaced while it's still the one that was read, a call that loses a race reads the key again
+    fn claim(&self, key: &String, claim: &IdempotencyRecord) -> Result<Option<Idempotent<T>>, PersistenceError> {
+        for _ in 0..CLAIM_ATTEMPTS {
+            let claimed = match self.adapter.load(key) {
+                Some(record) if record.expires_at <= self.clock.now_millis() => self.adapter.store_if(key, claim, is_record(&record))? == CasOutcome::Stored,
+                Some(record) if record.completed => return serde_json::from_slice(&record.result)
+                    .map(|value|Some(Idempotent::Replayed(value)))
+                    .map_err(|e|PersistenceError::Serialization{message: e.to_string()}),
+                Some(_) => return Ok(Some(Idempotent::InProgress)),
+                // only a key another call claimed since it was read is a lost race, other errors are returned
+                None => match self.adapter.store(key, claim) {
+                    Ok(()) => true,
+                    Err(StoreError { kind: Some(PersistenceError::UniqueViolation { .. }), .. }) => false,
+                    Err(e) => return Err(e.into())
+                }
+            };
+            if claimed {
+                return Ok(None);
+            }
+        }
+        Ok(Some(Idempotent::InProgress))
+    }
+}
+
+// matches the stored record only while it's still record
+fn is_record(record: &IdempotencyRecord) -> Query {
+    Query::and(
+        Query::Equals("expires_at".to_string(), PersistenceData::Integer(record.expires_at)),
+        Query::Equals("result".to_string(), PersistenceData::Bytes(record.result.clone()))
+    )
+}
+
+// unique per claim across the processes sharing the table, as long as their clocks don't repeat a nanosecond
+fn claim_id() -> Vec<u8> {
+    static CLAIMS: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d|d.as_nanos()).unwrap_or(0);
+    [&nanos.to_be_bytes()[..], &std::process::id().to_be_bytes(), &CLAIMS.fetch_add(1, Ordering::Relaxed).to_be_bytes()].concat()
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::PersistenceError;
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::idempotency::{Idempotent, IdempotencyStore};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::sqlite_connection;
+
+    #[test]
+    fn test_idempotency_store() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let store = IdempotencyStore::<u32, _>::new(SqlitePersistence::new(db_connection, "idempotency"))
+            .with_lock_timeout(Duration::from_secs(30))
+            .with_clock(clock.clone());
+        assert!(store.initialize().is_some());
+        let ttl = Duration::from_secs(3600);
+        let charge = |amount: u32|move ||Ok::<_, PersistenceError>(amount);
+
+        // a retry with the same key while the first call runs finds it in progress
+        let first = store.execute("payment-1", ttl, ||{
+            assert_eq!(store.execute("payment-1", ttl, charge(1)).ok(), Some(Idempotent::InProgress));
+            Ok::<_, PersistenceError>(100)
+        });
+        assert_eq!(first.ok(), Some(Idempotent::Executed(100)));
+        assert_eq!(store.execute("payment-1", ttl, charge(200)).ok(), Some(Idempotent::Replayed(100)));
+
+        // failures aren't stored
+        let failed = store.execute("payment-2", ttl, ||Err(PersistenceError::Backend { message: "declined".to_string() }));
+        assert!(failed.is_err());
+        assert_eq!(store.execute("payment-2", ttl, charge(300)).ok(), Some(Idempotent::Executed(300)));
+
+        clock.advance(ttl);
+        assert_eq!(store.execute("payment-1", ttl, charge(400)).ok(), Some(Idempotent::Executed(400)));
+        assert_eq!(store.purge_expired().ok(), Some(1));
+        assert!(store.forget("payment-1").is_ok_and(|removed|removed));
+    }
+
+    #[test]
+    fn test_expired_claims() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let store = IdempotencyStore::<u32, _>::new(SqlitePersistence::new(db_connection, "idempotency"))
+            .with_lock_timeout(Duration::from_secs(30))
+            .with_clock(clock.clone());
+        assert!(store.initialize().is_some());
+        let ttl = Duration::from_secs(3600);
+        let charge = |amount: u32|move ||Ok::<_, PersistenceError>(amount);
+
+        // the first call outlives its claim and a retry takes the key over, the first result doesn't replace the retry's
+        let first = store.execute("payment-1", ttl, ||{
+            clock.advance(Duration::from_secs(31));
+            assert_eq!(store.execute("payment-1", ttl, charge(2)).ok(), Some(Idempotent::Executed(2)));
+            Ok::<_, PersistenceError>(1)
+        });
+        assert_eq!(first.ok(), Some(Idempotent::Executed(1)));
+        assert_eq!(store.execute("payment-1", ttl, charge(3)).ok(), Some(Idempotent::Replayed(2)));
+
+        // a failing call doesn't release a claim that isn't its own anymore
+        let failed = store.execute("payment-2", ttl, ||{
+            clock.advance(Duration::from_secs(31));
+            assert_eq!(store.execute("payment-2", ttl, ||{
+                assert_eq!(store.execute("payment-2", ttl, charge(6)).ok(), Some(Idempotent::InProgress));
+                Ok::<_, PersistenceError>(5)
+            }).ok(), Some(Idempotent::Executed(5)));
+            Err(PersistenceError::Backend { message: "declined".to_string() })
+        });
+        assert!(failed.is_err());
+        assert_eq!(store.execute("payment-2", ttl, charge(7)).ok(), Some(Idempotent::Replayed(5)));
+    }
+
+    #[test]
+    fn test_claim_errors() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let store = IdempotencyStore::<u32, _>::new(SqlitePersistence::new(db_connection.clone(), "idempotency"));
+        assert!(store.initialize().is_some());
+
+        // a store that fails for another reason than a taken key is an error, not a call in progress
+        assert!(db_connection.execute("PRAGMA query_only = 1").is_ok());
+        let refused = store.execute("payment-1", Duration::from_secs(3600), ||Ok::<_, PersistenceError>(1));
+        assert!(matches!(refused, Err(PersistenceError::ReadOnly { .. })));
+    }
+
+    #[test]
+    fn test_failed_release_keeps_op_error() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let store = IdempotencyStore::<u32, _>::new(SqlitePersistence::new(db_connection.clone(), "idempotency"));
+        assert!(store.initialize().is_some());
+
+        // releasing the claim fails too, the caller still gets op's error
+        let failed = store.execute("payment-1", Duration::from_secs(3600), ||{
+            assert!(db_connection.execute("PRAGMA query_only = 1").is_ok());
+            Err(PersistenceError::Backend { message: "declined".to_string() })
+        });
+        assert!(matches!(failed, Err(PersistenceError::Backend { message }) if message == "declined"));
+    }
+
+    #[test]
+    fn test_huge_durations() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let store = IdempotencyStore::<u32, _>::new(SqlitePersistence::new(db_connection, "idempotency"))
+            .with_lock_timeout(Duration::MAX)
+            .with_clock(clock.clone());
+        assert!(store.initialize().is_some());
+        let charge = |amount: u32|move ||Ok::<_, PersistenceError>(amount);
+
+        // a claim and a result kept past the end of time don't wrap into the past and expire at once
+        let first = store.execute("payment-1", Duration::MAX, ||{
+            assert_eq!(store.execute("payment-1", Duration::MAX, charge(2)).ok(), Some(Idempotent::InProgress));
+            Ok::<_, PersistenceError>(1)
+        });
+        assert_eq!(first.ok(), Some(Idempotent::Executed(1)));
+        clock.advance(Duration::from_secs(1_000_000));
+        assert_eq!(store.execute("payment-1", Duration::MAX, charge(3)).ok(), Some(Idempotent::Replayed(1)));
+        assert_eq!(store.purge_expired().ok(), Some(0));
+    }
+}
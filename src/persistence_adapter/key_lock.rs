@@ -0,0 +1,163 @@
+use std::{collections::HashMap, sync::Mutex};
+use crate::persistence_adapter::layer::Layer;
+use crate::persistence_adapter::page::stable_hash;
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+
+const DEFAULT_STRIPES: usize = 64;
+
+// Serializes writers of the same key within the process, so read-modify-write sections run through
+// with_key_lock can't interleave and lose each other's changes. Keys are spread over a fixed number of
+// striped locks by a hash of their serialized form: keys sharing a stripe also wait on each other, more
+// stripes means less of that. The wrapper's single row writes (store, update, patch, delete) take the
+// key's lock too. Other processes and writes made around the wrapper aren't held back, use the backend's
+// transactions or store_if for those
+pub struct KeyLocked<A> {
+    adapter: A,
+    stripes: Vec<Mutex<()>>
+}
+
+impl<A> KeyLocked<A> {
+    pub fn new(adapter: A) -> Self {
+        KeyLocked::with_stripes(adapter, DEFAULT_STRIPES)
+    }
+
+    pub fn with_stripes(adapter: A, stripes: usize) -> Self {
+        KeyLocked { adapter, stripes: (0..stripes.max(1)).map(|_|Mutex::new(())).collect() }
+    }
+
+    // Runs f while holding key's lock and returns what it returns. f is given the wrapped adapter so its
+    // writes to key don't wait on the lock already held
+    pub fn with_key_lock<Key, Data, Spec: PersistenceSpec<Key, Data>, R>(&self, key: &Key, f: impl FnOnce(&A) -> R) -> R {
+        let _guard = self.stripe::<Key, Data, Spec>(key).lock().unwrap_or_else(|e|e.into_inner());
+        f(&self.adapter)
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.adapter
+    }
+
+    pub fn into_inner(self) -> A {
+        self.adapter
+    }
+
+    fn stripe<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key) -> &Mutex<()> {
+        let hash = stable_hash(&format!("{:?}", Spec::serialize_key(key)));
+        &self.stripes[(hash % self.stripes.len() as u64) as usize]
+    }
+}
+
+// wraps adapters in a KeyLocked with this many stripes
+#[derive(Debug, Clone, Copy)]
+pub struct KeyLockLayer {
+    pub stripes: usize
+}
+
+impl Default for KeyLockLayer {
+    fn default() -> Self {
+        KeyLockLayer { stripes: DEFAULT_STRIPES }
+    }
+}
+
+impl<A> Layer<A> for KeyLockLayer {
+    type Wrapped = KeyLocked<A>;
+
+    fn layer(&self, adapter: A) -> KeyLocked<A> {
+        KeyLocked::with_stripes(adapter, self.stripes)
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> PersistenceAdapter<Key, Data, Spec> for KeyLocked<A> {
+    fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.adapter.load(key)
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        self.with_key_lock::<Key, Data, Spec, _>(key, |adapter|adapter.delete(key))
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.with_key_lock::<Key, Data, Spec, _>(key, |adapter|adapter.store(key, data))
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.adapter.contains(key)
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        self.adapter.clear()
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan(start, limit)
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan_range(from, to, limit)
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        self.with_key_lock::<Key, Data, Spec, _>(key, |adapter|adapter.update(key, data, only_update))
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        self.with_key_lock::<Key, Data, Spec, _>(key, |adapter|adapter.patch(key, changes))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.adapter.capabilities()
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapterQueryable<Key, Data, Spec>> PersistenceAdapterQueryable<Key, Data, Spec> for KeyLocked<A> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.query(query, start, limit)
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.adapter.clear_where(query)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::thread;
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::key_lock::KeyLocked;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_with_key_lock() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let locked = KeyLocked::with_stripes(SqlitePersistence::new(db_connection, "test_table"), 4);
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &locked;
+        adapter.initialize();
+        let row = AllSupportedTypes::with_integer(0);
+        let keys = ["a".to_string(), "b".to_string()];
+        for key in &keys {
+            assert!(adapter.store(key, &row).is_ok());
+        }
+
+        // concurrent read-modify-write increments, none of them lost
+        thread::scope(|scope|{
+            for i in 0..8 {
+                let (locked, key) = (&locked, &keys[i % 2]);
+                scope.spawn(move ||for _ in 0..25 {
+                    locked.with_key_lock::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>(key, |inner|{
+                        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = inner;
+                        let mut current = adapter.load(key).expect("Row is missing");
+                        current.integer += 1;
+                        thread::yield_now();
+                        assert_eq!(adapter.update(key, &current, Some(&["integer"])).ok(), Some(1));
+                    });
+                });
+            }
+        });
+        assert_eq!(keys.iter().map(|key|adapter.load(key).map(|r|r.integer)).collect::<Vec<_>>(), vec![Some(100), Some(100)]);
+    }
+}
@@ -0,0 +1,365 @@
+use std::{fmt::{Debug, Display}, sync::{Arc, Mutex, atomic::{AtomicI64, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use crate::persistence_adapter::{PersistenceData, PersistenceError};
+use crate::persistence_adapter::clock::{Clock, SystemClock};
+
+// Makes up keys for new rows, see Repository::store_generated. Keys from the database itself
+// (auto-increment) come from the adapter instead, e.g. SqlitePersistence::store_generated. Fails when
+// the generator can't make a key, e.g. without a system random number generator
+pub trait KeyGenerator<Key> {
+    fn generate(&self) -> Result<Key, PersistenceError>;
+}
+
+// Where random key generators get their random bits. They use OsRandom unless given another source with
+// with_random, tests use a fixed one to get known keys
+pub trait RandomSource: Debug + Send + Sync {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), PersistenceError>;
+}
+
+// The operating system's random number generator
+#[cfg(feature = "keygen")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsRandom {}
+
+#[cfg(feature = "keygen")]
+impl RandomSource for OsRandom {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), PersistenceError> {
+        getrandom::fill(bytes).map_err(|e|PersistenceError::Backend { message: format!("No system random number generator: {e}") })
+    }
+}
+
+#[cfg(feature = "keygen")]
+fn random_bytes<const N: usize>(source: &dyn RandomSource) -> Result<[u8; N], PersistenceError> {
+    let mut bytes = [0; N];
+    source.fill(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Random UUIDs in their usual lowercase 8-4-4-4-12 form
+#[cfg(feature = "keygen")]
+#[derive(Debug, Clone)]
+pub struct UuidV4 {
+    random: Arc<dyn RandomSource>
+}
+
+#[cfg(feature = "keygen")]
+impl UuidV4 {
+    pub fn new() -> Self {
+        UuidV4 { random: Arc::new(OsRandom {}) }
+    }
+
+    pub fn with_random(mut self, random: Arc<dyn RandomSource>) -> Self {
+        self.random = random;
+        self
+    }
+}
+
+#[cfg(feature = "keygen")]
+impl Default for UuidV4 {
+    fn default() -> Self {
+        UuidV4::new()
+    }
+}
+
+#[cfg(feature = "keygen")]
+impl KeyGenerator<String> for UuidV4 {
+    fn generate(&self) -> Result<String, PersistenceError> {
+        let mut bytes = random_bytes::<16>(self.random.as_ref())?;
+        bytes[6] = (bytes[6] & 0x0f) | 0x40; // version 4
+        bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+        let hex = bytes.iter().map(|b|format!("{b:02x}")).collect::<String>();
+        Ok(format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]))
+    }
+}
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+// 48 bits of milliseconds followed by 80 random bits, as 26 characters of Crockford base32
+fn encode_ulid(millis: u64, random: u128) -> String {
+    let value = ((millis as u128 & 0xffff_ffff_ffff) << 80) | (random & ((1 << 80) - 1));
+    (0..26).rev().map(|i|CROCKFORD[((value >> (i * 5)) & 0x1f) as usize] as char).collect()
+}
+
+// 26 character ULIDs, a millisecond timestamp followed by 80 random bits in Crockford base32.
+// They sort by creation time, keys made within the same millisecond are in random order
+#[cfg(feature = "keygen")]
+#[derive(Debug, Clone)]
+pub struct Ulid {
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn RandomSource>
+}
+
+#[cfg(feature = "keygen")]
+impl Ulid {
+    pub fn new() -> Self {
+        Ulid { clock: Arc::new(SystemClock {}), random: Arc::new(OsRandom {}) }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_random(mut self, random: Arc<dyn RandomSource>) -> Self {
+        self.random = random;
+        self
+    }
+}
+
+#[cfg(feature = "keygen")]
+impl Default for Ulid {
+    fn default() -> Self {
+        Ulid::new()
+    }
+}
+
+#[cfg(feature = "keygen")]
+impl KeyGenerator<String> for Ulid {
+    fn generate(&self) -> Result<String, PersistenceError> {
+        let random = random_bytes::<10>(self.random.as_ref())?.iter().fold(0u128, |v, b|(v << 8) | *b as u128);
+        Ok(encode_ulid(self.clock.now_millis().max(0) as u64, random))
+    }
+}
+
+#[cfg(feature = "keygen")]
+impl KeyGenerator<TimeOrderedKey> for Ulid {
+    fn generate(&self) -> Result<TimeOrderedKey, PersistenceError> {
+        KeyGenerator::<String>::generate(self).map(TimeOrderedKey)
+    }
+}
+
+// A ULID key for event and log style tables, where string order is creation order so the most recent rows
+// are a scan_range away, see Repository::scan_time_range. Stored as a String, use to_persistence_data and
+// from_persistence_data in the spec's serialize_key and deserialize_key
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimeOrderedKey(String);
+
+impl TimeOrderedKey {
+    #[cfg(feature = "keygen")]
+    pub fn new() -> Result<Self, PersistenceError> {
+        KeyGenerator::<TimeOrderedKey>::generate(&Ulid::new())
+    }
+
+    // Sorts before every key created at or after time and after every key created before it
+    pub fn lower_bound(time: SystemTime) -> Self {
+        let millis = time.duration_since(UNIX_EPOCH).map(|d|d.as_millis() as u64).unwrap_or_default();
+        TimeOrderedKey(encode_ulid(millis, 0))
+    }
+
+    // None unless s is a 26 character ULID in upper case Crockford base32
+    pub fn parse(s: &str) -> Option<Self> {
+        let valid = s.len() == 26 && s.as_bytes()[0] <= b'7' && s.bytes().all(|c|CROCKFORD.contains(&c));
+        valid.then(||TimeOrderedKey(s.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // when the key was created, to the millisecond
+    pub fn timestamp(&self) -> SystemTime {
+        let millis = self.0.bytes().take(10).fold(0u64, |v, c|(v << 5) | CROCKFORD.iter().position(|a|*a == c).unwrap_or_default() as u64);
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    pub fn to_persistence_data(&self) -> PersistenceData {
+        PersistenceData::String(self.0.clone())
+    }
+
+    pub fn from_persistence_data(data: &PersistenceData) -> Option<Self> {
+        data.to_str().and_then(TimeOrderedKey::parse)
+    }
+}
+
+impl Display for TimeOrderedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Twitter style 64 bit ids: 41 bits of milliseconds since epoch_millis, a 10 bit node id and a 12 bit
+// sequence. Unique as long as every process generating keys for the same table has its own node id
+#[derive(Debug)]
+pub struct Snowflake {
+    epoch_millis: u64,
+    node: u16,
+    clock: Arc<dyn Clock>,
+    state: Mutex<(u64, u16)> // last millisecond used and the sequence within it
+}
+
+impl Snowflake {
+    pub const MAX_NODE: u16 = 0x3ff;
+
+    // node is truncated to 10 bits
+    pub fn new(epoch_millis: u64, node: u16) -> Self {
+        Snowflake { epoch_millis, node: node & Snowflake::MAX_NODE, clock: Arc::new(SystemClock {}), state: Mutex::new((0, 0)) }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl KeyGenerator<i64> for Snowflake {
+    fn generate(&self) -> Result<i64, PersistenceError> {
+        let mut state = self.state.lock().unwrap_or_else(|e|e.into_inner());
+        let mut millis = (self.clock.now_millis().max(0) as u64).saturating_sub(self.epoch_millis).max(state.0);
+        let sequence = match millis == state.0 {
+            true if state.1 == 0xfff => {
+                // 4096 ids in this millisecond already, borrow the next one. Ids stay ordered and the clock
+                // catches up, a stopped clock doesn't hang the generator
+                millis += 1;
+                0
+            },
+            true => state.1 + 1,
+            false => 0
+        };
+        *state = (millis, sequence);
+        Ok((((millis & 0x1ff_ffff_ffff) << 22) | ((self.node as u64) << 12) | sequence as u64) as i64)
+    }
+}
+
+// 1, 2, 3... starting after start, for tests that need to know which keys will be handed out
+#[derive(Debug, Default)]
+pub struct Sequential {
+    last: AtomicI64
+}
+
+impl Sequential {
+    pub fn new(start: i64) -> Self {
+        Sequential { last: AtomicI64::new(start) }
+    }
+}
+
+impl KeyGenerator<i64> for Sequential {
+    fn generate(&self) -> Result<i64, PersistenceError> {
+        Ok(self.last.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+impl KeyGenerator<String> for Sequential {
+    fn generate(&self) -> Result<String, PersistenceError> {
+        KeyGenerator::<i64>::generate(self).map(|key|key.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::keygen::{KeyGenerator, Sequential, Snowflake, TimeOrderedKey};
+
+    #[test]
+    fn test_snowflake() {
+        let generator = Snowflake::new(1_600_000_000_000, 7);
+        let keys = (0..10_000).map(|_|generator.generate().expect("Failed to generate")).collect::<Vec<_>>();
+        assert!(keys.windows(2).all(|pair|pair[0] < pair[1]));
+        assert!(keys.iter().all(|key|*key > 0 && (key >> 12) & 0x3ff == 7));
+    }
+
+    #[test]
+    fn test_deterministic_keys() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_millis(1_000)));
+        let generator = Snowflake::new(0, 1).with_clock(clock.clone());
+        assert_eq!(generator.generate().ok(), Some((1_000 << 22) | (1 << 12)));
+        let keys = (0..5_000).map(|_|generator.generate().expect("Failed to generate")).collect::<Vec<_>>();
+        assert!(keys.windows(2).all(|pair|pair[0] < pair[1]));
+        assert_eq!(keys.last().map(|key|key >> 22), Some(1_001));
+
+        let sequential = Sequential::new(10);
+        assert_eq!(KeyGenerator::<i64>::generate(&sequential).ok(), Some(11));
+        assert_eq!(KeyGenerator::<String>::generate(&sequential).ok(), Some("12".to_string()));
+    }
+
+    #[test]
+    fn test_time_ordered_key_bounds() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let bound = TimeOrderedKey::lower_bound(time);
+        assert_eq!(bound.timestamp(), time);
+        assert_eq!(TimeOrderedKey::parse(bound.as_str()), Some(bound.clone()));
+        assert!(TimeOrderedKey::parse("not a ulid").is_none());
+        assert!(TimeOrderedKey::lower_bound(time - Duration::from_millis(1)) < bound);
+        let last_of_millisecond = format!("{}ZZZZZZZZZZZZZZZZ", &bound.as_str()[..10]);
+        assert!(TimeOrderedKey::parse(&last_of_millisecond).is_some_and(|key|key > bound && key < TimeOrderedKey::lower_bound(time + Duration::from_millis(1))));
+    }
+
+    #[cfg(feature = "keygen")]
+    #[test]
+    fn test_random_keys() {
+        use std::collections::HashSet;
+        use crate::persistence_adapter::keygen::{Ulid, UuidV4};
+
+        let uuid = UuidV4::new().generate().expect("Failed to generate");
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(uuid.as_bytes()[14], b'4');
+        assert!(matches!(uuid.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+
+        let ulids = (0..100).map(|_|KeyGenerator::<String>::generate(&Ulid::new()).expect("Failed to generate")).collect::<HashSet<_>>();
+        assert_eq!(ulids.len(), 100);
+        assert!(ulids.iter().all(|ulid|TimeOrderedKey::parse(ulid).is_some()));
+        let key = TimeOrderedKey::new().expect("Failed to generate");
+        assert!(key.timestamp().elapsed().is_ok_and(|age|age < Duration::from_secs(60)));
+    }
+
+    #[cfg(feature = "keygen")]
+    #[test]
+    fn test_deterministic_ulid() {
+        use crate::persistence_adapter::PersistenceError;
+        use crate::persistence_adapter::keygen::{RandomSource, Ulid};
+
+        #[derive(Debug)]
+        struct Fixed(u8);
+        impl RandomSource for Fixed {
+            fn fill(&self, bytes: &mut [u8]) -> Result<(), PersistenceError> {
+                bytes.fill(self.0);
+                Ok(())
+            }
+        }
+
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let clock = Arc::new(ManualClock::new(time));
+        let generator = Ulid::new().with_clock(clock.clone()).with_random(Arc::new(Fixed(0)));
+        let first = KeyGenerator::<TimeOrderedKey>::generate(&generator).expect("Failed to generate");
+        assert_eq!(first, TimeOrderedKey::lower_bound(time));
+        assert_eq!(KeyGenerator::<TimeOrderedKey>::generate(&generator).ok(), Some(first.clone()));
+        clock.advance(Duration::from_millis(1));
+        assert!(KeyGenerator::<TimeOrderedKey>::generate(&generator).is_ok_and(|key|key.timestamp() == time + Duration::from_millis(1)));
+
+        let all_ones = Ulid::new().with_clock(clock).with_random(Arc::new(Fixed(0xff)));
+        assert!(KeyGenerator::<String>::generate(&all_ones).is_ok_and(|ulid|ulid.ends_with("ZZZZZZZZZZZZZZZZ")));
+    }
+
+    #[cfg(feature = "keygen")]
+    #[test]
+    fn test_deterministic_uuid() {
+        use std::sync::Mutex;
+        use crate::persistence_adapter::PersistenceError;
+        use crate::persistence_adapter::keygen::{RandomSource, UuidV4};
+
+        // xorshift from a fixed seed
+        #[derive(Debug)]
+        struct Seeded(Mutex<u64>);
+        impl RandomSource for Seeded {
+            fn fill(&self, bytes: &mut [u8]) -> Result<(), PersistenceError> {
+                let mut state = self.0.lock().unwrap();
+                for byte in bytes {
+                    *state ^= *state << 13;
+                    *state ^= *state >> 7;
+                    *state ^= *state << 17;
+                    *byte = *state as u8;
+                }
+                Ok(())
+            }
+        }
+
+        let keys = |seed: u64|{
+            let generator = UuidV4::new().with_random(Arc::new(Seeded(Mutex::new(seed))));
+            (0..10).map(|_|generator.generate().expect("Failed to generate")).collect::<Vec<_>>()
+        };
+        let first = keys(2151);
+        assert_eq!(first, keys(2151));
+        assert_ne!(first, keys(2152));
+        assert!(first.iter().all(|uuid|uuid.as_bytes()[14] == b'4'));
+        assert_eq!(first.iter().collect::<std::collections::HashSet<_>>().len(), 10);
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterUpsert, PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, SpecError};
+
+const KV_FIELDS: [PersistenceType; 2] = [
+    PersistenceType::String("key"),
+    PersistenceType::Bytes("value")
+];
+
+// Built-in spec for KvStore, every value is stored as JSON bytes under a string key
+pub struct KvSpec {}
+
+impl PersistenceSpec<String, Vec<u8>> for KvSpec {
+    fn fields() -> &'static [PersistenceType] {
+        &KV_FIELDS
+    }
+
+    fn key_field() -> &'static str {
+        "key"
+    }
+
+    fn serialize_key(key: &String) -> PersistenceData {
+        PersistenceData::String(key.clone())
+    }
+
+    fn deserialize_key(key: &PersistenceData) -> Option<String> {
+        key.to_str().map(str::to_string)
+    }
+
+    fn serialize_data(data: &Vec<u8>) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+        Ok(HashMap::from([("value", PersistenceData::Bytes(data.clone()))]))
+    }
+
+    fn deserialize_data(mut data: HashMap<&'static str, PersistenceData>) -> Result<Vec<u8>, SpecError> {
+        data.remove("value").and_then(PersistenceData::into_bytes).ok_or_else(||SpecError::missing("value"))
+    }
+}
+
+// Settings/metadata bag on top of any adapter, values of any serde type without writing a spec for them
+pub struct KvStore<A: PersistenceAdapter<String, Vec<u8>, KvSpec> + PersistenceAdapterUpsert<String, Vec<u8>, KvSpec>> {
+    adapter: A
+}
+
+impl<A: PersistenceAdapter<String, Vec<u8>, KvSpec> + PersistenceAdapterUpsert<String, Vec<u8>, KvSpec>> KvStore<A> {
+    pub fn new(adapter: A) -> Self {
+        KvStore { adapter }
+    }
+
+    pub fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, PersistenceError> {
+        match self.adapter.load(&key.to_string()) {
+            Some(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(|e|PersistenceError::Serialization{message: e.to_string()}),
+            None => Ok(None)
+        }
+    }
+
+    // inserts or replaces the value under key
+    pub fn put<T: Serialize>(&self, key: &str, value: &T) -> Result<(), PersistenceError> {
+        let bytes = serde_json::to_vec(value).map_err(|e|PersistenceError::Serialization{message: e.to_string()})?;
+        self.adapter.upsert(&key.to_string(), &bytes)?;
+        Ok(())
+    }
+
+    // returns whether there was a value to remove
+    pub fn remove(&self, key: &str) -> Result<bool, PersistenceError> {
+        Ok(self.adapter.delete(&key.to_string())? > 0)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.adapter.contains(&key.to_string())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{collections::HashMap, sync::Arc, thread};
+    use crate::persistence_adapter::kv::KvStore;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::sqlite_connection;
+
+    #[test]
+    fn test_kv_put_get() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let kv = KvStore::new(SqlitePersistence::new(db_connection, "settings"));
+        assert!(kv.initialize().is_some());
+
+        assert_eq!(kv.get::<u32>("retries").ok(), Some(None));
+        assert!(kv.put("retries", &3u32).is_ok());
+        assert!(kv.put("retries", &5u32).is_ok());
+        assert_eq!(kv.get::<u32>("retries").ok(), Some(Some(5)));
+
+        let limits = HashMap::from([("upload".to_string(), 10u64)]);
+        assert!(kv.put("limits", &limits).is_ok());
+        assert_eq!(kv.get::<HashMap<String, u64>>("limits").ok(), Some(Some(limits)));
+        assert!(kv.get::<String>("limits").is_err());
+
+        assert!(kv.remove("retries").is_ok_and(|removed|removed));
+        assert!(!kv.contains("retries"));
+        assert!(kv.remove("retries").is_ok_and(|removed|!removed));
+    }
+
+    #[test]
+    fn test_kv_concurrent_put() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let kv = Arc::new(KvStore::new(SqlitePersistence::new(db_connection, "settings")));
+        assert!(kv.initialize().is_some());
+
+        // every writer may find the key missing, none of them may fail on the others' rows
+        for round in 0..200 {
+            let key = format!("key{round}");
+            let writers = (0..8u32).map(|i|{
+                let (kv, key) = (kv.clone(), key.clone());
+                thread::spawn(move ||kv.put(&key, &i))
+            }).collect::<Vec<_>>();
+            for writer in writers {
+                assert!(writer.join().expect("Writer panicked").is_ok());
+            }
+            assert!(kv.get::<u32>(&key).is_ok_and(|v|v.is_some_and(|v|v < 8)));
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+use crate::persistence_adapter::layer::Layer;
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Load, // load and contains
+    Store, // store, update and patch
+    Delete, // delete, clear and clear_where
+    Scan, // scan and scan_range
+    Query
+}
+
+// 32 linear buckets per power of two, values are kept to within about 3%
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+// HDR style histogram of durations in microseconds. Memory grows with the log of the largest value, not the count
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    min: u64,
+    max: u64
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let index = Self::bucket(micros);
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+        self.min = if self.count == 0 { micros } else { self.min.min(micros) };
+        self.max = self.max.max(micros);
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Duration {
+        Duration::from_micros(self.min)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    // The latency at or below which quantile of the recorded latencies fall, e.g. 0.99 for p99. Rounded
+    // up to the end of its bucket, never above max. Zero while empty
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(Self::bucket_end(index).min(self.max));
+            }
+        }
+        Duration::ZERO
+    }
+
+    fn bucket(micros: u64) -> usize {
+        if micros < SUB_BUCKETS {
+            return micros as usize;
+        }
+        let shift = 63 - micros.leading_zeros() - SUB_BUCKET_BITS;
+        (((shift + 1) as u64 * SUB_BUCKETS) + (micros >> shift) - SUB_BUCKETS) as usize
+    }
+
+    // the largest value in the bucket
+    fn bucket_end(index: usize) -> u64 {
+        let index = index as u64;
+        if index < SUB_BUCKETS {
+            return index;
+        }
+        let shift = index / SUB_BUCKETS - 1;
+        ((index % SUB_BUCKETS + SUB_BUCKETS) << shift) + (1 << shift) - 1
+    }
+}
+
+// Latencies recorded by an InstrumentedPersistence, per operation. Operations that weren't called are absent
+#[derive(Debug, Clone, Default)]
+pub struct LatencyReport {
+    pub operations: HashMap<Operation, LatencyHistogram>
+}
+
+impl LatencyReport {
+    pub fn get(&self, operation: Operation) -> Option<&LatencyHistogram> {
+        self.operations.get(&operation)
+    }
+}
+
+// Wraps an adapter and times every call into a histogram for its Operation, see latency_report()
+pub struct InstrumentedPersistence<A> {
+    adapter: A,
+    report: Mutex<LatencyReport>
+}
+
+impl<A> InstrumentedPersistence<A> {
+    pub fn new(adapter: A) -> Self {
+        InstrumentedPersistence { adapter, report: Mutex::new(LatencyReport::default()) }
+    }
+
+    pub fn latency_report(&self) -> LatencyReport {
+        self.report.lock().unwrap_or_else(|e|e.into_inner()).clone()
+    }
+
+    // starts over, e.g. after each reporting interval
+    pub fn reset(&self) {
+        *self.report.lock().unwrap_or_else(|e|e.into_inner()) = LatencyReport::default();
+    }
+
+    pub fn into_inner(self) -> A {
+        self.adapter
+    }
+
+    fn timed<T>(&self, operation: Operation, call: impl FnOnce(&A) -> T) -> T {
+        let started = Instant::now();
+        let result = call(&self.adapter);
+        let elapsed = started.elapsed();
+        self.report.lock().unwrap_or_else(|e|e.into_inner()).operations.entry(operation).or_default().record(elapsed);
+        result
+    }
+}
+
+// wraps adapters in an InstrumentedPersistence
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstrumentedLayer;
+
+impl<A> Layer<A> for InstrumentedLayer {
+    type Wrapped = InstrumentedPersistence<A>;
+
+    fn layer(&self, adapter: A) -> InstrumentedPersistence<A> {
+        InstrumentedPersistence::new(adapter)
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> PersistenceAdapter<Key, Data, Spec> for InstrumentedPersistence<A> {
+    fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.timed(Operation::Load, |a|a.load(key))
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        self.timed(Operation::Delete, |a|a.delete(key))
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.timed(Operation::Store, |a|a.store(key, data))
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.timed(Operation::Load, |a|a.contains(key))
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        self.timed(Operation::Delete, |a|a.clear())
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.timed(Operation::Scan, |a|a.scan(start, limit))
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.timed(Operation::Scan, |a|a.scan_range(from, to, limit))
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        self.timed(Operation::Store, |a|a.update(key, data, only_update))
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        self.timed(Operation::Store, |a|a.patch(key, changes))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.adapter.capabilities()
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapterQueryable<Key, Data, Spec>> PersistenceAdapterQueryable<Key, Data, Spec> for InstrumentedPersistence<A> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.timed(Operation::Query, |a|a.query(query, start, limit))
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.timed(Operation::Delete, |a|a.clear_where(query))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::Duration;
+    use crate::persistence_adapter::latency::LatencyHistogram;
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), Duration::ZERO);
+        for micros in 1..=10_000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 10_000);
+        assert_eq!((histogram.min(), histogram.max()), (Duration::from_micros(1), Duration::from_micros(10_000)));
+        for (quantile, expected) in [(0.5, 5_000.0), (0.9, 9_000.0), (0.99, 9_900.0)] {
+            let micros = histogram.percentile(quantile).as_micros() as f64;
+            assert!(micros >= expected && micros <= expected * 1.04, "p{quantile} was {micros}");
+        }
+        assert_eq!(histogram.percentile(1.0), Duration::from_micros(10_000));
+        assert_eq!(histogram.percentile(0.0001), Duration::from_micros(1));
+    }
+}
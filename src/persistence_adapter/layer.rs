@@ -0,0 +1,85 @@
+// Wraps an adapter in another, e.g. an InstrumentedPersistence or a DryRun, so wrappers can be stacked
+// without nesting constructors. Wrappers configured by settings alone have a layer next to them (DryRunLayer,
+// FaultLayer, InstrumentedLayer, KeyLockLayer, RetryLayer). The ones that also take other adapters or a
+// caller's state, e.g. ArchivingPersistence, RoutedPersistence or PolicyEnforcedPersistence, are layered
+// with layer_fn, which turns any constructor into a layer
+pub trait Layer<A> {
+    type Wrapped;
+
+    fn layer(&self, adapter: A) -> Self::Wrapped;
+}
+
+// A layer calling f with the adapter, for wrappers that take more than the adapter:
+// layer_fn(|adapter|FaultInjectingPersistence::new(adapter).with_error_rate(0.1))
+pub fn layer_fn<F>(f: F) -> LayerFn<F> {
+    LayerFn { f }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LayerFn<F> {
+    f: F
+}
+
+impl<A, W, F: Fn(A) -> W> Layer<A> for LayerFn<F> {
+    type Wrapped = W;
+
+    fn layer(&self, adapter: A) -> W {
+        (self.f)(adapter)
+    }
+}
+
+// Stacks layers on an adapter, each one wrapping the ones before it: in
+// AdapterBuilder::new(sqlite).layer(KeyLockLayer::default()).layer(InstrumentedLayer).build()
+// calls go through the InstrumentedPersistence first, then the KeyLocked, then sqlite
+#[derive(Debug, Clone)]
+pub struct AdapterBuilder<A> {
+    adapter: A
+}
+
+impl<A> AdapterBuilder<A> {
+    pub fn new(adapter: A) -> Self {
+        AdapterBuilder { adapter }
+    }
+
+    pub fn layer<L: Layer<A>>(self, layer: L) -> AdapterBuilder<L::Wrapped> {
+        AdapterBuilder { adapter: layer.layer(self.adapter) }
+    }
+
+    pub fn build(self) -> A {
+        self.adapter
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::dry_run::DryRunLayer;
+    use crate::persistence_adapter::fault::{FaultInjectingPersistence, FaultLayer};
+    use crate::persistence_adapter::key_lock::KeyLockLayer;
+    use crate::persistence_adapter::latency::{InstrumentedLayer, Operation};
+    use crate::persistence_adapter::layer::{layer_fn, AdapterBuilder};
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_adapter_builder() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let built = AdapterBuilder::new(persistence)
+            .layer(layer_fn(|adapter|FaultInjectingPersistence::new(adapter).with_seed(1)))
+            .layer(KeyLockLayer::default())
+            .layer(FaultLayer { seed: Some(2), ..FaultLayer::default() })
+            .layer(InstrumentedLayer)
+            .build();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &built;
+        let row = AllSupportedTypes::with_integer(1);
+        assert!(adapter.store(&"a".to_string(), &row).is_ok());
+        assert_eq!(adapter.load(&"a".to_string()), Some(row.clone()));
+        assert_eq!(built.latency_report().get(Operation::Store).map(|h|h.count()), Some(1));
+
+        // a dry run on top keeps every write from reaching the layers below
+        let dry_run = AdapterBuilder::new(built).layer(DryRunLayer).build();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &dry_run;
+        assert!(adapter.store(&"b".to_string(), &row).is_ok());
+        assert!(!adapter.contains(&"b".to_string()));
+        assert_eq!(dry_run.planned().len(), 1);
+    }
+}
@@ -0,0 +1,327 @@
+use std::{cmp::Ordering, collections::HashMap, sync::Mutex};
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MockCall {
+    Initialize,
+    Load,
+    Delete,
+    Store,
+    Contains,
+    Clear,
+    Scan,
+    ScanRange,
+    Update,
+    Patch,
+    Query,
+    ClearWhere
+}
+
+// What a scripted call does instead of its usual work
+#[derive(Debug, Clone)]
+pub enum MockFailure {
+    // Fails with PersistenceError::Backend carrying the message, e.g. "database is locked". Calls that
+    // can't return an error act as if there is no data: load returns None, contains false, scans nothing
+    Error(String),
+    // Replaces these fields of every row the call reads or writes, so loads can return corrupted data.
+    // Rows that no longer deserialize are left out
+    Corrupt(HashMap<&'static str, PersistenceData>)
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedCall {
+    pub call: MockCall,
+    pub key: Option<PersistenceData> // the serialized key, for calls about a single row
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    rows: Vec<(PersistenceData, HashMap<&'static str, PersistenceData>)>, // serialized, in key order
+    calls: Vec<RecordedCall>,
+    failures: Vec<(MockCall, Option<usize>, MockFailure)> // None fails every call of the kind
+}
+
+// In-memory adapter for testing code built on the adapter traits. Rows are kept serialized, so specs
+// round trip like with a real backend. Failures are scripted per call kind, e.g. fail the third store
+// with fail(MockCall::Store, 3, ...), and every call is recorded for assertions
+#[derive(Debug, Default)]
+pub struct MockPersistence {
+    state: Mutex<MockState>
+}
+
+impl MockPersistence {
+    pub fn new() -> Self {
+        MockPersistence::default()
+    }
+
+    // The nth call of this kind, counting from 1 and including calls made before, fails with failure
+    pub fn fail(&self, call: MockCall, nth: usize, failure: MockFailure) {
+        self.state().failures.push((call, Some(nth), failure));
+    }
+
+    // every call of this kind from now on fails with failure
+    pub fn fail_all(&self, call: MockCall, failure: MockFailure) {
+        self.state().failures.push((call, None, failure));
+    }
+
+    // drops scripted failures, the stored rows and recorded calls stay
+    pub fn reset_failures(&self) {
+        self.state().failures.clear();
+    }
+
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.state().calls.clone()
+    }
+
+    pub fn call_count(&self, call: MockCall) -> usize {
+        self.state().calls.iter().filter(|recorded|recorded.call == call).count()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e|e.into_inner())
+    }
+
+    // records the call and returns the failure scripted for it, if any
+    fn record(state: &mut MockState, call: MockCall, key: Option<PersistenceData>) -> Option<MockFailure> {
+        state.calls.push(RecordedCall { call, key });
+        let count = state.calls.iter().filter(|recorded|recorded.call == call).count();
+        state.failures.iter().find(|(c, nth, _)|*c == call && nth.is_none_or(|nth|nth == count)).map(|(_, _, failure)|failure.clone())
+    }
+
+    fn error(failure: Option<MockFailure>) -> Result<Option<HashMap<&'static str, PersistenceData>>, PersistenceError> {
+        match failure {
+            Some(MockFailure::Error(message)) => Err(PersistenceError::Backend { message }),
+            Some(MockFailure::Corrupt(fields)) => Ok(Some(fields)),
+            None => Ok(None)
+        }
+    }
+
+    fn position(rows: &[(PersistenceData, HashMap<&'static str, PersistenceData>)], key: &PersistenceData) -> Result<usize, usize> {
+        rows.binary_search_by(|(k, _)|compare(k, key).unwrap_or(Ordering::Less))
+    }
+
+    fn deserialize<Key, Data, Spec: PersistenceSpec<Key, Data>>(key: &PersistenceData, fields: &HashMap<&'static str, PersistenceData>, corrupt: &Option<HashMap<&'static str, PersistenceData>>) -> Option<(Key, Data)> {
+        let mut fields = fields.clone();
+        fields.insert(Spec::key_field(), key.clone());
+        if let Some(corrupt) = corrupt {
+            fields.extend(corrupt.iter().map(|(name, value)|(*name, value.clone())));
+        }
+        let key = fields.get(Spec::key_field()).and_then(Spec::deserialize_key)?;
+        Some((key, Spec::deserialize_data(fields).ok()?))
+    }
+
+    fn rows<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, call: MockCall, filter: impl Fn(&PersistenceData, &HashMap<&'static str, PersistenceData>) -> bool) -> Vec<(Key, Data)> {
+        let mut state = self.state();
+        let Ok(corrupt) = Self::error(Self::record(&mut state, call, None)) else {
+            return Vec::new();
+        };
+        state.rows.iter().filter(|(key, fields)|filter(key, fields)).filter_map(|(key, fields)|Self::deserialize::<Key, Data, Spec>(key, fields, &corrupt)).collect()
+    }
+
+    fn serialize<Key, Data, Spec: PersistenceSpec<Key, Data>>(data: &Data, corrupt: Option<HashMap<&'static str, PersistenceData>>) -> Result<HashMap<&'static str, PersistenceData>, PersistenceError> {
+        let mut fields = Spec::serialize_data(data)?;
+        fields.remove(Spec::key_field());
+        fields.extend(corrupt.unwrap_or_default());
+        Ok(fields)
+    }
+
+    fn check_fields<Key, Data, Spec: PersistenceSpec<Key, Data>>(names: &[&str]) -> Result<(), PersistenceError> {
+        match names.iter().find(|name|**name == Spec::key_field() || Spec::fields().iter().all(|f|f.get_name() != **name)) {
+            Some(name) => Err(PersistenceError::FieldNotAllowed { field: name.to_string() }),
+            None => Ok(())
+        }
+    }
+}
+
+// orders values of the same kind, None for values of different kinds
+fn compare(a: &PersistenceData, b: &PersistenceData) -> Option<Ordering> {
+    match (a, b) {
+        (PersistenceData::String(a), PersistenceData::String(b)) => Some(a.cmp(b)),
+        (PersistenceData::Bytes(a), PersistenceData::Bytes(b)) => Some(a.cmp(b)),
+        (PersistenceData::Integer(a), PersistenceData::Integer(b)) => Some(a.cmp(b)),
+        (PersistenceData::UnsignedInteger(a), PersistenceData::UnsignedInteger(b)) => Some(a.cmp(b)),
+        (PersistenceData::Float(a), PersistenceData::Float(b)) => a.partial_cmp(b),
+        (PersistenceData::Double(a), PersistenceData::Double(b)) => a.partial_cmp(b),
+        #[cfg(feature = "decimal")]
+        (PersistenceData::Decimal(a), PersistenceData::Decimal(b)) => Some(a.cmp(b)),
+        _ => None
+    }
+}
+
+fn matches(query: &Query, key_field: &str, key: &PersistenceData, fields: &HashMap<&'static str, PersistenceData>) -> bool {
+    let field = |name: &str|match name == key_field {
+        true => Some(key),
+        false => fields.get(name)
+    };
+    match query {
+        Query::Or(a, b) => matches(a, key_field, key, fields) || matches(b, key_field, key, fields),
+        Query::And(a, b) => matches(a, key_field, key, fields) && matches(b, key_field, key, fields),
+        Query::Not(a) => !matches(a, key_field, key, fields),
+        Query::Equals(name, value) => field(name).and_then(|f|compare(f, value)) == Some(Ordering::Equal),
+        Query::GreaterThan(name, value) => field(name).and_then(|f|compare(f, value)) == Some(Ordering::Greater),
+        Query::LessThan(name, value) => field(name).and_then(|f|compare(f, value)) == Some(Ordering::Less)
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>> PersistenceAdapter<Key, Data, Spec> for MockPersistence {
+    fn initialize(&self) -> Option<()> {
+        let mut state = self.state();
+        Self::error(Self::record(&mut state, MockCall::Initialize, None)).ok().map(|_|())
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        let key = Spec::serialize_key(key);
+        let mut state = self.state();
+        let corrupt = Self::error(Self::record(&mut state, MockCall::Load, Some(key.clone()))).ok()?;
+        let index = Self::position(&state.rows, &key).ok()?;
+        Self::deserialize::<Key, Data, Spec>(&key, &state.rows[index].1, &corrupt).map(|(_, data)|data)
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        let key = Spec::serialize_key(key);
+        let mut state = self.state();
+        Self::error(Self::record(&mut state, MockCall::Delete, Some(key.clone())))?;
+        match Self::position(&state.rows, &key) {
+            Ok(index) => {
+                state.rows.remove(index);
+                Ok(1)
+            },
+            Err(_) => Ok(0)
+        }
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        let serialized_key = Spec::serialize_key(key);
+        let mut state = self.state();
+        let stored = Self::error(Self::record(&mut state, MockCall::Store, Some(serialized_key.clone())))
+            .and_then(|corrupt|Self::serialize::<Key, Data, Spec>(data, corrupt))
+            .and_then(|fields|match Self::position(&state.rows, &serialized_key) {
+                Ok(_) => Err(PersistenceError::UniqueViolation { message: "UNIQUE constraint failed".to_string() }),
+                Err(index) => {
+                    state.rows.insert(index, (serialized_key.clone(), fields));
+                    Ok(())
+                }
+            });
+        stored.map_err(StoreError::from)
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        let key = Spec::serialize_key(key);
+        let mut state = self.state();
+        Self::error(Self::record(&mut state, MockCall::Contains, Some(key.clone()))).is_ok() && Self::position(&state.rows, &key).is_ok()
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        let mut state = self.state();
+        Self::error(Self::record(&mut state, MockCall::Clear, None))?;
+        let cleared = state.rows.len() as u64;
+        state.rows.clear();
+        Ok(cleared)
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.rows::<Key, Data, Spec>(MockCall::Scan, |_, _|true).into_iter().skip(start).take(limit.unwrap_or(usize::MAX)).collect()
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        let (from, to) = (from.map(Spec::serialize_key), to.map(Spec::serialize_key));
+        let in_range = |key: &PersistenceData, _: &HashMap<&'static str, PersistenceData>|
+            from.as_ref().is_none_or(|from|compare(key, from).is_some_and(Ordering::is_ge)) && to.as_ref().is_none_or(|to|compare(key, to).is_some_and(Ordering::is_lt));
+        self.rows::<Key, Data, Spec>(MockCall::ScanRange, in_range).into_iter().take(limit.unwrap_or(usize::MAX)).collect()
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        let serialized_key = Spec::serialize_key(key);
+        let mut state = self.state();
+        let updated = Self::error(Self::record(&mut state, MockCall::Update, Some(serialized_key.clone())))
+            .and_then(|corrupt|Self::serialize::<Key, Data, Spec>(data, corrupt))
+            .and_then(|mut fields|{
+                if let Some(only_update) = only_update {
+                    let only_update = only_update.iter().copied().filter(|name|*name != Spec::key_field()).collect::<Vec<_>>();
+                    Self::check_fields::<Key, Data, Spec>(&only_update)?;
+                    fields.retain(|name, _|only_update.contains(name));
+                }
+                match Self::position(&state.rows, &serialized_key) {
+                    Ok(index) => {
+                        state.rows[index].1.extend(fields);
+                        Ok(1)
+                    },
+                    Err(_) => Ok(0)
+                }
+            });
+        updated.map_err(StoreError::from)
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        let key = Spec::serialize_key(key);
+        let mut state = self.state();
+        let corrupt = Self::error(Self::record(&mut state, MockCall::Patch, Some(key.clone())))?;
+        Self::check_fields::<Key, Data, Spec>(&changes.keys().copied().collect::<Vec<_>>())?;
+        match Self::position(&state.rows, &key) {
+            Ok(index) => {
+                state.rows[index].1.extend(changes.into_iter().chain(corrupt.unwrap_or_default()));
+                Ok(1)
+            },
+            Err(_) => Ok(0)
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            supports_query: true,
+            ordered_scan: true,
+            ..Capabilities::default()
+        }
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>> PersistenceAdapterQueryable<Key, Data, Spec> for MockPersistence {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.rows::<Key, Data, Spec>(MockCall::Query, |key, fields|matches(&query, Spec::key_field(), key, fields)).into_iter().skip(start).take(limit.unwrap_or(usize::MAX)).collect()
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        let mut state = self.state();
+        Self::error(Self::record(&mut state, MockCall::ClearWhere, None))?;
+        let before = state.rows.len();
+        state.rows.retain(|(key, fields)|!matches(&query, Spec::key_field(), key, fields));
+        Ok((before - state.rows.len()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, Query};
+    use crate::persistence_adapter::mock::{MockCall, MockFailure, MockPersistence};
+    use crate::tests::{AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_mock_failures() {
+        let mock = MockPersistence::new();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &mock;
+        assert!(adapter.initialize().is_some());
+
+        let entry = AllSupportedTypes::with_integer(1);
+
+        mock.fail(MockCall::Store, 3, MockFailure::Error("database is locked".to_string()));
+        assert!(adapter.store(&"b".to_string(), &entry).is_ok());
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+        assert!(adapter.store(&"c".to_string(), &entry).is_err_and(|e|e.message.contains("database is locked")));
+        assert!(adapter.store(&"c".to_string(), &entry).is_ok());
+        assert!(adapter.store(&"c".to_string(), &entry).is_err());
+
+        assert_eq!(adapter.scan(0, None).into_iter().map(|(k, _)|k).collect::<Vec<_>>(), vec!["a", "b", "c"]);
+        assert_eq!(adapter.scan_range(Some(&"b".to_string()), None, None).len(), 2);
+        assert_eq!(adapter.patch(&"a".to_string(), HashMap::from([("integer", PersistenceData::Integer(2))])).ok(), Some(1));
+        assert_eq!(PersistenceAdapterQueryable::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::query(&mock, Query::GreaterThan("integer".to_string(), PersistenceData::Integer(1)), 0, None).len(), 1);
+
+        mock.fail_all(MockCall::Load, MockFailure::Corrupt(HashMap::from([("string", PersistenceData::String("garbage".to_string()))])));
+        assert_eq!(adapter.load(&"a".to_string()).map(|a|a.string), Some("garbage".to_string()));
+        mock.reset_failures();
+        assert_eq!(adapter.load(&"a".to_string()).map(|a|a.string), Some("s".to_string()));
+
+        assert_eq!(mock.call_count(MockCall::Store), 5);
+        assert_eq!(mock.calls().last().and_then(|c|c.key.clone()).and_then(PersistenceData::into_string), Some("a".to_string()));
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt::Display;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, Query};
+
+// Where the next page of a paginated scan or query starts: the first key not returned yet and a hash of the
+// filter it belongs to. Unlike an offset it stays on the same row when rows before it are added or removed.
+// Hand it out as its string form and read it back with parse, its contents aren't part of the API
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(into = "String", try_from = "String"))]
+pub struct PageToken {
+    filter_hash: u64,
+    next_key: PersistenceData
+}
+
+// a page of rows, next is None on the last page
+#[derive(Debug, Clone)]
+pub struct Page<Key, Data> {
+    pub rows: Vec<(Key, Data)>,
+    pub next: Option<PageToken>
+}
+
+impl PageToken {
+    pub(crate) fn new(filter: Option<&Query>, next_key: PersistenceData) -> Self {
+        PageToken { filter_hash: filter_hash(filter), next_key }
+    }
+
+    pub fn parse(token: &str) -> Result<Self, PersistenceError> {
+        let invalid = ||PersistenceError::Serialization { message: "Invalid page token".to_string() };
+        let bytes = (0..token.len()).step_by(2)
+            .map(|i|token.get(i..i + 2).and_then(|pair|u8::from_str_radix(pair, 16).ok()))
+            .collect::<Option<Vec<_>>>().ok_or_else(invalid)?;
+        let (hash, rest) = bytes.split_at_checked(8).ok_or_else(invalid)?;
+        let (tag, value) = rest.split_first().ok_or_else(invalid)?;
+        let fixed = |value: &[u8]|<[u8; 8]>::try_from(value).map_err(|_|invalid());
+        let next_key = match tag {
+            0 => PersistenceData::String(String::from_utf8(value.to_vec()).map_err(|_|invalid())?),
+            1 => PersistenceData::Bytes(value.to_vec()),
+            2 => PersistenceData::Integer(i64::from_be_bytes(fixed(value)?)),
+            3 => PersistenceData::UnsignedInteger(u64::from_be_bytes(fixed(value)?)),
+            4 => PersistenceData::Float(f32::from_be_bytes(value.try_into().map_err(|_|invalid())?)),
+            5 => PersistenceData::Double(f64::from_be_bytes(fixed(value)?)),
+            #[cfg(feature = "decimal")]
+            6 => PersistenceData::Decimal(std::str::from_utf8(value).ok().and_then(|s|s.parse().ok()).ok_or_else(invalid)?),
+            _ => return Err(invalid())
+        };
+        Ok(PageToken { filter_hash: u64::from_be_bytes(fixed(hash)?), next_key })
+    }
+
+    // the key the next page starts at, if the token was made for filter
+    pub(crate) fn next_key(&self, filter: Option<&Query>) -> Result<&PersistenceData, PersistenceError> {
+        match self.filter_hash == filter_hash(filter) {
+            true => Ok(&self.next_key),
+            false => Err(PersistenceError::Serialization { message: "The page token belongs to another filter".to_string() })
+        }
+    }
+}
+
+impl Display for PageToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (tag, value) = match &self.next_key {
+            PersistenceData::String(s) => (0u8, s.as_bytes().to_vec()),
+            PersistenceData::Bytes(b) => (1, b.clone()),
+            PersistenceData::Integer(i) => (2, i.to_be_bytes().to_vec()),
+            PersistenceData::UnsignedInteger(u) => (3, u.to_be_bytes().to_vec()),
+            PersistenceData::Float(x) => (4, x.to_be_bytes().to_vec()),
+            PersistenceData::Double(x) => (5, x.to_be_bytes().to_vec()),
+            #[cfg(feature = "decimal")]
+            PersistenceData::Decimal(d) => (6, d.to_string().into_bytes())
+        };
+        self.filter_hash.to_be_bytes().iter().chain([tag].iter()).chain(value.iter()).try_for_each(|b|write!(f, "{b:02x}"))
+    }
+}
+
+impl From<PageToken> for String {
+    fn from(token: PageToken) -> Self {
+        token.to_string()
+    }
+}
+
+impl TryFrom<String> for PageToken {
+    type Error = PersistenceError;
+
+    fn try_from(token: String) -> Result<Self, Self::Error> {
+        PageToken::parse(&token)
+    }
+}
+
+// the filter's debug form hashed, so a token is only accepted with the filter it was made for
+fn filter_hash(filter: Option<&Query>) -> u64 {
+    stable_hash(&filter.map(|filter|format!("{filter:?}")).unwrap_or_default())
+}
+
+// FNV-1a, stable across processes and builds unlike the std hashers
+pub(crate) fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, b|(hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use crate::persistence_adapter::{PersistenceData, Query};
+    use crate::persistence_adapter::page::PageToken;
+    use crate::persistence_adapter::repository::Repository;
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_page_tokens() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(persistence);
+        let row = AllSupportedTypes::with_integer;
+        for i in 0..5 {
+            assert!(repo.store(&i.to_string(), &row(i)).is_ok());
+        }
+        let keys = |rows: &[(String, AllSupportedTypes)]|rows.iter().map(|(key, _)|key.clone()).collect::<Vec<_>>();
+
+        let first = repo.scan_page(None, 2).expect("Failed to scan");
+        assert_eq!(keys(&first.rows), vec!["0", "1"]);
+        // the token is handed out as a string and keeps its place when earlier rows go
+        let token = first.next.expect("Should have a next page").to_string();
+        assert!(repo.delete(&"0".to_string()).is_ok());
+        let second = repo.scan_page(Some(&PageToken::parse(&token).expect("Failed to parse")), 2).expect("Failed to scan");
+        assert_eq!(keys(&second.rows), vec!["2", "3"]);
+        let last = repo.scan_page(second.next.as_ref(), 2).expect("Failed to scan");
+        assert_eq!(keys(&last.rows), vec!["4"]);
+        assert!(last.next.is_none());
+
+        let odd = ||Query::or(Query::Equals("integer".to_string(), PersistenceData::Integer(1)), Query::GreaterThan("integer".to_string(), PersistenceData::Integer(2)));
+        let page = repo.query_page(odd(), None, 2).expect("Failed to query");
+        assert_eq!(keys(&page.rows), vec!["1", "3"]);
+        let page = repo.query_page(odd(), page.next.as_ref(), 2).expect("Failed to query");
+        assert_eq!(keys(&page.rows), vec!["4"]);
+
+        // tokens only continue the scan or query they came from
+        assert!(repo.query_page(odd(), second.next.as_ref(), 2).is_err());
+        assert!(PageToken::parse("not a token").is_err());
+
+        // the extra row read past a limit of usize::MAX doesn't overflow it
+        let all = repo.scan_page(None, usize::MAX).expect("Failed to scan");
+        assert_eq!(keys(&all.rows), vec!["1", "2", "3", "4"]);
+        assert!(all.next.is_none());
+        assert!(repo.query_page(odd(), None, usize::MAX).is_ok_and(|page|keys(&page.rows) == vec!["1", "3", "4"]));
+    }
+}
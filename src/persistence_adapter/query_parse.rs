@@ -0,0 +1,331 @@
+use std::fmt::Display;
+use crate::persistence_adapter::{PersistenceData, Query};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryParseError {
+    pub message: String,
+    pub position: usize // byte offset into the parsed string
+}
+
+impl Display for QueryParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for QueryParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Value(Value),
+    Operator(&'static str),
+    And,
+    Or,
+    Not,
+    OpenParen,
+    CloseParen
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    UnsignedInteger(u64),
+    Double(f64)
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Identifier(name) => format!("field '{name}'"),
+            Token::Value(_) => "value".to_string(),
+            Token::Operator(op) => format!("'{op}'"),
+            Token::And => "AND".to_string(),
+            Token::Or => "OR".to_string(),
+            Token::Not => "NOT".to_string(),
+            Token::OpenParen => "'('".to_string(),
+            Token::CloseParen => "')'".to_string(),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, QueryParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => { chars.next(); },
+            '(' => { chars.next(); tokens.push((Token::OpenParen, position)); },
+            ')' => { chars.next(); tokens.push((Token::CloseParen, position)); },
+            '=' => { chars.next(); tokens.push((Token::Operator("="), position)); },
+            '!' => {
+                chars.next();
+                match chars.next() {
+                    Some((_, '=')) => tokens.push((Token::Operator("!="), position)),
+                    _ => return Err(QueryParseError { message: "expected '=' after '!'".to_string(), position })
+                }
+            },
+            '<' | '>' => {
+                chars.next();
+                let op = match (c, chars.peek()) {
+                    ('<', Some((_, '='))) => { chars.next(); "<=" },
+                    ('>', Some((_, '='))) => { chars.next(); ">=" },
+                    ('<', Some((_, '>'))) => { chars.next(); "!=" },
+                    ('<', _) => "<",
+                    _ => ">",
+                };
+                tokens.push((Token::Operator(op), position));
+            },
+            '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) => {
+                            // a doubled quote is an escaped quote, like in SQL
+                            if let Some((_, '\'')) = chars.peek() {
+                                chars.next();
+                                value.push('\'');
+                            } else {
+                                break;
+                            }
+                        },
+                        Some((_, c)) => value.push(c),
+                        None => return Err(QueryParseError { message: "unterminated string".to_string(), position })
+                    }
+                }
+                tokens.push((Token::Value(Value::String(value)), position));
+            },
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let mut literal = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || ((c == '-' || c == '+') && (literal.is_empty() || literal.ends_with(['e', 'E']))) {
+                        literal.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let value = if let Ok(i) = literal.parse::<i64>() {
+                    Value::Integer(i)
+                } else if let Ok(u) = literal.parse::<u64>() {
+                    Value::UnsignedInteger(u)
+                } else if let Ok(d) = literal.parse::<f64>() {
+                    Value::Double(d)
+                } else {
+                    return Err(QueryParseError { message: format!("invalid number '{literal}'"), position });
+                };
+                tokens.push((Token::Value(value), position));
+            },
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        word.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let token = match word.to_ascii_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Identifier(word)
+                };
+                tokens.push((token, position));
+            },
+            _ => return Err(QueryParseError { message: format!("unexpected character '{c}'"), position })
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Filters come from callers, these keep a hostile one from overflowing the stack, here or in the recursion
+// over the parsed query when it's run or dropped
+const MAX_DEPTH: usize = 64; // NOTs and parentheses nested in each other
+const MAX_COMPARISONS: usize = 1000;
+
+// Recursive descent over: or := and (OR and)*, and := unary (AND unary)*, unary := NOT unary | ( or ) | field op value
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+    end: usize,
+    depth: usize,
+    comparisons: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(t, _)|t)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map(|(_, p)|*p).unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).map(|(t, _)|t.clone());
+        self.index += 1;
+        token
+    }
+
+    fn error<T>(&self, expected: &str) -> Result<T, QueryParseError> {
+        let found = self.peek().map(Token::describe).unwrap_or_else(||"end of input".to_string());
+        Err(QueryParseError { message: format!("expected {expected}, found {found}"), position: self.position() })
+    }
+
+    fn parse_or(&mut self) -> Result<Query, QueryParseError> {
+        let mut query = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            query = Query::or(query, self.parse_and()?);
+        }
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<Query, QueryParseError> {
+        let mut query = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            query = Query::and(query, self.parse_unary()?);
+        }
+        Ok(query)
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, QueryParseError> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.enter()?;
+                self.next();
+                let query = Query::not(self.parse_unary()?);
+                self.depth -= 1;
+                Ok(query)
+            },
+            Some(Token::OpenParen) => {
+                self.enter()?;
+                self.next();
+                let query = self.parse_or()?;
+                if self.peek() != Some(&Token::CloseParen) {
+                    return self.error("')'");
+                }
+                self.next();
+                self.depth -= 1;
+                Ok(query)
+            },
+            Some(Token::Identifier(_)) => self.parse_comparison(),
+            _ => self.error("field name, NOT or '('")
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), QueryParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(QueryParseError { message: format!("nested deeper than {MAX_DEPTH} levels"), position: self.position() });
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn parse_comparison(&mut self) -> Result<Query, QueryParseError> {
+        if self.comparisons == MAX_COMPARISONS {
+            return Err(QueryParseError { message: format!("more than {MAX_COMPARISONS} comparisons"), position: self.position() });
+        }
+        self.comparisons += 1;
+        let Some(Token::Identifier(field)) = self.peek().cloned() else {
+            return self.error("field name");
+        };
+        self.next();
+        let op = match self.peek() {
+            Some(Token::Operator(op)) => *op,
+            _ => return self.error(&format!("comparison operator after '{field}'"))
+        };
+        self.next();
+        let value = match self.peek() {
+            Some(Token::Value(value)) => match value.clone() {
+                Value::String(s) => PersistenceData::String(s),
+                Value::Integer(i) => PersistenceData::Integer(i),
+                Value::UnsignedInteger(u) => PersistenceData::UnsignedInteger(u),
+                Value::Double(d) => PersistenceData::Double(d),
+            },
+            _ => return self.error(&format!("value after '{field} {op}'"))
+        };
+        self.next();
+
+        Ok(match op {
+            "=" => Query::Equals(field, value),
+            "!=" => Query::not(Query::Equals(field, value)),
+            ">" => Query::GreaterThan(field, value),
+            "<" => Query::LessThan(field, value),
+            ">=" => Query::not(Query::LessThan(field, value)),
+            _ => Query::not(Query::GreaterThan(field, value)),
+        })
+    }
+}
+
+impl Query {
+    // Parses filters like "age > 18 AND (name = 'bob' OR NOT admin = 1)". Supports =, !=, <>, <, >, <=, >=,
+    // AND, OR, NOT and parentheses; strings are single quoted with '' as an escaped quote. Filters nesting NOTs
+    // and parentheses more than 64 levels deep or with more than 1000 comparisons are rejected
+    pub fn parse(input: &str) -> Result<Query, QueryParseError> {
+        let mut parser = Parser { tokens: tokenize(input)?, index: 0, end: input.len(), depth: 0, comparisons: 0 };
+        let query = parser.parse_or()?;
+        if parser.peek().is_some() {
+            return parser.error("AND, OR or end of input");
+        }
+        Ok(query)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::{PersistenceData, Query};
+
+    #[test]
+    fn test_parse_query() {
+        let parsed = Query::parse("age >= 18 and (name = 'bob''s' OR NOT score < -1.5)").expect("Failed to parse");
+        let expected = Query::and(
+            Query::not(Query::LessThan("age".to_string(), PersistenceData::Integer(18))),
+            Query::or(
+                Query::Equals("name".to_string(), PersistenceData::String("bob's".to_string())),
+                Query::not(Query::LessThan("score".to_string(), PersistenceData::Double(-1.5)))
+            )
+        );
+        assert_eq!(format!("{parsed:?}"), format!("{expected:?}"));
+
+        let parsed = Query::parse("a = 1 OR b = 2 AND c = 18446744073709551615").expect("Failed to parse");
+        let expected = Query::or(
+            Query::Equals("a".to_string(), PersistenceData::Integer(1)),
+            Query::and(
+                Query::Equals("b".to_string(), PersistenceData::Integer(2)),
+                Query::Equals("c".to_string(), PersistenceData::UnsignedInteger(u64::MAX))
+            )
+        );
+        assert_eq!(format!("{parsed:?}"), format!("{expected:?}"));
+    }
+
+    #[test]
+    fn test_parse_query_errors() {
+        let error = |input: &str|Query::parse(input).expect_err("Parse should fail").to_string();
+
+        assert_eq!(error("age 18"), "expected comparison operator after 'age', found value at position 4");
+        assert_eq!(error("age > "), "expected value after 'age >', found end of input at position 6");
+        assert_eq!(error("(age > 1"), "expected ')', found end of input at position 8");
+        assert_eq!(error("name = 'bob"), "unterminated string at position 7");
+        assert_eq!(error("age > 1 age < 2"), "expected AND, OR or end of input, found field 'age' at position 8");
+        assert_eq!(error("age ~ 1"), "unexpected character '~' at position 4");
+    }
+
+    #[test]
+    fn test_parse_query_limits() {
+        let error = |input: &str|Query::parse(input).expect_err("Parse should fail").to_string();
+
+        assert!(Query::parse(&format!("{}a = 1{}", "(".repeat(64), ")".repeat(64))).is_ok());
+        assert_eq!(error(&format!("{}a = 1{}", "(".repeat(65), ")".repeat(65))), "nested deeper than 64 levels at position 64");
+        assert_eq!(error(&format!("{}a = 1", "NOT ".repeat(200_000))), "nested deeper than 64 levels at position 256");
+        assert!(error(&"(".repeat(200_000)).starts_with("nested deeper than 64 levels"));
+        assert!(error(&vec!["a = 1"; 200_000].join(" AND ")).starts_with("more than 1000 comparisons"));
+    }
+}
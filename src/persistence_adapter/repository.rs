@@ -0,0 +1,192 @@
+use std::{collections::HashMap, marker::PhantomData, time::SystemTime};
+use crate::persistence_adapter::{keygen::{KeyGenerator, TimeOrderedKey}, page::{Page, PageToken}, Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+
+// Binds an adapter to one Key/Data/Spec combination so calls don't need the trait turbofish,
+// e.g. repo.load(&key) instead of PersistenceAdapter::<Key, Data, Spec>::load(&adapter, &key)
+pub struct Repository<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> {
+    adapter: A,
+    _marker: PhantomData<(Key, Data, Spec)>
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> Repository<Key, Data, Spec, A> {
+    pub fn new(adapter: A) -> Self {
+        Repository { adapter, _marker: PhantomData }
+    }
+
+    pub fn adapter(&self) -> &A {
+        &self.adapter
+    }
+
+    pub fn into_adapter(self) -> A {
+        self.adapter
+    }
+
+    pub fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    pub fn load(&self, key: &Key) -> Option<Data> {
+        self.adapter.load(key)
+    }
+
+    pub fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        self.adapter.delete(key)
+    }
+
+    pub fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.adapter.store(key, data)
+    }
+
+    // stores data under a new key from generator and returns the key
+    pub fn store_generated(&self, generator: &impl KeyGenerator<Key>, data: &Data) -> Result<Key, StoreError> {
+        let key = generator.generate()?;
+        self.adapter.store(&key, data)?;
+        Ok(key)
+    }
+
+    pub fn contains(&self, key: &Key) -> bool {
+        self.adapter.contains(key)
+    }
+
+    pub fn clear(&self) -> Result<u64, PersistenceError> {
+        self.adapter.clear()
+    }
+
+    pub fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan(start, limit)
+    }
+
+    pub fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan_range(from, to, limit)
+    }
+
+    // Keyset pagination on adapters with ordered_scan: up to limit rows from where token left off, the first
+    // page without one. Pass the returned page's next to get the page after it
+    pub fn scan_page(&self, token: Option<&PageToken>, limit: usize) -> Result<Page<Key, Data>, PersistenceError> {
+        let from = token.map(|token|Self::deserialize_page_key(token.next_key(None)?)).transpose()?;
+        Ok(Self::page(self.adapter.scan_range(from.as_ref(), None, Some(limit.saturating_add(1))), limit, None))
+    }
+
+    pub fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        self.adapter.update(key, data, only_update)
+    }
+
+    pub fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        self.adapter.patch(key, changes)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.adapter.capabilities()
+    }
+
+    // one row past limit is read to know whether there's a next page, it's where that page starts
+    fn page(mut rows: Vec<(Key, Data)>, limit: usize, filter: Option<&Query>) -> Page<Key, Data> {
+        let next = match rows.len() > limit {
+            true => rows.pop().map(|(key, _)|PageToken::new(filter, Spec::serialize_key(&key))),
+            false => None
+        };
+        Page { rows, next }
+    }
+
+    fn deserialize_page_key(key: &PersistenceData) -> Result<Key, PersistenceError> {
+        Spec::deserialize_key(key).ok_or_else(||PersistenceError::Serialization { message: "Invalid key in page token".to_string() })
+    }
+}
+
+impl<Data, Spec: PersistenceSpec<TimeOrderedKey, Data>, A: PersistenceAdapter<TimeOrderedKey, Data, Spec>> Repository<TimeOrderedKey, Data, Spec, A> {
+    // Rows whose keys were created in [from, to), oldest first on adapters with ordered_scan
+    pub fn scan_time_range(&self, from: SystemTime, to: SystemTime, limit: Option<usize>) -> Vec<(TimeOrderedKey, Data)> {
+        self.adapter.scan_range(Some(&TimeOrderedKey::lower_bound(from)), Some(&TimeOrderedKey::lower_bound(to)), limit)
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec>> Repository<Key, Data, Spec, A> {
+    pub fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.query(query, start, limit)
+    }
+
+    pub fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.adapter.clear_where(query)
+    }
+
+    // Keyset pagination of a query's results, like scan_page. A token only continues the query it came from
+    pub fn query_page(&self, query: Query, token: Option<&PageToken>, limit: usize) -> Result<Page<Key, Data>, PersistenceError> {
+        let filter = match token {
+            Some(token) => {
+                let next_key = token.next_key(Some(&query))?;
+                Self::deserialize_page_key(next_key)?;
+                Query::and(query.clone(), Query::not(Query::LessThan(Spec::key_field().to_string(), next_key.clone())))
+            },
+            None => query.clone()
+        };
+        Ok(Self::page(self.adapter.query(filter, 0, Some(limit.saturating_add(1))), limit, Some(&query)))
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, Query};
+    use crate::persistence_adapter::keygen::{KeyGenerator, Sequential};
+    use crate::persistence_adapter::repository::Repository;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    type Repo = Repository<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, SqlitePersistence>;
+
+    #[test]
+    fn test_repository() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let repo = Repo::new(SqlitePersistence::new(db_connection, "test_table"));
+        assert!(repo.initialize().is_some());
+        assert!(repo.store(&"a".to_string(), &AllSupportedTypes::with_integer(1)).is_ok());
+        assert!(repo.store(&"a".to_string(), &AllSupportedTypes::with_integer(1)).is_err());
+        assert!(repo.store(&"b".to_string(), &AllSupportedTypes::with_integer(2)).is_ok());
+        assert!(repo.contains(&"a".to_string()));
+        assert_eq!(repo.load(&"a".to_string()), Some(AllSupportedTypes::with_integer(1)));
+        assert_eq!(repo.scan(0, None).len(), 2);
+        assert_eq!(repo.scan_range(Some(&"b".to_string()), None, None), vec![("b".to_string(), AllSupportedTypes::with_integer(2))]);
+        assert_eq!(repo.query(Query::GreaterThan("integer".to_string(), PersistenceData::Integer(1)), 0, None), vec![("b".to_string(), AllSupportedTypes::with_integer(2))]);
+
+        assert_eq!(repo.update(&"a".to_string(), &AllSupportedTypes::with_integer(3), Some(&["integer"])).ok(), Some(1));
+        assert_eq!(repo.patch(&"b".to_string(), HashMap::from([("integer", PersistenceData::Integer(4))])).ok(), Some(1));
+        assert_eq!(repo.scan(0, None), vec![("a".to_string(), AllSupportedTypes::with_integer(3)), ("b".to_string(), AllSupportedTypes::with_integer(4))]);
+        assert_eq!(repo.update(&"missing".to_string(), &AllSupportedTypes::with_integer(1), None).ok(), Some(0));
+
+        assert_eq!(repo.clear_where(Query::Equals("integer".to_string(), PersistenceData::Integer(4))).ok(), Some(1));
+        assert_eq!(repo.delete(&"a".to_string()).ok(), Some(1));
+        assert!(!repo.contains(&"a".to_string()));
+        assert_eq!(repo.clear().ok(), Some(0));
+    }
+
+    #[test]
+    fn test_repository_store_generated() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let repo = Repo::new(SqlitePersistence::new(db_connection, "test_table"));
+        repo.initialize();
+        let generator = Sequential::new(0);
+        assert_eq!(repo.store_generated(&generator, &AllSupportedTypes::with_integer(1)).ok(), Some("1".to_string()));
+        assert_eq!(repo.store_generated(&generator, &AllSupportedTypes::with_integer(2)).ok(), Some("2".to_string()));
+        assert_eq!(repo.load(&"2".to_string()), Some(AllSupportedTypes::with_integer(2)));
+
+        // a key that's already taken is an error, not an overwrite
+        assert!(repo.store_generated(&Sequential::new(0), &AllSupportedTypes::with_integer(3)).is_err());
+        assert_eq!(repo.load(&"1".to_string()), Some(AllSupportedTypes::with_integer(1)));
+
+        // nor is a generator that can't make a key
+        struct Failing;
+        impl KeyGenerator<String> for Failing {
+            fn generate(&self) -> Result<String, PersistenceError> {
+                Err(PersistenceError::Backend { message: "No keys left".to_string() })
+            }
+        }
+        assert!(repo.store_generated(&Failing, &AllSupportedTypes::with_integer(3)).is_err_and(|e|matches!(e.kind, Some(PersistenceError::Backend { .. }))));
+        assert_eq!(repo.scan(0, None).len(), 2);
+
+        // the adapter is still reachable for what the repository doesn't wrap
+        let adapter = repo.into_adapter();
+        assert_eq!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::scan(&adapter, 0, None).len(), 2);
+    }
+}
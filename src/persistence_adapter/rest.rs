@@ -0,0 +1,211 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+use axum::{Json, Router};
+use axum::extract::{Path, Query as QueryParams, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use crate::persistence_adapter::{page::PageToken, repository::Repository, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, Query, Row};
+
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+#[derive(serde::Deserialize)]
+struct ListParams {
+    filter: Option<String>,
+    after: Option<String>,
+    limit: Option<usize>
+}
+
+// an error response, {"error": message}
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(error: impl Display) -> Self {
+        ApiError(StatusCode::BAD_REQUEST, error.to_string())
+    }
+
+    // errors in what the client sent are its fault, everything else is the server's
+    fn from_persistence(error: PersistenceError) -> Self {
+        match error {
+            PersistenceError::Serialization { .. } | PersistenceError::Spec { .. } | PersistenceError::FieldNotAllowed { .. } => ApiError::bad_request(error),
+            PersistenceError::UniqueViolation { .. } | PersistenceError::VersionConflict { .. } => ApiError(StatusCode::CONFLICT, error.to_string()),
+            PersistenceError::AccessDenied { .. } => ApiError(StatusCode::FORBIDDEN, error.to_string()),
+            error => ApiError(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+// Routes for a small admin API over adapter, nest it under a path of its own:
+//   GET    /       rows in key order, ?filter= in Query::parse syntax, ?limit= (100, at most 1000) and ?after=
+//                  with the next token of the previous page. Returns {"rows": [...], "next": token or null}
+//   GET    /{key}  the row, 404 if there's none
+//   PUT    /{key}  stores the row in the body, replacing an existing one
+//   DELETE /{key}  204, or 404 if there was no row
+// Rows are JSON objects of the spec's fields, key included, as Row::to_json writes them. The key in the path
+// is read as the key field's type. Filters may only name the spec's fields, sensitive ones excluded. The
+// adapter's calls block, so serve it from a multi-threaded runtime
+pub fn crud_router<Key, Data, Spec, A>(adapter: A) -> Router
+where
+    Key: Send + Sync + 'static,
+    Data: Send + Sync + 'static,
+    Spec: PersistenceSpec<Key, Data> + Send + Sync + 'static,
+    A: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec> + Send + Sync + 'static
+{
+    let repository = Arc::new(Repository::<Key, Data, Spec, A>::new(adapter));
+    Router::new()
+        .route("/", get(list::<Key, Data, Spec, A>))
+        .route("/{key}", get(load::<Key, Data, Spec, A>).put(store::<Key, Data, Spec, A>).delete(delete::<Key, Data, Spec, A>))
+        .with_state(repository)
+}
+
+type Repo<Key, Data, Spec, A> = State<Arc<Repository<Key, Data, Spec, A>>>;
+
+async fn list<Key, Data, Spec, A>(State(repository): Repo<Key, Data, Spec, A>, QueryParams(params): QueryParams<ListParams>) -> Result<Json<serde_json::Value>, ApiError>
+where
+    Spec: PersistenceSpec<Key, Data>,
+    A: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec>
+{
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let token = params.after.as_deref().map(PageToken::parse).transpose().map_err(ApiError::from_persistence)?;
+    let page = match params.filter.as_deref().filter(|f|!f.trim().is_empty()) {
+        Some(filter) => {
+            let query = Query::parse(filter).map_err(ApiError::bad_request)?;
+            let allowed = Spec::fields().iter().map(|f|f.get_name()).filter(|name|!Spec::sensitive_fields().contains(name)).collect::<Vec<_>>();
+            query.validate_fields(&allowed).map_err(ApiError::from_persistence)?;
+            repository.query_page(query, token.as_ref(), limit)
+        },
+        None => repository.scan_page(token.as_ref(), limit)
+    }.map_err(ApiError::from_persistence)?;
+
+    let rows = page.rows.iter().map(|(key, data)|row_json::<Key, Data, Spec>(key, data)).collect::<Result<Vec<_>, _>>()?;
+    Ok(Json(serde_json::json!({ "rows": rows, "next": page.next.map(|token|token.to_string()) })))
+}
+
+async fn load<Key, Data, Spec, A>(State(repository): Repo<Key, Data, Spec, A>, Path(key): Path<String>) -> Result<Json<serde_json::Value>, ApiError>
+where
+    Spec: PersistenceSpec<Key, Data>,
+    A: PersistenceAdapter<Key, Data, Spec>
+{
+    let key = parse_key::<Key, Data, Spec>(&key)?;
+    match repository.load(&key) {
+        Some(data) => Ok(Json(row_json::<Key, Data, Spec>(&key, &data)?)),
+        None => Err(ApiError(StatusCode::NOT_FOUND, "No such row".to_string()))
+    }
+}
+
+async fn store<Key, Data, Spec, A>(State(repository): Repo<Key, Data, Spec, A>, Path(key): Path<String>, Json(body): Json<serde_json::Value>) -> Result<StatusCode, ApiError>
+where
+    Spec: PersistenceSpec<Key, Data>,
+    A: PersistenceAdapter<Key, Data, Spec>
+{
+    let key = parse_key::<Key, Data, Spec>(&key)?;
+    let mut fields = Row::from_json(&body, Spec::fields()).map_err(ApiError::bad_request)?.into_inner();
+    fields.remove(Spec::key_field());
+    let data = Spec::deserialize_data(fields).map_err(|e|ApiError::from_persistence(e.into()))?;
+    let failed = |e: crate::persistence_adapter::StoreError|ApiError::from_persistence(e.into());
+    if repository.update(&key, &data, None).map_err(failed)? == 0 {
+        repository.store(&key, &data).map_err(failed)?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete<Key, Data, Spec, A>(State(repository): Repo<Key, Data, Spec, A>, Path(key): Path<String>) -> Result<StatusCode, ApiError>
+where
+    Spec: PersistenceSpec<Key, Data>,
+    A: PersistenceAdapter<Key, Data, Spec>
+{
+    let key = parse_key::<Key, Data, Spec>(&key)?;
+    match repository.delete(&key).map_err(ApiError::from_persistence)? {
+        0 => Err(ApiError(StatusCode::NOT_FOUND, "No such row".to_string())),
+        _ => Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+// the path segment as the key field's type, Bytes keys are hex
+fn parse_key<Key, Data, Spec: PersistenceSpec<Key, Data>>(text: &str) -> Result<Key, ApiError> {
+    let invalid = ||ApiError::bad_request(format!("Invalid key {text:?}"));
+    let field = Spec::fields().iter().find(|f|f.get_name() == Spec::key_field()).ok_or_else(invalid)?;
+    let data = match field {
+        PersistenceType::String(_) => PersistenceData::String(text.to_string()),
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => PersistenceData::String(text.to_string()),
+        PersistenceType::Bytes(_) => PersistenceData::Bytes((0..text.len()).step_by(2)
+            .map(|i|text.get(i..i + 2).and_then(|pair|u8::from_str_radix(pair, 16).ok()))
+            .collect::<Option<_>>().ok_or_else(invalid)?),
+        PersistenceType::Integer(_) => PersistenceData::Integer(text.parse().map_err(|_|invalid())?),
+        PersistenceType::UnsignedInteger(_) => PersistenceData::UnsignedInteger(text.parse().map_err(|_|invalid())?),
+        PersistenceType::Float(_) => PersistenceData::Float(text.parse().map_err(|_|invalid())?),
+        PersistenceType::Double(_) => PersistenceData::Double(text.parse().map_err(|_|invalid())?),
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => PersistenceData::Decimal(text.parse().map_err(|_|invalid())?),
+    };
+    Spec::deserialize_key(&data).ok_or_else(invalid)
+}
+
+fn row_json<Key, Data, Spec: PersistenceSpec<Key, Data>>(key: &Key, data: &Data) -> Result<serde_json::Value, ApiError> {
+    let mut fields: HashMap<_, _> = Spec::serialize_data(data).map_err(|e|ApiError::from_persistence(e.into()))?;
+    fields.insert(Spec::key_field(), Spec::serialize_key(key));
+    Ok(Row::from(fields).to_json())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Method, Request, StatusCode};
+    use tower::ServiceExt;
+    use crate::persistence_adapter::rest::crud_router;
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[tokio::test]
+    async fn test_crud_router() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let router = crud_router::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>(persistence);
+        let request = |method: Method, uri: &str, body: Option<serde_json::Value>|{
+            let router = router.clone();
+            let request = Request::builder().method(method).uri(uri).header("content-type", "application/json")
+                .body(body.map(|b|Body::from(b.to_string())).unwrap_or_default()).expect("Failed to build request");
+            async move {
+                let response = router.oneshot(request).await.expect("Failed to call router");
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await.expect("Failed to read body");
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+        let row = |integer: i64|serde_json::json!({ "string": "s", "bytes": [1, 2], "integer": integer, "unsigned_integer": 1, "float": 0.5, "double": 1.5 });
+
+        for i in 0..3 {
+            assert_eq!(request(Method::PUT, &format!("/{i}"), Some(row(i))).await.0, StatusCode::NO_CONTENT);
+        }
+        assert_eq!(request(Method::PUT, "/1", Some(row(10))).await.0, StatusCode::NO_CONTENT);
+        let (status, loaded) = request(Method::GET, "/1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(loaded["key"], "1");
+        assert_eq!(loaded["integer"], 10);
+        assert_eq!(loaded["bytes"], serde_json::json!([1, 2]));
+        assert_eq!(request(Method::GET, "/9", None).await.0, StatusCode::NOT_FOUND);
+
+        let (_, page) = request(Method::GET, "/?limit=2", None).await;
+        assert_eq!(page["rows"].as_array().map(|r|r.len()), Some(2));
+        let next = page["next"].as_str().expect("Should have a next page");
+        let (_, page) = request(Method::GET, &format!("/?limit=2&after={next}"), None).await;
+        assert_eq!(page["rows"][0]["key"], "2");
+        assert!(page["next"].is_null());
+
+        let (status, filtered) = request(Method::GET, "/?filter=integer%20%3E%201", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(filtered["rows"].as_array().map(|rows|rows.iter().map(|r|r["key"].clone()).collect::<Vec<_>>()), Some(vec!["1".into(), "2".into()]));
+        assert_eq!(request(Method::GET, "/?filter=integer%20%3E", None).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(request(Method::GET, "/?filter=secret%20%3D%201", None).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(request(Method::PUT, "/3", Some(serde_json::json!({ "integer": "not a number" }))).await.0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(request(Method::DELETE, "/0", None).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(request(Method::DELETE, "/0", None).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(request(Method::GET, "/0", None).await.0, StatusCode::NOT_FOUND);
+    }
+}
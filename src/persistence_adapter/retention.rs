@@ -0,0 +1,229 @@
+use std::{sync::{Arc, Mutex, mpsc::{self, RecvTimeoutError, Sender}}, thread::{self, JoinHandle}, time::Duration};
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceAdapterTtl, PersistenceData, PersistenceError, PersistenceSpec, Query, SpecError};
+use crate::persistence_adapter::clock::{duration_millis, Clock, SystemClock};
+
+// How long rows of a table are kept. field is an Integer field holding unix milliseconds, e.g. when an
+// event happened: rows older than max_age go, and beyond max_rows the oldest rows go
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    pub field: &'static str,
+    pub max_age: Option<Duration>,
+    pub max_rows: Option<usize>
+}
+
+impl RetentionPolicy {
+    pub fn max_age(field: &'static str, max_age: Duration) -> Self {
+        RetentionPolicy { field, max_age: Some(max_age), max_rows: None }
+    }
+
+    pub fn max_rows(field: &'static str, max_rows: usize) -> Self {
+        RetentionPolicy { field, max_age: None, max_rows: Some(max_rows) }
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn with_max_rows(mut self, max_rows: usize) -> Self {
+        self.max_rows = Some(max_rows);
+        self
+    }
+
+    // The rows of adapter beyond the policy at now. Rows past max_age are found with a query, max_rows scans
+    // the whole table to find the oldest ones
+    pub fn expired<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec>>(&self, adapter: &A, now: i64) -> Result<Vec<(Key, Data)>, PersistenceError> {
+        let timestamp = |data: &Data|Spec::serialize_data(data)?.get(self.field).and_then(PersistenceData::to_int)
+            .ok_or_else(||SpecError::new(self.field, "retention fields must be Integer timestamps"));
+        let threshold = self.max_age.map(|max_age|now.saturating_sub(duration_millis(max_age)));
+        let mut expired = match threshold {
+            Some(threshold) => adapter.query(Query::LessThan(self.field.to_string(), PersistenceData::Integer(threshold)), 0, None),
+            None => Vec::new()
+        };
+        if let Some(max_rows) = self.max_rows {
+            let mut rows = adapter.scan(0, None).into_iter().map(|row|Ok((timestamp(&row.1)?, row))).collect::<Result<Vec<_>, SpecError>>()?;
+            // the rows expired by age are already in, newest first so everything past max_rows is the oldest
+            rows.retain(|(at, _)|threshold.is_none_or(|threshold|*at >= threshold));
+            rows.sort_by_key(|(at, _)|std::cmp::Reverse(*at));
+            expired.extend(rows.into_iter().skip(max_rows).map(|(_, row)|row));
+        }
+        Ok(expired)
+    }
+}
+
+type Enforce = Box<dyn Fn(i64) -> Result<u64, PersistenceError> + Send + Sync>;
+
+// Retention policies registered per table, enforced on demand with enforce_retention or periodically on
+// a background thread with spawn. Rows beyond a policy are deleted, or moved to an archive adapter first
+pub struct Retention {
+    tables: Vec<Enforce>,
+    clock: Arc<dyn Clock>
+}
+
+impl Retention {
+    pub fn new() -> Self {
+        Retention { tables: Vec::new(), clock: Arc::new(SystemClock {}) }
+    }
+
+    // the clock row ages are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // deletes adapter's rows beyond policy
+    pub fn register<Key: 'static, Data: 'static, Spec: PersistenceSpec<Key, Data> + 'static, A: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec> + Send + Sync + 'static>(&mut self, adapter: Arc<A>, policy: RetentionPolicy) {
+        self.tables.push(Box::new(move |now|{
+            let mut deleted = 0;
+            for (key, _) in policy.expired(adapter.as_ref(), now)? {
+                deleted += adapter.delete(&key)?;
+            }
+            Ok(deleted)
+        }));
+    }
+
+    // Moves adapter's rows beyond policy into archive, each row is stored there before it's deleted. A row
+    // the archive already has is overwritten, so a run interrupted between the two is finished by the next
+    pub fn register_archiving<Key: 'static, Data: 'static, Spec: PersistenceSpec<Key, Data> + 'static, A: PersistenceAdapter<Key, Data, Spec> + PersistenceAdapterQueryable<Key, Data, Spec> + Send + Sync + 'static, B: PersistenceAdapter<Key, Data, Spec> + Send + Sync + 'static>(&mut self, adapter: Arc<A>, policy: RetentionPolicy, archive: Arc<B>) {
+        self.tables.push(Box::new(move |now|{
+            let mut archived = 0;
+            for (key, data) in policy.expired(adapter.as_ref(), now)? {
+                if archive.update(&key, &data, None)? == 0 {
+                    archive.store(&key, &data)?;
+                }
+                archived += adapter.delete(&key)?;
+            }
+            Ok(archived)
+        }));
+    }
+
+    // Purges adapter's expired rows along with the policies, see PersistenceAdapterTtl
+    pub fn register_expiring<Key: 'static, Data: 'static, Spec: PersistenceSpec<Key, Data> + 'static, A: PersistenceAdapterTtl<Key, Data, Spec> + Send + Sync + 'static>(&mut self, adapter: Arc<A>) {
+        self.tables.push(Box::new(move |_|adapter.purge_expired()));
+    }
+
+    // Applies every registered policy, returns the number of rows deleted or archived. Stops at the first error
+    pub fn enforce_retention(&self) -> Result<u64, PersistenceError> {
+        let now = self.clock.now_millis();
+        self.tables.iter().map(|enforce|enforce(now)).sum()
+    }
+
+    // Enforces the policies every interval on a background thread until the returned task is dropped
+    pub fn spawn(self, interval: Duration) -> RetentionTask {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let last_error = Arc::new(Mutex::new(None));
+        let thread = {
+            let last_error = last_error.clone();
+            thread::spawn(move ||loop {
+                let result = self.enforce_retention();
+                *last_error.lock().unwrap_or_else(|e|e.into_inner()) = result.err().map(|e|e.to_string());
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {},
+                    _ => break
+                }
+            })
+        };
+        RetentionTask { last_error, stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Background enforcement started by Retention::spawn, stops when dropped
+#[derive(Debug)]
+pub struct RetentionTask {
+    last_error: Arc<Mutex<Option<String>>>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl RetentionTask {
+    // the error of the latest run, None if it succeeded
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|e|e.into_inner()).clone()
+    }
+}
+
+impl Drop for RetentionTask {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{sync::Arc, thread::sleep, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::retention::{Retention, RetentionPolicy};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_retention() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let open = |table: &str|{
+            let persistence = Arc::new(SqlitePersistence::new(db_connection.clone(), table));
+            PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::initialize(persistence.as_ref());
+            persistence
+        };
+        let (logs, events, archive) = (open("logs"), open("events"), open("archive"));
+        // "integer" is when the row was written, one second apart
+        let row = |at: i64|AllSupportedTypes::with_integer(at * 1_000);
+        for at in 1..=10 {
+            assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(logs.as_ref(), &format!("{at:02}"), &row(at)).is_ok());
+            assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(events.as_ref(), &format!("{at:02}"), &row(at)).is_ok());
+        }
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(10)));
+        let mut retention = Retention::new().with_clock(clock.clone());
+        retention.register::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>(logs.clone(), RetentionPolicy::max_age("integer", Duration::from_secs(5)).with_max_rows(3));
+        retention.register_archiving::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _, _>(events.clone(), RetentionPolicy::max_age("integer", Duration::from_secs(3)), archive.clone());
+
+        // logs: 1 to 4 are too old and 5 to 7 over the row limit, events 1 to 6 are archived
+        assert_eq!(retention.enforce_retention().ok(), Some(7 + 6));
+        let keys = |adapter: &SqlitePersistence|PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::scan(adapter, 0, None).into_iter().map(|(key, _)|key).collect::<Vec<_>>();
+        assert_eq!(keys(&logs), vec!["08", "09", "10"]);
+        assert_eq!(keys(&events), vec!["07", "08", "09", "10"]);
+        assert_eq!(keys(&archive).len(), 6);
+
+        clock.advance(Duration::from_secs(20));
+        let task = retention.spawn(Duration::from_millis(10));
+        for _ in 0..100 {
+            if keys(&events).is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        assert!(keys(&events).is_empty() && keys(&logs).is_empty());
+        assert_eq!(task.last_error(), None);
+        assert_eq!(keys(&archive).len(), 10);
+    }
+
+    #[test]
+    fn test_retention_huge_max_age() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let logs = Arc::new(SqlitePersistence::new(db_connection, "logs"));
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = logs.as_ref();
+        adapter.initialize();
+        for (key, at) in [("ancient", -1_000_000_000_000), ("epoch", 0), ("now", 1_000_000)] {
+            assert!(adapter.store(&key.to_string(), &AllSupportedTypes::with_integer(at)).is_ok());
+        }
+
+        // Duration::MAX keeps rows for ever, the threshold saturates rather than wrapping to some arbitrary time
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let mut retention = Retention::new().with_clock(clock);
+        retention.register::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>(logs.clone(), RetentionPolicy::max_age("integer", Duration::MAX));
+        assert_eq!(retention.enforce_retention().ok(), Some(0));
+        assert_eq!(adapter.scan(0, None).len(), 3);
+    }
+}
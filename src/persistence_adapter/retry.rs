@@ -0,0 +1,187 @@
+use std::{collections::HashMap, thread, time::Duration};
+use crate::persistence_adapter::layer::Layer;
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+
+const DEFAULT_ATTEMPTS: u32 = 5;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(10);
+
+// Wraps an adapter and retries writes that failed with PersistenceError::Busy, another connection held the
+// lock, sleeping backoff before the second attempt and twice as long before each one after that. Other
+// errors and the last attempt's Busy are returned as they are. Reads that can't return an error aren't
+// retried, they'd need the adapter's last error to tell a busy database from missing data
+pub struct RetryingPersistence<A> {
+    adapter: A,
+    max_attempts: u32,
+    backoff: Duration
+}
+
+impl<A> RetryingPersistence<A> {
+    // up to 5 attempts with a 10ms first backoff until configured otherwise
+    pub fn new(adapter: A) -> Self {
+        RetryingPersistence { adapter, max_attempts: DEFAULT_ATTEMPTS, backoff: DEFAULT_BACKOFF }
+    }
+
+    // attempts including the first, 1 doesn't retry
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.adapter
+    }
+
+    pub fn into_inner(self) -> A {
+        self.adapter
+    }
+
+    fn retry<T, E>(&self, is_busy: impl Fn(&E) -> bool, call: impl Fn(&A) -> Result<T, E>) -> Result<T, E> {
+        let mut backoff = self.backoff;
+        for _ in 1..self.max_attempts {
+            match call(&self.adapter) {
+                Err(e) if is_busy(&e) => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                },
+                result => return result
+            }
+        }
+        call(&self.adapter)
+    }
+
+    fn retry_busy<T>(&self, call: impl Fn(&A) -> Result<T, PersistenceError>) -> Result<T, PersistenceError> {
+        self.retry(|e|matches!(e, PersistenceError::Busy { .. }), call)
+    }
+
+    fn retry_store<T>(&self, call: impl Fn(&A) -> Result<T, StoreError>) -> Result<T, StoreError> {
+        self.retry(StoreError::is_busy, call)
+    }
+}
+
+// wraps adapters in a RetryingPersistence with these settings
+#[derive(Debug, Clone, Copy)]
+pub struct RetryLayer {
+    pub max_attempts: u32,
+    pub backoff: Duration
+}
+
+impl Default for RetryLayer {
+    fn default() -> Self {
+        RetryLayer { max_attempts: DEFAULT_ATTEMPTS, backoff: DEFAULT_BACKOFF }
+    }
+}
+
+impl<A> Layer<A> for RetryLayer {
+    type Wrapped = RetryingPersistence<A>;
+
+    fn layer(&self, adapter: A) -> RetryingPersistence<A> {
+        RetryingPersistence::new(adapter).with_max_attempts(self.max_attempts).with_backoff(self.backoff)
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> PersistenceAdapter<Key, Data, Spec> for RetryingPersistence<A> {
+    fn initialize(&self) -> Option<()> {
+        self.adapter.initialize()
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.adapter.load(key)
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        self.retry_busy(|adapter|adapter.delete(key))
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.retry_store(|adapter|adapter.store(key, data))
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.adapter.contains(key)
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        self.retry_busy(|adapter|adapter.clear())
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan(start, limit)
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.scan_range(from, to, limit)
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        self.retry_store(|adapter|adapter.update(key, data, only_update))
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        self.retry_busy(|adapter|adapter.patch(key, changes.clone()))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.adapter.capabilities()
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapterQueryable<Key, Data, Spec>> PersistenceAdapterQueryable<Key, Data, Spec> for RetryingPersistence<A> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.adapter.query(query, start, limit)
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.retry_busy(|adapter|adapter.clear_where(query.clone()))
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{sync::Arc, thread, time::Duration};
+    use sqlite_::Connection;
+    use tempdir::TempDir;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceError};
+    use crate::persistence_adapter::layer::AdapterBuilder;
+    use crate::persistence_adapter::retry::RetryLayer;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_retry_busy() {
+        let temp_dir = TempDir::new("sqlite_test").expect("Failed to create tempdir");
+
+        let path = temp_dir.path().join("test.sqlite");
+        let db_connection = Arc::new(Connection::open_with_full_mutex(&path).expect("Failed to open temp db"));
+
+        let persistence = SqlitePersistence::new(db_connection, "test_table");
+        PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::initialize(&persistence);
+        let retrying = AdapterBuilder::new(persistence).layer(RetryLayer { max_attempts: 6, backoff: Duration::from_millis(2) }).build();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &retrying;
+        let row = AllSupportedTypes::with_integer(1);
+
+        // another connection holding the write lock for a while
+        let other = Connection::open_with_full_mutex(&path).expect("Failed to open temp db");
+        assert!(other.execute("BEGIN IMMEDIATE").is_ok());
+        let holder = thread::spawn(move ||{
+            thread::sleep(Duration::from_millis(30));
+            other.execute("ROLLBACK").is_ok()
+        });
+        assert!(adapter.store(&"a".to_string(), &row).is_ok());
+        assert!(holder.join().is_ok_and(|rolled_back|rolled_back));
+        assert_eq!(adapter.load(&"a".to_string()), Some(row.clone()));
+
+        // a lock held for longer than every attempt comes back as Busy, other errors aren't retried
+        let other = Connection::open_with_full_mutex(&path).expect("Failed to open temp db");
+        assert!(other.execute("BEGIN IMMEDIATE").is_ok());
+        assert!(matches!(adapter.delete(&"a".to_string()), Err(PersistenceError::Busy { .. })));
+        assert!(adapter.store(&"b".to_string(), &row).is_err_and(|e|e.is_busy()));
+        assert!(other.execute("ROLLBACK").is_ok());
+        assert!(adapter.store(&"a".to_string(), &row).is_err_and(|e|!e.is_busy()));
+        assert_eq!(adapter.delete(&"a".to_string()).ok(), Some(1));
+    }
+}
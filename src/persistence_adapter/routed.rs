@@ -0,0 +1,176 @@
+use std::{collections::HashMap, sync::{Arc, atomic::{AtomicI64, AtomicUsize, Ordering}}, time::Duration};
+use crate::persistence_adapter::{Capabilities, PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceError, PersistenceSpec, Query, StoreError};
+use crate::persistence_adapter::clock::{duration_millis, Clock, SystemClock};
+
+// Sends writes to a primary and spreads reads (load, contains, scans and queries) over read replicas
+// round robin, for deployments replicating a database. Replicas lag behind the primary, so for
+// max_staleness after a write through this wrapper reads go to the primary too, which gives read your
+// writes as long as replication catches up within it. initialize only runs against the primary,
+// the replicas get the schema through replication
+pub struct RoutedPersistence<A> {
+    primary: A,
+    replicas: Vec<A>,
+    next_replica: AtomicUsize,
+    max_staleness: Duration,
+    last_write: AtomicI64, // unix milliseconds, i64::MIN before any write
+    clock: Arc<dyn Clock>
+}
+
+impl<A> RoutedPersistence<A> {
+    // all reads go to the primary while replicas is empty
+    pub fn new(primary: A, replicas: Vec<A>) -> Self {
+        RoutedPersistence { primary, replicas, next_replica: AtomicUsize::new(0), max_staleness: Duration::ZERO, last_write: AtomicI64::new(i64::MIN), clock: Arc::new(SystemClock {}) }
+    }
+
+    // how far replicas may lag behind, reads go to the primary for this long after each write
+    pub fn with_max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = max_staleness;
+        self
+    }
+
+    // the clock the staleness window is measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    pub fn replicas(&self) -> &[A] {
+        &self.replicas
+    }
+
+    fn reader(&self) -> &A {
+        let since_write = self.clock.now_millis().saturating_sub(self.last_write.load(Ordering::SeqCst));
+        if self.replicas.is_empty() || since_write < duration_millis(self.max_staleness) {
+            return &self.primary;
+        }
+        &self.replicas[self.next_replica.fetch_add(1, Ordering::Relaxed) % self.replicas.len()]
+    }
+
+    // recorded before the write so a read racing it can't go to a replica that's missing it
+    fn writer(&self) -> &A {
+        self.last_write.fetch_max(self.clock.now_millis(), Ordering::SeqCst);
+        &self.primary
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapter<Key, Data, Spec>> PersistenceAdapter<Key, Data, Spec> for RoutedPersistence<A> {
+    fn initialize(&self) -> Option<()> {
+        self.primary.initialize()
+    }
+
+    fn load(&self, key: &Key) -> Option<Data> {
+        self.reader().load(key)
+    }
+
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
+        self.writer().delete(key)
+    }
+
+    fn store(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.writer().store(key, data)
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.reader().contains(key)
+    }
+
+    fn clear(&self) -> Result<u64, PersistenceError> {
+        self.writer().clear()
+    }
+
+    fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.reader().scan(start, limit)
+    }
+
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.reader().scan_range(from, to, limit)
+    }
+
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        self.writer().update(key, data, only_update)
+    }
+
+    fn patch(&self, key: &Key, changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        self.writer().patch(key, changes)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.primary.capabilities()
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>, A: PersistenceAdapterQueryable<Key, Data, Spec>> PersistenceAdapterQueryable<Key, Data, Spec> for RoutedPersistence<A> {
+    fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
+        self.reader().query(query, start, limit)
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
+        self.writer().clear_where(query)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests{
+    use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+    use sqlite_::Connection;
+    use tempdir::TempDir;
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::routed::RoutedPersistence;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_routed_persistence() {
+        let temp_dir = TempDir::new("sqlite_test").expect("Failed to create tempdir");
+
+        let open = |name: &str|{
+            let persistence = SqlitePersistence::new(Arc::new(Connection::open_with_full_mutex(temp_dir.path().join(name)).expect("Failed to open temp db")), "test_table");
+            PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::initialize(&persistence);
+            persistence
+        };
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let routed = RoutedPersistence::new(open("primary.sqlite"), vec![open("replica1.sqlite"), open("replica2.sqlite")])
+            .with_max_staleness(Duration::from_secs(2))
+            .with_clock(clock.clone());
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &routed;
+
+        let row = AllSupportedTypes::with_integer(1);
+        let key = "a".to_string();
+        assert!(adapter.store(&key, &row).is_ok());
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::contains(routed.primary(), &key));
+        assert!(!PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::contains(&routed.replicas()[0], &key));
+        // within the staleness window the write is read back from the primary
+        assert_eq!(adapter.load(&key), Some(row.clone()));
+
+        // afterwards reads alternate between the replicas, only the second has caught up
+        clock.advance(Duration::from_secs(2));
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&routed.replicas()[1], &key, &row).is_ok());
+        let reads = (0..4).map(|_|adapter.contains(&key)).collect::<Vec<_>>();
+        assert_eq!(reads, vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn test_routed_persistence_huge_staleness() {
+        let temp_dir = TempDir::new("sqlite_test").expect("Failed to create tempdir");
+
+        let open = |name: &str|{
+            let persistence = SqlitePersistence::new(Arc::new(Connection::open_with_full_mutex(temp_dir.path().join(name)).expect("Failed to open temp db")), "test_table");
+            PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::initialize(&persistence);
+            persistence
+        };
+        let routed = RoutedPersistence::new(open("primary.sqlite"), vec![open("replica.sqlite")]).with_max_staleness(Duration::MAX);
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &routed;
+
+        // before any write reads go to the replica, after one they stay on the primary
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&routed.replicas()[0], &"replicated".to_string(), &AllSupportedTypes::with_integer(1)).is_ok());
+        assert!(adapter.contains(&"replicated".to_string()));
+        assert!(adapter.store(&"a".to_string(), &AllSupportedTypes::with_integer(2)).is_ok());
+        assert!(adapter.contains(&"a".to_string()));
+        assert!(!adapter.contains(&"replicated".to_string()));
+    }
+}
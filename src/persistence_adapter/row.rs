@@ -0,0 +1,255 @@
+use std::{collections::HashMap, fmt::Display};
+use crate::persistence_adapter::{PersistenceData, SpecError};
+#[cfg(feature = "serde")]
+use crate::persistence_adapter::PersistenceType;
+
+// Types a field can be read as, one per PersistenceData variant
+pub trait FromPersistenceData: Sized {
+    const TYPE_NAME: &'static str;
+    fn from_persistence_data(data: PersistenceData) -> Option<Self>;
+}
+
+macro_rules! impl_from_persistence_data {
+    ($type:ty, $variant:ident) => {
+        impl FromPersistenceData for $type {
+            const TYPE_NAME: &'static str = stringify!($variant);
+
+            fn from_persistence_data(data: PersistenceData) -> Option<Self> {
+                if let PersistenceData::$variant(v) = data {
+                    return Some(v)
+                }
+                None
+            }
+        }
+    };
+}
+
+impl_from_persistence_data!(String, String);
+impl_from_persistence_data!(Vec<u8>, Bytes);
+impl_from_persistence_data!(i64, Integer);
+impl_from_persistence_data!(u64, UnsignedInteger);
+impl_from_persistence_data!(f32, Float);
+impl_from_persistence_data!(f64, Double);
+#[cfg(feature = "decimal")]
+impl_from_persistence_data!(rust_decimal::Decimal, Decimal);
+
+fn type_name(data: &PersistenceData) -> &'static str {
+    match data {
+        PersistenceData::String(_) => String::TYPE_NAME,
+        PersistenceData::Bytes(_) => Vec::<u8>::TYPE_NAME,
+        PersistenceData::Integer(_) => i64::TYPE_NAME,
+        PersistenceData::UnsignedInteger(_) => u64::TYPE_NAME,
+        PersistenceData::Float(_) => f32::TYPE_NAME,
+        PersistenceData::Double(_) => f64::TYPE_NAME,
+        #[cfg(feature = "decimal")]
+        PersistenceData::Decimal(_) => rust_decimal::Decimal::TYPE_NAME,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowError {
+    Missing { field: String },
+    WrongType { field: String, expected: &'static str, found: &'static str },
+    Unknown { field: String } // not one of the spec's fields
+}
+
+impl Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RowError::Missing { field } => write!(f, "field {field} is missing"),
+            RowError::WrongType { field, expected, found } => write!(f, "field {field} is {found}, expected {expected}"),
+            RowError::Unknown { field } => write!(f, "field {field} is not in the spec"),
+        }
+    }
+}
+
+impl std::error::Error for RowError {}
+
+impl From<RowError> for SpecError {
+    fn from(error: RowError) -> Self {
+        match error {
+            RowError::Missing { field } => SpecError { field, reason: "missing".to_string() },
+            RowError::WrongType { field, expected, found } => SpecError { field, reason: format!("is {found}, expected {expected}") },
+            RowError::Unknown { field } => SpecError { field, reason: "not in the spec".to_string() },
+        }
+    }
+}
+
+// The fields of one stored row, for spec deserialize_data implementations:
+// Row::from(data).get::<i64>("count")? instead of data.get("count").and_then(PersistenceData::to_int)
+#[derive(Debug, Clone, Default)]
+pub struct Row {
+    fields: HashMap<&'static str, PersistenceData>
+}
+
+impl Row {
+    pub fn get<T: FromPersistenceData>(&self, field: &str) -> Result<T, RowError> {
+        let data = self.fields.get(field).ok_or_else(||RowError::Missing { field: field.to_string() })?;
+        Row::convert(field, data.clone())
+    }
+
+    // like get but moves the value out, avoids cloning strings and bytes
+    pub fn take<T: FromPersistenceData>(&mut self, field: &str) -> Result<T, RowError> {
+        let data = self.fields.remove(field).ok_or_else(||RowError::Missing { field: field.to_string() })?;
+        Row::convert(field, data)
+    }
+
+    // None if the field is absent, still an error if it holds another type
+    pub fn get_optional<T: FromPersistenceData>(&self, field: &str) -> Result<Option<T>, RowError> {
+        match self.fields.get(field) {
+            Some(data) => Row::convert(field, data.clone()).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    pub fn into_inner(self) -> HashMap<&'static str, PersistenceData> {
+        self.fields
+    }
+
+    fn convert<T: FromPersistenceData>(field: &str, data: PersistenceData) -> Result<T, RowError> {
+        let found = type_name(&data);
+        T::from_persistence_data(data).ok_or_else(||RowError::WrongType { field: field.to_string(), expected: T::TYPE_NAME, found })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Row {
+    // The fields as a JSON object, for admin UIs and exports that don't know the spec's types. Bytes become
+    // arrays of numbers, decimals strings so they keep their precision, and NaN or infinite floats null
+    pub fn to_json(&self) -> serde_json::Value {
+        self.fields.iter().map(|(name, data)|{
+            let value = match data {
+                PersistenceData::String(s) => s.clone().into(),
+                PersistenceData::Bytes(b) => b.clone().into(),
+                PersistenceData::Integer(i) => (*i).into(),
+                PersistenceData::UnsignedInteger(u) => (*u).into(),
+                PersistenceData::Float(f) => (*f).into(),
+                PersistenceData::Double(d) => (*d).into(),
+                #[cfg(feature = "decimal")]
+                PersistenceData::Decimal(d) => d.to_string().into()
+            };
+            (name.to_string(), value)
+        }).collect::<serde_json::Map<_, _>>().into()
+    }
+
+    // Reads a JSON object written by to_json back, typed by fields (usually Spec::fields()). Fields absent from
+    // the object are left out for the spec to default or reject, members that aren't spec fields are errors
+    pub fn from_json(value: &serde_json::Value, fields: &'static [PersistenceType]) -> Result<Row, RowError> {
+        let object = value.as_object().ok_or_else(||RowError::WrongType { field: String::new(), expected: "object", found: json_type_name(value) })?;
+        let mut row = HashMap::new();
+        for (name, value) in object {
+            let field = fields.iter().find(|f|f.get_name() == name).ok_or_else(||RowError::Unknown { field: name.clone() })?;
+            row.insert(field.get_name(), from_json_value(field, value).ok_or_else(||RowError::WrongType { field: name.clone(), expected: field_type_name(field), found: json_type_name(value) })?);
+        }
+        Ok(Row { fields: row })
+    }
+}
+
+#[cfg(feature = "serde")]
+fn from_json_value(field: &PersistenceType, value: &serde_json::Value) -> Option<PersistenceData> {
+    Some(match field {
+        PersistenceType::String(_) => PersistenceData::String(value.as_str()?.to_string()),
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => PersistenceData::String(value.as_str()?.to_string()),
+        PersistenceType::Bytes(_) => PersistenceData::Bytes(value.as_array()?.iter().map(|b|b.as_u64().and_then(|b|u8::try_from(b).ok())).collect::<Option<_>>()?),
+        PersistenceType::Integer(_) => PersistenceData::Integer(value.as_i64()?),
+        PersistenceType::UnsignedInteger(_) => PersistenceData::UnsignedInteger(value.as_u64()?),
+        PersistenceType::Float(_) => PersistenceData::Float(value.as_f64().or_else(||value.is_null().then_some(f64::NAN))? as f32),
+        PersistenceType::Double(_) => PersistenceData::Double(value.as_f64().or_else(||value.is_null().then_some(f64::NAN))?),
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => PersistenceData::Decimal(match value {
+            serde_json::Value::Number(n) => n.to_string().parse().ok()?,
+            other => other.as_str()?.parse().ok()?
+        })
+    })
+}
+
+#[cfg(feature = "serde")]
+fn field_type_name(field: &PersistenceType) -> &'static str {
+    match field {
+        PersistenceType::String(_) => String::TYPE_NAME,
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => String::TYPE_NAME,
+        PersistenceType::Bytes(_) => Vec::<u8>::TYPE_NAME,
+        PersistenceType::Integer(_) => i64::TYPE_NAME,
+        PersistenceType::UnsignedInteger(_) => u64::TYPE_NAME,
+        PersistenceType::Float(_) => f32::TYPE_NAME,
+        PersistenceType::Double(_) => f64::TYPE_NAME,
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => rust_decimal::Decimal::TYPE_NAME,
+    }
+}
+
+#[cfg(feature = "serde")]
+fn json_type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object"
+    }
+}
+
+impl From<HashMap<&'static str, PersistenceData>> for Row {
+    fn from(fields: HashMap<&'static str, PersistenceData>) -> Self {
+        Row { fields }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceData, Row, RowError, SpecError};
+
+    #[test]
+    fn test_row_get() {
+        let mut row = Row::from(HashMap::from([
+            ("name", PersistenceData::String("bob".to_string())),
+            ("age", PersistenceData::UnsignedInteger(30))
+        ]));
+
+        assert_eq!(row.get::<u64>("age"), Ok(30));
+        assert_eq!(row.get_optional::<f64>("height"), Ok(None));
+        assert_eq!(row.get::<i64>("age"), Err(RowError::WrongType { field: "age".to_string(), expected: "Integer", found: "UnsignedInteger" }));
+        assert_eq!(row.take::<String>("name"), Ok("bob".to_string()));
+        assert_eq!(row.get::<String>("name"), Err(RowError::Missing { field: "name".to_string() }));
+
+        let error = SpecError::from(row.get::<String>("age").expect_err("Wrong type should fail"));
+        assert_eq!(error.to_string(), "field age: is UnsignedInteger, expected String");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_row_json() {
+        use crate::tests::AllSupportedTypesPersistenceSpec;
+        use crate::persistence_adapter::PersistenceSpec;
+
+        let row = Row::from(HashMap::from([
+            ("key", PersistenceData::String("a".to_string())),
+            ("bytes", PersistenceData::Bytes(vec![0, 255])),
+            ("integer", PersistenceData::Integer(-3)),
+            ("unsigned_integer", PersistenceData::UnsignedInteger(u64::MAX)),
+            ("double", PersistenceData::Double(0.5))
+        ]));
+        let json = row.to_json();
+        assert_eq!(json, serde_json::json!({ "key": "a", "bytes": [0, 255], "integer": -3, "unsigned_integer": u64::MAX, "double": 0.5 }));
+
+        let back = Row::from_json(&json, AllSupportedTypesPersistenceSpec::fields()).expect("Failed to read json");
+        assert_eq!(back.get::<Vec<u8>>("bytes"), Ok(vec![0, 255]));
+        assert_eq!(back.get::<u64>("unsigned_integer"), Ok(u64::MAX));
+        assert_eq!(back.get::<f64>("double"), Ok(0.5));
+        assert!(!back.contains("string"));
+
+        let fields = AllSupportedTypesPersistenceSpec::fields();
+        assert_eq!(Row::from_json(&serde_json::json!({ "integer": "3" }), fields).err(), Some(RowError::WrongType { field: "integer".to_string(), expected: "Integer", found: "a string" }));
+        assert_eq!(Row::from_json(&serde_json::json!({ "bytes": [256] }), fields).err().map(|e|e.to_string()), Some("field bytes is an array, expected Bytes".to_string()));
+        assert_eq!(Row::from_json(&serde_json::json!({ "other": 1 }), fields).err(), Some(RowError::Unknown { field: "other".to_string() }));
+        assert!(Row::from_json(&serde_json::json!([1]), fields).is_err());
+    }
+}
@@ -1,42 +1,673 @@
-use std::{sync::Arc, collections::HashMap};
+use std::{any::Any, sync::{Arc, Mutex}, collections::{HashMap, VecDeque}, fmt::Debug, path::Path, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use debug_ignore::DebugIgnore;
 use sqlite_::{ConnectionWithFullMutex, Statement};
 use sqlite_::State::{Row, Done};
 use itertools::intersperse;
-use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceSpec, PersistenceType, PersistenceData, StoreError};
+use crate::persistence_adapter::clock::{Clock, SystemClock};
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterHealth, PersistenceAdapterQueryable, PersistenceAdapterUpsert, Capabilities, HealthReport, PersistenceSpec, PersistenceType, PersistenceData, StoreError, PersistenceError, SpecError};
 
 use super::Query;
 
+mod admin;
+#[cfg(feature = "arrow")]
+mod arrow;
+mod backfill;
+mod blob;
+mod bulk;
+mod change_feed;
+mod checksum;
+mod conditional;
+#[cfg(feature = "decimal")]
+mod decimal;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod external_blob;
+mod generated;
+#[cfg(feature = "hashed")]
+mod hashed;
+mod import;
+mod join;
+mod leader;
+mod lock;
+#[cfg(feature = "otel")]
+mod otel;
+mod outbox;
+#[cfg(feature = "parquet")]
+mod parquet;
+mod partition;
+mod preflight;
+mod purge;
+mod queue;
+mod rate_limit;
+mod schema;
+mod scheduler;
+mod snapshot;
+mod stats;
+mod tenant;
+mod transaction;
+mod ttl;
+mod url;
+mod version;
+pub use admin::RawRow;
+pub use blob::BlobReader;
+pub use bulk::{BulkError, BulkOptions, BulkReport};
+pub use change_feed::{ChangeFeed, FeedEntry};
+pub use super::CasOutcome;
+#[cfg(feature = "encryption")]
+pub use encryption::{KeyProvider, StaticKey};
+pub use external_blob::ExternalBlobStore;
+pub use import::{ConflictPolicy, ImportFormat};
+pub use leader::{Campaign, LeaderElector, LeadershipEvent};
+pub use lock::{LockGuard, LockManager};
+pub use outbox::{Outbox, OutboxMessage};
+pub use partition::PartitionHandle;
+pub use preflight::{PreflightFinding, PreflightReport};
+pub use queue::{PersistentQueue, QueueMessage};
+pub use rate_limit::{PersistentRateLimiter, RateLimitDecision};
+pub use schema::SchemaDrift;
+pub use scheduler::{Cron, Schedule, ScheduleParseError, ScheduledTask, Scheduler};
+pub use snapshot::ScanSnapshot;
+pub use transaction::Transaction;
+#[cfg(feature = "serde")]
+pub(crate) use url::check_pragmas;
+
+// sqlite's default limit for the size of a string or blob, builds can lower it with SQLITE_MAX_LENGTH
+const SQLITE_MAX_LENGTH: u64 = 1_000_000_000;
+
+impl From<sqlite_::Error> for PersistenceError {
+    fn from(error: sqlite_::Error) -> Self {
+        let message = format!("{error:?}");
+        classify_error(&error, message)
+    }
+}
+
+// Picks the PersistenceError variant for an error's result code. Constraint failures all share one primary
+// code, and extended codes aren't enabled on the connection, so the kind comes from sqlite's message prefix
+fn classify_error(error: &sqlite_::Error, message: String) -> PersistenceError {
+    const SQLITE_BUSY: isize = 5;
+    const SQLITE_LOCKED: isize = 6;
+    const SQLITE_READONLY: isize = 8;
+    const SQLITE_CONSTRAINT: isize = 19;
+    let detail = error.message.as_deref().unwrap_or_default();
+    match error.code {
+        Some(SQLITE_BUSY | SQLITE_LOCKED) => PersistenceError::Busy { message },
+        Some(SQLITE_READONLY) => PersistenceError::ReadOnly { message },
+        Some(SQLITE_CONSTRAINT) if detail.starts_with("UNIQUE") || detail.starts_with("PRIMARY KEY") => PersistenceError::UniqueViolation { message },
+        Some(SQLITE_CONSTRAINT) if detail.starts_with("NOT NULL") => PersistenceError::NotNullViolation { message },
+        Some(SQLITE_CONSTRAINT) if detail.starts_with("FOREIGN KEY") => PersistenceError::ForeignKeyViolation { message },
+        _ => PersistenceError::Backend { message }
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d|d.as_millis() as i64).unwrap_or(0)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b|format!("{b:02x}")).collect()
+}
+
+// table, field and savepoint names come from callers, a quote in one must not end the identifier
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn unreadable(column: &str, error: sqlite_::Error) -> SpecError {
+    SpecError::new(column, &format!("unreadable value: {error:?}"))
+}
+
+// a NULL is left to read_field, which reports it as unreadable
+fn check_stored_type(column: &str, stored: sqlite_::Type, expected: &'static str) -> Result<(), SpecError> {
+    let stored = match stored {
+        sqlite_::Type::Binary => "BLOB",
+        sqlite_::Type::Float => "REAL",
+        sqlite_::Type::Integer => "INTEGER",
+        sqlite_::Type::String => "TEXT",
+        sqlite_::Type::Null => return Ok(())
+    };
+    match stored == expected {
+        true => Ok(()),
+        false => Err(SpecError::new(column, &format!("stored as {stored} but the spec expects {expected}")))
+    }
+}
+
+fn column_type(field: &PersistenceType) -> &'static str {
+    match field {
+        PersistenceType::String(_) => "TEXT",
+        PersistenceType::Bytes(_) => "BLOB",
+        PersistenceType::Integer(_) | PersistenceType::UnsignedInteger(_) => "INTEGER",
+        PersistenceType::Float(_) | PersistenceType::Double(_) => "REAL",
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => "TEXT",
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => "TEXT",
+    }
+}
+
+// which maintenance tasks SqlitePersistence::maintain should run
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceOptions {
+    pub vacuum: bool, // rebuilds the whole database file to reclaim free pages
+    pub analyze: bool, // refreshes query planner statistics for this table
+    pub wal_checkpoint: bool // copies the WAL back into the database and truncates it
+}
+
+// what reading a row does when the table's columns don't match the spec's fields
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DeserializationMode {
+    #[default]
+    Strict, // extra or missing columns fail the row
+    Lenient // extra columns are ignored, missing ones are left out for the spec to default
+}
+
+// a statement that took at least the slow query threshold. parameters only describe the bound values'
+// types and sizes so the log doesn't end up holding user data
+#[derive(Debug, Clone)]
+pub struct SlowQuery {
+    pub sql: String,
+    pub duration: Duration,
+    pub parameters: Vec<String>
+}
+
+#[derive(Debug)]
+struct SlowQueryLog {
+    threshold: Duration,
+    capacity: usize,
+    entries: VecDeque<SlowQuery>
+}
+
+// records its statement in the slow query log when dropped, if it ran for longer than the threshold,
+// and ends its trace span
+struct StatementTimer<'a> {
+    log: Option<&'a Mutex<SlowQueryLog>>,
+    sql: String,
+    parameters: Vec<String>,
+    started: Instant,
+    #[cfg(feature = "otel")]
+    _span: otel::StatementSpan
+}
+
+impl Drop for StatementTimer<'_> {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed();
+        if let Some(Ok(mut log)) = self.log.map(Mutex::lock) {
+            if duration < log.threshold || log.capacity == 0 {
+                return;
+            }
+            if log.entries.len() == log.capacity {
+                log.entries.pop_front();
+            }
+            log.entries.push_back(SlowQuery { sql: std::mem::take(&mut self.sql), duration, parameters: std::mem::take(&mut self.parameters) });
+        }
+    }
+}
+
+fn describe_parameter(value: &PersistenceData) -> String {
+    match value {
+        PersistenceData::String(s) => format!("String({} bytes)", s.len()),
+        PersistenceData::Bytes(b) => format!("Bytes({} bytes)", b.len()),
+        PersistenceData::Integer(_) => "Integer".to_string(),
+        PersistenceData::UnsignedInteger(_) => "UnsignedInteger".to_string(),
+        PersistenceData::Float(_) => "Float".to_string(),
+        PersistenceData::Double(_) => "Double".to_string(),
+        #[cfg(feature = "decimal")]
+        PersistenceData::Decimal(_) => "Decimal".to_string(),
+    }
+}
+
+// a row's spec fields, stored checksum and stored version, see read_columns
+type RowColumns = (HashMap<&'static str, PersistenceData>, Option<String>, Option<u32>);
+
+// called with every statement's SQL and bound values before it runs
+type StatementHook = Arc<dyn Fn(&str, &[PersistenceData]) + Send + Sync>;
+
 // used for specifying how sqlite should be used to store data
 #[derive(Debug, Clone)]
 pub struct SqlitePersistence {
     connection: DebugIgnore<Arc<ConnectionWithFullMutex>>,
-    table_name: String
+    table_name: String,
+    last_error: Arc<Mutex<Option<String>>>,
+    slow_query_log: Option<Arc<Mutex<SlowQueryLog>>>,
+    statement_hook: Option<DebugIgnore<StatementHook>>,
+    deserialization_mode: DeserializationMode,
+    external_blobs: Option<ExternalBlobStore>,
+    checksums: bool,
+    versioned: bool,
+    ttl: bool,
+    clock: Arc<dyn Clock>,
+    tenant: Option<String>,
+    #[cfg(feature = "encryption")]
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    #[cfg(feature = "otel")]
+    trace_context: Option<opentelemetry::Context>
 }
 
 impl SqlitePersistence{
     pub fn new(connection: Arc<ConnectionWithFullMutex>, table_name: &str) -> Self {
-        SqlitePersistence { connection: DebugIgnore(connection), table_name: table_name.to_string() }
+        SqlitePersistence { connection: DebugIgnore(connection), table_name: table_name.to_string(), last_error: Arc::new(Mutex::new(None)), slow_query_log: None, statement_hook: None, deserialization_mode: DeserializationMode::default(), external_blobs: None, checksums: false, versioned: false, ttl: false, clock: Arc::new(SystemClock {}), tenant: None, #[cfg(feature = "encryption")] key_provider: None, #[cfg(feature = "otel")] trace_context: None }
+    }
+
+    // Keeps the last capacity statements that took at least threshold, see slow_queries()
+    pub fn with_slow_query_log(mut self, threshold: Duration, capacity: usize) -> Self {
+        self.slow_query_log = Some(Arc::new(Mutex::new(SlowQueryLog { threshold, capacity, entries: VecDeque::new() })));
+        self
+    }
+
+    // Calls hook with the SQL and bound values of every statement the adapter runs, transaction control and
+    // maintenance included, for debugging, auditing or looking at query shapes. Unlike the slow query log the
+    // values are passed as they are, user data included. Components given the connection rather than an
+    // adapter (LockManager, PersistentQueue, Outbox, PersistentRateLimiter, Scheduler, ChangeFeed) run
+    // their own statements, which aren't passed. Replaces any earlier hook
+    pub fn set_statement_hook(&mut self, hook: impl Fn(&str, &[PersistenceData]) + Send + Sync + 'static) {
+        self.statement_hook = Some(DebugIgnore(Arc::new(hook)));
+    }
+
+    pub fn with_deserialization_mode(mut self, mode: DeserializationMode) -> Self {
+        self.deserialization_mode = mode;
+        self
+    }
+
+    // Where row expiry, recorded schema times and external blob ages are measured from, SystemClock unless
+    // set here
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // oldest first, empty unless enabled with with_slow_query_log
+    pub fn slow_queries(&self) -> Vec<SlowQuery> {
+        self.slow_query_log.as_ref().and_then(|log|log.lock().ok().map(|log|log.entries.iter().cloned().collect())).unwrap_or_default()
+    }
+
+    pub fn maintain(&self, options: MaintenanceOptions) -> Result<(), PersistenceError> {
+        if options.analyze {
+            self.execute_timed(&format!("ANALYZE {}", quote_identifier(&self.table_name)))?;
+        }
+        if options.vacuum {
+            self.execute_timed("VACUUM")?;
+        }
+        if options.wal_checkpoint {
+            self.execute_timed("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        }
+        Ok(())
+    }
+
+    // Writes a consistent snapshot of the whole database to a new file at path using VACUUM INTO,
+    // the connection stays usable while the snapshot is taken. Fails if the file already exists
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<(), PersistenceError> {
+        let path = path.as_ref().to_str().ok_or_else(||self.backend_error("Backup path is not valid UTF-8"))?;
+        let _timer = self.time_statement("VACUUM INTO ?", [&PersistenceData::String(path.to_string())]);
+        let mut statement = self.connection.prepare("VACUUM INTO ?").map_err(|e|self.backend_error(e))?;
+        statement.bind((1, path)).map_err(|e|self.backend_error(e))?;
+        statement.next().map_err(|e|self.backend_error(e))?;
+        Ok(())
+    }
+
+    // Runs PRAGMA integrity_check over the whole database file and returns the problems it finds,
+    // empty if the file is intact. Reads every page, so it can take a while on large databases
+    pub fn integrity_check(&self) -> Result<Vec<String>, PersistenceError> {
+        self.run_integrity_pragma("integrity_check")
+    }
+
+    // Like integrity_check but skips the index and uniqueness checks, much faster on large databases
+    pub fn quick_check(&self) -> Result<Vec<String>, PersistenceError> {
+        self.run_integrity_pragma("quick_check")
+    }
+
+    // The SQL that query() would run for this filter followed by sqlite's EXPLAIN QUERY PLAN for it,
+    // for finding filters that scan the whole table because of a missing index
+    pub fn explain<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, query: &Query) -> Result<String, PersistenceError> {
+        let (command, placeholder_values) = self.query_command(Spec::key_field(), query, 0, None);
+        let explain = format!("EXPLAIN QUERY PLAN {command}");
+        let _timer = self.time_statement(&explain, &placeholder_values);
+        let mut statement = self.connection.prepare(explain).map_err(|e|self.backend_error(e))?;
+        for (i, value) in placeholder_values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+
+        let mut explanation = format!("{command}\nQUERY PLAN");
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            explanation.push_str("\n  ");
+            explanation.push_str(&statement.read::<String, &str>("detail").map_err(|e|self.backend_error(e))?);
+        }
+        Ok(explanation)
+    }
+
+    // Checkpoints the WAL file and closes the connection. The connection is only closed here if no other
+    // SqlitePersistence (or caller) still holds it, otherwise it stays open for them
+    pub fn close(self) -> Result<(), PersistenceError> {
+        self.execute_timed("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        if let Ok(connection) = Arc::try_unwrap(self.connection.0) {
+            drop(connection);
+        }
+        Ok(())
+    }
+}
+
+// without the hashed feature there are no Hashed fields
+#[cfg(not(feature = "hashed"))]
+impl SqlitePersistence {
+    fn hash_fields(&self, _spec_types: &'static [PersistenceType], _key_field: &str, _data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), PersistenceError> {
+        Ok(())
+    }
+}
+
+// without the encryption feature nothing is encrypted, and sensitive fields are refused rather than stored as plaintext
+#[cfg(not(feature = "encryption"))]
+impl SqlitePersistence {
+    fn encrypt_fields<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, _data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), PersistenceError> {
+        match Spec::sensitive_fields().first() {
+            Some(field) => Err(SpecError::new(field, "is sensitive, build with the encryption feature to store it").into()),
+            None => Ok(())
+        }
+    }
+
+    #[allow(clippy::extra_unused_type_parameters)] // called the same way as the encryption feature's
+    fn decrypt_fields<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, _data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), PersistenceError> {
+        Ok(())
     }
 }
 
 impl SqlitePersistence {
-    fn collect_fields(spec_types: &'static [PersistenceType], prepared_query: &Statement) -> HashMap<String, PersistenceData>{
+    fn record_error<E: Debug>(&self, error: E) -> String {
+        let message = format!("{error:?}");
+        if let Ok(mut last_error) = self.last_error.lock() {
+            *last_error = Some(message.clone());
+        }
+        message
+    }
+
+    // records the error, sqlite errors come back as their typed variant and everything else as Backend
+    fn backend_error<E: Debug + 'static>(&self, error: E) -> PersistenceError {
+        let message = self.record_error(&error);
+        match (&error as &dyn Any).downcast_ref::<sqlite_::Error>() {
+            Some(error) => classify_error(error, message),
+            None => PersistenceError::Backend { message }
+        }
+    }
+
+    fn run_integrity_pragma(&self, pragma: &str) -> Result<Vec<String>, PersistenceError> {
+        let command = format!("PRAGMA {pragma}");
+        let _timer = self.time_statement(&command, []);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        let mut problems = Vec::new();
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            let line = statement.read::<String, usize>(0).map_err(|e|self.backend_error(e))?;
+            if line != "ok" {
+                problems.push(line);
+            }
+        }
+        if !problems.is_empty() {
+            self.record_error(format!("{pragma} found {} problems", problems.len()));
+        }
+        Ok(problems)
+    }
+
+    // passes a statement to the statement hook and starts timing it for the slow query log and its trace span,
+    // the timer records it when dropped
+    fn time_statement<'a>(&self, command: &str, parameters: impl IntoIterator<Item = &'a PersistenceData>) -> Option<StatementTimer<'_>> {
+        let parameters = parameters.into_iter().collect::<Vec<_>>();
+        if let Some(hook) = &self.statement_hook {
+            hook(command, &parameters.iter().map(|&value|value.clone()).collect::<Vec<_>>());
+        }
+        let log = self.slow_query_log.as_deref();
+        #[cfg(not(feature = "otel"))]
+        log?;
+        Some(StatementTimer {
+            log,
+            sql: log.map(|_|command.to_string()).unwrap_or_default(),
+            parameters: log.map(|_|parameters.into_iter().map(describe_parameter).collect()).unwrap_or_default(),
+            started: Instant::now(),
+            #[cfg(feature = "otel")]
+            _span: self.statement_span(command)
+        })
+    }
+
+    // runs a statement without parameters or results through time_statement
+    fn execute_timed(&self, command: &str) -> Result<(), PersistenceError> {
+        let _timer = self.time_statement(command, []);
+        self.connection.execute(command).map_err(|e|self.backend_error(e))
+    }
+
+    fn collect_fields<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, prepared_query: &Statement) -> Result<HashMap<&'static str, PersistenceData>, PersistenceError> {
+        let (mut fields, checksum, version) = self.read_columns(Spec::fields(), Spec::sensitive_fields(), prepared_query)?;
+        self.decrypt_fields::<Key, Data, Spec>(&mut fields)?;
+        self.verify_checksum::<Key, Data, Spec>(&fields, checksum)?;
+        if self.versioned {
+            Spec::upcast(version.unwrap_or(1), &mut fields)?;
+        }
+        Ok(fields)
+    }
+
+    // the row's spec fields, its stored checksum if checksums are enabled and its version if versioning is
+    fn read_columns(&self, spec_types: &'static [PersistenceType], sensitive_fields: &[&str], prepared_query: &Statement) -> Result<RowColumns, SpecError> {
         let mut data_out = HashMap::new();
+        let mut checksum = None;
+        let mut version = None;
 
         for column in prepared_query.column_names().iter() {
-            let column_info = spec_types.iter().filter(|f|f.get_name().eq(column)).next().expect("Unknown table field");
-            match column_info {
-                PersistenceType::String(n) => {data_out.insert(n.to_string(), PersistenceData::String(prepared_query.read(column.as_str()).expect("Invalid column")));},
-                PersistenceType::Bytes(n) => {data_out.insert(n.to_string(), PersistenceData::Bytes(prepared_query.read(column.as_str()).expect("Invalid column")));},
-                PersistenceType::Integer(n) => {data_out.insert(n.to_string(), PersistenceData::Integer(prepared_query.read(column.as_str()).expect("Invalid column")));},
-                PersistenceType::UnsignedInteger(n) => {data_out.insert(n.to_string(), PersistenceData::UnsignedInteger(prepared_query.read::<i64, &str>(column.as_str()).expect("Invalid column") as u64));},
-                PersistenceType::Float(n) =>{data_out.insert(n.to_string(), PersistenceData::Float(prepared_query.read::<f64, &str>(column.as_str()).expect("Invalid column") as f32));},
-                PersistenceType::Double(n) => {data_out.insert(n.to_string(), PersistenceData::Double(prepared_query.read(column.as_str()).expect("Invalid column")));},
+            match spec_types.iter().find(|f|f.get_name().eq(column)) {
+                None if self.tenant.is_some() && column == tenant::TENANT_COLUMN => {},
+                None if self.ttl && column == ttl::EXPIRES_COLUMN => {},
+                None if self.checksums && column == checksum::CHECKSUM_COLUMN => {
+                    checksum = prepared_query.read::<Option<String>, &str>(column).map_err(|e|unreadable(column, e))?;
+                },
+                None if self.versioned && column == version::VERSION_COLUMN => {
+                    version = prepared_query.read::<Option<i64>, &str>(column).map_err(|e|unreadable(column, e))?.map(|v|v.clamp(0, u32::MAX as i64) as u32);
+                },
+                Some(column_info) => {
+                    let sensitive = sensitive_fields.contains(&column_info.get_name());
+                    data_out.insert(column_info.get_name(), self.read_spec_field(column_info, sensitive, prepared_query, column)?);
+                },
+                None if self.deserialization_mode == DeserializationMode::Lenient => {},
+                None => return Err(SpecError::new(column, "column is not part of the spec"))
+            }
+        }
+
+        if self.deserialization_mode == DeserializationMode::Strict {
+            if let Some(missing) = spec_types.iter().find(|f|!data_out.contains_key(f.get_name())) {
+                return Err(SpecError::new(missing.get_name(), "column is missing from the table"));
+            }
+        }
+
+        Ok((data_out, checksum, version))
+    }
+
+    // decodes by what the spec says the column holds rather than by what happens to be stored in it: the ciphertext
+    // of a sensitive field, a reference in a Bytes field kept in the external blob store, or a value of the field's
+    // own type. Anything else stored in the column is an error
+    fn read_spec_field(&self, field: &PersistenceType, sensitive: bool, prepared_query: &Statement, column: &str) -> Result<PersistenceData, SpecError> {
+        let stored = prepared_query.column_type(column).map_err(|e|unreadable(column, e))?;
+        if cfg!(feature = "encryption") && sensitive {
+            // whatever the field's type, decrypt_fields reads it
+            check_stored_type(column, stored, "BLOB")?;
+            return Ok(PersistenceData::Bytes(prepared_query.read(column).map_err(|e|unreadable(column, e))?));
+        }
+        if let (PersistenceType::Bytes(name), sqlite_::Type::String, Some(_)) = (field, stored, &self.external_blobs) {
+            let reference = prepared_query.read::<String, &str>(column).map_err(|e|unreadable(column, e))?;
+            if let Some(bytes) = self.read_external_blob(&reference) {
+                return Ok(PersistenceData::Bytes(bytes.map_err(|e|SpecError::new(name, &format!("external blob {reference}: {e}")))?));
+            }
+        }
+        check_stored_type(column, stored, column_type(field))?;
+        SqlitePersistence::read_field(field, prepared_query, column)
+    }
+
+    // fails rather than panics on values of the wrong type, e.g. a NULL written by another tool
+    fn read_field(field: &PersistenceType, prepared_query: &Statement, column: &str) -> Result<PersistenceData, SpecError> {
+        let unreadable = |e|unreadable(column, e);
+        Ok(match field {
+            PersistenceType::String(_) => PersistenceData::String(prepared_query.read(column).map_err(unreadable)?),
+            PersistenceType::Bytes(_) => PersistenceData::Bytes(prepared_query.read(column).map_err(unreadable)?),
+            PersistenceType::Integer(_) => PersistenceData::Integer(prepared_query.read(column).map_err(unreadable)?),
+            PersistenceType::UnsignedInteger(_) => PersistenceData::UnsignedInteger(prepared_query.read::<i64, &str>(column).map_err(unreadable)? as u64),
+            PersistenceType::Float(_) => PersistenceData::Float(prepared_query.read::<f64, &str>(column).map_err(unreadable)? as f32),
+            PersistenceType::Double(_) => PersistenceData::Double(prepared_query.read(column).map_err(unreadable)?),
+            #[cfg(feature = "decimal")]
+            PersistenceType::Decimal(_) => PersistenceData::Decimal(decimal::decode(&prepared_query.read::<String, &str>(column).map_err(unreadable)?).ok_or_else(||SpecError::new(column, "Invalid decimal"))?),
+            #[cfg(feature = "hashed")]
+            PersistenceType::Hashed(_) => PersistenceData::String(prepared_query.read(column).map_err(unreadable)?),
+        })
+    }
+
+    fn bind_data<T: sqlite_::ParameterIndex>(statement: &mut Statement, index: T, value: &PersistenceData) -> sqlite_::Result<()> {
+        match value {
+            PersistenceData::String(s) => statement.bind((index, s.as_str())),
+            PersistenceData::Bytes(b) => statement.bind((index, &b[..])),
+            PersistenceData::Integer(i) => statement.bind((index, *i)),
+            PersistenceData::UnsignedInteger(u) => statement.bind((index, *u as i64)),
+            PersistenceData::Float(f) => statement.bind((index, *f as f64)),
+            PersistenceData::Double(d) => statement.bind((index, *d)),
+            #[cfg(feature = "decimal")]
+            PersistenceData::Decimal(d) => statement.bind((index, decimal::encode(d).as_str())),
+        }
+    }
+
+    // steps a statement ending in RETURNING 1 to completion and counts its rows. Unlike the connection's
+    // change count this only sees the statement's own rows, whatever other threads run meanwhile
+    fn count_returned(&self, statement: &mut Statement) -> Result<u64, PersistenceError> {
+        let mut count = 0;
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // adds a column the adapter needs to a table created before it was enabled, e.g. _version after turning on
+    // versioning. Rows already stored get NULL in it, which every such column reads as its default
+    fn add_missing_column(&self, column: &str, column_type: &str) -> Option<()> {
+        if self.has_column(column).map_err(|e|self.record_error(e)).ok()? {
+            return Some(());
+        }
+        let command = format!("ALTER TABLE {} ADD COLUMN {} {column_type}", quote_identifier(&self.table_name), quote_identifier(column));
+        let _timer = self.time_statement(&command, []);
+        self.connection.execute(command).map_err(|e|self.record_error(e)).ok()
+    }
+
+    fn has_column(&self, column: &str) -> sqlite_::Result<bool> {
+        let command = "SELECT 1 FROM pragma_table_info(?) WHERE name = ?";
+        let _timer = self.time_statement(command, [&PersistenceData::String(self.table_name.clone()), &PersistenceData::String(column.to_string())]);
+        let mut statement = self.connection.prepare(command)?;
+        statement.bind((1, self.table_name.as_str()))?;
+        statement.bind((2, column))?;
+        Ok(statement.next()? == Row)
+    }
+
+    fn read_row<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, prepared_query: &Statement) -> Result<(Key, Data), PersistenceError> {
+        let fields = self.collect_fields::<Key, Data, Spec>(prepared_query)?;
+        let key = Spec::deserialize_key(fields.get(Spec::key_field()).ok_or_else(||SpecError::missing(Spec::key_field()))?).ok_or_else(||SpecError::new(Spec::key_field(), "Invalid key"))?;
+        Ok((key, Spec::deserialize_data(fields)?))
+    }
+
+    // stores a new row, expiring after ttl if given. A row already stored under the key is handled by conflict.
+    // Errors keep their variant, e.g. for bulk_load to tell a taken key from a row that failed on its own
+    fn insert<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, data: &Data, ttl: Option<Duration>, conflict: ConflictPolicy) -> Result<(), PersistenceError> {
+        let mut command = String::new();
+        command.push_str(match conflict {
+            ConflictPolicy::Abort => "INSERT INTO ",
+            ConflictPolicy::Skip => "INSERT OR IGNORE INTO ",
+            ConflictPolicy::Replace => "INSERT OR REPLACE INTO "
+        });
+        command.push_str(&quote_identifier(&self.table_name));
+        command.push_str(" (");
+        intersperse(Spec::fields().iter().map(|f|quote_identifier(f.get_name())), ", ".to_string()).for_each(|s|command.push_str(&s));
+        command.push_str(&self.version_column());
+        command.push_str(&self.expires_column());
+        if self.checksums {
+            command.push_str(&format!(", {}", checksum::CHECKSUM_COLUMN));
+        }
+        if self.tenant.is_some() {
+            command.push_str(&format!(", {}", tenant::TENANT_COLUMN));
+        }
+
+        command.push_str(") values (");
+        
+        intersperse(Spec::fields().iter().map(|_|"?"), ", ").for_each(|s|command.push_str(s));
+        command.push_str(&self.version_value::<Key, Data, Spec>());
+        command.push_str(&self.expires_value(ttl));
+        if self.checksums {
+            command.push_str(", ?");
+        }
+        if self.tenant.is_some() {
+            command.push_str(", :tenant");
+        }
+
+        command.push(')');
+
+        let mut serialized = Spec::serialize_data(data)?;
+        self.hash_fields(Spec::fields(), Spec::key_field(), &mut serialized)?;
+        let serialized_key = Spec::serialize_key(key);
+        let checksum = self.checksums.then(||checksum::row_checksum(Spec::fields(), |name|if name == Spec::key_field() {Some(&serialized_key)} else {serialized.get(name)}));
+        self.encrypt_fields::<Key, Data, Spec>(&mut serialized)?;
+        self.externalize_blobs(Spec::fields(), Spec::key_field(), &mut serialized)?;
+        // an expired row is only removed along with the insert replacing it
+        let savepoint = self.ttl.then(||self.savepoint("insert")).transpose()?;
+        self.remove_expired::<Key, Data, Spec>(&serialized_key)?;
+        let _timer = self.time_statement(&command, Spec::fields().iter().filter_map(|f|serialized.get(f.get_name()).or(if f.get_name() == Spec::key_field() {Some(&serialized_key)} else {None})));
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        for (field_index, v) in Spec::fields().iter().enumerate() {
+            let field_name = v.get_name();
+            let value = serialized.get(field_name).or_else(||if field_name == Spec::key_field() {Some(&serialized_key)}else{None}).ok_or_else(||SpecError::missing(field_name))?;
+            SqlitePersistence::bind_data(&mut statement, field_index + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        if let Some(checksum) = checksum {
+            statement.bind((Spec::fields().len() + 1, checksum.as_str())).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        statement.next().map_err(|e|self.backend_error(e))?;
+        drop(statement);
+        if let Some(savepoint) = savepoint {
+            savepoint.release()?;
+        }
+        Ok(())
+    }
+
+    // runs a SELECT and reads its rows, a statement that fails to prepare or bind is recorded as the last
+    // error and reads as no rows
+    fn prepare_rows<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, command: &str, values: &[PersistenceData]) -> Vec<(Key, Data)> {
+        let prepared = self.connection.prepare(command).and_then(|mut prepared_query|{
+            for (i, value) in values.iter().enumerate() {
+                SqlitePersistence::bind_data(&mut prepared_query, i + 1, value)?;
+            }
+            self.bind_tenant(&mut prepared_query)?;
+            Ok(prepared_query)
+        });
+        match prepared {
+            Ok(mut prepared_query) => self.read_rows::<Key, Data, Spec>(&mut prepared_query),
+            Err(e) => {
+                self.record_error(e);
+                Vec::new()
+            }
+        }
+    }
+
+    // rows that fail to deserialize are skipped and recorded as the last error
+    fn read_rows<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, prepared_query: &mut Statement) -> Vec<(Key, Data)> {
+        let mut rows_out = Vec::new();
+
+        let mut state = prepared_query.next();
+        while let Ok(s) = state {
+            match s {
+                Row => {
+                    match self.read_row::<Key, Data, Spec>(prepared_query) {
+                        Ok(row) => rows_out.push(row),
+                        Err(e) => { self.record_error(e); }
+                    }
+                },
+                Done => {
+                    break;
+                }
             }
+            state = prepared_query.next();
         }
 
-        data_out
+        rows_out
+    }
+
+    fn query_command(&self, key_field: &str, query: &Query, start: usize, limit: Option<usize>) -> (String, Vec<PersistenceData>) {
+        let (query_string, _num_placeholders, placeholder_values)  = SqlitePersistence::generate_filter(query, 0, Vec::new());
+        (format!("SELECT * FROM {} WHERE {}{} ORDER BY {} LIMIT {} OFFSET {};", quote_identifier(&self.table_name), query_string, self.and_row_scope(), quote_identifier(key_field), limit.map(|l|l as isize).unwrap_or(-1), start), placeholder_values)
     }
 
     fn generate_filter(query: &Query, start_index: usize, mut values: Vec<PersistenceData>) -> (String, usize, Vec<PersistenceData>) {
@@ -57,15 +688,15 @@ impl SqlitePersistence {
             },
             Query::Equals(a, b) => {
                 values.push(b.clone());
-                (format!(" \"{}\"=? ", a), start_index+1, values)
+                (format!(" {}=? ", quote_identifier(a)), start_index+1, values)
             },
             Query::GreaterThan(a, b) => {
                 values.push(b.clone());
-                (format!(" \"{}\">? ", a), start_index+1, values)
+                (format!(" {}>? ", quote_identifier(a)), start_index+1, values)
             },
             Query::LessThan(a, b) => {
                 values.push(b.clone());
-                (format!(" \"{}\"<? ", a), start_index+1, values)
+                (format!(" {}<? ", quote_identifier(a)), start_index+1, values)
             },
         }
     }
@@ -74,280 +705,319 @@ impl SqlitePersistence {
 impl<Key, Data, Spec: PersistenceSpec<Key, Data>> PersistenceAdapter<Key, Data, Spec> for SqlitePersistence {
     fn initialize(&self) -> Option<()> {
         let mut command = String::new();
-        command.push_str("CREATE TABLE IF NOT EXISTS \"");
-        command.push_str(&self.table_name);
-        command.push_str("\" (");
-        intersperse(Spec::fields().iter().map(|e|{
-            match e {
-                PersistenceType::String(name) => format!("{name} TEXT"),
-                PersistenceType::Bytes(name) => format!("{name} BLOB"),
-                PersistenceType::Integer(name) |  PersistenceType::UnsignedInteger(name) => format!("{name} INTEGER"),
-                PersistenceType::Float(name)   |  PersistenceType::Double(name) => format!("{name} REAL"),
-            }
-        }), ", ".to_string()).for_each(|s|command.push_str(&s));
-        command.push_str(format!(", PRIMARY KEY ({}) );", Spec::key_field()).as_str());
-        println!("{}", command);
-        self.connection.execute(command).ok()
+        command.push_str("CREATE TABLE IF NOT EXISTS ");
+        command.push_str(&quote_identifier(&self.table_name));
+        command.push_str(" (");
+        intersperse(Spec::fields().iter().map(|e|format!("{} {}", quote_identifier(e.get_name()), column_type(e))), ", ".to_string()).for_each(|s|command.push_str(&s));
+        if self.checksums {
+            command.push_str(&format!(", \"{}\" TEXT", checksum::CHECKSUM_COLUMN));
+        }
+        if self.versioned {
+            command.push_str(&format!(", \"{}\" INTEGER", version::VERSION_COLUMN));
+        }
+        if self.ttl {
+            command.push_str(&format!(", \"{}\" INTEGER", ttl::EXPIRES_COLUMN));
+        }
+        match self.tenant {
+            Some(_) => command.push_str(format!(", \"{}\" TEXT NOT NULL, PRIMARY KEY (\"{}\", {}) );", tenant::TENANT_COLUMN, tenant::TENANT_COLUMN, quote_identifier(Spec::key_field())).as_str()),
+            None => command.push_str(format!(", PRIMARY KEY ({}) );", quote_identifier(Spec::key_field())).as_str())
+        }
+        {
+            let _timer = self.time_statement(&command, []);
+            self.connection.execute(command).ok()?;
+        }
+        if self.checksums {
+            self.add_missing_column(checksum::CHECKSUM_COLUMN, "TEXT")?;
+        }
+        if self.versioned {
+            self.add_missing_column(version::VERSION_COLUMN, "INTEGER")?;
+        }
+        if self.ttl {
+            self.add_missing_column(ttl::EXPIRES_COLUMN, "INTEGER")?;
+        }
+        Some(())
     }
 
     fn load(&self, key: &Key) -> Option<Data> {
         let mut command = String::new();
-        command.push_str("SELECT * FROM \"");
-        command.push_str(&self.table_name);
-        command.push_str("\" WHERE \"");
-        command.push_str(Spec::key_field());
-        command.push_str("\" = :primary_key");
-
-        let mut prepared_query = self.connection.prepare(command).unwrap();
+        command.push_str("SELECT * FROM ");
+        command.push_str(&quote_identifier(&self.table_name));
+        command.push_str(" WHERE ");
+        command.push_str(&quote_identifier(Spec::key_field()));
+        command.push_str(" = :primary_key");
+        command.push_str(&self.and_row_scope());
 
         let serialized_key = Spec::serialize_key(key);
+        let _timer = self.time_statement(&command, [&serialized_key]);
 
+        let mut prepared_query = self.connection.prepare(command).map_err(|e|self.record_error(e)).ok()?;
 
-        match serialized_key {
-            PersistenceData::String(s) => {prepared_query.bind((":primary_key", s.as_str())).ok()?},
-            PersistenceData::Bytes(b) => {prepared_query.bind((":primary_key", &b[..])).ok()?},
-            PersistenceData::Integer(i) => {prepared_query.bind((":primary_key", i)).ok()?},
-            PersistenceData::UnsignedInteger(u) => {prepared_query.bind((":primary_key", u as i64)).ok()?},
-            PersistenceData::Float(f) => {prepared_query.bind((":primary_key", f as f64)).ok()?},
-            PersistenceData::Double(d) => {prepared_query.bind((":primary_key", d)).ok()?},
-        };
+        SqlitePersistence::bind_data(&mut prepared_query, ":primary_key", &serialized_key).ok()?;
+        self.bind_tenant(&mut prepared_query).ok()?;
 
         prepared_query.next().ok().and_then(|s|{
             match s {
-                Row => Spec::deserialize_data(SqlitePersistence::collect_fields(Spec::fields(), &prepared_query)),
+                Row => self.collect_fields::<Key, Data, Spec>(&prepared_query).and_then(|fields|Ok(Spec::deserialize_data(fields)?)).map_err(|e|self.record_error(e)).ok(),
                 Done => None
             }
         })
     }
 
-    fn store(&self, key: Key, data: Data) -> Result<(), crate::persistence_adapter::StoreError> {
-        let mut command = String::new();
-        command.push_str("INSERT INTO ");
-        command.push_str(&self.table_name.as_str());
-        command.push_str(" (");
-        intersperse(Spec::fields().iter().map(PersistenceType::get_name), ", ").for_each(|s|command.push_str(s));
-
-        command.push_str(") values (");
-        
-        intersperse(Spec::fields().iter().map(|_|"?"), ", ").for_each(|s|command.push_str(s));
-
-        command.push_str(")");
-
-        if let Some(serialized) = Spec::serialize_data(&data) {
-            let mut statement = self.connection.prepare(command).expect("Invalid statement");
-            let serialized_key = Spec::serialize_key(&key);
-            Spec::fields().iter().enumerate().for_each(|(field_index, v)|{
-                let field_index = field_index + 1;
-                let field_name = v.get_name();
-                let _ = match serialized.get(field_name).or_else(||if field_name == Spec::key_field() {Some(&serialized_key)}else{None}).expect("Missing serialized field") {
-                    PersistenceData::String(s) => statement.bind((field_index, s.as_str())),
-                    PersistenceData::Bytes(b) => statement.bind((field_index, &b[..])),
-                    PersistenceData::Integer(i) =>   statement.bind((field_index, *i)),
-                    PersistenceData::UnsignedInteger(u) => statement.bind((field_index, *u as i64)),
-                    PersistenceData::Float(f) => statement.bind((field_index, *f as f64)),
-                    PersistenceData::Double(d) => statement.bind((field_index, *d)),
-                };
-            });
-            let _ = statement.next().map_err(|e|StoreError{message: format!("{e:?}")})?;
-            println!("Stored");
-            Ok(())
-        }else{
-            Err(StoreError{ message: "Failed to serialize data".to_string()})
-        }
-        
+    fn store(&self, key: &Key, data: &Data) -> Result<(), crate::persistence_adapter::StoreError> {
+        self.insert::<Key, Data, Spec>(key, data, None, ConflictPolicy::Abort).map_err(StoreError::from)
     }
 
-    fn delete(&self, key: Key) -> Option<()> {
+    fn delete(&self, key: &Key) -> Result<u64, PersistenceError> {
         let mut command = String::new();
 
         command.push_str("DELETE FROM ");
-        command.push_str(&self.table_name);
-        command.push_str(" WHERE \"");
-        command.push_str(Spec::key_field());
-        command.push_str("\"=?");
-
-        let mut statement = self.connection.prepare(command).expect("Invalid command");
-        let _ = match Spec::serialize_key(&key) {
-            PersistenceData::String(s) => statement.bind((1, s.as_str())),
-            PersistenceData::Bytes(b) => statement.bind((1, &b[..])),
-            PersistenceData::Integer(i) => statement.bind((1, i)),
-            PersistenceData::UnsignedInteger(u) => statement.bind((1, u as i64)),
-            PersistenceData::Float(f) => statement.bind((1, f as f64)),
-            PersistenceData::Double(d) => statement.bind((1, d)),
-        };
-        println!("Deleted");
-        let _ = statement.next().ok()?;
+        command.push_str(&quote_identifier(&self.table_name));
+        command.push_str(" WHERE ");
+        command.push_str(&quote_identifier(Spec::key_field()));
+        command.push_str("=?");
+        command.push_str(&self.and_row_scope());
+        command.push_str(" RETURNING 1");
 
-        Some(())
+        let serialized_key = Spec::serialize_key(key);
+        let _timer = self.time_statement(&command, [&serialized_key]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        SqlitePersistence::bind_data(&mut statement, 1, &serialized_key).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        match statement.next().map_err(|e|self.backend_error(e))? {
+            Row => Ok(1),
+            Done => Ok(0)
+        }
     }
 
     fn contains(&self, key: &Key) -> bool {
         let mut command = String::new();
 
         command.push_str("SELECT ");
-        command.push_str(Spec::key_field());
+        command.push_str(&quote_identifier(Spec::key_field()));
         command.push_str(" FROM ");
-        command.push_str(&self.table_name);
+        command.push_str(&quote_identifier(&self.table_name));
         command.push_str(" WHERE ");
-        command.push_str(Spec::key_field());
+        command.push_str(&quote_identifier(Spec::key_field()));
         command.push_str("=?");
+        command.push_str(&self.and_row_scope());
 
-        println!("contains: {command}");
-        let mut statement = self.connection.prepare(command).expect("Invalid command");
-        let _ = match Spec::serialize_key(&key) {
-            PersistenceData::String(s) => statement.bind((1, s.as_str())),
-            PersistenceData::Bytes(b) => statement.bind((1, &b[..])),
-            PersistenceData::Integer(i) => statement.bind((1, i)),
-            PersistenceData::UnsignedInteger(u) => statement.bind((1, u as i64)),
-            PersistenceData::Float(f) => statement.bind((1, f as f64)),
-            PersistenceData::Double(d) => statement.bind((1, d)),
+        let serialized_key = Spec::serialize_key(key);
+        let _timer = self.time_statement(&command, [&serialized_key]);
+        let Ok(mut statement) = self.connection.prepare(command).map_err(|e|self.record_error(e)) else {
+            return false;
         };
-
-        'read_lines: while let Ok(s) = statement.next() {
-            match s {
-                sqlite_::State::Row => return true,
-                _ => break 'read_lines
-            }
+        if SqlitePersistence::bind_data(&mut statement, 1, &serialized_key).map_err(|e|self.record_error(e)).is_err() || self.bind_tenant(&mut statement).map_err(|e|self.record_error(e)).is_err() {
+            return false;
         }
-        println!("Contains");
-        return false;
+
+        matches!(statement.next(), Ok(sqlite_::State::Row))
     }
 
-    fn clear(&self) {
-        println!("All rows deleted from {}", self.table_name);
+    fn clear(&self) -> Result<u64, PersistenceError> {
         let mut command = String::new();
         command.push_str("DELETE FROM ");
-        command.push_str(&self.table_name);
-        let _ = self.connection.execute(command);
-        println!("Clear");
+        command.push_str(&quote_identifier(&self.table_name));
+        command.push_str(&self.where_row_scope());
+        command.push_str(" RETURNING 1");
+        let _timer = self.time_statement(&command, []);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        self.count_returned(&mut statement)
     }
 
     fn scan(&self, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
         let mut command = String::new();
-        command.push_str(&format!("SELECT * FROM \"{}\" ORDER BY \"{}\" LIMIT {} OFFSET {}", &self.table_name, Spec::key_field(), limit.map(|l|l as isize).unwrap_or(-1), start));
+        command.push_str(&format!("SELECT * FROM {}{} ORDER BY {} LIMIT {} OFFSET {}", quote_identifier(&self.table_name), self.where_row_scope(), quote_identifier(Spec::key_field()), limit.map(|l|l as isize).unwrap_or(-1), start));
 
-        let mut prepared_query = self.connection.prepare(command).unwrap();
-        let mut rows_out = Vec::new();
+        let _timer = self.time_statement(&command, []);
+        self.prepare_rows::<Key, Data, Spec>(&command, &[])
+    }
 
-        let mut state = prepared_query.next();
-        while let Ok(s) = state {
-            match s {
-                Row => {
-                    let fields = SqlitePersistence::collect_fields(Spec::fields(),  &prepared_query);
-                    let key = Spec::deserialize_key(fields.get(Spec::key_field()).expect("Key field not present")).expect("Invalid key found while deserializing");
-                    match Spec::deserialize_data(fields) {
-                        Some(entry) => rows_out.push((key, entry)),
-                        None => {}
-                    }
-                },
-                Done => {
-                    break;
+    fn scan_range(&self, from: Option<&Key>, to: Option<&Key>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        let mut bounds = Vec::new();
+        let mut values = Vec::new();
+        if let Some(from) = from {
+            bounds.push(format!("{} >= ?", quote_identifier(Spec::key_field())));
+            values.push(Spec::serialize_key(from));
+        }
+        if let Some(to) = to {
+            bounds.push(format!("{} < ?", quote_identifier(Spec::key_field())));
+            values.push(Spec::serialize_key(to));
+        }
+        bounds.extend(self.row_scope());
+
+        let mut command = String::new();
+        command.push_str(&format!("SELECT * FROM {}", quote_identifier(&self.table_name)));
+        if !bounds.is_empty() {
+            command.push_str(" WHERE ");
+            intersperse(bounds.iter().map(String::as_str), " AND ").for_each(|s|command.push_str(s));
+        }
+        command.push_str(&format!(" ORDER BY {} LIMIT {}", quote_identifier(Spec::key_field()), limit.map(|l|l as isize).unwrap_or(-1)));
+
+        let _timer = self.time_statement(&command, &values);
+        self.prepare_rows::<Key, Data, Spec>(&command, &values)
+    }
+    fn update(&self, key: &Key, data: &Data, only_update: Option<&[&str]>) -> Result<u64, StoreError> {
+        let mut serialized = Spec::serialize_data(data)?;
+        self.hash_fields(Spec::fields(), Spec::key_field(), &mut serialized).map_err(StoreError::from)?;
+        self.encrypt_fields::<Key, Data, Spec>(&mut serialized).map_err(StoreError::from)?;
+        self.externalize_blobs(Spec::fields(), Spec::key_field(), &mut serialized).map_err(StoreError::from)?;
+        let updatable = Spec::fields().iter().map(PersistenceType::get_name).filter(|name|*name != Spec::key_field()).collect::<Vec<_>>();
+        let fields = match only_update {
+            Some(only) => {
+                if let Some(unknown) = only.iter().find(|name|**name != Spec::key_field() && !updatable.contains(name)) {
+                    return Err(StoreError { message: format!("Unknown field {unknown}"), kind: None });
                 }
-            }
-            state = prepared_query.next();
+                only.iter().copied().filter(|name|*name != Spec::key_field()).collect()
+            },
+            None => updatable
+        };
+        if fields.is_empty() {
+            return Ok(PersistenceAdapter::<Key, Data, Spec>::contains(self, key) as u64);
         }
 
-        rows_out
+        // values are bound positionally in the same order as the SET list, followed by the key
+        let mut values = fields.iter().map(|name|serialized.get(name).ok_or_else(||SpecError::missing(name))).collect::<Result<Vec<_>, _>>()?;
+        let serialized_key = Spec::serialize_key(key);
+        values.push(&serialized_key);
+
+        let mut command = format!("UPDATE {} SET ", quote_identifier(&self.table_name));
+        intersperse(fields.iter().map(|name|format!("{} = ?", quote_identifier(name))), ", ".to_string()).for_each(|s|command.push_str(&s));
+        let version_condition = match only_update {
+            None => {
+                command.push_str(&self.set_version::<Key, Data, Spec>());
+                String::new()
+            },
+            Some(_) => self.and_current_version::<Key, Data, Spec>()
+        };
+        command.push_str(&format!(" WHERE {} = ?{}{version_condition} RETURNING 1", quote_identifier(Spec::key_field()), self.and_row_scope()));
+
+        // the row and its checksum are written together or not at all
+        let savepoint = self.checksums.then(||self.savepoint("update")).transpose().map_err(StoreError::from)?;
+        let _timer = self.time_statement(&command, values.iter().copied());
+        let mut statement = self.connection.prepare(command).map_err(|e|StoreError::from(self.backend_error(e)))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|StoreError::from(self.backend_error(e)))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|StoreError::from(self.backend_error(e)))?;
+        let updated = match statement.next().map_err(|e|StoreError::from(self.backend_error(e)))? {
+            Row => 1,
+            Done => 0
+        };
+        drop(statement);
+        if updated > 0 {
+            self.refresh_checksum::<Key, Data, Spec>(&serialized_key).map_err(StoreError::from)?;
+        } else if only_update.is_some() {
+            self.check_partial_write::<Key, Data, Spec>(&serialized_key).map_err(StoreError::from)?;
+        }
+        if let Some(savepoint) = savepoint {
+            savepoint.release().map_err(StoreError::from)?;
+        }
+        Ok(updated)
     }
-    
-    fn update(&self, key: &Key, data: Data, only_update: Option<&[&str]>) -> Result<(), StoreError> {
-        let mut command = String::new();
-        command.push_str("UPDATE ");
-        command.push_str(&self.table_name.as_str());
-        command.push_str(" SET ");
-        match only_update{
-            Some(k) => intersperse(k.iter().filter(|x|*x!=&Spec::key_field()).map(|name|format!("{} = ?", name)), ", ".to_string()).for_each(|s|command.push_str(&s)),
-            None => intersperse(Spec::fields().iter().map(PersistenceType::get_name).filter(|x|x!=&Spec::key_field()).map(|name|format!("{} = ?", name)), ", ".to_string()).for_each(|s|command.push_str(&s)),
-        }
-        command.push_str(format!(" WHERE {} = :key", Spec::key_field()).as_str());
-
-        println!("Executing {}", command);
-        if let Some(serialized) = Spec::serialize_data(&data) {
-            let mut statement = self.connection.prepare(command).expect("Invalid statement");
-            let _ = match Spec::serialize_key(key) {
-                PersistenceData::String(s) => statement.bind((":key", s.as_str())),
-                PersistenceData::Bytes(b) => statement.bind((":key", b.as_slice())),
-                PersistenceData::Integer(i) => statement.bind((":key", i)),
-                PersistenceData::UnsignedInteger(u) => statement.bind((":key", u as i64)),
-                PersistenceData::Float(f) => statement.bind((":key", f as f64)),
-                PersistenceData::Double(d) => statement.bind((":key", d)),
-            };
-            let bind_fields = |(field_index, v): (usize, &PersistenceType)|{
-                let field_index = field_index + 1;
-                let field_name = v.get_name();
-                let _ = match serialized.get(field_name).expect("Missing serialized field") {
-                    PersistenceData::String(s) => statement.bind((field_index, s.as_str())),
-                    PersistenceData::Bytes(b) => statement.bind((field_index, &b[..])),
-                    PersistenceData::Integer(i) =>   statement.bind((field_index, *i)),
-                    PersistenceData::UnsignedInteger(u) => statement.bind((field_index, *u as i64)),
-                    PersistenceData::Float(f) => statement.bind((field_index, *f as f64)),
-                    PersistenceData::Double(d) => statement.bind((field_index, *d)),
-                };
-            };
-            match only_update {
-                Some(f) => Spec::fields().iter().filter(|v|f.contains(&v.get_name())).enumerate().for_each(bind_fields),
-                None => Spec::fields().iter().filter(|v|v.get_name()!=Spec::key_field()).enumerate().for_each(bind_fields),
-            }
-            let _ = statement.next().map_err(|e|StoreError{message: format!("{e:?}")})?;
-            println!("Stored");
-            Ok(())
-        }else{
-            Err(StoreError{ message: "Failed to serialize data".to_string()})
+
+    fn patch(&self, key: &Key, mut changes: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        if let Some(field) = changes.keys().find(|name|**name == Spec::key_field() || !Spec::fields().iter().any(|f|f.get_name() == **name)) {
+            return Err(PersistenceError::FieldNotAllowed { field: field.to_string() });
+        }
+        if changes.is_empty() {
+            return Ok(PersistenceAdapter::<Key, Data, Spec>::contains(self, key) as u64);
+        }
+
+        self.hash_fields(Spec::fields(), Spec::key_field(), &mut changes)?;
+        self.encrypt_fields::<Key, Data, Spec>(&mut changes)?;
+        self.externalize_blobs(Spec::fields(), Spec::key_field(), &mut changes)?;
+        let changes = changes.into_iter().collect::<Vec<_>>();
+        let mut command = format!("UPDATE {} SET ", quote_identifier(&self.table_name));
+        intersperse(changes.iter().map(|(name, _)|format!("{} = ?", quote_identifier(name))), ", ".to_string()).for_each(|s|command.push_str(&s));
+        command.push_str(&format!(" WHERE {} = ?{}{} RETURNING 1", quote_identifier(Spec::key_field()), self.and_row_scope(), self.and_current_version::<Key, Data, Spec>()));
+
+        let serialized_key = Spec::serialize_key(key);
+        let savepoint = self.checksums.then(||self.savepoint("patch")).transpose()?;
+        let _timer = self.time_statement(&command, changes.iter().map(|(_, value)|value).chain([&serialized_key]));
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        for (i, (_, value)) in changes.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        SqlitePersistence::bind_data(&mut statement, changes.len() + 1, &serialized_key).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        let updated = match statement.next().map_err(|e|self.backend_error(e))? {
+            Row => 1,
+            Done => 0
+        };
+        drop(statement);
+        if updated > 0 {
+            self.refresh_checksum::<Key, Data, Spec>(&serialized_key)?;
+        } else {
+            self.check_partial_write::<Key, Data, Spec>(&serialized_key)?;
+        }
+        if let Some(savepoint) = savepoint {
+            savepoint.release()?;
+        }
+        Ok(updated)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            supports_query: true,
+            supports_transactions: true,
+            supports_ttl: self.ttl,
+            ordered_scan: true,
+            max_blob_size: Some(SQLITE_MAX_LENGTH)
         }
     }
 }
 
 impl<Key, Data, Spec: PersistenceSpec<Key, Data>> PersistenceAdapterQueryable<Key, Data, Spec> for SqlitePersistence {
     fn query(&self, query: Query, start: usize, limit: Option<usize>) -> Vec<(Key, Data)> {
-        let mut command = String::new();
+        let (command, placeholder_values) = self.query_command(Spec::key_field(), &query, start, limit);
+        let _timer = self.time_statement(&command, &placeholder_values);
+        self.prepare_rows::<Key, Data, Spec>(&command, &placeholder_values)
+    }
+
+    fn clear_where(&self, query: Query) -> Result<u64, PersistenceError> {
         let (query_string, _num_placeholders, placeholder_values)  = SqlitePersistence::generate_filter(&query, 0, Vec::new());
-        command.push_str(&format!("SELECT * FROM \"{}\" WHERE {} ORDER BY {} LIMIT {} OFFSET {};", &self.table_name, query_string, Spec::key_field(), limit.map(|l|l as isize).unwrap_or(-1), start, ));
-        let mut prepared_query = self.connection.prepare(command).unwrap();
+        let command = format!("DELETE FROM {} WHERE {}{} RETURNING 1", quote_identifier(&self.table_name), query_string, self.and_row_scope());
+        let _timer = self.time_statement(&command, &placeholder_values);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
         for (i, value) in placeholder_values.iter().enumerate() {
-            let bind_field = i+1;
-            match value {
-                PersistenceData::String(s) => prepared_query.bind((bind_field, s.as_str())),
-                PersistenceData::Bytes(b) => prepared_query.bind((bind_field, &b[..])),
-                PersistenceData::Integer(i_v) => prepared_query.bind((bind_field, *i_v)),
-                PersistenceData::UnsignedInteger(u) => prepared_query.bind((bind_field, *u as i64)),
-                PersistenceData::Float(f) => prepared_query.bind((bind_field, *f as f64)),
-                PersistenceData::Double(d) => prepared_query.bind((bind_field, *d)),
-            }.expect("Failed to bind data");
-        }
-
-        let mut rows_out = Vec::new();
-
-        let mut state = prepared_query.next();
-        while let Ok(s) = state {
-            match s {
-                Row => {
-                    let fields = SqlitePersistence::collect_fields(Spec::fields(),  &prepared_query);
-                    let key = Spec::deserialize_key(fields.get(Spec::key_field()).expect("Key field not present")).expect("Invalid key found while deserializing");
-                    match Spec::deserialize_data(fields) {
-                        Some(entry) => rows_out.push((key, entry)),
-                        None => {}
-                    }
-                },
-                Done => {
-                    break;
-                }
-            }
-            state = prepared_query.next();
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
         }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        self.count_returned(&mut statement)
+    }
+}
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>> PersistenceAdapterUpsert<Key, Data, Spec> for SqlitePersistence {
+    // INSERT OR REPLACE, the old row is deleted and the new one inserted in the same statement
+    fn upsert(&self, key: &Key, data: &Data) -> Result<(), StoreError> {
+        self.insert::<Key, Data, Spec>(key, data, None, ConflictPolicy::Replace).map_err(StoreError::from)
+    }
+}
 
-        rows_out
+impl PersistenceAdapterHealth for SqlitePersistence {
+    fn health(&self) -> Result<HealthReport, PersistenceError> {
+        let started = Instant::now();
+        let _timer = self.time_statement("SELECT 1", []);
+        let mut statement = self.connection.prepare("SELECT 1").map_err(|e|self.backend_error(e))?;
+        statement.next().map_err(|e|self.backend_error(e))?;
+        Ok(HealthReport {
+            latency: started.elapsed(),
+            last_error: self.last_error.lock().ok().and_then(|e|e.clone())
+        })
     }
 }
+
 #[cfg(test)]
 mod tests{
     use tempdir::TempDir;
     use sqlite_::Connection;
-    use std::sync::Arc;
-    use rand::{thread_rng, Rng};
-    use rand::distributions::Alphanumeric;
-    use crate::persistence_adapter::sqlite::SqlitePersistence;
-    use crate::tests::AllSupportedTypes;
-    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, Query};
+    use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+    use rand::{rng, rngs::StdRng, Rng, SeedableRng};
+    use rand::distr::Alphanumeric;
+    use crate::persistence_adapter::sqlite::{DeserializationMode, MaintenanceOptions, SqlitePersistence};
+    use crate::tests::{sqlite_connection, sqlite_persistence, AllSupportedTypes};
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterHealth, PersistenceAdapterQueryable, PersistenceData, PersistenceError, Query};
+    use crate::persistence_adapter::repository::Repository;
     use crate::tests::AllSupportedTypesPersistenceSpec;
 
 
@@ -363,29 +1033,29 @@ mod tests{
 
         PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::initialize(&persistence);
 
-        PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::clear(&persistence);
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::clear(&persistence).is_ok());
 
         assert_eq!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::scan(&persistence, 0, None).len(), 0);
 
         let x = AllSupportedTypes{
-            string: thread_rng().sample_iter(&Alphanumeric).take(64).map(char::from).collect(),
-            bytes: thread_rng().gen_iter::<u8>().take(64).collect(),
-            integer: thread_rng().gen::<i64>(),
-            unsigned_integer: thread_rng().gen::<u32>() as u64,
+            string: rng().sample_iter(&Alphanumeric).take(64).map(char::from).collect(),
+            bytes: rng().random_iter::<u8>().take(64).collect(),
+            integer: rng().random::<i64>(),
+            unsigned_integer: rng().random::<u32>() as u64,
             float: 0.0,
-            double: thread_rng().gen::<f64>()
+            double: rng().random::<f64>()
         };
 
         let y = AllSupportedTypes{
-            string: thread_rng().sample_iter(&Alphanumeric).take(64).map(char::from).collect(),
-            bytes: thread_rng().gen_iter::<u8>().take(64).collect(),
-            integer: thread_rng().gen::<i64>(),
-            unsigned_integer: thread_rng().gen::<u32>() as u64,
+            string: rng().sample_iter(&Alphanumeric).take(64).map(char::from).collect(),
+            bytes: rng().random_iter::<u8>().take(64).collect(),
+            integer: rng().random::<i64>(),
+            unsigned_integer: rng().random::<u32>() as u64,
             float: 1.0,
-            double: thread_rng().gen::<f64>()
+            double: rng().random::<f64>()
         };
 
-        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&persistence, "test".to_string(), x.clone()).is_ok());
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&persistence, &"test".to_string(), &x).is_ok());
 
         assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::contains(&persistence, &("test".to_string())));
 
@@ -395,25 +1065,394 @@ mod tests{
 
         assert_eq!(PersistenceAdapterQueryable::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::query(&persistence, Query::Equals("key".to_string(), PersistenceData::String("test".to_string())), 0, None), vec![("test".to_string(), x.clone())]);
 
-        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&persistence, "test1".to_string(), y.clone()).is_ok());
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&persistence, &"test1".to_string(), &y).is_ok());
 
         assert_eq!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::scan(&persistence, 0, None), vec![("test".to_string(), x.clone()), ("test1".to_string(), y.clone())]);
 
         assert_eq!(PersistenceAdapterQueryable::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::query(&persistence, Query::GreaterThan("float".to_string(), PersistenceData::Float(0.0)), 0, None), vec![("test1".to_string(), y.clone())]);
 
-        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::update(&persistence, &"test1".to_string(), x.clone(), Some(&vec!["float"])).is_ok());
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::update(&persistence, &"test1".to_string(), &x, Some(&["float"])).is_ok());
 
         assert_eq!(PersistenceAdapterQueryable::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::query(&persistence, Query::GreaterThan("float".to_string(), PersistenceData::Float(0.0)), 0, None), vec![]);
 
-        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::update(&persistence, &"test1".to_string(), y.clone(), None).is_ok());
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::update(&persistence, &"test1".to_string(), &y, None).is_ok());
 
-        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::delete(&persistence, "test".to_string()).is_some());
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::delete(&persistence, &"test".to_string()).is_ok_and(|deleted|deleted == 1));
 
         assert_eq!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::scan(&persistence, 0, None), vec![("test1".to_string(), y)]);
 
         assert!(!PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::contains(&persistence, &("test".to_string())));
     }
 
+    #[test]
+    fn test_health() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        let row = AllSupportedTypes::with_integer(1);
+
+        assert!(adapter.store(&"test".to_string(), &row).is_ok());
+        assert!(persistence.health().is_ok_and(|h|h.last_error.is_none()));
+
+        // a failed write is reported as the last error
+        assert!(adapter.store(&"test".to_string(), &row).is_err());
+        assert!(persistence.health().is_ok_and(|h|h.last_error.is_some()));
+    }
+
+    #[test]
+    fn test_close() {
+        let temp_dir = TempDir::new("sqlite_test").expect("Failed to create tempdir");
+
+        let temp_db_name = temp_dir.path().join("test.sqlite");
+        let db_connection = Arc::new(Connection::open_with_full_mutex(&temp_db_name).expect("Failed to open temp db"));
+        assert!(db_connection.execute("PRAGMA journal_mode=WAL").is_ok());
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let other = SqlitePersistence::new(db_connection, "test_table");
+        PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::initialize(&persistence);
+        let row = AllSupportedTypes::with_integer(1);
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&persistence, &"test".to_string(), &row).is_ok());
+
+        // a connection still shared with another adapter stays open for it
+        assert!(persistence.close().is_ok());
+        assert_eq!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::load(&other, &"test".to_string()), Some(row.clone()));
+        assert!(other.close().is_ok());
+
+        // everything written is in the database file once the last one is closed
+        let reopened = SqlitePersistence::new(Arc::new(Connection::open_with_full_mutex(&temp_db_name).expect("Failed to open temp db")), "test_table");
+        assert_eq!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::load(&reopened, &"test".to_string()), Some(row));
+    }
+
+    #[test]
+    fn test_backup_to() {
+        let (temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        let row = AllSupportedTypes::with_integer(1);
+        assert!(adapter.store(&"test".to_string(), &row).is_ok());
+
+        let backup_path = temp_dir.path().join("backup.sqlite");
+        assert!(persistence.backup_to(&backup_path).is_ok());
+        // an existing file isn't overwritten
+        assert!(persistence.backup_to(&backup_path).is_err());
+
+        // the source stays usable and later writes don't reach the snapshot
+        assert!(adapter.store(&"test1".to_string(), &row).is_ok());
+        let backup = SqlitePersistence::new(Arc::new(Connection::open_with_full_mutex(&backup_path).expect("Failed to open backup db")), "test_table");
+        let backup_adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &backup;
+        assert_eq!(backup_adapter.scan(0, None), vec![("test".to_string(), row)]);
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(persistence);
+
+        let entry = AllSupportedTypes::with_integer(-1);
+
+        for key in ["a", "b", "c", "d"] {
+            assert!(repo.store(&key.to_string(), &entry).is_ok());
+        }
+
+        let keys = |rows: Vec<(String, AllSupportedTypes)>|rows.into_iter().map(|(k, _)|k).collect::<Vec<_>>();
+
+        assert_eq!(keys(repo.scan_range(Some(&"b".to_string()), Some(&"d".to_string()), None)), vec!["b", "c"]);
+        assert_eq!(keys(repo.scan_range(Some(&"b".to_string()), None, Some(1))), vec!["b"]);
+        assert_eq!(keys(repo.scan_range(None, Some(&"c".to_string()), None)), vec!["a", "b"]);
+        assert_eq!(keys(repo.scan_range(None, None, None)).len(), 4);
+    }
+
+    #[test]
+    fn test_maintain() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        assert!(adapter.store(&"a".to_string(), &AllSupportedTypes::with_integer(1)).is_ok());
+        assert_eq!(adapter.delete(&"a".to_string()).ok(), Some(1));
+        assert!(persistence.maintain(MaintenanceOptions{vacuum: true, analyze: true, wal_checkpoint: true}).is_ok());
+        assert!(persistence.maintain(MaintenanceOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_explain() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let plan = persistence.explain::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&Query::Equals("key".to_string(), PersistenceData::String("a".to_string()))).expect("Failed to explain");
+        assert!(plan.starts_with("SELECT * FROM \"test_table\" WHERE"));
+        assert!(plan.contains("QUERY PLAN\n") && plan.contains("USING INDEX"));
+    }
+
+    #[test]
+    fn test_slow_query_log() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(persistence.with_slow_query_log(Duration::ZERO, 3));
+        assert!(repo.adapter().slow_queries().is_empty());
+        for key in ["a", "b"] {
+            assert!(repo.store(&key.to_string(), &AllSupportedTypes::with_integer(1)).is_ok());
+        }
+        assert_eq!(repo.delete(&"a".to_string()).ok(), Some(1));
+        assert_eq!(repo.clear().ok(), Some(1));
+
+        // only the last three are kept
+        let slow_queries = repo.adapter().slow_queries();
+        assert_eq!(slow_queries.len(), 3);
+        assert!(slow_queries[0].sql.starts_with("INSERT INTO \"test_table\""));
+        assert!(slow_queries[1].sql.starts_with("DELETE FROM \"test_table\" WHERE"));
+        assert_eq!(slow_queries[1].parameters, vec!["String(1 bytes)"]);
+        assert_eq!(slow_queries[2].sql, "DELETE FROM \"test_table\" RETURNING 1");
+
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let fast = persistence.with_slow_query_log(Duration::from_secs(3600), 3);
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::clear(&fast).is_ok());
+        assert!(fast.slow_queries().is_empty());
+    }
+
+    #[test]
+    fn test_patch() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(persistence);
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(repo.store(&"a".to_string(), &entry).is_ok());
+
+        assert_eq!(repo.patch(&"a".to_string(), HashMap::from([("integer", PersistenceData::Integer(5)), ("string", PersistenceData::String("patched".to_string()))])).ok(), Some(1));
+        assert_eq!(repo.load(&"a".to_string()), Some(AllSupportedTypes { string: "patched".to_string(), ..AllSupportedTypes::with_integer(5) }));
+        assert!(matches!(repo.patch(&"a".to_string(), HashMap::from([("key", PersistenceData::String("z".to_string()))])), Err(PersistenceError::FieldNotAllowed { field }) if field == "key"));
+        assert!(matches!(repo.patch(&"a".to_string(), HashMap::from([("unknown", PersistenceData::Integer(1))])), Err(PersistenceError::FieldNotAllowed { .. })));
+        assert!(repo.load(&"a".to_string()).is_some_and(|a|a.integer == 5));
+    }
+
+    #[test]
+    fn test_update() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(persistence);
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(repo.store(&"b".to_string(), &entry).is_ok());
+
+        // only the listed fields change, the key in the list is ignored
+        let updated = AllSupportedTypes { double: 7.0, ..AllSupportedTypes::with_integer(7) };
+        assert_eq!(repo.update(&"b".to_string(), &AllSupportedTypes { string: "ignored".to_string(), ..updated.clone() }, Some(&["double", "key", "integer"])).ok(), Some(1));
+        assert_eq!(repo.load(&"b".to_string()), Some(updated.clone()));
+        assert!(repo.update(&"b".to_string(), &entry, Some(&["unknown"])).is_err());
+        assert_eq!(repo.load(&"b".to_string()), Some(updated));
+
+        assert_eq!(repo.update(&"b".to_string(), &entry, None).ok(), Some(1));
+        assert_eq!(repo.load(&"b".to_string()), Some(entry));
+    }
+
+    #[test]
+    fn test_affected_rows() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(persistence);
+        let entry = AllSupportedTypes::with_integer(1);
+        for key in ["a", "b", "c", "d"] {
+            assert!(repo.store(&key.to_string(), &entry).is_ok());
+        }
+
+        assert_eq!(repo.update(&"a".to_string(), &entry, None).ok(), Some(1));
+        assert_eq!(repo.update(&"missing".to_string(), &entry, None).ok(), Some(0));
+        assert!(!repo.contains(&"missing".to_string()));
+        assert_eq!(repo.patch(&"a".to_string(), HashMap::from([("integer", PersistenceData::Integer(5))])).ok(), Some(1));
+        assert_eq!(repo.patch(&"missing".to_string(), HashMap::from([("integer", PersistenceData::Integer(5))])).ok(), Some(0));
+        assert_eq!(repo.delete(&"d".to_string()).ok(), Some(1));
+        assert_eq!(repo.delete(&"d".to_string()).ok(), Some(0));
+
+        assert_eq!(repo.clear_where(Query::LessThan("key".to_string(), PersistenceData::String("c".to_string()))).ok(), Some(2));
+        assert_eq!(repo.clear_where(Query::LessThan("key".to_string(), PersistenceData::String("c".to_string()))).ok(), Some(0));
+        assert_eq!(repo.clear().ok(), Some(1));
+        assert_eq!(repo.clear().ok(), Some(0));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let capabilities = PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::capabilities(&persistence);
+        assert!(capabilities.supports_query && capabilities.supports_transactions && capabilities.ordered_scan);
+        assert!(!capabilities.supports_ttl);
+        let capabilities = PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::capabilities(&persistence.with_ttl());
+        assert!(capabilities.supports_ttl);
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        for key in ["a", "b"] {
+            assert!(PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store(&persistence, &key.to_string(), &AllSupportedTypes::with_integer(1)).is_ok());
+        }
+        assert!(persistence.integrity_check().is_ok_and(|problems|problems.is_empty()));
+        assert!(persistence.quick_check().is_ok_and(|problems|problems.is_empty()));
+    }
+
+    #[test]
+    fn test_deserialization_mode() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let strict = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(SqlitePersistence::new(db_connection.clone(), "test_table"));
+        let lenient = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(SqlitePersistence::new(db_connection.clone(), "test_table").with_deserialization_mode(DeserializationMode::Lenient));
+
+        strict.initialize();
+
+        let entry = AllSupportedTypes::with_integer(-1);
+        assert!(strict.store(&"a".to_string(), &entry).is_ok());
+        assert!(db_connection.execute("ALTER TABLE \"test_table\" ADD COLUMN added_later TEXT").is_ok());
+
+        assert!(strict.load(&"a".to_string()).is_none());
+        assert!(strict.scan(0, None).is_empty());
+        assert!(strict.adapter().health().is_ok_and(|report|report.last_error.is_some_and(|e|e.contains("added_later"))));
+
+        assert_eq!(lenient.load(&"a".to_string()), Some(entry));
+        assert_eq!(lenient.scan(0, None).len(), 1);
+    }
+
+    #[test]
+    fn test_stored_type_checked() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(SqlitePersistence::new(db_connection.clone(), "test_table"));
+        repo.initialize();
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(repo.store(&"a".to_string(), &entry).is_ok());
+        let last_error = ||repo.adapter().health().ok().and_then(|report|report.last_error).unwrap_or_default();
+
+        // text in a Bytes column is only a reference when there is an external blob store to resolve it in
+        assert!(db_connection.execute("UPDATE \"test_table\" SET \"bytes\" = 'reference'").is_ok());
+        assert!(repo.load(&"a".to_string()).is_none());
+        assert!(last_error().contains("stored as TEXT but the spec expects BLOB"));
+
+        // and a blob elsewhere is only a ciphertext in a sensitive field
+        assert!(db_connection.execute("UPDATE \"test_table\" SET \"bytes\" = x'01', \"integer\" = x'00'").is_ok());
+        assert!(repo.load(&"a".to_string()).is_none());
+        assert!(last_error().contains("stored as BLOB but the spec expects INTEGER"));
+
+        assert!(db_connection.execute("UPDATE \"test_table\" SET \"integer\" = 1").is_ok());
+        assert_eq!(repo.load(&"a".to_string()), Some(entry));
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let temp_dir = TempDir::new("sqlite_test").expect("Failed to create tempdir");
+
+        let path = temp_dir.path().join("test.sqlite");
+        let db_connection = Arc::new(Connection::open_with_full_mutex(&path).expect("Failed to open temp db"));
+
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(SqlitePersistence::new(db_connection.clone(), "test_table"));
+        repo.initialize();
+        let row = |string: &str|AllSupportedTypes { string: string.to_string(), ..AllSupportedTypes::with_integer(1) };
+        assert!(repo.store(&"a".to_string(), &row("x")).is_ok());
+        assert!(repo.store(&"b".to_string(), &row("y")).is_ok());
+        assert!(db_connection.execute("CREATE UNIQUE INDEX unique_string ON \"test_table\" (string)").is_ok());
+
+        let duplicate = repo.patch(&"b".to_string(), HashMap::from([("string", PersistenceData::String("x".to_string()))]));
+        assert!(matches!(duplicate, Err(PersistenceError::UniqueViolation { message }) if message.contains("test_table.string")));
+        assert!(repo.adapter().health().is_ok_and(|report|report.last_error.is_some_and(|e|e.contains("UNIQUE"))));
+        assert!(repo.store(&"a".to_string(), &row("z")).is_err_and(|e|matches!(e.kind, Some(PersistenceError::UniqueViolation { .. }))));
+
+        // another connection holding the write lock
+        let other = Connection::open_with_full_mutex(&path).expect("Failed to open temp db");
+        assert!(other.execute("BEGIN IMMEDIATE").is_ok());
+        assert!(matches!(repo.delete(&"a".to_string()), Err(PersistenceError::Busy { .. })));
+        assert!(repo.store(&"c".to_string(), &row("z")).is_err_and(|e|e.is_busy()));
+        assert!(other.execute("ROLLBACK").is_ok());
+
+        assert!(db_connection.execute("PRAGMA query_only = 1").is_ok());
+        assert!(matches!(repo.delete(&"a".to_string()), Err(PersistenceError::ReadOnly { .. })));
+
+        let not_null = sqlite_::Error { code: Some(19), message: Some("NOT NULL constraint failed: test_table.string".to_string()) };
+        assert!(matches!(PersistenceError::from(not_null), PersistenceError::NotNullViolation { .. }));
+        let foreign_key = sqlite_::Error { code: Some(19), message: Some("FOREIGN KEY constraint failed".to_string()) };
+        assert!(matches!(PersistenceError::from(foreign_key), PersistenceError::ForeignKeyViolation { .. }));
+        let check = sqlite_::Error { code: Some(19), message: Some("CHECK constraint failed: positive".to_string()) };
+        assert!(matches!(PersistenceError::from(check), PersistenceError::Backend { .. }));
+    }
+
+    // Random table names, field names and values from characters that tend to break SQL, plus rows of the
+    // wrong types written around the adapter. Anything may fail, nothing may panic
+    #[test]
+    fn test_hostile_inputs() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        const HOSTILE: [&str; 16] = ["\"", "'", ";", "\0", "--", "[", "]", "?", ":tenant", "%", "\\", "é", "🦀", " ", "a", "key"];
+        const FIELDS: [&str; 6] = ["", "\"", "key", "string\"; DROP TABLE x; --", "integer", "\0"];
+        // seeded so a failure reproduces
+        let mut rng = StdRng::seed_from_u64(2184);
+        let mut hostile = |max: usize|(0..rng.random_range(0..=max)).map(|_|HOSTILE[rng.random_range(0..HOSTILE.len())]).collect::<String>();
+        let names = (0..20).map(|_|hostile(4)).chain(["test_table".to_string()]).collect::<Vec<_>>();
+        let values = (0..20).map(|_|hostile(8)).collect::<Vec<_>>();
+
+        for table_name in &names {
+            let persistence = SqlitePersistence::new(db_connection.clone(), table_name);
+            let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+            let queryable: &dyn PersistenceAdapterQueryable<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+            adapter.initialize();
+            for (i, value) in values.iter().enumerate() {
+                let row = AllSupportedTypes { string: value.clone(), bytes: value.as_bytes().to_vec(), unsigned_integer: u64::MAX, float: f32::NAN, double: f64::INFINITY, ..AllSupportedTypes::with_integer(i64::MIN) };
+                let _ = adapter.store(value, &row);
+                let _ = adapter.update(value, &row, Some(&[FIELDS[i % FIELDS.len()]]));
+                let _ = adapter.patch(value, HashMap::from([(FIELDS[i % FIELDS.len()], PersistenceData::String(value.clone()))]));
+                let _ = adapter.load(value);
+                let _ = adapter.contains(value);
+                let _ = adapter.scan_range(Some(value), None, Some(2));
+                let filter = Query::or(Query::Equals(FIELDS[i % FIELDS.len()].to_string(), PersistenceData::String(value.clone())), Query::Equals(value.clone(), PersistenceData::Bytes(vec![0])));
+                let _ = queryable.query(filter.clone(), 0, None);
+                let _ = queryable.clear_where(filter);
+                let _ = adapter.delete(value);
+            }
+            let _ = adapter.scan(0, None);
+            let _ = adapter.clear();
+        }
+
+        // rows another tool wrote with NULLs and values of the wrong type
+        assert!(db_connection.execute("INSERT INTO \"test_table\" VALUES (NULL, NULL, NULL, NULL, NULL, NULL, NULL), ('b', x'ff', 'text', 'text', x'00', 'text', NULL), (1, 2, 3, 4, 5, 6, 7)").is_ok());
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(SqlitePersistence::new(db_connection, "test_table"));
+        let _ = repo.load(&"b".to_string());
+        let _ = repo.scan(0, None);
+        let _ = repo.query(Query::Equals("key".to_string(), PersistenceData::String("b".to_string())), 0, None);
+        assert!(repo.adapter().health().is_ok_and(|report|report.last_error.is_some()));
+    }
+
+    #[test]
+    fn test_quoted_table_name() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        for table_name in ["\"", "te\"st", "a\"; DROP TABLE b; --", "\"\""] {
+            let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(SqlitePersistence::new(db_connection.clone(), table_name));
+            repo.initialize();
+            let key = format!("{table_name}\0");
+            let row = AllSupportedTypes { string: table_name.to_string(), ..AllSupportedTypes::with_integer(1) };
+            assert!(repo.store(&key, &row).is_ok());
+            assert_eq!(repo.load(&key), Some(row.clone()));
+            assert_eq!(repo.scan(0, None).len(), 1);
+            assert_eq!(repo.scan_range(Some(&key), None, None).len(), 1);
+
+            assert!(repo.update(&key, &AllSupportedTypes::with_integer(2), Some(&["integer"])).is_ok());
+            assert!(repo.patch(&key, HashMap::from([("string", PersistenceData::String("patched".to_string()))])).is_ok());
+            assert_eq!(repo.load(&key), Some(AllSupportedTypes { string: "patched".to_string(), ..AllSupportedTypes::with_integer(2) }));
+            assert_eq!(repo.query(Query::Equals("integer".to_string(), PersistenceData::Integer(2)), 0, None).len(), 1);
+            assert!(repo.clear_where(Query::Equals("integer".to_string(), PersistenceData::Integer(2))).is_ok_and(|cleared|cleared == 1));
+        }
+    }
+
+    #[test]
+    fn test_statement_hook() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let mut persistence = SqlitePersistence::new(db_connection, "test_table");
+        let sink = captured.clone();
+        persistence.set_statement_hook(move |sql, values|sink.lock().unwrap().push((sql.to_string(), values.to_vec())));
+        let repo = Repository::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>::new(persistence);
+        repo.initialize();
+        assert!(repo.load(&"a".to_string()).is_none());
+        assert!(repo.delete(&"a".to_string()).is_ok());
+        assert!(repo.adapter().transaction().and_then(|transaction|transaction.commit()).is_ok());
+
+        let captured = captured.lock().unwrap();
+        assert_eq!(captured.len(), 5);
+        assert!(captured[0].0.starts_with("CREATE TABLE IF NOT EXISTS \"test_table\""));
+        assert!(captured[1].0.starts_with("SELECT"));
+        assert!(captured[2].0.starts_with("DELETE FROM \"test_table\" WHERE"));
+        // unlike the slow query log the hook sees the values themselves
+        assert_eq!(captured[2].1.iter().map(PersistenceData::to_str).collect::<Vec<_>>(), vec![Some("a")]);
+        // transaction control goes through the hook too
+        assert_eq!(captured[3].0, "BEGIN");
+        assert_eq!(captured[4].0, "COMMIT");
+    }
+
     #[test]
     fn test_generate_query() {
         let filter = Query::and(
@@ -426,6 +1465,9 @@ mod tests{
             Query::Equals("string".to_string(), PersistenceData::String("hello!".to_string()))
         );
 
-        println!("{:?}", SqlitePersistence::generate_filter(&filter, 0, Vec::new()));
+        let (condition, next_index, values) = SqlitePersistence::generate_filter(&filter, 0, Vec::new());
+        assert_eq!(condition, "( ( ( NOT  \"integer\"=?  ) OR  \"unsigned_integer\"=?  ) AND  \"string\"=?  )");
+        assert_eq!(next_index, 3);
+        assert_eq!(values.iter().map(PersistenceData::to_str).collect::<Vec<_>>(), vec![None, None, Some("hello!")]);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,104 @@
+use sqlite_::{Statement, Value};
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, Query};
+use super::{quote_identifier, SqlitePersistence};
+
+// A row read without a spec, columns in table order. NULL columns are None
+pub type RawRow = Vec<(String, Option<PersistenceData>)>;
+
+// Spec-less access for tooling like the dmfg-persist binary, which has to work on tables it has no spec for.
+// Values come back as sqlite stores them: integers as Integer, reals as Double, text as String, blobs as Bytes
+impl SqlitePersistence {
+    // every table in the database except sqlite's own
+    pub fn tables(&self) -> Result<Vec<String>, PersistenceError> {
+        let command = "SELECT name FROM sqlite_schema WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name";
+        let _timer = self.time_statement(command, []);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        let mut tables = Vec::new();
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            tables.push(statement.read::<String, usize>(0).map_err(|e|self.backend_error(e))?);
+        }
+        Ok(tables)
+    }
+
+    // the table's columns and their declared types, in table order. Empty if the table doesn't exist
+    pub fn table_columns(&self) -> Result<Vec<(String, String)>, PersistenceError> {
+        let command = "SELECT name, type FROM pragma_table_info(?) ORDER BY cid";
+        let _timer = self.time_statement(command, [&PersistenceData::String(self.table_name.clone())]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        statement.bind((1, self.table_name.as_str())).map_err(|e|self.backend_error(e))?;
+        let mut columns = Vec::new();
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            columns.push((statement.read::<String, usize>(0).map_err(|e|self.backend_error(e))?, statement.read::<String, usize>(1).map_err(|e|self.backend_error(e))?));
+        }
+        Ok(columns)
+    }
+
+    // Rows matching filter, or all of them, including the internal columns like _checksum
+    pub fn raw_rows(&self, filter: Option<&Query>, limit: Option<usize>) -> Result<Vec<RawRow>, PersistenceError> {
+        let (condition, values) = match filter {
+            Some(filter) => {
+                let (condition, _, values) = SqlitePersistence::generate_filter(filter, 0, Vec::new());
+                (format!(" WHERE {condition}{}", self.and_row_scope()), values)
+            },
+            None => (self.where_row_scope(), Vec::new())
+        };
+        let command = format!("SELECT * FROM {}{condition} LIMIT {}", quote_identifier(&self.table_name), limit.map(|l|l as isize).unwrap_or(-1));
+
+        let _timer = self.time_statement(&command, &values);
+        let mut statement = self.connection.prepare(&command).map_err(|e|self.backend_error(e))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+
+        let mut rows = Vec::new();
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            rows.push(self.read_raw_row(&statement)?);
+        }
+        Ok(rows)
+    }
+
+    fn read_raw_row(&self, statement: &Statement) -> Result<RawRow, PersistenceError> {
+        statement.column_names().iter().enumerate().map(|(i, name)|{
+            let value = match statement.read::<Value, usize>(i).map_err(|e|self.backend_error(e))? {
+                Value::Binary(b) => Some(PersistenceData::Bytes(b)),
+                Value::Float(f) => Some(PersistenceData::Double(f)),
+                Value::Integer(i) => Some(PersistenceData::Integer(i)),
+                Value::String(s) => Some(PersistenceData::String(s)),
+                Value::Null => None
+            };
+            Ok((name.clone(), value))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, Query};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_raw_rows() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+        assert!(adapter.store(&"b".to_string(), &AllSupportedTypes { integer: 2, ..entry.clone() }).is_ok());
+
+        assert_eq!(persistence.tables().ok(), Some(vec!["test_table".to_string()]));
+        let columns = persistence.table_columns().expect("Failed to read columns");
+        assert_eq!(columns.first().map(|(name, _)|name.as_str()), Some("key"));
+        assert!(SqlitePersistence::new(db_connection, "missing").table_columns().is_ok_and(|columns|columns.is_empty()));
+
+        let rows = persistence.raw_rows(Some(&Query::Equals("integer".to_string(), PersistenceData::Integer(2))), None).expect("Failed to read rows");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].len(), columns.len());
+        assert_eq!(format!("{:?}", rows[0][0]), format!("{:?}", ("key".to_string(), Some(PersistenceData::String("b".to_string())))));
+        assert_eq!(persistence.raw_rows(None, Some(1)).map(|rows|rows.len()).ok(), Some(1));
+    }
+}
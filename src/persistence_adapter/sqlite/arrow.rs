@@ -0,0 +1,197 @@
+use std::{collections::HashMap, sync::Arc};
+use arrow::array::{ArrayRef, BinaryArray, Float32Array, Float64Array, Int64Array, StringArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, Query};
+use super::{quote_identifier, SqlitePersistence};
+
+// Decimals and hashes are written as their text, like they are stored in the table
+fn data_type(field: &PersistenceType) -> DataType {
+    match field {
+        PersistenceType::String(_) => DataType::Utf8,
+        PersistenceType::Bytes(_) => DataType::Binary,
+        PersistenceType::Integer(_) => DataType::Int64,
+        PersistenceType::UnsignedInteger(_) => DataType::UInt64,
+        PersistenceType::Float(_) => DataType::Float32,
+        PersistenceType::Double(_) => DataType::Float64,
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => DataType::Utf8,
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => DataType::Utf8,
+    }
+}
+
+// one non-nullable column per spec field, in the spec's order
+pub(super) fn arrow_schema(fields: &'static [PersistenceType]) -> SchemaRef {
+    Arc::new(Schema::new(fields.iter().map(|f|Field::new(f.get_name(), data_type(f), false)).collect::<Vec<_>>()))
+}
+
+fn column(field: &PersistenceType, rows: &[HashMap<&'static str, PersistenceData>]) -> Result<ArrayRef, String> {
+    fn values<'a, T>(name: &str, rows: &'a [HashMap<&'static str, PersistenceData>], read: impl Fn(&'a PersistenceData) -> Option<T>) -> Result<Vec<T>, String> {
+        rows.iter().map(|row|row.get(name).and_then(&read).ok_or_else(||format!("{name} is missing or of another type"))).collect()
+    }
+    let name = field.get_name();
+    Ok(match field {
+        PersistenceType::String(_) => Arc::new(StringArray::from(values(name, rows, |v|match v { PersistenceData::String(s) => Some(s.as_str()), _ => None })?)),
+        PersistenceType::Bytes(_) => Arc::new(BinaryArray::from_vec(values(name, rows, |v|v.to_bytes())?)),
+        PersistenceType::Integer(_) => Arc::new(Int64Array::from(values(name, rows, |v|match v { PersistenceData::Integer(i) => Some(*i), _ => None })?)),
+        PersistenceType::UnsignedInteger(_) => Arc::new(UInt64Array::from(values(name, rows, |v|match v { PersistenceData::UnsignedInteger(u) => Some(*u), _ => None })?)),
+        PersistenceType::Float(_) => Arc::new(Float32Array::from(values(name, rows, |v|match v { PersistenceData::Float(f) => Some(*f), _ => None })?)),
+        PersistenceType::Double(_) => Arc::new(Float64Array::from(values(name, rows, |v|match v { PersistenceData::Double(d) => Some(*d), _ => None })?)),
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => Arc::new(StringArray::from(values(name, rows, |v|match v { PersistenceData::Decimal(d) => Some(d.to_string()), _ => None })?)),
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => Arc::new(StringArray::from(values(name, rows, |v|match v { PersistenceData::String(s) => Some(s.as_str()), _ => None })?)),
+    })
+}
+
+pub(super) fn to_record_batch(fields: &'static [PersistenceType], rows: &[HashMap<&'static str, PersistenceData>]) -> Result<RecordBatch, String> {
+    let columns = fields.iter().map(|f|column(f, rows)).collect::<Result<Vec<_>, _>>()?;
+    RecordBatch::try_new(arrow_schema(fields), columns).map_err(|e|e.to_string())
+}
+
+// The rows of a batch as spec fields, columns are found by name and must have the type arrow_schema gives them
+#[cfg(feature = "parquet")]
+pub(super) fn from_record_batch(fields: &'static [PersistenceType], batch: &RecordBatch) -> Result<Vec<HashMap<&'static str, PersistenceData>>, String> {
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::{Float32Type, Float64Type, Int64Type, UInt64Type};
+    let mut rows = vec![HashMap::new(); batch.num_rows()];
+    for field in fields {
+        let name = field.get_name();
+        let column = batch.column_by_name(name).ok_or_else(||format!("{name} is missing"))?;
+        if column.data_type() != &data_type(field) {
+            return Err(format!("{name} is {} instead of {}", column.data_type(), data_type(field)));
+        }
+        if column.null_count() > 0 {
+            return Err(format!("{name} has nulls"));
+        }
+        for (i, row) in rows.iter_mut().enumerate() {
+            let value = match field {
+                PersistenceType::String(_) => PersistenceData::String(column.as_string::<i32>().value(i).to_string()),
+                PersistenceType::Bytes(_) => PersistenceData::Bytes(column.as_binary::<i32>().value(i).to_vec()),
+                PersistenceType::Integer(_) => PersistenceData::Integer(column.as_primitive::<Int64Type>().value(i)),
+                PersistenceType::UnsignedInteger(_) => PersistenceData::UnsignedInteger(column.as_primitive::<UInt64Type>().value(i)),
+                PersistenceType::Float(_) => PersistenceData::Float(column.as_primitive::<Float32Type>().value(i)),
+                PersistenceType::Double(_) => PersistenceData::Double(column.as_primitive::<Float64Type>().value(i)),
+                #[cfg(feature = "decimal")]
+                PersistenceType::Decimal(_) => {
+                    let text = column.as_string::<i32>().value(i);
+                    PersistenceData::Decimal(text.parse().map_err(|_|format!("{name}: {text:?} is not a decimal"))?)
+                },
+                #[cfg(feature = "hashed")]
+                PersistenceType::Hashed(_) => PersistenceData::String(column.as_string::<i32>().value(i).to_string()),
+            };
+            row.insert(name, value);
+        }
+    }
+    Ok(rows)
+}
+
+impl SqlitePersistence {
+    // Reads the rows matching filter, or all rows, in key order as Arrow record batches of up to batch_size
+    // rows with one column per spec field. Rows are read a batch at a time after the last key of the previous
+    // batch. Like scan, rows that fail to read are skipped and recorded as the last error, a failing statement
+    // ends the iterator and is recorded too
+    pub fn scan_arrow<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, filter: Option<Query>, batch_size: usize) -> impl Iterator<Item = RecordBatch> + '_ {
+        let batch_size = batch_size.max(1);
+        let mut last_key = None;
+        let mut exhausted = false;
+        std::iter::from_fn(move ||{
+            while !exhausted {
+                match self.read_arrow_batch::<Key, Data, Spec>(filter.as_ref(), last_key.as_ref(), batch_size) {
+                    Ok((batch, next_key, read)) => {
+                        exhausted = read < batch_size;
+                        last_key = next_key;
+                        if batch.num_rows() > 0 {
+                            return Some(batch);
+                        }
+                    },
+                    Err(e) => {
+                        self.record_error(e);
+                        exhausted = true;
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    // the next batch after last_key, the last key read and how many rows were read including skipped ones
+    pub(super) fn read_arrow_batch<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, filter: Option<&Query>, last_key: Option<&PersistenceData>, batch_size: usize) -> Result<(RecordBatch, Option<PersistenceData>, usize), PersistenceError> {
+        let key_field = Spec::fields().iter().find(|f|f.get_name() == Spec::key_field())
+            .ok_or_else(||PersistenceError::FieldNotAllowed { field: Spec::key_field().to_string() })?;
+
+        let (mut conditions, mut values) = match filter {
+            Some(filter) => {
+                let (condition, _, values) = SqlitePersistence::generate_filter(filter, 0, Vec::new());
+                (vec![condition], values)
+            },
+            None => (Vec::new(), Vec::new())
+        };
+        if let Some(last_key) = last_key {
+            conditions.push(format!("{} > ?", quote_identifier(Spec::key_field())));
+            values.push(last_key.clone());
+        }
+        conditions.extend(self.row_scope());
+
+        let mut command = format!("SELECT * FROM {}", quote_identifier(&self.table_name));
+        if !conditions.is_empty() {
+            command.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+        }
+        command.push_str(&format!(" ORDER BY {} LIMIT {batch_size}", quote_identifier(Spec::key_field())));
+
+        let _timer = self.time_statement(&command, &values);
+        let mut statement = self.connection.prepare(&command).map_err(|e|self.backend_error(e))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+
+        let mut rows = Vec::new();
+        let mut next_key = last_key.cloned();
+        let mut read = 0;
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            read += 1;
+            next_key = Some(SqlitePersistence::read_field(key_field, &statement, Spec::key_field())?);
+            match self.collect_fields::<Key, Data, Spec>(&statement) {
+                Ok(fields) => rows.push(fields),
+                Err(e) => { self.record_error(e); }
+            }
+        }
+        let batch = to_record_batch(Spec::fields(), &rows).map_err(|e|self.backend_error(e))?;
+        Ok((batch, next_key, read))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use arrow::array::AsArray;
+    use arrow::datatypes::{DataType, Int64Type};
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, Query};
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_scan_arrow() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        for i in 0..5 {
+            let entry = AllSupportedTypes{ string: format!("row {i}"), bytes: vec![i as u8], integer: i, unsigned_integer: i as u64, float: i as f32, double: i as f64 };
+            adapter.store(&format!("key{i}"), &entry).expect("Failed to store");
+        }
+
+        let batches = persistence.scan_arrow::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(None, 2).collect::<Vec<_>>();
+        assert_eq!(batches.iter().map(|b|b.num_rows()).collect::<Vec<_>>(), vec![2, 2, 1]);
+        let schema = batches[0].schema();
+        assert_eq!(schema.fields().iter().map(|f|f.name().as_str()).collect::<Vec<_>>(), vec!["key", "string", "bytes", "integer", "unsigned_integer", "float", "double"]);
+        assert_eq!(schema.field_with_name("bytes").map(|f|f.data_type().clone()).ok(), Some(DataType::Binary));
+        assert_eq!(batches[2].column_by_name("key").map(|c|c.as_string::<i32>().value(0).to_string()), Some("key4".to_string()));
+        assert_eq!(batches[1].column_by_name("bytes").map(|c|c.as_binary::<i32>().value(1).to_vec()), Some(vec![3]));
+
+        let filtered = persistence.scan_arrow::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Some(Query::GreaterThan("integer".to_string(), PersistenceData::Integer(2))), 10).collect::<Vec<_>>();
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].column_by_name("integer").map(|c|c.as_primitive::<Int64Type>().values().to_vec()), Some(vec![3, 4]));
+
+        assert_eq!(persistence.scan_arrow::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Some(Query::Equals("integer".to_string(), PersistenceData::Integer(9))), 10).count(), 0);
+    }
+}
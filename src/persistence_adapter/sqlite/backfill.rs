@@ -0,0 +1,172 @@
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, SpecError};
+use super::{checksum::CHECKSUM_COLUMN, intersperse, quote_identifier, version::VERSION_COLUMN, SqlitePersistence};
+
+impl SqlitePersistence {
+    // Fills field, a column added to the table that Spec doesn't have yet, from each row's data: run it with
+    // the spec from before the column was added. Only rows where the column is null are filled. Each batch
+    // of batch_size rows is one savepoint, committed on its own outside a transaction, and progress is called
+    // with the number filled so far after each one. A run that is stopped or fails keeps its finished batches, and running it again picks up the rows
+    // still null. Rows that can't be read are skipped and recorded as the last error. The value is stored
+    // as computed, without encryption or hashing. The checksums of filled rows are cleared, like rows
+    // written by store_blob_stream. Returns the number of rows filled
+    pub fn backfill<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, field: &str, compute: impl Fn(&Data) -> PersistenceData, batch_size: usize, mut progress: impl FnMut(u64)) -> Result<u64, PersistenceError> {
+        if Spec::fields().iter().any(|f|f.get_name() == field) || !self.table_columns()?.iter().any(|(name, _)|name == field) {
+            return Err(PersistenceError::FieldNotAllowed { field: field.to_string() });
+        }
+        let key_type = Spec::fields().iter().find(|f|f.get_name() == Spec::key_field()).ok_or_else(||SpecError::missing(Spec::key_field()))?;
+        let key_field = quote_identifier(Spec::key_field());
+        let column = quote_identifier(field);
+
+        // only the spec's columns, so rows read the same in every DeserializationMode
+        let mut columns = intersperse(Spec::fields().iter().map(|f|quote_identifier(f.get_name())), ", ".to_string()).collect::<String>();
+        if self.versioned {
+            columns.push_str(&format!(", \"{VERSION_COLUMN}\""));
+        }
+        let select = |after: bool|format!(
+            "SELECT {columns} FROM {} WHERE {column} IS NULL{}{} ORDER BY {key_field} LIMIT {}",
+            quote_identifier(&self.table_name), if after { format!(" AND {key_field} > ?") } else { String::new() }, self.and_row_scope(), batch_size.max(1)
+        );
+        let clear_checksum = if self.checksums { format!(", \"{CHECKSUM_COLUMN}\" = NULL") } else { String::new() };
+        let update = format!("UPDATE {} SET {column} = ?{clear_checksum} WHERE {key_field} = ?{} RETURNING 1", quote_identifier(&self.table_name), self.and_row_scope());
+
+        let mut filled = 0;
+        // the last key looked at, so rows that can't be read or filled aren't read again in this run
+        let mut after: Option<PersistenceData> = None;
+        loop {
+            let savepoint = self.savepoint("backfill")?;
+            let command = select(after.is_some());
+            let _timer = self.time_statement(&command, after.as_slice());
+            let mut statement = self.connection.prepare(&command).map_err(|e|self.backend_error(e))?;
+            if let Some(after) = &after {
+                SqlitePersistence::bind_data(&mut statement, 1, after).map_err(|e|self.backend_error(e))?;
+            }
+            self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+            let mut read = 0;
+            let mut values = Vec::new();
+            while statement.next().map_err(|e|self.backend_error(e))? == Row {
+                let key = SqlitePersistence::read_field(key_type, &statement, Spec::key_field())?;
+                match self.read_row::<Key, Data, Spec>(&statement) {
+                    Ok((_, data)) => values.push((compute(&data), key.clone())),
+                    Err(e) => { self.record_error(e); }
+                }
+                after = Some(key);
+                read += 1;
+            }
+            drop(statement);
+            if read == 0 {
+                savepoint.release()?;
+                return Ok(filled);
+            }
+
+            let mut statement = self.connection.prepare(&update).map_err(|e|self.backend_error(e))?;
+            for (value, key) in &values {
+                let _timer = self.time_statement(&update, [value, key]);
+                statement.reset().map_err(|e|self.backend_error(e))?;
+                SqlitePersistence::bind_data(&mut statement, 1, value).map_err(|e|self.backend_error(e))?;
+                SqlitePersistence::bind_data(&mut statement, 2, key).map_err(|e|self.backend_error(e))?;
+                self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+                filled += self.count_returned(&mut statement)?;
+            }
+            drop(statement);
+            savepoint.release()?;
+            progress(filled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, SpecError};
+    use crate::persistence_adapter::sqlite::{DeserializationMode, SqlitePersistence};
+    use crate::tests::sqlite_connection;
+
+    const NOTE_FIELDS: [PersistenceType; 2] = [
+        PersistenceType::Integer("id"),
+        PersistenceType::String("text")
+    ];
+
+    // notes before a length column was added
+    struct NoteSpec {}
+
+    impl PersistenceSpec<i64, String> for NoteSpec {
+        fn fields() -> &'static [PersistenceType] {
+            &NOTE_FIELDS
+        }
+
+        fn key_field() -> &'static str {
+            "id"
+        }
+
+        fn serialize_key(key: &i64) -> PersistenceData {
+            PersistenceData::Integer(*key)
+        }
+
+        fn deserialize_key(key: &PersistenceData) -> Option<i64> {
+            key.to_int()
+        }
+
+        fn serialize_data(data: &String) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+            Ok(HashMap::from([("text", PersistenceData::String(data.clone()))]))
+        }
+
+        fn deserialize_data(mut data: HashMap<&'static str, PersistenceData>) -> Result<String, SpecError> {
+            data.remove("text").and_then(PersistenceData::into_string).ok_or_else(||SpecError::missing("text"))
+        }
+    }
+
+    #[test]
+    fn test_backfill() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "notes").with_checksums();
+        let adapter: &dyn PersistenceAdapter<i64, String, NoteSpec> = &persistence;
+        adapter.initialize();
+        for (id, text) in [(1, "a"), (2, "bb"), (3, "ccc"), (4, "dddd"), (5, "eeeee")] {
+            assert!(adapter.store(&id, &text.to_string()).is_ok());
+        }
+        db_connection.execute("ALTER TABLE notes ADD COLUMN length INTEGER").expect("Failed to add column");
+        db_connection.execute("UPDATE notes SET length = 0 WHERE id = 3").expect("Failed to fill a row");
+
+        let length = |text: &String|PersistenceData::Integer(text.len() as i64);
+        let mut reported = Vec::new();
+        assert_eq!(persistence.backfill::<i64, String, NoteSpec>("length", length, 2, |n|reported.push(n)).ok(), Some(4));
+        assert_eq!(reported, vec![2, 4]);
+        let mut statement = db_connection.prepare("SELECT group_concat(length) FROM (SELECT length FROM notes ORDER BY id)").expect("Failed to prepare");
+        statement.next().expect("Failed to read");
+        assert_eq!(statement.read::<String, usize>(0).ok().as_deref(), Some("1,2,0,4,5"));
+        drop(statement);
+        // filled rows no longer have a checksum to fail, the others still verify. The old spec only reads
+        // the table leniently now that it has a column the spec doesn't
+        let lenient = persistence.clone().with_deserialization_mode(DeserializationMode::Lenient);
+        assert_eq!(PersistenceAdapter::<i64, String, NoteSpec>::load(&lenient, &2).as_deref(), Some("bb"));
+        assert_eq!(lenient.verify_all::<i64, String, NoteSpec>().map(|c|c.len()).ok(), Some(0));
+
+        // nothing left to fill
+        assert_eq!(persistence.backfill::<i64, String, NoteSpec>("length", length, 2, |_|{}).ok(), Some(0));
+        for field in ["text", "missing"] {
+            assert!(matches!(persistence.backfill::<i64, String, NoteSpec>(field, length, 2, |_|{}), Err(PersistenceError::FieldNotAllowed { .. })));
+        }
+    }
+
+    #[test]
+    fn test_backfill_in_transaction() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "notes");
+        let adapter: &dyn PersistenceAdapter<i64, String, NoteSpec> = &persistence;
+        adapter.initialize();
+        for (id, text) in [(1, "a"), (2, "bb"), (3, "ccc")] {
+            assert!(adapter.store(&id, &text.to_string()).is_ok());
+        }
+        db_connection.execute("ALTER TABLE notes ADD COLUMN length INTEGER").expect("Failed to add column");
+
+        // the filled batches are part of the transaction and go with it
+        let length = |text: &String|PersistenceData::Integer(text.len() as i64);
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert_eq!(persistence.backfill::<i64, String, NoteSpec>("length", length, 2, |_|{}).ok(), Some(3));
+        assert!(transaction.rollback().is_ok());
+        assert_eq!(persistence.backfill::<i64, String, NoteSpec>("length", length, 2, |_|{}).ok(), Some(3));
+    }
+}
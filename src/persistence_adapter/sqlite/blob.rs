@@ -0,0 +1,246 @@
+use std::{ffi::{CStr, CString}, fs::File, io::{self, Read}, os::raw::{c_int, c_void}, ptr, sync::Arc};
+use sqlite_::ConnectionWithFullMutex;
+use sqlite_::State::Row;
+use sqlite3_sys as ffi;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType};
+use super::{checksum::CHECKSUM_COLUMN, quote_identifier, SqlitePersistence};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// an open sqlite3_blob, closed when dropped
+struct BlobHandle(*mut ffi::sqlite3_blob);
+
+impl Drop for BlobHandle {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_blob_close(self.0) };
+    }
+}
+
+fn error_message(code: c_int) -> String {
+    unsafe { CStr::from_ptr(ffi::sqlite3_errstr(code)) }.to_string_lossy().into_owned()
+}
+
+enum BlobSource {
+    Table {
+        _connection: Arc<ConnectionWithFullMutex>, // the blob handle is only valid while the connection is open
+        blob: BlobHandle,
+        offset: usize
+    },
+    External(File)
+}
+
+// Reads a Bytes field straight from the database file, or from its file in the ExternalBlobStore,
+// see SqlitePersistence::load_blob_stream. Reads from the table fail once the row is changed or deleted
+pub struct BlobReader {
+    source: BlobSource,
+    length: usize
+}
+
+impl BlobReader {
+    pub fn len(&self) -> u64 {
+        self.length as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.source {
+            BlobSource::Table { blob, offset, .. } => {
+                let n = buf.len().min(self.length - *offset);
+                if n == 0 {
+                    return Ok(0);
+                }
+                let code = unsafe { ffi::sqlite3_blob_read(blob.0, buf.as_mut_ptr() as *mut c_void, n as c_int, *offset as c_int) };
+                if code != ffi::SQLITE_OK {
+                    return Err(io::Error::other(error_message(code)));
+                }
+                *offset += n;
+                Ok(n)
+            },
+            BlobSource::External(file) => file.read(buf)
+        }
+    }
+}
+
+impl SqlitePersistence {
+    // Writes length bytes from reader into a Bytes field of an existing row without holding the whole value
+    // in memory. Returns the number of rows written to, 0 if there is no row with this key. If reader fails
+    // or doesn't produce exactly length bytes an error is returned and the row is left as it was
+    pub fn store_blob_stream<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, field: &str, mut reader: impl Read, length: u64) -> Result<u64, PersistenceError> {
+        Self::check_bytes_field::<Key, Data, Spec>(field)?;
+        let length = usize::try_from(length).ok().filter(|l|*l <= c_int::MAX as usize)
+            .ok_or_else(||PersistenceError::Backend { message: format!("Blob of {length} bytes is too large") })?;
+
+        // the checksum would need the whole value in memory, streamed rows are left unverified instead
+        let clear_checksum = if self.checksums { format!(", \"{CHECKSUM_COLUMN}\" = NULL") } else { String::new() };
+        let savepoint = self.savepoint("store_blob_stream")?;
+        let Some(rowid) = self.blob_rowid::<Key, Data, Spec>(
+            format!("UPDATE {} SET {} = zeroblob(?){clear_checksum} WHERE {} = ?{} RETURNING rowid", quote_identifier(&self.table_name), quote_identifier(field), quote_identifier(Spec::key_field()), self.and_row_scope()),
+            key, Some(length as i64)
+        )? else {
+            return Ok(0);
+        };
+
+        let blob = self.open_blob(field, rowid, true)?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let n = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(self.backend_error(e))
+            };
+            if offset + n > length {
+                return Err(self.backend_error(format!("Reader produced more than {length} bytes")));
+            }
+            let code = unsafe { ffi::sqlite3_blob_write(blob.0, buffer.as_ptr() as *const c_void, n as c_int, offset as c_int) };
+            if code != ffi::SQLITE_OK {
+                return Err(self.backend_error(error_message(code)));
+            }
+            offset += n;
+        }
+        if offset != length {
+            return Err(self.backend_error(format!("Reader produced {offset} of {length} bytes")));
+        }
+
+        let code = unsafe { ffi::sqlite3_blob_close(blob.0) };
+        std::mem::forget(blob);
+        if code != ffi::SQLITE_OK {
+            return Err(self.backend_error(error_message(code)));
+        }
+        savepoint.release()?;
+        Ok(1)
+    }
+
+    // Opens a Bytes field for reading in chunks, None if there is no row with this key
+    pub fn load_blob_stream<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, field: &str) -> Result<Option<BlobReader>, PersistenceError> {
+        Self::check_bytes_field::<Key, Data, Spec>(field)?;
+
+        if let Some(reference) = self.external_blob_reference::<Key, Data, Spec>(key, field)? {
+            if let Some(path) = self.external_blob_path(&reference) {
+                let file = path.and_then(File::open).map_err(|e|self.backend_error(e))?;
+                let length = file.metadata().map_err(|e|self.backend_error(e))?.len() as usize;
+                return Ok(Some(BlobReader { source: BlobSource::External(file), length }));
+            }
+        }
+
+        let Some(rowid) = self.blob_rowid::<Key, Data, Spec>(format!("SELECT rowid FROM {} WHERE {} = ?{}", quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope()), key, None)? else {
+            return Ok(None);
+        };
+
+        let blob = self.open_blob(field, rowid, false)?;
+        let length = unsafe { ffi::sqlite3_blob_bytes(blob.0) } as usize;
+        Ok(Some(BlobReader { source: BlobSource::Table { _connection: self.connection.0.clone(), blob, offset: 0 }, length }))
+    }
+
+    fn check_bytes_field<Key, Data, Spec: PersistenceSpec<Key, Data>>(field: &str) -> Result<(), PersistenceError> {
+        match Spec::fields().iter().find(|f|f.get_name() == field) {
+            // sensitive fields hold ciphertext, which only the whole-value paths encrypt and decrypt
+            Some(PersistenceType::Bytes(name)) if *name != Spec::key_field() && !Spec::sensitive_fields().contains(name) => Ok(()),
+            _ => Err(PersistenceError::FieldNotAllowed { field: field.to_string() })
+        }
+    }
+
+    // runs a statement that returns the rowid of the key's row, if there is one, to completion
+    fn blob_rowid<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, command: String, key: &Key, length: Option<i64>) -> Result<Option<i64>, PersistenceError> {
+        let values = length.map(PersistenceData::Integer).into_iter().chain([Spec::serialize_key(key)]).collect::<Vec<_>>();
+        let _timer = self.time_statement(&command, &values);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+
+        let mut rowid = None;
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            rowid = Some(statement.read::<i64, usize>(0).map_err(|e|self.backend_error(e))?);
+        }
+        Ok(rowid)
+    }
+
+    fn open_blob(&self, field: &str, rowid: i64, write: bool) -> Result<BlobHandle, PersistenceError> {
+        let table = CString::new(self.table_name.as_str()).map_err(|e|self.backend_error(e))?;
+        let column = CString::new(field).map_err(|e|self.backend_error(e))?;
+        let mut blob = ptr::null_mut();
+        let code = unsafe { ffi::sqlite3_blob_open(self.connection.as_raw(), c"main".as_ptr(), table.as_ptr(), column.as_ptr(), rowid, write as c_int, &mut blob) };
+        if code != ffi::SQLITE_OK {
+            return Err(self.backend_error(error_message(code)));
+        }
+        Ok(BlobHandle(blob))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Read;
+    use rand::{rng, Rng};
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceError};
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+    #[cfg(feature = "encryption")]
+    use {std::{collections::HashMap, sync::Arc}, crate::persistence_adapter::{PersistenceData, PersistenceSpec, PersistenceType, SpecError}, crate::persistence_adapter::sqlite::{SqlitePersistence, StaticKey}, crate::tests::sqlite_connection};
+
+    // AllSupportedTypesPersistenceSpec with bytes encrypted
+    #[cfg(feature = "encryption")]
+    struct SensitiveSpec;
+    #[cfg(feature = "encryption")]
+    impl PersistenceSpec<String, AllSupportedTypes> for SensitiveSpec {
+        fn fields() -> &'static [PersistenceType] { AllSupportedTypesPersistenceSpec::fields() }
+        fn key_field() -> &'static str { AllSupportedTypesPersistenceSpec::key_field() }
+        fn serialize_key(key: &String) -> PersistenceData { AllSupportedTypesPersistenceSpec::serialize_key(key) }
+        fn deserialize_key(key: &PersistenceData) -> Option<String> { AllSupportedTypesPersistenceSpec::deserialize_key(key) }
+        fn serialize_data(data: &AllSupportedTypes) -> Result<HashMap<&'static str, PersistenceData>, SpecError> { AllSupportedTypesPersistenceSpec::serialize_data(data) }
+        fn deserialize_data(data: HashMap<&'static str, PersistenceData>) -> Result<AllSupportedTypes, SpecError> { AllSupportedTypesPersistenceSpec::deserialize_data(data) }
+        fn sensitive_fields() -> &'static [&'static str] { &["bytes"] }
+    }
+
+    #[test]
+    fn test_blob_stream() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+
+        let entry = AllSupportedTypes { bytes: Vec::new(), ..AllSupportedTypes::with_integer(-1) };
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+
+        let payload = rng().random_iter::<u8>().take(300 * 1024 + 7).collect::<Vec<_>>();
+        let stored = persistence.store_blob_stream::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"a".to_string(), "bytes", &payload[..], payload.len() as u64);
+        assert_eq!(stored.ok(), Some(1));
+
+        let mut reader = persistence.load_blob_stream::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"a".to_string(), "bytes")
+            .expect("Failed to open blob").expect("Row should exist");
+        assert_eq!(reader.len(), payload.len() as u64);
+        let mut read_back = Vec::new();
+        assert!(reader.read_to_end(&mut read_back).is_ok());
+        assert_eq!(read_back, payload);
+        assert_eq!(adapter.load(&"a".to_string()).map(|a|a.bytes), Some(payload.clone()));
+
+        assert!(persistence.store_blob_stream::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"a".to_string(), "bytes", &payload[..10], 11).is_err());
+        assert!(persistence.store_blob_stream::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"a".to_string(), "bytes", &payload[..], 10).is_err());
+        assert_eq!(adapter.load(&"a".to_string()).map(|a|a.bytes), Some(payload.clone()));
+        assert_eq!(persistence.store_blob_stream::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"missing".to_string(), "bytes", &payload[..], payload.len() as u64).ok(), Some(0));
+        assert!(persistence.load_blob_stream::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"missing".to_string(), "bytes").is_ok_and(|r|r.is_none()));
+        assert!(matches!(persistence.load_blob_stream::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"a".to_string(), "string"), Err(PersistenceError::FieldNotAllowed { .. })));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_blob_stream_sensitive_field() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection, "test_table").with_encryption(Arc::new(StaticKey::new([7; 32])));
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, SensitiveSpec> = &persistence;
+        adapter.initialize();
+
+        let entry = AllSupportedTypes::with_integer(-1);
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+
+        // streaming would write plaintext over the ciphertext and read the ciphertext back
+        assert!(matches!(persistence.store_blob_stream::<String, AllSupportedTypes, SensitiveSpec>(&"a".to_string(), "bytes", &[4, 5, 6][..], 3), Err(PersistenceError::FieldNotAllowed { .. })));
+        assert!(matches!(persistence.load_blob_stream::<String, AllSupportedTypes, SensitiveSpec>(&"a".to_string(), "bytes"), Err(PersistenceError::FieldNotAllowed { .. })));
+        assert_eq!(adapter.load(&"a".to_string()), Some(entry));
+    }
+}
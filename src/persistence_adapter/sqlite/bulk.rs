@@ -0,0 +1,174 @@
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, PersistenceSpec};
+use super::{ConflictPolicy, SqlitePersistence};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BulkOptions {
+    pub batch_size: usize, // rows per savepoint, committed together outside a transaction
+    pub conflict: ConflictPolicy, // for rows whose key is already in the table, Abort reports them as errors
+    pub max_errors: Option<usize> // stop once this many rows failed, None to load everything that can be loaded
+}
+
+impl Default for BulkOptions {
+    fn default() -> Self {
+        BulkOptions { batch_size: 1_000, conflict: ConflictPolicy::Abort, max_errors: None }
+    }
+}
+
+// A row bulk_load couldn't write
+#[derive(Debug, Clone)]
+pub struct BulkError {
+    pub index: usize, // position in the rows given
+    pub key: PersistenceData,
+    pub message: String
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BulkReport {
+    pub inserted: u64, // rows written, replaced ones included
+    pub skipped: u64, // rows left out under ConflictPolicy::Skip
+    pub errors: Vec<BulkError>
+}
+
+impl SqlitePersistence {
+    // Stores rows batch_size at a time, each batch in one savepoint, for ETL jobs that shouldn't stop at
+    // one bad record. A row that fails, e.g. because it doesn't serialize or breaks a constraint, is
+    // reported in errors and the load goes on with the next one, the batch's other rows are kept. Only
+    // failing to start or commit a batch ends the load with an error, earlier batches stay in the table.
+    // Inside a transaction the batches become part of it rather than being committed one by one.
+    // Stopping at max_errors keeps the rows loaded so far, report.errors' last index says where it stopped
+    pub fn bulk_load<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, rows: impl IntoIterator<Item = (Key, Data)>, options: BulkOptions) -> Result<BulkReport, PersistenceError> {
+        let mut report = BulkReport::default();
+        let mut rows = rows.into_iter().enumerate().peekable();
+        while rows.peek().is_some() {
+            let savepoint = self.savepoint("bulk_load")?;
+            for (index, (key, data)) in rows.by_ref().take(options.batch_size.max(1)) {
+                let error = match self.insert::<Key, Data, Spec>(&key, &data, None, ConflictPolicy::Abort) {
+                    Ok(()) => {
+                        report.inserted += 1;
+                        continue;
+                    },
+                    Err(e) => e
+                };
+                // only a key that's already taken is a conflict, any other failure is the row's own error
+                let conflict = matches!(error, PersistenceError::UniqueViolation { .. }) && PersistenceAdapter::<Key, Data, Spec>::contains(self, &key);
+                let message = match options.conflict {
+                    ConflictPolicy::Skip if conflict => {
+                        report.skipped += 1;
+                        continue;
+                    },
+                    ConflictPolicy::Replace if conflict => match PersistenceAdapter::<Key, Data, Spec>::update(self, &key, &data, None) {
+                        Ok(_) => {
+                            report.inserted += 1;
+                            continue;
+                        },
+                        Err(e) => e.message
+                    },
+                    _ => error.to_string()
+                };
+                report.errors.push(BulkError { index, key: Spec::serialize_key(&key), message });
+                if options.max_errors.is_some_and(|max|report.errors.len() >= max) {
+                    savepoint.release()?;
+                    return Ok(report);
+                }
+            }
+            savepoint.release()?;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceError};
+    use crate::persistence_adapter::sqlite::{BulkOptions, BulkReport, ConflictPolicy, SqlitePersistence};
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    fn rows(keys: &[&str], integer: i64) -> Vec<(String, AllSupportedTypes)> {
+        keys.iter().map(|key|(key.to_string(), AllSupportedTypes::with_integer(integer))).collect()
+    }
+
+    fn bulk_load(persistence: &SqlitePersistence, rows: Vec<(String, AllSupportedTypes)>, options: BulkOptions) -> Result<BulkReport, PersistenceError> {
+        persistence.bulk_load::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(rows, options)
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        let row = AllSupportedTypes::with_integer;
+        assert!(adapter.store(&"003".to_string(), &row(-1)).is_ok());
+        let input = ||(0..10).map(|i|(format!("{i:03}"), row(i))).chain([("005".to_string(), row(-5))]).collect::<Vec<_>>();
+        let load = |options|bulk_load(&persistence, input(), options).expect("Failed to load");
+
+        // existing keys and the duplicate in the input fail on their own, the rest go in
+        let report = load(BulkOptions { batch_size: 4, ..BulkOptions::default() });
+        assert_eq!((report.inserted, report.skipped), (9, 0));
+        assert_eq!(report.errors.iter().map(|e|(e.index, e.key.to_str())).collect::<Vec<_>>(), vec![(3, Some("003")), (10, Some("005"))]);
+        assert!(report.errors.iter().all(|e|e.message.starts_with("UniqueViolation")));
+        assert_eq!(adapter.load(&"003".to_string()).map(|r|r.integer), Some(-1));
+        assert_eq!(adapter.scan(0, None).len(), 10);
+
+        let report = load(BulkOptions { conflict: ConflictPolicy::Skip, ..BulkOptions::default() });
+        assert_eq!((report.inserted, report.skipped, report.errors.len()), (0, 11, 0));
+
+        let report = load(BulkOptions { conflict: ConflictPolicy::Replace, ..BulkOptions::default() });
+        assert_eq!((report.inserted, report.skipped, report.errors.len()), (11, 0, 0));
+        assert_eq!(adapter.load(&"003".to_string()).map(|r|r.integer), Some(3));
+        assert_eq!(adapter.load(&"005".to_string()).map(|r|r.integer), Some(-5));
+
+        assert!(adapter.clear().is_ok());
+        assert!(adapter.store(&"001".to_string(), &row(-1)).is_ok());
+        let report = load(BulkOptions { max_errors: Some(1), ..BulkOptions::default() });
+        assert_eq!((report.inserted, report.errors.len()), (1, 1));
+        assert_eq!(adapter.scan(0, None).len(), 2);
+    }
+
+    #[test]
+    fn test_bulk_load_other_unique_index() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        assert!(persistence.connection.execute("CREATE UNIQUE INDEX test_table_integer ON test_table (integer)").is_ok());
+        assert!(bulk_load(&persistence, rows(&["a"], 1), BulkOptions::default()).is_ok_and(|report|report.inserted == 1));
+
+        // a new key whose row breaks another unique index is an error under every policy, not a conflict
+        for conflict in [ConflictPolicy::Abort, ConflictPolicy::Skip, ConflictPolicy::Replace] {
+            let report = bulk_load(&persistence, rows(&["b"], 1), BulkOptions { conflict, ..BulkOptions::default() }).expect("Failed to load");
+            assert_eq!((report.inserted, report.skipped), (0, 0));
+            assert_eq!(report.errors.iter().map(|e|(e.index, e.key.to_str())).collect::<Vec<_>>(), vec![(0, Some("b"))]);
+        }
+        assert!(!PersistenceAdapter::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::contains(&persistence, &"b".to_string()));
+    }
+
+    #[test]
+    fn test_bulk_load_read_only() {
+        let (temp_dir, persistence) = sqlite_persistence();
+        assert!(bulk_load(&persistence, rows(&["a"], 1), BulkOptions::default()).is_ok_and(|report|report.inserted == 1));
+        let url = format!("sqlite://{}?mode=ro", temp_dir.path().join("test.sqlite").to_str().expect("Tempdir isn't UTF-8").replace(' ', "%20"));
+        let read_only = SqlitePersistence::from_url(&url, "test_table").expect("Failed to open");
+
+        // a write the database refuses isn't taken for a conflict because the key happens to exist
+        for conflict in [ConflictPolicy::Skip, ConflictPolicy::Replace] {
+            let report = bulk_load(&read_only, rows(&["a", "b"], 2), BulkOptions { conflict, ..BulkOptions::default() }).expect("Failed to load");
+            assert_eq!((report.inserted, report.skipped), (0, 0));
+            assert!(report.errors.iter().all(|e|e.message.starts_with("ReadOnly")));
+            assert_eq!(report.errors.len(), 2);
+        }
+
+        let report = bulk_load(&read_only, rows(&["b", "c", "d"], 2), BulkOptions { max_errors: Some(2), ..BulkOptions::default() }).expect("Failed to load");
+        assert_eq!(report.errors.iter().map(|e|e.index).collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_bulk_load_in_transaction() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+
+        let transaction = persistence.transaction().expect("Failed to begin");
+        let report = bulk_load(&persistence, rows(&["a", "b", "c"], 1), BulkOptions { batch_size: 2, ..BulkOptions::default() });
+        assert!(report.is_ok_and(|report|report.inserted == 3));
+        assert_eq!(adapter.scan(0, None).len(), 3);
+
+        // the batches are part of the transaction and go with it
+        assert!(transaction.rollback().is_ok());
+        assert!(adapter.scan(0, None).is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use debug_ignore::DebugIgnore;
+use sqlite_::{ConnectionWithFullMutex, Type};
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError};
+use super::quote_identifier;
+
+// The latest change to a row: its key serialized as it's stored and whether the row was deleted
+#[derive(Debug, Clone)]
+pub struct FeedEntry {
+    pub rowversion: i64,
+    pub key: PersistenceData,
+    pub deleted: bool
+}
+
+// A change feed for a table written through SqlitePersistence, or anything else, that consumers poll
+// instead of being notified. Triggers on the table keep one entry per key in "{table}_feed" with a
+// rowversion taken from an AUTOINCREMENT sequence, bumped on every insert, update and delete. Rowversions
+// are never reused and, with sqlite's single writer, become visible in order, so a consumer that remembers
+// the last rowversion it handled sees every key changed since, at its latest state. Load the rows for
+// the keys that weren't deleted. Entries are per key only, a tenant scoped table shares them across tenants
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    connection: DebugIgnore<Arc<ConnectionWithFullMutex>>,
+    table_name: String,
+    key_field: String
+}
+
+impl ChangeFeed {
+    pub fn new(connection: Arc<ConnectionWithFullMutex>, table_name: &str, key_field: &str) -> Self {
+        ChangeFeed { connection: DebugIgnore(connection), table_name: table_name.to_string(), key_field: key_field.to_string() }
+    }
+
+    // Creates the feed table and the triggers, the watched table must exist. Rows already in it only show
+    // up once they change
+    pub fn initialize(&self) -> Result<(), PersistenceError> {
+        let (table, feed, key) = (quote_identifier(&self.table_name), quote_identifier(&self.feed_table()), quote_identifier(&self.key_field));
+        self.connection.execute(format!("CREATE TABLE IF NOT EXISTS {feed} (rowversion INTEGER PRIMARY KEY AUTOINCREMENT, key UNIQUE NOT NULL, deleted INTEGER NOT NULL)"))?;
+        for (event, row, deleted) in [("INSERT", "NEW", 0), ("UPDATE", "NEW", 0), ("DELETE", "OLD", 1)] {
+            self.connection.execute(format!(
+                "CREATE TRIGGER IF NOT EXISTS {} AFTER {event} ON {table} BEGIN INSERT OR REPLACE INTO {feed} (key, deleted) VALUES ({row}.{key}, {deleted}); END",
+                quote_identifier(&format!("{}_{}", self.feed_table(), event.to_lowercase()))
+            ))?;
+        }
+        Ok(())
+    }
+
+    // the changes after cursor, oldest first. Start from 0 and pass the last rowversion handled
+    pub fn poll_since(&self, cursor: i64, limit: usize) -> Result<Vec<FeedEntry>, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("SELECT rowversion, key, deleted FROM {} WHERE rowversion > ? ORDER BY rowversion LIMIT ?", quote_identifier(&self.feed_table())))?;
+        statement.bind((1, cursor))?;
+        statement.bind((2, limit as i64))?;
+        let mut entries = Vec::new();
+        while statement.next()? == Row {
+            let key = match statement.column_type(1)? {
+                Type::Integer => PersistenceData::Integer(statement.read(1)?),
+                Type::Float => PersistenceData::Double(statement.read(1)?),
+                Type::Binary => PersistenceData::Bytes(statement.read(1)?),
+                _ => PersistenceData::String(statement.read(1)?)
+            };
+            entries.push(FeedEntry { rowversion: statement.read(0)?, key, deleted: statement.read::<i64, usize>(2)? != 0 });
+        }
+        Ok(entries)
+    }
+
+    // the rowversion of the latest change, 0 before any
+    pub fn head(&self) -> Result<i64, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("SELECT coalesce(max(rowversion), 0) FROM {}", quote_identifier(&self.feed_table())))?;
+        statement.next()?;
+        Ok(statement.read(0)?)
+    }
+
+    // Drops the deletions up to rowversion once every consumer is past it, returns the number dropped
+    pub fn compact(&self, up_to: i64) -> Result<u64, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("DELETE FROM {} WHERE deleted = 1 AND rowversion <= ? RETURNING 1", quote_identifier(&self.feed_table())))?;
+        statement.bind((1, up_to))?;
+        let mut dropped = 0;
+        while statement.next()? == Row {
+            dropped += 1;
+        }
+        Ok(dropped)
+    }
+
+    fn feed_table(&self) -> String {
+        format!("{}_feed", self.table_name)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::sqlite::{ChangeFeed, SqlitePersistence};
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_change_feed() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        let feed = ChangeFeed::new(db_connection, "test_table", "key");
+        feed.initialize().expect("Failed to initialize");
+        assert_eq!(feed.head().ok(), Some(0));
+
+        let row = AllSupportedTypes::with_integer(1);
+        for key in ["a", "b", "c"] {
+            assert!(adapter.store(&key.to_string(), &row).is_ok());
+        }
+        let changes = feed.poll_since(0, 2).expect("Failed to poll");
+        assert_eq!(changes.iter().map(|c|(c.rowversion, c.key.to_str().unwrap_or_default())).collect::<Vec<_>>(), vec![(1, "a"), (2, "b")]);
+
+        // a key changed again moves past the cursor, once
+        assert!(adapter.update(&"a".to_string(), &row, None).is_ok());
+        assert!(adapter.delete(&"b".to_string()).is_ok());
+        let changes = feed.poll_since(2, 10).expect("Failed to poll");
+        assert_eq!(changes.iter().map(|c|(c.rowversion, c.key.to_str().unwrap_or_default(), c.deleted)).collect::<Vec<_>>(), vec![(3, "c", false), (4, "a", false), (5, "b", true)]);
+        assert_eq!(feed.head().ok(), Some(5));
+        assert_eq!(feed.compact(5).ok(), Some(1));
+        assert_eq!(feed.compact(5).ok(), Some(0));
+        assert!(feed.poll_since(5, 10).is_ok_and(|changes|changes.is_empty()));
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType};
+use super::{quote_identifier, to_hex, SqlitePersistence};
+
+pub(super) const CHECKSUM_COLUMN: &str = "_checksum";
+
+// SHA-256 over every spec field in spec order, each as its name, a type tag and its length prefixed value.
+// Computed over the values before external blobs are split off, so it also covers the blob files
+pub(super) fn row_checksum<'a>(spec_types: &[PersistenceType], value_of: impl Fn(&str) -> Option<&'a PersistenceData>) -> String {
+    let mut hasher = Sha256::new();
+    for field in spec_types {
+        hasher.update(field.get_name().as_bytes());
+        let (tag, bytes) = match value_of(field.get_name()) {
+            None => (0u8, Vec::new()),
+            Some(PersistenceData::String(s)) => (1, s.as_bytes().to_vec()),
+            Some(PersistenceData::Bytes(b)) => (2, b.clone()),
+            Some(PersistenceData::Integer(i)) => (3, i.to_le_bytes().to_vec()),
+            Some(PersistenceData::UnsignedInteger(u)) => (4, u.to_le_bytes().to_vec()),
+            Some(PersistenceData::Float(f)) => (5, f.to_le_bytes().to_vec()),
+            Some(PersistenceData::Double(d)) => (6, d.to_le_bytes().to_vec()),
+            #[cfg(feature = "decimal")]
+            Some(PersistenceData::Decimal(d)) => (7, super::decimal::encode(d).into_bytes()),
+        };
+        hasher.update([0, tag]);
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    }
+    to_hex(&hasher.finalize())
+}
+
+impl SqlitePersistence {
+    // Keeps a checksum of every row in a _checksum column, added by initialize. Rows are verified whenever
+    // they're read and fail with PersistenceError::Corrupted on a mismatch. Rows without a checksum, written
+    // before checksums were enabled or through store_blob_stream, aren't verified
+    pub fn with_checksums(mut self) -> Self {
+        self.checksums = true;
+        self
+    }
+
+    // Reads every row and returns the keys of the ones whose checksum doesn't match
+    pub fn verify_all<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> Result<Vec<PersistenceData>, PersistenceError> {
+        let command = format!("SELECT * FROM {}{} ORDER BY {}", quote_identifier(&self.table_name), self.where_row_scope(), quote_identifier(Spec::key_field()));
+        let _timer = self.time_statement(&command, []);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        let mut corrupted = Vec::new();
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            match self.collect_fields::<Key, Data, Spec>(&statement) {
+                Ok(_) => {},
+                Err(PersistenceError::Corrupted { key }) => corrupted.push(key),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(corrupted)
+    }
+
+    pub(super) fn verify_checksum<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, fields: &HashMap<&'static str, PersistenceData>, stored: Option<String>) -> Result<(), PersistenceError> {
+        match stored {
+            Some(stored) if stored != row_checksum(Spec::fields(), |name|fields.get(name)) => {
+                let key = fields.get(Spec::key_field()).cloned().unwrap_or(PersistenceData::Bytes(Vec::new()));
+                Err(self.record_corruption(key))
+            },
+            _ => Ok(())
+        }
+    }
+
+    // recomputes the checksum of a row after a write that only changed some of its fields
+    pub(super) fn refresh_checksum<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, serialized_key: &PersistenceData) -> Result<(), PersistenceError> {
+        if !self.checksums {
+            return Ok(());
+        }
+        let command = format!("SELECT * FROM {} WHERE {} = ?{}", quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope());
+        let timer = self.time_statement(&command, [serialized_key]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        SqlitePersistence::bind_data(&mut statement, 1, serialized_key).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        if statement.next().map_err(|e|self.backend_error(e))? != Row {
+            return Ok(());
+        }
+        let (mut fields, _, _) = self.read_columns(Spec::fields(), Spec::sensitive_fields(), &statement)?;
+        self.decrypt_fields::<Key, Data, Spec>(&mut fields)?;
+        let checksum = row_checksum(Spec::fields(), |name|fields.get(name));
+        drop((statement, timer));
+
+        let command = format!("UPDATE {} SET \"{CHECKSUM_COLUMN}\" = ? WHERE {} = ?{}", quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope());
+        let _timer = self.time_statement(&command, [&PersistenceData::String(checksum.clone()), serialized_key]);
+        let mut update = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        update.bind((1, checksum.as_str())).map_err(|e|self.backend_error(e))?;
+        SqlitePersistence::bind_data(&mut update, 2, serialized_key).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut update).map_err(|e|self.backend_error(e))?;
+        update.next().map_err(|e|self.backend_error(e))?;
+        Ok(())
+    }
+
+    fn record_corruption(&self, key: PersistenceData) -> PersistenceError {
+        self.record_error(format!("Checksum mismatch for {key:?}"));
+        PersistenceError::Corrupted { key }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_checksums() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table").with_checksums();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+
+        let entry = AllSupportedTypes { float: 1.5, ..AllSupportedTypes::with_integer(-1) };
+        for key in ["a", "b", "c"] {
+            assert!(adapter.store(&key.to_string(), &entry).is_ok());
+        }
+        assert!(adapter.update(&"b".to_string(), &AllSupportedTypes { integer: 2, ..entry.clone() }, Some(&["integer"])).is_ok());
+        assert!(adapter.patch(&"c".to_string(), HashMap::from([("string", PersistenceData::String("patched".to_string()))])).is_ok());
+        assert!(persistence.verify_all::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>().is_ok_and(|corrupted|corrupted.is_empty()));
+        assert_eq!(adapter.load(&"b".to_string()).map(|b|b.integer), Some(2));
+
+        // written behind the adapter's back, the checksum no longer matches
+        assert!(db_connection.execute("UPDATE \"test_table\" SET integer = 5 WHERE key = 'a'").is_ok());
+        assert!(adapter.load(&"a".to_string()).is_none());
+        assert_eq!(adapter.scan(0, None).len(), 2);
+        let corrupted = persistence.verify_all::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>().expect("Failed to verify");
+        assert_eq!(format!("{corrupted:?}"), format!("{:?}", vec![PersistenceData::String("a".to_string())]));
+    }
+
+    #[test]
+    fn test_checksum_written_with_row() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table").with_checksums();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+
+        // the row is written, then refreshing its checksum fails: the row must keep its old values and checksum
+        assert!(db_connection.execute("CREATE TRIGGER no_checksum BEFORE UPDATE OF _checksum ON test_table BEGIN SELECT RAISE(ABORT, 'refused'); END").is_ok());
+        assert!(adapter.update(&"a".to_string(), &AllSupportedTypes { integer: 2, ..entry.clone() }, None).is_err());
+        assert!(adapter.patch(&"a".to_string(), HashMap::from([("integer", PersistenceData::Integer(3))])).is_err());
+        assert_eq!(adapter.load(&"a".to_string()), Some(entry.clone()));
+
+        // inside a transaction only the failed write is undone
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert!(adapter.store(&"b".to_string(), &entry).is_ok());
+        assert!(adapter.update(&"a".to_string(), &AllSupportedTypes { integer: 2, ..entry.clone() }, None).is_err());
+        assert!(transaction.commit().is_ok());
+        assert_eq!(adapter.load(&"a".to_string()), Some(entry.clone()));
+        assert_eq!(adapter.load(&"b".to_string()), Some(entry));
+    }
+
+    #[test]
+    fn test_checksums_existing_table() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let plain = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let unchecked: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &plain;
+        unchecked.initialize();
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(unchecked.store(&"old".to_string(), &entry).is_ok());
+
+        // initialize adds the _checksum column to the existing table
+        let persistence = SqlitePersistence::new(db_connection, "test_table").with_checksums();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        assert!(adapter.initialize().is_some());
+        assert!(adapter.store(&"new".to_string(), &entry).is_ok());
+        assert!(adapter.patch(&"old".to_string(), HashMap::from([("integer", PersistenceData::Integer(2))])).is_ok());
+
+        // rows written before checksums were enabled stay readable
+        assert_eq!(adapter.load(&"old".to_string()).map(|r|r.integer), Some(2));
+        assert_eq!(adapter.load(&"new".to_string()), Some(entry));
+        assert!(persistence.verify_all::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>().is_ok_and(|corrupted|corrupted.is_empty()));
+    }
+}
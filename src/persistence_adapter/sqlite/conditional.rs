@@ -0,0 +1,114 @@
+use sqlite_::State::{Done, Row};
+use crate::persistence_adapter::{CasOutcome, PersistenceAdapter, PersistenceAdapterConditional, PersistenceError, PersistenceSpec, PersistenceType, Query, SpecError};
+use super::{intersperse, quote_identifier, SqlitePersistence};
+
+impl SqlitePersistence {
+    // Replaces key's row with data only if the stored row matches condition, e.g. Equals("version", 3) for
+    // optimistic concurrency. The comparison and the write are one UPDATE statement, so no other write can
+    // come in between. Conditions on sensitive or hashed fields compare against the stored ciphertext or hash
+    pub fn store_if<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, data: &Data, condition: Query) -> Result<CasOutcome, PersistenceError> {
+        condition.validate_fields(&Spec::fields().iter().map(PersistenceType::get_name).collect::<Vec<_>>())?;
+        let mut serialized = Spec::serialize_data(data)?;
+        self.hash_fields(Spec::fields(), Spec::key_field(), &mut serialized)?;
+        self.encrypt_fields::<Key, Data, Spec>(&mut serialized)?;
+        self.externalize_blobs(Spec::fields(), Spec::key_field(), &mut serialized)?;
+        let fields = Spec::fields().iter().map(PersistenceType::get_name).filter(|name|*name != Spec::key_field()).collect::<Vec<_>>();
+
+        // values are bound positionally: the SET list, the key, then the condition's
+        let mut values = fields.iter().map(|name|serialized.remove(name).ok_or_else(||SpecError::missing(name))).collect::<Result<Vec<_>, _>>()?;
+        let serialized_key = Spec::serialize_key(key);
+        values.push(serialized_key.clone());
+        let (filter, _, values) = SqlitePersistence::generate_filter(&condition, values.len(), values);
+
+        let mut command = format!("UPDATE {} SET ", quote_identifier(&self.table_name));
+        intersperse(fields.iter().map(|name|format!("{} = ?", quote_identifier(name))), ", ".to_string()).for_each(|s|command.push_str(&s));
+        command.push_str(&self.set_version::<Key, Data, Spec>());
+        command.push_str(&format!(" WHERE {} = ? AND {filter}{} RETURNING 1", quote_identifier(Spec::key_field()), self.and_row_scope()));
+
+        let savepoint = self.checksums.then(||self.savepoint("store_if")).transpose()?;
+        let _timer = self.time_statement(&command, &values);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        let stored = statement.next().map_err(|e|self.backend_error(e))? == Row;
+        drop(statement);
+        if stored {
+            self.refresh_checksum::<Key, Data, Spec>(&serialized_key)?;
+        }
+        if let Some(savepoint) = savepoint {
+            savepoint.release()?;
+        }
+        if stored {
+            return Ok(CasOutcome::Stored);
+        }
+        match PersistenceAdapter::<Key, Data, Spec>::contains(self, key) {
+            true => Ok(CasOutcome::ConditionFailed),
+            false => Ok(CasOutcome::Missing)
+        }
+    }
+
+    // Deletes key's row only if it matches condition, in one DELETE statement. Returns the number of deleted rows
+    pub fn delete_if<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, condition: Query) -> Result<u64, PersistenceError> {
+        condition.validate_fields(&Spec::fields().iter().map(PersistenceType::get_name).collect::<Vec<_>>())?;
+        let (filter, _, values) = SqlitePersistence::generate_filter(&condition, 1, vec![Spec::serialize_key(key)]);
+        let command = format!("DELETE FROM {} WHERE {} = ? AND {filter}{} RETURNING 1", quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope());
+
+        let _timer = self.time_statement(&command, &values);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        match statement.next().map_err(|e|self.backend_error(e))? {
+            Row => Ok(1),
+            Done => Ok(0)
+        }
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>> PersistenceAdapterConditional<Key, Data, Spec> for SqlitePersistence {
+    fn store_if(&self, key: &Key, data: &Data, condition: Query) -> Result<CasOutcome, PersistenceError> {
+        SqlitePersistence::store_if::<Key, Data, Spec>(self, key, data, condition)
+    }
+
+    fn delete_if(&self, key: &Key, condition: Query) -> Result<u64, PersistenceError> {
+        SqlitePersistence::delete_if::<Key, Data, Spec>(self, key, condition)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, Query};
+    use crate::persistence_adapter::sqlite::{CasOutcome, SqlitePersistence};
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_store_if() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection, "test_table").with_checksums();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        let key = "a".to_string();
+        let row = AllSupportedTypes::with_integer;
+        assert!(adapter.store(&key, &row(1)).is_ok());
+
+        let store_if = |integer, expected|persistence.store_if::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&key, &row(integer), Query::Equals("integer".to_string(), PersistenceData::Integer(expected)));
+        assert_eq!(store_if(2, 1).ok(), Some(CasOutcome::Stored));
+        assert_eq!(store_if(3, 1).ok(), Some(CasOutcome::ConditionFailed));
+        assert_eq!(adapter.load(&key), Some(row(2)));
+
+        let missing = persistence.store_if::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"b".to_string(), &row(1), Query::LessThan("integer".to_string(), PersistenceData::Integer(10)));
+        assert_eq!(missing.ok(), Some(CasOutcome::Missing));
+        let unknown = persistence.store_if::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&key, &row(1), Query::Equals("nope".to_string(), PersistenceData::Integer(2)));
+        assert!(matches!(unknown, Err(PersistenceError::FieldNotAllowed { .. })));
+
+        let delete_if = |expected|persistence.delete_if::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&key, Query::Equals("integer".to_string(), PersistenceData::Integer(expected)));
+        assert_eq!(delete_if(1).ok(), Some(0));
+        assert!(adapter.contains(&key));
+        assert_eq!(delete_if(2).ok(), Some(1));
+        assert!(!adapter.contains(&key));
+    }
+}
@@ -0,0 +1,62 @@
+use std::str::FromStr;
+use rust_decimal::Decimal;
+
+// Decimals are stored as fixed width TEXT so sqlite's plain string comparison orders them numerically:
+// a sign digit (0 negative, 1 otherwise), 29 integer digits, '.', 28 fraction digits. Negative values store
+// the nines' complement of their digits so larger magnitudes sort first. Trailing zeros aren't kept, 1.50 reads back as 1.5
+const INTEGER_DIGITS: usize = 29;
+const FRACTION_DIGITS: usize = 28;
+
+pub(super) fn encode(value: &Decimal) -> String {
+    let value = value.normalize();
+    let mantissa = value.mantissa().unsigned_abs();
+    let divisor = 10u128.pow(value.scale());
+    let fraction = format!("{:0width$}", mantissa % divisor, width = value.scale() as usize);
+    let digits = format!("{:0INTEGER_DIGITS$}.{fraction:0<FRACTION_DIGITS$}", mantissa / divisor);
+
+    if value.is_sign_negative() && !value.is_zero() {
+        format!("0{}", complement(&digits))
+    } else {
+        format!("1{digits}")
+    }
+}
+
+pub(super) fn decode(encoded: &str) -> Option<Decimal> {
+    let (sign, digits) = encoded.split_at_checked(1)?;
+    let value = match sign {
+        "0" => format!("-{}", complement(digits)),
+        "1" => digits.to_string(),
+        _ => return None
+    };
+    Decimal::from_str(&value).ok().map(|d|d.normalize())
+}
+
+fn complement(digits: &str) -> String {
+    digits.chars().map(|c|match c.to_digit(10) {
+        Some(d) => char::from_digit(9 - d, 10).unwrap_or(c),
+        None => c
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests{
+    use std::str::FromStr;
+    use rust_decimal::Decimal;
+    use super::{decode, encode};
+
+    #[test]
+    fn test_decimal_encoding_order() {
+        let mut values = ["-79228162514264337593543950335", "-12.5", "-1.05", "-1", "-0.0000000000000000000000000001", "0", "0.1", "1.50", "2", "10", "79228162514264337593543950335"]
+            .map(|v|Decimal::from_str(v).expect("Invalid decimal"));
+
+        for value in &values {
+            assert_eq!(decode(&encode(value)), Some(*value));
+        }
+        assert_eq!(encode(&Decimal::from_str("-0").expect("Invalid decimal")), encode(&Decimal::ZERO));
+
+        let mut encoded = values.iter().map(encode).collect::<Vec<_>>();
+        encoded.sort();
+        values.sort();
+        assert_eq!(encoded, values.iter().map(encode).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,311 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+use itertools::intersperse;
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, SpecError};
+use super::{quote_identifier, SqlitePersistence};
+
+// first byte of every encrypted value, so the format can change later
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+
+// Where the key for Spec::sensitive_fields comes from, e.g. a secrets manager or a KMS data key
+pub trait KeyProvider: Debug + Send + Sync {
+    fn key(&self) -> [u8; 32]; // an AES-256 key
+}
+
+// a key held in memory, Debug doesn't print it
+#[derive(Clone)]
+pub struct StaticKey([u8; 32]);
+
+impl StaticKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        StaticKey(key)
+    }
+}
+
+impl Debug for StaticKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StaticKey(..)")
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn key(&self) -> [u8; 32] {
+        self.0
+    }
+}
+
+// the plaintext of a value, the spec field's type says how to read it back
+fn encode(value: &PersistenceData) -> Vec<u8> {
+    match value {
+        PersistenceData::String(s) => s.as_bytes().to_vec(),
+        PersistenceData::Bytes(b) => b.clone(),
+        PersistenceData::Integer(i) => i.to_le_bytes().to_vec(),
+        PersistenceData::UnsignedInteger(u) => u.to_le_bytes().to_vec(),
+        PersistenceData::Float(f) => f.to_le_bytes().to_vec(),
+        PersistenceData::Double(d) => d.to_le_bytes().to_vec(),
+        #[cfg(feature = "decimal")]
+        PersistenceData::Decimal(d) => super::decimal::encode(d).into_bytes()
+    }
+}
+
+fn decode(field: &PersistenceType, bytes: Vec<u8>) -> Option<PersistenceData> {
+    Some(match field {
+        PersistenceType::String(_) => PersistenceData::String(String::from_utf8(bytes).ok()?),
+        PersistenceType::Bytes(_) => PersistenceData::Bytes(bytes),
+        PersistenceType::Integer(_) => PersistenceData::Integer(i64::from_le_bytes(bytes.try_into().ok()?)),
+        PersistenceType::UnsignedInteger(_) => PersistenceData::UnsignedInteger(u64::from_le_bytes(bytes.try_into().ok()?)),
+        PersistenceType::Float(_) => PersistenceData::Float(f32::from_le_bytes(bytes.try_into().ok()?)),
+        PersistenceType::Double(_) => PersistenceData::Double(f64::from_le_bytes(bytes.try_into().ok()?)),
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => PersistenceData::Decimal(super::decimal::decode(&String::from_utf8(bytes).ok()?)?),
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => PersistenceData::String(String::from_utf8(bytes).ok()?)
+    })
+}
+
+// AES-256-GCM with a random nonce: version, nonce, then the ciphertext and tag. The field name is the
+// associated data, so a value copied into another encrypted column fails to decrypt
+pub(super) fn encrypt(provider: &dyn KeyProvider, field: &str, value: &PersistenceData) -> Result<Vec<u8>, String> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    getrandom::fill(&mut nonce).map_err(|e|e.to_string())?;
+    let cipher = Aes256Gcm::new(&provider.key().into());
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: &encode(value), aad: field.as_bytes() }).map_err(|_|"encryption failed".to_string())?;
+    let mut out = Vec::with_capacity(1 + NONCE_LENGTH + ciphertext.len());
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&nonce);
+    out.extend(ciphertext);
+    Ok(out)
+}
+
+// None if the value wasn't encrypted with this provider's key for this field, or was tampered with
+pub(super) fn decrypt(provider: &dyn KeyProvider, field: &PersistenceType, encrypted: &[u8]) -> Option<PersistenceData> {
+    let (&version, rest) = encrypted.split_first()?;
+    if version != FORMAT_VERSION || rest.len() < NONCE_LENGTH {
+        return None;
+    }
+    let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+    let cipher = Aes256Gcm::new(&provider.key().into());
+    let plaintext = cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: field.get_name().as_bytes() }).ok()?;
+    decode(field, plaintext)
+}
+
+impl SqlitePersistence {
+    // Encrypts the values of Spec::sensitive_fields with the provider's key before they're written and
+    // decrypts them when read. The other fields stay plaintext and queryable. Checksums cover the plaintext
+    pub fn with_encryption(mut self, key_provider: Arc<dyn KeyProvider>) -> Self {
+        self.key_provider = Some(key_provider);
+        self
+    }
+
+    pub(super) fn encrypt_fields<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), PersistenceError> {
+        if let Some(field) = Spec::sensitive_fields().iter().find(|f|**f == Spec::key_field()) {
+            return Err(PersistenceError::FieldNotAllowed { field: field.to_string() });
+        }
+        for field in Spec::sensitive_fields() {
+            let Some(provider) = &self.key_provider else {
+                return Err(SpecError::new(field, "is sensitive but no key provider is set, see with_encryption").into());
+            };
+            if let Some(value) = data.get(field) {
+                let encrypted = encrypt(provider.as_ref(), field, value).map_err(|e|self.backend_error(e))?;
+                data.insert(field, PersistenceData::Bytes(encrypted));
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn decrypt_fields<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), PersistenceError> {
+        for field in Spec::fields().iter().filter(|f|Spec::sensitive_fields().contains(&f.get_name())) {
+            let Some(PersistenceData::Bytes(encrypted)) = data.get(field.get_name()) else {
+                continue;
+            };
+            let provider = self.key_provider.as_ref().ok_or_else(||SpecError::new(field.get_name(), "is encrypted but no key provider is set, see with_encryption"))?;
+            let value = decrypt(provider.as_ref(), field, encrypted).ok_or_else(||SpecError::new(field.get_name(), "failed to decrypt, wrong key or tampered value"))?;
+            data.insert(field.get_name(), value);
+        }
+        Ok(())
+    }
+
+    // Re-encrypts the sensitive fields of every row (of this tenant, with with_tenant) from old's key to new's,
+    // batch_size rows per savepoint, committed on their own outside a transaction, calling progress with the
+    // number of rows looked at after each batch. Values already under new's key are skipped, so an interrupted
+    // rotation resumes by running it again. Rows are under either key until it finishes, switch readers to new
+    // with with_encryption afterwards.
+    // Returns the number of rows re-encrypted
+    pub fn rotate_keys<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, old: &dyn KeyProvider, new: &dyn KeyProvider, batch_size: usize, mut progress: impl FnMut(u64)) -> Result<u64, PersistenceError> {
+        let key_type = Spec::fields().iter().find(|f|f.get_name() == Spec::key_field()).ok_or_else(||SpecError::missing(Spec::key_field()))?;
+        let sensitive = Spec::fields().iter().filter(|f|Spec::sensitive_fields().contains(&f.get_name())).collect::<Vec<_>>();
+        if sensitive.is_empty() {
+            return Ok(0);
+        }
+        let columns = intersperse(sensitive.iter().map(|f|quote_identifier(f.get_name())), ", ".to_string()).collect::<String>();
+        let key_field = Spec::key_field();
+        let (table, quoted_key) = (quote_identifier(&self.table_name), quote_identifier(key_field));
+        let batch_size = batch_size.max(1);
+
+        let mut last_key: Option<PersistenceData> = None;
+        let mut seen = 0;
+        let mut rotated = 0;
+        loop {
+            let savepoint = self.savepoint("rotate_keys")?;
+            let after = match last_key {
+                Some(_) => format!(" AND {quoted_key} > :after"),
+                None => String::new()
+            };
+            let command = format!("SELECT {quoted_key}, {columns} FROM {table} WHERE 1{after}{} ORDER BY {quoted_key} LIMIT {batch_size}", self.and_row_scope());
+            let timer = self.time_statement(&command, last_key.as_slice());
+            let mut select = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+            if let Some(last_key) = &last_key {
+                SqlitePersistence::bind_data(&mut select, ":after", last_key).map_err(|e|self.backend_error(e))?;
+            }
+            self.bind_tenant(&mut select).map_err(|e|self.backend_error(e))?;
+
+            let mut batch = Vec::new();
+            let mut batch_rows = 0;
+            while select.next().map_err(|e|self.backend_error(e))? == Row {
+                let key = SqlitePersistence::read_field(key_type, &select, key_field)?;
+                let mut changes = HashMap::new();
+                for field in &sensitive {
+                    let encrypted = match select.column_type(field.get_name()).map_err(|e|self.backend_error(e))? {
+                        sqlite_::Type::Binary => select.read::<Vec<u8>, &str>(field.get_name()).map_err(|e|self.backend_error(e))?,
+                        // a reference to an external blob
+                        sqlite_::Type::String => {
+                            let reference = select.read::<String, &str>(field.get_name()).map_err(|e|self.backend_error(e))?;
+                            self.read_external_blob(&reference).ok_or_else(||self.backend_error(format!("{} of {key:?} is text, not an encrypted value", field.get_name())))?.map_err(|e|self.backend_error(e))?
+                        },
+                        _ => continue
+                    };
+                    if decrypt(new, field, &encrypted).is_some() {
+                        continue;
+                    }
+                    let value = decrypt(old, field, &encrypted).ok_or_else(||SpecError::new(field.get_name(), &format!("of {key:?} can't be decrypted with either key")))?;
+                    changes.insert(field.get_name(), PersistenceData::Bytes(encrypt(new, field.get_name(), &value).map_err(|e|self.backend_error(e))?));
+                }
+                if !changes.is_empty() {
+                    batch.push((key.clone(), changes));
+                }
+                last_key = Some(key);
+                batch_rows += 1;
+            }
+            drop((select, timer));
+
+            for (key, mut changes) in batch {
+                self.externalize_blobs(Spec::fields(), key_field, &mut changes)?;
+                let changes = changes.into_iter().collect::<Vec<_>>();
+                let set = intersperse(changes.iter().map(|(name, _)|format!("{} = ?", quote_identifier(name))), ", ".to_string()).collect::<String>();
+                let command = format!("UPDATE {table} SET {set} WHERE {quoted_key} = ?{}", self.and_row_scope());
+                let _timer = self.time_statement(&command, changes.iter().map(|(_, value)|value).chain([&key]));
+                let mut update = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+                for (i, (_, value)) in changes.iter().enumerate() {
+                    SqlitePersistence::bind_data(&mut update, i + 1, value).map_err(|e|self.backend_error(e))?;
+                }
+                SqlitePersistence::bind_data(&mut update, changes.len() + 1, &key).map_err(|e|self.backend_error(e))?;
+                self.bind_tenant(&mut update).map_err(|e|self.backend_error(e))?;
+                update.next().map_err(|e|self.backend_error(e))?;
+                rotated += 1;
+            }
+            savepoint.release()?;
+            seen += batch_rows as u64;
+            progress(seen);
+            if batch_rows < batch_size {
+                return Ok(rotated);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{collections::HashMap, sync::Arc};
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, PersistenceSpec, PersistenceType, Query, SpecError};
+    use crate::persistence_adapter::sqlite::{SqlitePersistence, StaticKey};
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    // AllSupportedTypesPersistenceSpec with string and integer encrypted
+    struct SensitiveSpec;
+    impl PersistenceSpec<String, AllSupportedTypes> for SensitiveSpec {
+        fn fields() -> &'static [PersistenceType] { AllSupportedTypesPersistenceSpec::fields() }
+        fn key_field() -> &'static str { AllSupportedTypesPersistenceSpec::key_field() }
+        fn serialize_key(key: &String) -> PersistenceData { AllSupportedTypesPersistenceSpec::serialize_key(key) }
+        fn deserialize_key(key: &PersistenceData) -> Option<String> { AllSupportedTypesPersistenceSpec::deserialize_key(key) }
+        fn serialize_data(data: &AllSupportedTypes) -> Result<HashMap<&'static str, PersistenceData>, SpecError> { AllSupportedTypesPersistenceSpec::serialize_data(data) }
+        fn deserialize_data(data: HashMap<&'static str, PersistenceData>) -> Result<AllSupportedTypes, SpecError> { AllSupportedTypesPersistenceSpec::deserialize_data(data) }
+        fn sensitive_fields() -> &'static [&'static str] { &["string", "integer"] }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table").with_checksums().with_encryption(Arc::new(StaticKey::new([7; 32])));
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, SensitiveSpec> = &persistence;
+        adapter.initialize();
+        let entry = AllSupportedTypes { string: "secret".to_string(), ..AllSupportedTypes::with_integer(42) };
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+        assert_eq!(adapter.load(&"a".to_string()), Some(entry.clone()));
+        assert!(adapter.patch(&"a".to_string(), HashMap::from([("integer", PersistenceData::Integer(43))])).is_ok_and(|n|n == 1));
+        assert_eq!(adapter.load(&"a".to_string()).map(|e|e.integer), Some(43));
+
+        // stored as ciphertext, the other fields stay queryable
+        let raw = persistence.raw_rows(None, None).expect("Failed to read rows");
+        assert!(raw[0].iter().any(|(name, value)|name == "string" && matches!(value, Some(PersistenceData::Bytes(b)) if !b.windows(6).any(|w|w == b"secret"))));
+        let queryable: &dyn PersistenceAdapterQueryable<String, AllSupportedTypes, SensitiveSpec> = &persistence;
+        assert_eq!(queryable.query(Query::Equals("unsigned_integer".to_string(), PersistenceData::UnsignedInteger(1)), 0, None).len(), 1);
+
+        let wrong_key = SqlitePersistence::new(db_connection.clone(), "test_table").with_encryption(Arc::new(StaticKey::new([8; 32])));
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, SensitiveSpec>::load(&wrong_key, &"a".to_string()).is_none());
+        let no_key = SqlitePersistence::new(db_connection, "test_table");
+        assert!(PersistenceAdapter::<String, AllSupportedTypes, SensitiveSpec>::store(&no_key, &"b".to_string(), &entry).is_err());
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let (old_key, new_key) = (StaticKey::new([1; 32]), StaticKey::new([2; 32]));
+        let old = SqlitePersistence::new(db_connection.clone(), "test_table").with_checksums().with_encryption(Arc::new(old_key.clone()));
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, SensitiveSpec> = &old;
+        adapter.initialize();
+        let entry = AllSupportedTypes { string: "secret".to_string(), ..AllSupportedTypes::with_integer(42) };
+        for i in 0..5 {
+            assert!(adapter.store(&format!("key{i}"), &AllSupportedTypes { integer: i, ..entry.clone() }).is_ok());
+        }
+
+        let mut reported = Vec::new();
+        let rotated = old.rotate_keys::<String, AllSupportedTypes, SensitiveSpec>(&old_key, &new_key, 2, |n|reported.push(n));
+        assert_eq!(rotated.ok(), Some(5));
+        assert_eq!(reported, vec![2, 4, 5]);
+
+        let new = SqlitePersistence::new(db_connection, "test_table").with_checksums().with_encryption(Arc::new(new_key.clone()));
+        let rows = PersistenceAdapter::<String, AllSupportedTypes, SensitiveSpec>::scan(&new, 0, None);
+        assert_eq!(rows.iter().map(|(_, e)|e.integer).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        assert!(adapter.load(&"key0".to_string()).is_none());
+
+        // rows written with the old key after a rotation started are picked up by running it again
+        assert!(adapter.store(&"key5".to_string(), &entry).is_ok());
+        assert_eq!(old.rotate_keys::<String, AllSupportedTypes, SensitiveSpec>(&old_key, &new_key, 2, |_|{}).ok(), Some(1));
+        assert_eq!(PersistenceAdapter::<String, AllSupportedTypes, SensitiveSpec>::load(&new, &"key5".to_string()), Some(entry));
+        assert!(old.rotate_keys::<String, AllSupportedTypes, SensitiveSpec>(&StaticKey::new([3; 32]), &StaticKey::new([4; 32]), 2, |_|{}).is_err());
+    }
+
+    #[test]
+    fn test_rotate_keys_in_transaction() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let (old_key, new_key) = (StaticKey::new([1; 32]), StaticKey::new([2; 32]));
+        let old = SqlitePersistence::new(db_connection, "test_table").with_encryption(Arc::new(old_key.clone()));
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, SensitiveSpec> = &old;
+        adapter.initialize();
+        let entry = AllSupportedTypes { string: "secret".to_string(), ..AllSupportedTypes::with_integer(42) };
+        for i in 0..3 {
+            assert!(adapter.store(&format!("key{i}"), &entry).is_ok());
+        }
+
+        // the rotated batches are part of the transaction and go with it
+        let transaction = old.transaction().expect("Failed to begin");
+        assert_eq!(old.rotate_keys::<String, AllSupportedTypes, SensitiveSpec>(&old_key, &new_key, 2, |_|{}).ok(), Some(3));
+        assert!(transaction.rollback().is_ok());
+        assert_eq!(adapter.load(&"key0".to_string()), Some(entry));
+    }
+}
@@ -0,0 +1,253 @@
+use std::{collections::{HashMap, HashSet}, fmt::Write, fs, io, path::PathBuf, process, sync::atomic::{AtomicU64, Ordering}, time::{Duration, SystemTime}};
+use sha2::{Digest, Sha256};
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType};
+use super::{quote_identifier, to_hex, SqlitePersistence};
+
+const REFERENCE_PREFIX: &str = "sha256:";
+
+static TEMPORARY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Where SqlitePersistence::with_external_blobs keeps large Bytes values. Every table gets its own
+// subdirectory, files are named after the SHA-256 of their content so equal values are stored once
+#[derive(Debug, Clone)]
+pub struct ExternalBlobStore {
+    directory: PathBuf,
+    threshold: usize
+}
+
+impl ExternalBlobStore {
+    // Bytes values longer than threshold bytes go to directory, shorter ones stay in the table
+    pub fn new(directory: impl Into<PathBuf>, threshold: usize) -> Self {
+        ExternalBlobStore { directory: directory.into(), threshold }
+    }
+
+    // table names are percent-encoded down to letters, digits, _ and - so no name can leave the directory
+    fn table_directory(&self, table_name: &str) -> PathBuf {
+        let name = table_name.bytes().fold(String::new(), |mut name, b|{
+            if b.is_ascii_alphanumeric() || b == b'_' || b == b'-' {
+                name.push(b as char);
+            } else {
+                let _ = write!(name, "%{b:02X}");
+            }
+            name
+        });
+        self.directory.join(name)
+    }
+
+    // returns the reference to keep in the table, now is the adapter's time
+    fn write(&self, table_name: &str, bytes: &[u8], now: SystemTime) -> io::Result<String> {
+        let hash = to_hex(&Sha256::digest(bytes));
+        let directory = self.table_directory(table_name);
+        let path = directory.join(&hash);
+        // an existing file gets a fresh mtime so collect_external_blobs' min_age covers this store too, if
+        // it was collected meanwhile it's written again
+        let touched = match fs::File::options().append(true).open(&path) {
+            Ok(file) => file.set_modified(now).map(|_|true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e)
+        }?;
+        if !touched {
+            fs::create_dir_all(&directory)?;
+            // written under a temporary name first so a crash never leaves a truncated blob under its hash
+            let temporary = directory.join(format!("{hash}.tmp-{}-{}", process::id(), TEMPORARY_COUNTER.fetch_add(1, Ordering::Relaxed)));
+            fs::write(&temporary, bytes)?;
+            fs::rename(&temporary, &path)?;
+        }
+        Ok(format!("{REFERENCE_PREFIX}{hash}"))
+    }
+
+    fn path(&self, table_name: &str, reference: &str) -> io::Result<PathBuf> {
+        match reference.strip_prefix(REFERENCE_PREFIX) {
+            Some(hash) if hash.len() == 64 && hash.chars().all(|c|c.is_ascii_hexdigit()) => Ok(self.table_directory(table_name).join(hash)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid blob reference {reference}")))
+        }
+    }
+}
+
+impl SqlitePersistence {
+    // Stores Bytes fields above the store's threshold as files, keeping a reference in the table that is
+    // resolved again on load. Values streamed with store_blob_stream always stay in the table
+    pub fn with_external_blobs(mut self, store: ExternalBlobStore) -> Self {
+        self.external_blobs = Some(store);
+        self
+    }
+
+    // Deletes blob files no row refers to anymore. Files younger than min_age by the adapter's clock are
+    // kept, they may belong to a store that hasn't inserted its row yet. Returns the number of deleted files
+    pub fn collect_external_blobs<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, min_age: Duration) -> Result<u64, PersistenceError> {
+        let Some(store) = &self.external_blobs else {
+            return Ok(0);
+        };
+
+        // blob files are shared by every tenant of the table, so this looks at all rows regardless of with_tenant
+        let mut referenced = HashSet::new();
+        for field in Spec::fields().iter().filter(|f|matches!(f, PersistenceType::Bytes(_)) && f.get_name() != Spec::key_field()) {
+            let command = format!("SELECT {0} FROM {1} WHERE typeof({0}) = 'text'", quote_identifier(field.get_name()), quote_identifier(&self.table_name));
+            let _timer = self.time_statement(&command, []);
+            let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+            while statement.next().map_err(|e|self.backend_error(e))? == Row {
+                let reference = statement.read::<String, usize>(0).map_err(|e|self.backend_error(e))?;
+                // text that isn't a reference refers to no file, it's no reason to keep every file either
+                if let Ok(path) = store.path(&self.table_name, &reference) {
+                    referenced.insert(path);
+                }
+            }
+        }
+
+        let entries = match fs::read_dir(store.table_directory(&self.table_name)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(self.backend_error(e))
+        };
+        let now = self.clock.now();
+        let mut deleted = 0;
+        for entry in entries {
+            let path = entry.map_err(|e|self.backend_error(e))?.path();
+            let age = fs::metadata(&path).and_then(|m|m.modified()).ok().and_then(|m|now.duration_since(m).ok()).unwrap_or_default();
+            if !referenced.contains(&path) && age >= min_age {
+                fs::remove_file(&path).map_err(|e|self.backend_error(e))?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    // replaces large Bytes values with references to files written to the external store
+    pub(super) fn externalize_blobs(&self, spec_types: &'static [PersistenceType], key_field: &str, data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), PersistenceError> {
+        let Some(store) = &self.external_blobs else {
+            return Ok(());
+        };
+        for field in spec_types.iter().filter(|f|matches!(f, PersistenceType::Bytes(_)) && f.get_name() != key_field) {
+            if let Some(PersistenceData::Bytes(bytes)) = data.get(field.get_name()) {
+                if bytes.len() > store.threshold {
+                    let reference = store.write(&self.table_name, bytes, self.clock.now()).map_err(|e|self.backend_error(e))?;
+                    data.insert(field.get_name(), PersistenceData::String(reference));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn external_blob_path(&self, reference: &str) -> Option<io::Result<PathBuf>> {
+        self.external_blobs.as_ref().map(|store|store.path(&self.table_name, reference))
+    }
+
+    pub(super) fn read_external_blob(&self, reference: &str) -> Option<io::Result<Vec<u8>>> {
+        self.external_blob_path(reference).map(|path|path.and_then(fs::read))
+    }
+
+    // the reference stored in field for key, if the value lives in the external store
+    pub(super) fn external_blob_reference<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, field: &str) -> Result<Option<String>, PersistenceError> {
+        if self.external_blobs.is_none() {
+            return Ok(None);
+        }
+        let command = format!("SELECT {0} FROM {1} WHERE {2} = ? AND typeof({0}) = 'text'{3}", quote_identifier(field), quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope());
+        let serialized_key = Spec::serialize_key(key);
+        let _timer = self.time_statement(&command, [&serialized_key]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        SqlitePersistence::bind_data(&mut statement, 1, &serialized_key).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        match statement.next().map_err(|e|self.backend_error(e))? {
+            Row => Ok(Some(statement.read::<String, usize>(0).map_err(|e|self.backend_error(e))?)),
+            _ => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{fs, io::Read, sync::Arc, time::{Duration, SystemTime}};
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::sqlite::{ExternalBlobStore, SqlitePersistence};
+    use crate::tests::{sqlite_connection, sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_external_blobs() {
+        let (temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection, "test_table").with_external_blobs(ExternalBlobStore::new(temp_dir.path().join("blobs"), 16));
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+
+        let large = AllSupportedTypes { string: "large".to_string(), bytes: vec![7; 1024], ..AllSupportedTypes::with_integer(-1) };
+        let small = AllSupportedTypes{ string: "small".to_string(), bytes: vec![1, 2, 3], ..large.clone() };
+
+        assert!(adapter.store(&"large".to_string(), &large).is_ok());
+        assert!(adapter.store(&"copy".to_string(), &large).is_ok());
+        assert!(adapter.store(&"small".to_string(), &small).is_ok());
+        let blob_count = ||std::fs::read_dir(temp_dir.path().join("blobs").join("test_table")).map(|d|d.count()).unwrap_or(0);
+        assert_eq!(blob_count(), 1);
+
+        assert_eq!(adapter.load(&"large".to_string()), Some(large.clone()));
+        assert_eq!(adapter.load(&"small".to_string()), Some(small.clone()));
+        assert_eq!(adapter.scan(0, None).len(), 3);
+
+        let mut streamed = Vec::new();
+        let mut reader = persistence.load_blob_stream::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&"large".to_string(), "bytes").expect("Failed to open blob").expect("Row should exist");
+        assert!(reader.read_to_end(&mut streamed).is_ok());
+        assert_eq!(streamed, large.bytes);
+
+        assert!(adapter.delete(&"large".to_string()).is_ok());
+        assert_eq!(persistence.collect_external_blobs::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Duration::ZERO).ok(), Some(0));
+        assert!(adapter.update(&"copy".to_string(), &small, None).is_ok());
+        assert_eq!(persistence.collect_external_blobs::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Duration::from_secs(3600)).ok(), Some(0));
+        assert_eq!(persistence.collect_external_blobs::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Duration::ZERO).ok(), Some(1));
+        assert_eq!(blob_count(), 0);
+    }
+
+    #[test]
+    fn test_external_blob_files() {
+        let (temp_dir, db_connection) = sqlite_connection();
+
+        let store = ExternalBlobStore::new(temp_dir.path().join("blobs"), 16);
+        assert!(store.write("../escaped", &[1; 32], SystemTime::now()).is_ok());
+        assert!(!temp_dir.path().join("escaped").exists());
+        assert!(temp_dir.path().join("blobs").join("%2E%2E%2Fescaped").is_dir());
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table").with_external_blobs(store);
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+
+        let large = AllSupportedTypes { string: "large".to_string(), bytes: vec![7; 1024], ..AllSupportedTypes::with_integer(-1) };
+        assert!(adapter.store(&"a".to_string(), &large).is_ok());
+        let file = fs::read_dir(temp_dir.path().join("blobs").join("test_table")).expect("Failed to list blobs").next().expect("Should have a blob").expect("Failed to read entry").path();
+
+        // storing the same value again makes the file young again
+        let old = SystemTime::now() - Duration::from_secs(7200);
+        assert!(fs::File::options().append(true).open(&file).and_then(|f|f.set_modified(old)).is_ok());
+        assert!(adapter.store(&"b".to_string(), &large).is_ok());
+        assert!(fs::metadata(&file).and_then(|m|m.modified()).is_ok_and(|m|m > old + Duration::from_secs(3600)));
+        assert!(adapter.delete(&"a".to_string()).is_ok());
+        assert!(adapter.delete(&"b".to_string()).is_ok());
+        assert_eq!(persistence.collect_external_blobs::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Duration::from_secs(3600)).ok(), Some(0));
+
+        // text in a Bytes column that isn't a reference doesn't stop the collection
+        let small = AllSupportedTypes{ bytes: vec![1], ..large.clone() };
+        assert!(adapter.store(&"c".to_string(), &small).is_ok());
+        assert!(db_connection.execute("UPDATE \"test_table\" SET \"bytes\" = 'not a reference'").is_ok());
+        assert_eq!(persistence.collect_external_blobs::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Duration::ZERO).ok(), Some(1));
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn test_external_blob_clock() {
+        let (temp_dir, persistence) = sqlite_persistence();
+        let clock = Arc::new(ManualClock::new(SystemTime::now() - Duration::from_secs(7200)));
+        let persistence = persistence.with_external_blobs(ExternalBlobStore::new(temp_dir.path().join("blobs"), 16)).with_clock(clock.clone());
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+
+        let large = AllSupportedTypes { bytes: vec![7; 1024], ..AllSupportedTypes::with_integer(1) };
+        assert!(adapter.store(&"a".to_string(), &large).is_ok());
+        assert!(adapter.store(&"b".to_string(), &large).is_ok());
+        assert!(adapter.delete(&"a".to_string()).is_ok());
+        assert!(adapter.delete(&"b".to_string()).is_ok());
+
+        // the file was touched at the clock's time, an hour later by the clock it's still young
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(persistence.collect_external_blobs::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Duration::from_secs(5400)).ok(), Some(0));
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(persistence.collect_external_blobs::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Duration::from_secs(5400)).ok(), Some(1));
+    }
+}
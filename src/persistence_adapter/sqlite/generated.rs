@@ -0,0 +1,112 @@
+use sqlite_::State::Row;
+use itertools::intersperse;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, SpecError};
+use super::{quote_identifier, SqlitePersistence};
+
+impl SqlitePersistence {
+    // Inserts data under a key picked by sqlite and returns the key. The key field must be an Integer, which
+    // makes it an alias of the rowid: new keys are one more than the largest key in the table, so the keys of
+    // deleted rows at the end can be handed out again. Not available with with_tenant, the key isn't the rowid there
+    pub fn store_generated<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, data: &Data) -> Result<Key, PersistenceError> {
+        if !matches!(Spec::fields().iter().find(|f|f.get_name() == Spec::key_field()), Some(PersistenceType::Integer(_))) {
+            return Err(SpecError::new(Spec::key_field(), "must be an Integer for generated keys").into());
+        }
+        if self.tenant.is_some() {
+            return Err(self.backend_error("Generated keys aren't available for tenant scoped tables"));
+        }
+
+        let mut serialized = Spec::serialize_data(data)?;
+        self.hash_fields(Spec::fields(), Spec::key_field(), &mut serialized)?;
+        self.encrypt_fields::<Key, Data, Spec>(&mut serialized)?;
+        self.externalize_blobs(Spec::fields(), Spec::key_field(), &mut serialized)?;
+        let fields = Spec::fields().iter().map(PersistenceType::get_name).filter(|name|*name != Spec::key_field()).collect::<Vec<_>>();
+        let values = fields.iter().map(|name|serialized.get(name).ok_or_else(||SpecError::missing(name))).collect::<Result<Vec<_>, _>>()?;
+
+        let mut command = format!("INSERT INTO {} (", quote_identifier(&self.table_name));
+        intersperse(fields.iter().map(|name|quote_identifier(name)), ", ".to_string()).for_each(|s|command.push_str(&s));
+        command.push_str(&self.version_column());
+        command.push_str(") VALUES (");
+        intersperse(fields.iter().map(|_|"?"), ", ").for_each(|s|command.push_str(s));
+        command.push_str(&self.version_value::<Key, Data, Spec>());
+        command.push_str(&format!(") RETURNING {}", quote_identifier(Spec::key_field())));
+
+        let savepoint = self.checksums.then(||self.savepoint("store_generated")).transpose()?;
+        let _timer = self.time_statement(&command, values.iter().copied());
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        let key = match statement.next().map_err(|e|self.backend_error(e))? {
+            Row => PersistenceData::Integer(statement.read::<i64, usize>(0).map_err(|e|self.backend_error(e))?),
+            _ => return Err(self.backend_error("Insert returned no key"))
+        };
+        drop(statement);
+
+        // the checksum covers the key, which is only known now
+        self.refresh_checksum::<Key, Data, Spec>(&key)?;
+        if let Some(savepoint) = savepoint {
+            savepoint.release()?;
+        }
+        Spec::deserialize_key(&key).ok_or_else(||SpecError::new(Spec::key_field(), "generated key doesn't deserialize").into())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceSpec, PersistenceType, SpecError};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    const NOTE_FIELDS: [PersistenceType; 2] = [
+        PersistenceType::Integer("id"),
+        PersistenceType::String("text")
+    ];
+
+    struct NoteSpec {}
+
+    impl PersistenceSpec<i64, String> for NoteSpec {
+        fn fields() -> &'static [PersistenceType] {
+            &NOTE_FIELDS
+        }
+
+        fn key_field() -> &'static str {
+            "id"
+        }
+
+        fn serialize_key(key: &i64) -> PersistenceData {
+            PersistenceData::Integer(*key)
+        }
+
+        fn deserialize_key(key: &PersistenceData) -> Option<i64> {
+            key.to_int()
+        }
+
+        fn serialize_data(data: &String) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+            Ok(HashMap::from([("text", PersistenceData::String(data.clone()))]))
+        }
+
+        fn deserialize_data(mut data: HashMap<&'static str, PersistenceData>) -> Result<String, SpecError> {
+            data.remove("text").and_then(PersistenceData::into_string).ok_or_else(||SpecError::missing("text"))
+        }
+    }
+
+    #[test]
+    fn test_store_generated() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "notes").with_checksums();
+        let adapter: &dyn PersistenceAdapter<i64, String, NoteSpec> = &persistence;
+        adapter.initialize();
+
+        let first = persistence.store_generated::<i64, String, NoteSpec>(&"first".to_string()).expect("Failed to store");
+        let second = persistence.store_generated::<i64, String, NoteSpec>(&"second".to_string()).expect("Failed to store");
+        assert_eq!(second, first + 1);
+        assert_eq!(adapter.load(&second), Some("second".to_string()));
+
+        // string keys can't be generated by sqlite
+        let strings = SqlitePersistence::new(db_connection, "strings");
+        let entry = AllSupportedTypes{ string: String::new(), bytes: Vec::new(), integer: 0, unsigned_integer: 0, float: 0.0, double: 0.0 };
+        assert!(strings.store_generated::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&entry).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{PasswordHash, SaltString};
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, SpecError};
+use super::{quote_identifier, SqlitePersistence};
+
+fn is_hash(value: &str) -> bool {
+    PasswordHash::new(value).is_ok_and(|hash|hash.algorithm.as_str() == "argon2id")
+}
+
+fn hash(plaintext: &str) -> Result<String, String> {
+    let mut salt = [0u8; 16];
+    getrandom::fill(&mut salt).map_err(|e|e.to_string())?;
+    let salt = SaltString::encode_b64(&salt).map_err(|e|e.to_string())?;
+    Argon2::default().hash_password(plaintext.as_bytes(), &salt).map(|hash|hash.to_string()).map_err(|e|e.to_string())
+}
+
+impl SqlitePersistence {
+    // Replaces the values of Hashed fields with their argon2id hash before they're written. Values that
+    // already are an argon2id hash, e.g. from a row that was loaded and stored back, are kept as they are
+    pub(super) fn hash_fields(&self, spec_types: &'static [PersistenceType], key_field: &str, data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), PersistenceError> {
+        for field in spec_types.iter().filter(|f|matches!(f, PersistenceType::Hashed(_))) {
+            if field.get_name() == key_field {
+                return Err(PersistenceError::FieldNotAllowed { field: key_field.to_string() });
+            }
+            match data.get(field.get_name()) {
+                Some(PersistenceData::String(value)) if is_hash(value) => {},
+                Some(PersistenceData::String(plaintext)) => {
+                    let hashed = hash(plaintext).map_err(|e|self.backend_error(e))?;
+                    data.insert(field.get_name(), PersistenceData::String(hashed));
+                },
+                Some(_) => return Err(SpecError::new(field.get_name(), "Hashed fields must be strings").into()),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Whether candidate matches the hash stored in the Hashed field of key's row. Only the hash is read, false
+    // if there's no such row or field. Errors are recorded as the last error
+    pub fn verify<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, field: &str, candidate: &str) -> bool {
+        let Some(field) = Spec::fields().iter().find(|f|matches!(f, PersistenceType::Hashed(name) if *name == field)) else {
+            return false;
+        };
+        let command = format!("SELECT {} FROM {} WHERE {} = ?{}", quote_identifier(field.get_name()), quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope());
+        let serialized_key = Spec::serialize_key(key);
+        let _timer = self.time_statement(&command, [&serialized_key]);
+        let stored = (||{
+            let mut statement = self.connection.prepare(&command)?;
+            SqlitePersistence::bind_data(&mut statement, 1, &serialized_key)?;
+            self.bind_tenant(&mut statement)?;
+            if statement.next()? != Row {
+                return Ok(None);
+            }
+            Ok(Some(match statement.column_type(0)? {
+                // encrypted, when the field is also sensitive
+                sqlite_::Type::Binary => PersistenceData::Bytes(statement.read(0)?),
+                _ => PersistenceData::String(statement.read(0)?)
+            }))
+        })().map_err(|e: sqlite_::Error|self.backend_error(e));
+        let Ok(Some(stored)) = stored else {
+            return false;
+        };
+
+        let mut fields = HashMap::from([(field.get_name(), stored)]);
+        if let Err(e) = self.decrypt_fields::<Key, Data, Spec>(&mut fields) {
+            self.record_error(e);
+            return false;
+        }
+        match fields.get(field.get_name()).and_then(PersistenceData::to_str).map(PasswordHash::new) {
+            Some(Ok(hash)) => Argon2::default().verify_password(candidate.as_bytes(), &hash).is_ok(),
+            Some(Err(e)) => {
+                self.record_error(e);
+                false
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceSpec, PersistenceType, SpecError};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::sqlite_connection;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Account {
+        name: String,
+        password: String
+    }
+
+    struct AccountSpec;
+    impl PersistenceSpec<String, Account> for AccountSpec {
+        fn fields() -> &'static [PersistenceType] {
+            &[PersistenceType::String("name"), PersistenceType::Hashed("password")]
+        }
+        fn key_field() -> &'static str {
+            "name"
+        }
+        fn serialize_key(key: &String) -> PersistenceData {
+            PersistenceData::String(key.clone())
+        }
+        fn deserialize_key(key: &PersistenceData) -> Option<String> {
+            key.to_str().map(str::to_string)
+        }
+        fn serialize_data(data: &Account) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+            Ok(HashMap::from([("name", PersistenceData::String(data.name.clone())), ("password", PersistenceData::String(data.password.clone()))]))
+        }
+        fn deserialize_data(data: HashMap<&'static str, PersistenceData>) -> Result<Account, SpecError> {
+            let read = |name|data.get(name).and_then(PersistenceData::to_str).map(str::to_string).ok_or_else(||SpecError::missing(name));
+            Ok(Account { name: read("name")?, password: read("password")? })
+        }
+    }
+
+    #[test]
+    fn test_hashed_fields() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection, "accounts").with_checksums();
+        let adapter: &dyn PersistenceAdapter<String, Account, AccountSpec> = &persistence;
+        adapter.initialize();
+        let alice = "alice".to_string();
+        assert!(adapter.store(&alice, &Account { name: alice.clone(), password: "hunter2".to_string() }).is_ok());
+
+        let loaded = adapter.load(&alice).expect("Failed to load");
+        assert!(loaded.password.starts_with("$argon2id$"));
+        assert!(persistence.verify::<String, Account, AccountSpec>(&alice, "password", "hunter2"));
+        assert!(!persistence.verify::<String, Account, AccountSpec>(&alice, "password", "hunter3"));
+        assert!(!persistence.verify::<String, Account, AccountSpec>(&"bob".to_string(), "password", "hunter2"));
+        assert!(!persistence.verify::<String, Account, AccountSpec>(&alice, "name", "alice"));
+
+        // storing the loaded row back keeps the hash instead of hashing it again
+        assert!(adapter.update(&alice, &loaded, None).is_ok_and(|n|n == 1));
+        assert_eq!(adapter.load(&alice), Some(loaded));
+        assert!(persistence.verify::<String, Account, AccountSpec>(&alice, "password", "hunter2"));
+        assert!(adapter.patch(&alice, HashMap::from([("password", PersistenceData::String("correct horse".to_string()))])).is_ok());
+        assert!(persistence.verify::<String, Account, AccountSpec>(&alice, "password", "correct horse"));
+    }
+}
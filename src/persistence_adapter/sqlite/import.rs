@@ -0,0 +1,324 @@
+use std::{collections::HashMap, fs::File, io::{BufRead, BufReader}, path::Path, str::FromStr};
+use itertools::intersperse;
+use sqlite_::Statement;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, SpecError};
+use super::{checksum, quote_identifier, tenant, SqlitePersistence};
+
+const IMPORT_BATCH_SIZE: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    Csv, // comma separated with a header line naming the columns, fields may be quoted with "
+    #[cfg(feature = "serde")]
+    NdJson // one JSON object per line
+}
+
+// what import_file does with a record whose key is already in the table
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ConflictPolicy {
+    #[default]
+    Abort,
+    Skip,
+    Replace
+}
+
+// a value read from the file, before it is coerced to the type of its spec field
+enum RawValue {
+    Text(String),
+    #[cfg(feature = "serde")]
+    Bytes(Vec<u8>),
+    #[cfg(feature = "serde")]
+    Null
+}
+
+#[cfg_attr(not(feature = "serde"), allow(clippy::infallible_destructuring_match))]
+fn coerce(field: &PersistenceType, value: RawValue) -> Result<PersistenceData, String> {
+    fn parse<T: FromStr>(text: &str) -> Result<T, String> {
+        text.trim().parse().map_err(|_|format!("can't parse {text:?}"))
+    }
+    let text = match value {
+        RawValue::Text(text) => text,
+        #[cfg(feature = "serde")]
+        RawValue::Bytes(bytes) if matches!(field, PersistenceType::Bytes(_)) => return Ok(PersistenceData::Bytes(bytes)),
+        #[cfg(feature = "serde")]
+        RawValue::Bytes(_) => return Err("is an array".to_string()),
+        #[cfg(feature = "serde")]
+        RawValue::Null => return Err("is null".to_string())
+    };
+    Ok(match field {
+        PersistenceType::String(_) => PersistenceData::String(text),
+        PersistenceType::Bytes(_) => PersistenceData::Bytes(from_hex(&text).ok_or_else(||format!("{text:?} is not hex"))?),
+        PersistenceType::Integer(_) => PersistenceData::Integer(parse(&text)?),
+        PersistenceType::UnsignedInteger(_) => PersistenceData::UnsignedInteger(parse(&text)?),
+        PersistenceType::Float(_) => PersistenceData::Float(parse(&text)?),
+        PersistenceType::Double(_) => PersistenceData::Double(parse(&text)?),
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => PersistenceData::Decimal(parse(&text)?),
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => PersistenceData::String(text),
+    })
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i|text.get(i..i + 2).and_then(|pair|u8::from_str_radix(pair, 16).ok())).collect()
+}
+
+// Splits one CSV record, reading more lines while a quoted field is still open
+fn read_csv_record(reader: &mut impl BufRead, line: &mut u64) -> Result<Option<Vec<String>>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut text = String::new();
+    loop {
+        text.clear();
+        if reader.read_line(&mut text).map_err(|e|e.to_string())? == 0 {
+            return match quoted {
+                true => Err("unterminated quoted field".to_string()),
+                false if fields.is_empty() && field.is_empty() => Ok(None),
+                false => { fields.push(field); Ok(Some(fields)) }
+            };
+        }
+        *line += 1;
+
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => { chars.next(); field.push('"'); },
+                ('"', true) => quoted = false,
+                ('"', false) if field.is_empty() => quoted = true,
+                (',', false) => fields.push(std::mem::take(&mut field)),
+                ('\n' | '\r', false) => {},
+                (c, _) => field.push(c)
+            }
+        }
+        if !quoted {
+            fields.push(field);
+            return Ok(Some(fields));
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn read_json_record(reader: &mut impl BufRead, line: &mut u64) -> Result<Option<Vec<(String, RawValue)>>, String> {
+    let mut text = String::new();
+    loop {
+        text.clear();
+        if reader.read_line(&mut text).map_err(|e|e.to_string())? == 0 {
+            return Ok(None);
+        }
+        *line += 1;
+        if !text.trim().is_empty() {
+            break;
+        }
+    }
+
+    let serde_json::Value::Object(object) = serde_json::from_str(&text).map_err(|e|e.to_string())? else {
+        return Err("is not a JSON object".to_string());
+    };
+    object.into_iter().map(|(column, value)|{
+        let value = match value {
+            serde_json::Value::Null => RawValue::Null,
+            serde_json::Value::String(s) => RawValue::Text(s),
+            serde_json::Value::Number(n) => RawValue::Text(n.to_string()),
+            serde_json::Value::Bool(b) => RawValue::Text((b as u8).to_string()),
+            serde_json::Value::Array(items) => RawValue::Bytes(items.iter().map(|i|i.as_u64().and_then(|b|u8::try_from(b).ok())).collect::<Option<_>>()
+                .ok_or_else(||format!("{column} is not an array of bytes"))?),
+            serde_json::Value::Object(_) => return Err(format!("{column} is an object"))
+        };
+        Ok((column, value))
+    }).collect::<Result<_, _>>().map(Some)
+}
+
+fn read_record(format: ImportFormat, header: &[String], reader: &mut impl BufRead, line: &mut u64) -> Result<Option<Vec<(String, RawValue)>>, String> {
+    match format {
+        ImportFormat::Csv => Ok(read_csv_record(reader, line)?.map(|fields|header.iter().cloned().zip(fields.into_iter().map(RawValue::Text)).collect())),
+        #[cfg(feature = "serde")]
+        ImportFormat::NdJson => read_json_record(reader, line)
+    }
+}
+
+impl SqlitePersistence {
+    // Streams records from a file into the table. Columns are matched to spec fields by name, or through
+    // mapping from file column to field name, columns that match no field are ignored. Values are coerced
+    // to their field's type, Bytes are read as hex in CSV and as hex or an array of numbers in NDJSON.
+    // Records are inserted in savepoints of 10000, committed one by one outside a transaction, progress is
+    // called with the number of records read after each one. Returns the number of inserted rows. On an error
+    // the current batch is rolled back, earlier batches stay in the table
+    pub fn import_file<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, path: impl AsRef<Path>, format: ImportFormat, conflict: ConflictPolicy, mapping: &HashMap<&str, &str>, mut progress: impl FnMut(u64)) -> Result<u64, PersistenceError> {
+        let mut reader = BufReader::new(File::open(path).map_err(|e|self.backend_error(e))?);
+        let mut line = 0;
+        let line_error = |line: u64, e: String|self.backend_error(format!("line {line}: {e}"));
+
+        let header = match format {
+            ImportFormat::Csv => match read_csv_record(&mut reader, &mut line).map_err(|e|line_error(line, e))? {
+                Some(header) => header,
+                None => return Ok(0)
+            },
+            #[cfg(feature = "serde")]
+            ImportFormat::NdJson => Vec::new()
+        };
+        let command = self.import_command::<Key, Data, Spec>(conflict);
+
+        let mut read = 0;
+        let mut inserted = 0;
+        let mut finished = false;
+        while !finished {
+            let savepoint = self.savepoint("import_file")?;
+            let mut statement = self.connection.prepare(&command).map_err(|e|self.backend_error(e))?;
+            let batch_end = read + IMPORT_BATCH_SIZE;
+            while read < batch_end {
+                let Some(record) = read_record(format, &header, &mut reader, &mut line).map_err(|e|line_error(line, e))? else {
+                    finished = true;
+                    break;
+                };
+                read += 1;
+
+                let mut raw = HashMap::new();
+                for (column, value) in record {
+                    let field = mapping.get(column.as_str()).copied().unwrap_or(column.as_str());
+                    if let Some(field) = Spec::fields().iter().find(|f|f.get_name() == field) {
+                        raw.insert(field.get_name(), value);
+                    }
+                }
+                let mut data = HashMap::new();
+                for field in Spec::fields() {
+                    let value = raw.remove(field.get_name()).ok_or_else(||SpecError::new(field.get_name(), &format!("line {line}: missing")))?;
+                    let value = coerce(field, value).map_err(|e|SpecError::new(field.get_name(), &format!("line {line}: {e}")))?;
+                    data.insert(field.get_name(), value);
+                }
+
+                inserted += self.import_row::<Key, Data, Spec>(&mut statement, &command, data).map_err(|e|match e {
+                    PersistenceError::Backend { message } => line_error(line, message),
+                    e => e
+                })?;
+            }
+            drop(statement);
+            savepoint.release()?;
+            progress(read);
+        }
+        Ok(inserted)
+    }
+
+    // the INSERT import_file and import_parquet run for each record, conflicting rows are handled by the policy
+    pub(super) fn import_command<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, conflict: ConflictPolicy) -> String {
+        let or = match conflict {
+            ConflictPolicy::Abort => "",
+            ConflictPolicy::Skip => " OR IGNORE",
+            ConflictPolicy::Replace => " OR REPLACE"
+        };
+        let mut command = format!("INSERT{or} INTO {} (", quote_identifier(&self.table_name));
+        intersperse(Spec::fields().iter().map(|f|quote_identifier(f.get_name())), ", ".to_string()).for_each(|s|command.push_str(&s));
+        command.push_str(&self.version_column());
+        if self.checksums {
+            command.push_str(&format!(", \"{}\"", checksum::CHECKSUM_COLUMN));
+        }
+        if self.tenant.is_some() {
+            command.push_str(&format!(", \"{}\"", tenant::TENANT_COLUMN));
+        }
+        command.push_str(") VALUES (");
+        intersperse(Spec::fields().iter().map(|_|"?"), ", ").for_each(|s|command.push_str(s));
+        command.push_str(&self.version_value::<Key, Data, Spec>());
+        if self.checksums {
+            command.push_str(", ?");
+        }
+        if self.tenant.is_some() {
+            command.push_str(", :tenant");
+        }
+        // an ignored row returns nothing, so the rows returned are the rows inserted
+        command.push_str(") RETURNING 1");
+        command
+    }
+
+    // writes one record with a statement prepared from import_command, returns 1 if it was inserted
+    // runs statement, prepared from command, for one row
+    pub(super) fn import_row<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, statement: &mut Statement, command: &str, mut data: HashMap<&'static str, PersistenceData>) -> Result<u64, PersistenceError> {
+        self.hash_fields(Spec::fields(), Spec::key_field(), &mut data)?;
+        let checksum = self.checksums.then(||PersistenceData::String(checksum::row_checksum(Spec::fields(), |name|data.get(name))));
+        self.encrypt_fields::<Key, Data, Spec>(&mut data)?;
+        self.externalize_blobs(Spec::fields(), Spec::key_field(), &mut data)?;
+        let _timer = self.time_statement(command, Spec::fields().iter().filter_map(|field|data.get(field.get_name())).chain(&checksum));
+        statement.reset().map_err(|e|self.backend_error(e))?;
+        for (i, field) in Spec::fields().iter().enumerate() {
+            if let Some(value) = data.get(field.get_name()) {
+                SqlitePersistence::bind_data(statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+            }
+        }
+        if let Some(checksum) = &checksum {
+            SqlitePersistence::bind_data(statement, Spec::fields().len() + 1, checksum).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(statement).map_err(|e|self.backend_error(e))?;
+        self.count_returned(statement)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::sqlite::{ConflictPolicy, ImportFormat};
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_import_csv() {
+        let (temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+
+        let csv_path = temp_dir.path().join("import.csv");
+        std::fs::write(&csv_path, "id,string,bytes,integer,unsigned_integer,float,double,ignored\n\
+            a,plain,0102,-1,1,1.5,2.5,x\n\
+            b,\"with, comma and \"\"quotes\"\"\nover two lines\",,2,2,0,0,y\n").expect("Failed to write csv");
+        let mapping = HashMap::from([("id", "key")]);
+        let import = |conflict, progress: &mut Vec<u64>|persistence.import_file::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&csv_path, ImportFormat::Csv, conflict, &mapping, |read|progress.push(read));
+
+        let mut progress = Vec::new();
+        assert_eq!(import(ConflictPolicy::Abort, &mut progress).ok(), Some(2));
+        assert_eq!(progress, vec![2]);
+        assert_eq!(adapter.load(&"a".to_string()), Some(AllSupportedTypes { string: "plain".to_string(), bytes: vec![1, 2], float: 1.5, double: 2.5, ..AllSupportedTypes::with_integer(-1) }));
+        assert_eq!(adapter.load(&"b".to_string()).map(|b|b.string), Some("with, comma and \"quotes\"\nover two lines".to_string()));
+
+        assert!(import(ConflictPolicy::Abort, &mut progress).is_err());
+        assert_eq!(import(ConflictPolicy::Skip, &mut progress).ok(), Some(0));
+        assert_eq!(import(ConflictPolicy::Replace, &mut progress).ok(), Some(2));
+        assert_eq!(adapter.scan(0, None).len(), 2);
+
+        #[cfg(feature = "serde")]
+        {
+            let json_path = temp_dir.path().join("import.ndjson");
+            std::fs::write(&json_path, "{\"key\": \"c\", \"string\": \"json\", \"bytes\": [3, 4], \"integer\": -3, \"unsigned_integer\": 3, \"float\": 3, \"double\": \"3.5\"}\n\n{\"key\": \"d\"}\n").expect("Failed to write ndjson");
+            let imported = persistence.import_file::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&json_path, ImportFormat::NdJson, ConflictPolicy::Abort, &HashMap::new(), |_|{});
+            assert!(matches!(imported, Err(crate::persistence_adapter::PersistenceError::Spec { field, .. }) if field == "string"));
+            assert!(adapter.load(&"c".to_string()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_import_in_transaction() {
+        let (temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+
+        let csv_path = temp_dir.path().join("import.csv");
+        std::fs::write(&csv_path, "key,string,bytes,integer,unsigned_integer,float,double
+a,plain,,1,1,0,0
+").expect("Failed to write csv");
+        let import = ||persistence.import_file::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&csv_path, ImportFormat::Csv, ConflictPolicy::Abort, &HashMap::new(), |_|{});
+
+        // the imported rows are part of the transaction and go with it
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert_eq!(import().ok(), Some(1));
+        assert!(adapter.contains(&"a".to_string()));
+        assert!(transaction.rollback().is_ok());
+        assert!(!adapter.contains(&"a".to_string()));
+
+        // a failed import inside a transaction only undoes its own batch
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert!(adapter.store(&"b".to_string(), &AllSupportedTypes::with_integer(2)).is_ok());
+        assert!(adapter.store(&"a".to_string(), &AllSupportedTypes::with_integer(3)).is_ok());
+        assert!(import().is_err());
+        assert!(transaction.commit().is_ok());
+        assert_eq!(adapter.scan(0, None).len(), 2);
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, Query};
+use super::{quote_identifier, SqlitePersistence};
+
+impl SqlitePersistence {
+    // The rows of this table paired with the rows of other's table where this table's on.0 equals other's
+    // on.1, an INNER JOIN. filter narrows this table's rows and other's rows before they are joined. Pairs
+    // come in order of this table's key, then other's. Both adapters must share the connection and tenant.
+    // Each side is read by its own adapter, so checksums and encryption apply as configured there; pairs
+    // where either row fails to read are skipped and recorded as the last error
+    pub fn join_query<KeyA, A, SpecA: PersistenceSpec<KeyA, A>, KeyB, B, SpecB: PersistenceSpec<KeyB, B>>(&self, other: &SqlitePersistence, on: (&str, &str), filter: (Option<&Query>, Option<&Query>)) -> Result<Vec<(A, B)>, PersistenceError> {
+        if !Arc::ptr_eq(&self.connection, &other.connection) || self.tenant != other.tenant {
+            return Err(self.backend_error("Joined adapters must share the connection and tenant"));
+        }
+        let fields_a = SpecA::fields().iter().map(PersistenceType::get_name).collect::<Vec<_>>();
+        let fields_b = SpecB::fields().iter().map(PersistenceType::get_name).collect::<Vec<_>>();
+        for (field, fields) in [(on.0, &fields_a), (on.1, &fields_b)] {
+            if !fields.contains(&field) {
+                return Err(PersistenceError::FieldNotAllowed { field: field.to_string() });
+            }
+        }
+        filter.0.map(|q|q.validate_fields(&fields_a)).transpose()?;
+        filter.1.map(|q|q.validate_fields(&fields_b)).transpose()?;
+
+        let (filter_a, next_index, values) = match filter.0 {
+            Some(query) => SqlitePersistence::generate_filter(query, 0, Vec::new()),
+            None => ("1".to_string(), 0, Vec::new())
+        };
+        let (filter_b, _, values) = match filter.1 {
+            Some(query) => SqlitePersistence::generate_filter(query, next_index, values),
+            None => ("1".to_string(), next_index, values)
+        };
+        let (table_a, table_b, tenant) = (quote_identifier(&self.table_name), quote_identifier(&other.table_name), self.and_row_scope());
+        let (on_a, on_b) = (quote_identifier(on.0), quote_identifier(on.1));
+        let (key_a, key_b) = (quote_identifier(SpecA::key_field()), quote_identifier(SpecB::key_field()));
+        let from = format!("FROM (SELECT * FROM {table_a} WHERE {filter_a}{tenant}) AS \"l\" INNER JOIN (SELECT * FROM {table_b} WHERE {filter_b}{tenant}) AS \"r\" ON \"l\".{on_a} = \"r\".{on_b} ORDER BY \"l\".{key_a}, \"r\".{key_b}");
+
+        // One statement per side, read in step: a row of one is read by its adapter's spec without the other's
+        // columns in the way. While either is running the connection keeps a single read transaction, so
+        // both see the same rows
+        let command_a = format!("SELECT \"l\".* {from}");
+        let command_b = format!("SELECT \"r\".* {from}");
+        let _timer_a = self.time_statement(&command_a, &values);
+        let mut statement_a = self.prepare_join(&command_a, &values)?;
+        let _timer_b = self.time_statement(&command_b, &values);
+        let mut statement_b = self.prepare_join(&command_b, &values)?;
+        let mut pairs = Vec::new();
+        while statement_a.next().map_err(|e|self.backend_error(e))? == Row {
+            if statement_b.next().map_err(|e|self.backend_error(e))? != Row {
+                break;
+            }
+            match (self.read_row::<KeyA, A, SpecA>(&statement_a), other.read_row::<KeyB, B, SpecB>(&statement_b)) {
+                (Ok((_, a)), Ok((_, b))) => pairs.push((a, b)),
+                (Err(e), _) | (_, Err(e)) => { self.record_error(e); }
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn prepare_join(&self, command: &str, values: &[PersistenceData]) -> Result<sqlite_::Statement<'_>, PersistenceError> {
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        Ok(statement)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{collections::HashMap, sync::Arc};
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, Query, SpecError};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    const NOTE_FIELDS: [PersistenceType; 3] = [
+        PersistenceType::Integer("id"),
+        PersistenceType::String("owner"),
+        PersistenceType::String("text")
+    ];
+
+    // notes written by the owner of an AllSupportedTypes row, data is (owner, text)
+    struct NoteSpec {}
+
+    impl PersistenceSpec<i64, (String, String)> for NoteSpec {
+        fn fields() -> &'static [PersistenceType] {
+            &NOTE_FIELDS
+        }
+
+        fn key_field() -> &'static str {
+            "id"
+        }
+
+        fn serialize_key(key: &i64) -> PersistenceData {
+            PersistenceData::Integer(*key)
+        }
+
+        fn deserialize_key(key: &PersistenceData) -> Option<i64> {
+            key.to_int()
+        }
+
+        fn serialize_data(data: &(String, String)) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+            Ok(HashMap::from([("owner", PersistenceData::String(data.0.clone())), ("text", PersistenceData::String(data.1.clone()))]))
+        }
+
+        fn deserialize_data(mut data: HashMap<&'static str, PersistenceData>) -> Result<(String, String), SpecError> {
+            let owner = data.remove("owner").and_then(PersistenceData::into_string).ok_or_else(||SpecError::missing("owner"))?;
+            let text = data.remove("text").and_then(PersistenceData::into_string).ok_or_else(||SpecError::missing("text"))?;
+            Ok((owner, text))
+        }
+    }
+
+    #[test]
+    fn test_join_query() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let people = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let notes = SqlitePersistence::new(db_connection, "notes");
+        let people_adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &people;
+        let notes_adapter: &dyn PersistenceAdapter<i64, (String, String), NoteSpec> = &notes;
+        people_adapter.initialize();
+        notes_adapter.initialize();
+        let row = AllSupportedTypes::with_integer;
+        for (key, integer) in [("ann", 1), ("bob", 2), ("cat", 3)] {
+            assert!(people_adapter.store(&key.to_string(), &row(integer)).is_ok());
+        }
+        for (id, owner, text) in [(1, "bob", "b1"), (2, "ann", "a1"), (3, "bob", "b2"), (4, "nobody", "x")] {
+            assert!(notes_adapter.store(&id, &(owner.to_string(), text.to_string())).is_ok());
+        }
+
+        let join = |filter|people.join_query::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, i64, (String, String), NoteSpec>(&notes, ("key", "owner"), filter);
+        let texts = |pairs: Vec<(AllSupportedTypes, (String, String))>|pairs.into_iter().map(|(person, (_, text))|(person.integer, text)).collect::<Vec<_>>();
+        assert_eq!(join((None, None)).map(texts).ok(), Some(vec![(1, "a1".to_string()), (2, "b1".to_string()), (2, "b2".to_string())]));
+
+        let above_one = Query::GreaterThan("integer".to_string(), PersistenceData::Integer(1));
+        let not_b1 = Query::Not(Arc::new(Query::Equals("text".to_string(), PersistenceData::String("b1".to_string()))));
+        assert_eq!(join((Some(&above_one), Some(&not_b1))).map(texts).ok(), Some(vec![(2, "b2".to_string())]));
+
+        // filters and join fields are checked against their own side's spec
+        assert!(matches!(join((Some(&not_b1), None)), Err(PersistenceError::FieldNotAllowed { field }) if field == "text"));
+        let unknown = people.join_query::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, i64, (String, String), NoteSpec>(&notes, ("key", "integer"), (None, None));
+        assert!(matches!(unknown, Err(PersistenceError::FieldNotAllowed { field }) if field == "integer"));
+    }
+}
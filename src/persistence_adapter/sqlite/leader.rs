@@ -0,0 +1,140 @@
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver, RecvTimeoutError, Sender}}, thread::{self, JoinHandle}, time::Duration};
+use crate::persistence_adapter::PersistenceError;
+use super::LockManager;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeadershipEvent {
+    Elected,
+    Lost // the lease couldn't be renewed, another node may already be leader
+}
+
+// Leader election for a small cluster sharing a database, on top of a LockManager lock named after the
+// election. The leader holds the lock with a lease it renews, other nodes keep trying to take it over
+// and succeed once a leader stops renewing
+#[derive(Debug, Clone)]
+pub struct LeaderElector {
+    locks: LockManager,
+    name: String
+}
+
+impl LeaderElector {
+    pub fn new(locks: LockManager, name: &str) -> Self {
+        LeaderElector { locks, name: name.to_string() }
+    }
+
+    // Campaigns for leadership as node_id on a background thread until the returned Campaign is dropped or
+    // resigns. It tries to take the lease every lease / 3 and, once leader, renews it as often. Act as
+    // leader only while Campaign::is_leader is true, in steps shorter than the lease: after a missed
+    // renewal another node may take over as soon as the lease runs out
+    pub fn campaign(&self, node_id: &str, lease: Duration) -> Campaign {
+        let (events, receiver) = mpsc::channel();
+        let (stop, stopped) = mpsc::channel();
+        let leader = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (locks, name, node_id, leader) = (self.locks.clone(), self.name.clone(), node_id.to_string(), leader.clone());
+            thread::spawn(move ||run_campaign(locks, name, node_id, lease, leader, events, stopped))
+        };
+        Campaign { leader, events: receiver, stop: Some(stop), thread: Some(thread) }
+    }
+
+    // the node_id of the current leader, None while there is none
+    pub fn leader(&self) -> Result<Option<String>, PersistenceError> {
+        Ok(self.locks.holder(&self.name)?.and_then(|token|token.rsplit_once('/').map(|(node_id, _)|node_id.to_string())))
+    }
+}
+
+fn run_campaign(locks: LockManager, name: String, node_id: String, lease: Duration, leader: Arc<AtomicBool>, events: Sender<LeadershipEvent>, stopped: Receiver<()>) {
+    let mut guard = None;
+    loop {
+        match &guard {
+            None => if let Ok(Some(acquired)) = locks.acquire_as(&name, &node_id, lease) {
+                guard = Some(acquired);
+                leader.store(true, Ordering::SeqCst);
+                let _ = events.send(LeadershipEvent::Elected);
+            },
+            // an error counts as lost too, the lease can't be known to still be held
+            Some(held) => if !held.renew(lease).unwrap_or(false) {
+                leader.store(false, Ordering::SeqCst);
+                guard = None;
+                let _ = events.send(LeadershipEvent::Lost);
+            }
+        }
+        match stopped.recv_timeout(lease / 3) {
+            Err(RecvTimeoutError::Timeout) => {},
+            _ => break
+        }
+    }
+    // resigning releases the lock through the guard so the next leader doesn't wait out the lease
+    leader.store(false, Ordering::SeqCst);
+    drop(guard);
+}
+
+// A running campaign, resigns when dropped
+#[derive(Debug)]
+pub struct Campaign {
+    leader: Arc<AtomicBool>,
+    events: Receiver<LeadershipEvent>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Campaign {
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::SeqCst)
+    }
+
+    // Elected and Lost as they happen, e.g. to start and stop leader-only work
+    pub fn events(&self) -> &Receiver<LeadershipEvent> {
+        &self.events
+    }
+
+    // stops campaigning and gives up leadership if held
+    pub fn resign(self) {}
+}
+
+impl Drop for Campaign {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::Duration;
+    use crate::persistence_adapter::sqlite::{LeaderElector, LeadershipEvent, LockManager};
+    use crate::tests::sqlite_connection;
+
+    #[test]
+    fn test_leader_election() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let locks = LockManager::new(db_connection.clone(), "locks");
+        locks.initialize().expect("Failed to initialize");
+        let elector = LeaderElector::new(locks, "scheduler");
+        assert_eq!(elector.leader().ok(), Some(None));
+
+        let lease = Duration::from_millis(300);
+        let first = elector.campaign("node-a", lease);
+        assert_eq!(first.events().recv_timeout(Duration::from_secs(5)).ok(), Some(LeadershipEvent::Elected));
+        assert!(first.is_leader());
+        assert_eq!(elector.leader().ok(), Some(Some("node-a".to_string())));
+
+        let second = elector.campaign("node/b", lease);
+        assert!(second.events().recv_timeout(lease * 2).is_err());
+        assert!(!second.is_leader());
+
+        // the lock is released on resigning, so the other node takes over within one retry
+        first.resign();
+        assert_eq!(second.events().recv_timeout(lease).ok(), Some(LeadershipEvent::Elected));
+        assert_eq!(elector.leader().ok(), Some(Some("node/b".to_string())));
+
+        // another holder taking over the lock row shows up as a loss at the next renewal
+        assert!(db_connection.execute("UPDATE locks SET token = 'node-c/1', expires_at = 9223372036854775807").is_ok());
+        assert_eq!(second.events().recv_timeout(lease).ok(), Some(LeadershipEvent::Lost));
+        assert!(!second.is_leader());
+        assert_eq!(elector.leader().ok(), Some(Some("node-c".to_string())));
+    }
+}
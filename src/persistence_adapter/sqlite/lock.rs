@@ -0,0 +1,191 @@
+use std::{sync::{Arc, atomic::{AtomicU64, Ordering}}, time::Duration};
+use debug_ignore::DebugIgnore;
+use sqlite_::ConnectionWithFullMutex;
+use sqlite_::State::Row;
+use crate::persistence_adapter::PersistenceError;
+use crate::persistence_adapter::clock::{duration_millis, Clock, SystemClock};
+use super::{now_millis, quote_identifier};
+
+static TOKEN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// unique per acquisition so a guard can never release a lock that expired and was taken by someone else
+fn new_token() -> String {
+    format!("{}-{}-{}", std::process::id(), now_millis(), TOKEN_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+// Named advisory locks stored in a table, usable by every process sharing the database
+#[derive(Debug, Clone)]
+pub struct LockManager {
+    connection: DebugIgnore<Arc<ConnectionWithFullMutex>>,
+    table_name: String,
+    clock: Arc<dyn Clock>
+}
+
+impl LockManager {
+    pub fn new(connection: Arc<ConnectionWithFullMutex>, table_name: &str) -> Self {
+        LockManager { connection: DebugIgnore(connection), table_name: table_name.to_string(), clock: Arc::new(SystemClock {}) }
+    }
+
+    // the clock ttls are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn initialize(&self) -> Result<(), PersistenceError> {
+        self.connection.execute(format!("CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, token TEXT NOT NULL, expires_at INTEGER NOT NULL)", quote_identifier(&self.table_name)))?;
+        Ok(())
+    }
+
+    // Takes the lock if it is free or its holder's ttl ran out, returns None while someone else holds it.
+    // The insert-or-steal is a single statement so two processes can never both succeed
+    pub fn acquire(&self, name: &str, ttl: Duration) -> Result<Option<LockGuard>, PersistenceError> {
+        self.acquire_with_token(name, new_token(), ttl)
+    }
+
+    // acquire with a token that starts with something identifying the holder, see holder
+    pub(super) fn acquire_as(&self, name: &str, holder: &str, ttl: Duration) -> Result<Option<LockGuard>, PersistenceError> {
+        self.acquire_with_token(name, format!("{holder}/{}", new_token()), ttl)
+    }
+
+    // the token of the lock's current holder, None while it's free or expired
+    pub(super) fn holder(&self, name: &str) -> Result<Option<String>, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("SELECT token FROM {} WHERE name = ? AND expires_at > ?", quote_identifier(&self.table_name)))?;
+        statement.bind((1, name))?;
+        statement.bind((2, self.clock.now_millis()))?;
+        match statement.next()? {
+            Row => Ok(Some(statement.read::<String, usize>(0)?)),
+            _ => Ok(None)
+        }
+    }
+
+    fn acquire_with_token(&self, name: &str, token: String, ttl: Duration) -> Result<Option<LockGuard>, PersistenceError> {
+        let now = self.clock.now_millis();
+        let command = format!(
+            "INSERT INTO {0} (name, token, expires_at) VALUES (?, ?, ?) ON CONFLICT(name) DO UPDATE SET token = excluded.token, expires_at = excluded.expires_at WHERE {0}.expires_at <= ? RETURNING token",
+            quote_identifier(&self.table_name)
+        );
+        let mut statement = self.connection.prepare(command)?;
+        statement.bind((1, name))?;
+        statement.bind((2, token.as_str()))?;
+        statement.bind((3, now.saturating_add(duration_millis(ttl))))?;
+        statement.bind((4, now))?;
+
+        match statement.next()? {
+            Row => Ok(Some(LockGuard { manager: self.clone(), name: name.to_string(), token, released: false })),
+            _ => Ok(None)
+        }
+    }
+
+    // runs a statement scoped to one holder's token, returns whether it touched the lock row
+    fn execute_for_token(&self, command: String, name: &str, token: &str, renewal: Option<(i64, i64)>) -> Result<bool, PersistenceError> {
+        let mut statement = self.connection.prepare(command)?;
+        statement.bind((":name", name))?;
+        statement.bind((":token", token))?;
+        if let Some((now, expires_at)) = renewal {
+            statement.bind((":now", now))?;
+            statement.bind((":expires_at", expires_at))?;
+        }
+        Ok(matches!(statement.next()?, Row))
+    }
+}
+
+// Held lock, released when dropped
+#[derive(Debug)]
+pub struct LockGuard {
+    manager: LockManager,
+    name: String,
+    token: String,
+    released: bool
+}
+
+impl LockGuard {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Pushes the expiry out to ttl from now, returns false if the lock already expired and was taken over
+    pub fn renew(&self, ttl: Duration) -> Result<bool, PersistenceError> {
+        let now = self.manager.clock.now_millis();
+        let command = format!("UPDATE {} SET expires_at = :expires_at WHERE name = :name AND token = :token AND expires_at > :now RETURNING token", quote_identifier(&self.manager.table_name));
+        self.manager.execute_for_token(command, &self.name, &self.token, Some((now, now.saturating_add(duration_millis(ttl)))))
+    }
+
+    // returns false if the lock had already expired and been taken over
+    pub fn release(mut self) -> Result<bool, PersistenceError> {
+        self.released = true;
+        self.release_inner()
+    }
+
+    fn release_inner(&self) -> Result<bool, PersistenceError> {
+        let command = format!("DELETE FROM {} WHERE name = :name AND token = :token RETURNING token", quote_identifier(&self.manager.table_name));
+        self.manager.execute_for_token(command, &self.name, &self.token, None)
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.release_inner();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{sync::Arc, thread::sleep, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::sqlite::LockManager;
+    use crate::tests::sqlite_connection;
+
+    #[test]
+    fn test_lock_acquire_release() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let locks = LockManager::new(db_connection, "locks");
+        assert!(locks.initialize().is_ok());
+
+        let guard = locks.acquire("migrations", Duration::from_secs(60)).expect("Failed to acquire").expect("Lock should be free");
+        assert!(locks.acquire("migrations", Duration::from_secs(60)).expect("Failed to acquire").is_none());
+        assert!(locks.acquire("cleanup", Duration::from_secs(60)).expect("Failed to acquire").is_some());
+        assert!(guard.renew(Duration::from_secs(60)).is_ok_and(|r|r));
+
+        drop(guard);
+        let guard = locks.acquire("migrations", Duration::from_millis(1)).expect("Failed to acquire").expect("Lock should be released");
+
+        sleep(Duration::from_millis(5));
+        let stolen = locks.acquire("migrations", Duration::from_secs(60)).expect("Failed to acquire");
+        assert!(stolen.is_some());
+        assert!(guard.release().is_ok_and(|released|!released));
+    }
+
+    #[test]
+    fn test_lock_expiry_with_manual_clock() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let locks = LockManager::new(db_connection, "locks").with_clock(clock.clone());
+        assert!(locks.initialize().is_ok());
+
+        let guard = locks.acquire("migrations", Duration::from_secs(60)).expect("Failed to acquire").expect("Lock should be free");
+        clock.advance(Duration::from_secs(59));
+        assert!(locks.acquire("migrations", Duration::from_secs(60)).expect("Failed to acquire").is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(guard.renew(Duration::from_secs(60)).is_ok_and(|renewed|!renewed));
+        assert!(locks.acquire("migrations", Duration::from_secs(60)).expect("Failed to acquire").is_some());
+    }
+
+    #[test]
+    fn test_lock_huge_ttl() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let locks = LockManager::new(db_connection, "locks");
+        assert!(locks.initialize().is_ok());
+
+        // ttls past the end of time hold the lock for ever rather than wrapping into the past
+        let guard = locks.acquire("forever", Duration::from_secs(u64::MAX)).expect("Failed to acquire").expect("Lock should be free");
+        assert!(locks.acquire("forever", Duration::from_secs(60)).expect("Failed to acquire").is_none());
+        assert!(guard.renew(Duration::from_millis(i64::MAX as u64)).is_ok_and(|renewed|renewed));
+        assert!(locks.acquire("forever", Duration::from_secs(60)).expect("Failed to acquire").is_none());
+    }
+}
@@ -0,0 +1,74 @@
+use opentelemetry::{global::{self, BoxedSpan}, trace::{Span, SpanBuilder, SpanKind, Tracer}, Context, KeyValue};
+use super::SqlitePersistence;
+
+const TRACER_NAME: &str = "dmfg-persistence";
+
+// A client span covering one statement, ended when dropped
+pub(super) struct StatementSpan(BoxedSpan);
+
+impl Drop for StatementSpan {
+    fn drop(&mut self) {
+        self.0.end();
+    }
+}
+
+impl SqlitePersistence {
+    // Parents the spans of this adapter's statements to parent instead of the context that is current when
+    // they run, for callers that don't attach their context to the thread. Clone the adapter per request:
+    // persistence.clone().with_trace_context(request_context)
+    pub fn with_trace_context(mut self, parent: opentelemetry::Context) -> Self {
+        self.trace_context = Some(parent);
+        self
+    }
+
+    // Spans go to the global tracer provider, named like "SELECT table" with the db.* semantic attributes.
+    // db.statement only has placeholders, bound values stay out of traces like they stay out of the slow query log
+    pub(super) fn statement_span(&self, command: &str) -> StatementSpan {
+        let operation = command.split_whitespace().next().unwrap_or_default().to_uppercase();
+        let builder = SpanBuilder::from_name(format!("{operation} {}", self.table_name))
+            .with_kind(SpanKind::Client)
+            .with_attributes([
+                KeyValue::new("db.system", "sqlite"),
+                KeyValue::new("db.statement", command.to_string()),
+                KeyValue::new("db.operation", operation),
+                KeyValue::new("db.sql.table", self.table_name.clone())
+            ]);
+        let tracer = global::tracer(TRACER_NAME);
+        let span = match &self.trace_context {
+            Some(parent) => tracer.build_with_context(builder, parent),
+            None => tracer.build_with_context(builder, &Context::current())
+        };
+        StatementSpan(span)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use opentelemetry::{global, trace::{Span, TraceContextExt, Tracer}, Context};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_statement_spans() {
+        let exporter = InMemorySpanExporter::default();
+        global::set_tracer_provider(SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build());
+
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let parent = global::tracer("test").start("handle request");
+        let parent_id = parent.span_context().span_id();
+        let persistence = SqlitePersistence::new(db_connection, "traced_table").with_trace_context(Context::current_with_span(parent));
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        assert!(adapter.load(&"a".to_string()).is_none());
+
+        // other tests share the global provider, only look at this table's spans
+        let spans = exporter.get_finished_spans().expect("Failed to get spans").into_iter().filter(|span|span.name.ends_with("traced_table")).collect::<Vec<_>>();
+        let select = spans.iter().find(|span|span.name == "SELECT traced_table").expect("Load should be traced");
+        assert_eq!(select.parent_span_id, parent_id);
+        assert!(select.attributes.iter().any(|kv|kv.key.as_str() == "db.system" && kv.value.as_str() == "sqlite"));
+        assert!(select.attributes.iter().any(|kv|kv.key.as_str() == "db.statement" && kv.value.as_str().starts_with("SELECT")));
+    }
+}
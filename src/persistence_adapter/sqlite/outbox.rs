@@ -0,0 +1,138 @@
+use std::sync::Arc;
+use debug_ignore::DebugIgnore;
+use sqlite_::{ConnectionWithFullMutex, Statement};
+use sqlite_::State::Row;
+use crate::persistence_adapter::PersistenceError;
+use crate::persistence_adapter::clock::{Clock, SystemClock};
+use super::quote_identifier;
+
+// An event recorded in an Outbox, waiting to be relayed
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxMessage {
+    pub id: u64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub created_at: i64 // milliseconds since the unix epoch
+}
+
+// Events to publish to a message broker, kept in a table next to the data they describe. Share the
+// connection with the SqlitePersistence and add events inside its transaction, then the events are
+// recorded if and only if the change commits. A relay polls the outbox, publishes and acks, so events
+// are delivered at least once and in order
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    connection: DebugIgnore<Arc<ConnectionWithFullMutex>>,
+    table_name: String,
+    clock: Arc<dyn Clock>
+}
+
+impl Outbox {
+    pub fn new(connection: Arc<ConnectionWithFullMutex>, table_name: &str) -> Self {
+        Outbox { connection: DebugIgnore(connection), table_name: table_name.to_string(), clock: Arc::new(SystemClock {}) }
+    }
+
+    // the clock created_at is taken from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn initialize(&self) -> Result<(), PersistenceError> {
+        self.connection.execute(format!(
+            "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, topic TEXT NOT NULL, payload BLOB NOT NULL, created_at INTEGER NOT NULL)",
+            quote_identifier(&self.table_name)
+        ))?;
+        Ok(())
+    }
+
+    // Records an event and returns its id. Ids grow with every add, ordering events across topics
+    pub fn add(&self, topic: &str, payload: &[u8]) -> Result<u64, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("INSERT INTO {} (topic, payload, created_at) VALUES (?, ?, ?) RETURNING id", quote_identifier(&self.table_name)))?;
+        statement.bind((1, topic))?;
+        statement.bind((2, payload))?;
+        statement.bind((3, self.clock.now_millis()))?;
+        statement.next()?;
+        Ok(statement.read::<i64, usize>(0)? as u64)
+    }
+
+    // The oldest events not acked yet. Polling doesn't claim them, run one relay per outbox
+    pub fn poll(&self, limit: usize) -> Result<Vec<OutboxMessage>, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("SELECT id, topic, payload, created_at FROM {} ORDER BY id LIMIT ?", quote_identifier(&self.table_name)))?;
+        statement.bind((1, limit as i64))?;
+        let mut messages = Vec::new();
+        while statement.next()? == Row {
+            messages.push(Self::read_message(&statement)?);
+        }
+        Ok(messages)
+    }
+
+    // Removes relayed events, returns how many were still in the outbox
+    pub fn ack(&self, ids: &[u64]) -> Result<usize, PersistenceError> {
+        let mut acked = 0;
+        let mut statement = self.connection.prepare(format!("DELETE FROM {} WHERE id = ? RETURNING id", quote_identifier(&self.table_name)))?;
+        for id in ids {
+            statement.reset()?;
+            statement.bind((1, *id as i64))?;
+            while statement.next()? == Row {
+                acked += 1;
+            }
+        }
+        Ok(acked)
+    }
+
+    pub fn pending(&self) -> Result<u64, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("SELECT count(*) FROM {}", quote_identifier(&self.table_name)))?;
+        statement.next()?;
+        Ok(statement.read::<i64, usize>(0)? as u64)
+    }
+
+    fn read_message(statement: &Statement) -> Result<OutboxMessage, PersistenceError> {
+        Ok(OutboxMessage {
+            id: statement.read::<i64, usize>(0)? as u64,
+            topic: statement.read::<String, usize>(1)?,
+            payload: statement.read::<Vec<u8>, usize>(2)?,
+            created_at: statement.read::<i64, usize>(3)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::sqlite::{Outbox, SqlitePersistence};
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_outbox() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        let outbox = Outbox::new(db_connection, "outbox");
+        assert!(outbox.initialize().is_ok());
+
+        let entry = AllSupportedTypes::with_integer(1);
+
+        // the event is only recorded when the change commits
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+        assert!(outbox.add("created", b"a").is_ok());
+        assert!(transaction.rollback().is_ok());
+        assert_eq!(outbox.pending().ok(), Some(0));
+
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+        assert!(outbox.add("created", b"a").is_ok());
+        assert!(adapter.delete(&"a".to_string()).is_ok());
+        assert!(outbox.add("deleted", b"a").is_ok());
+        assert!(transaction.commit().is_ok());
+
+        let messages = outbox.poll(10).expect("Failed to poll");
+        assert_eq!(messages.iter().map(|m|m.topic.as_str()).collect::<Vec<_>>(), vec!["created", "deleted"]);
+        assert_eq!(messages[0].payload, b"a");
+        assert_eq!(outbox.ack(&[messages[0].id]).ok(), Some(1));
+        assert_eq!(outbox.ack(&[messages[0].id]).ok(), Some(0));
+        assert_eq!(outbox.poll(10).map(|m|m.len()).ok(), Some(1));
+    }
+}
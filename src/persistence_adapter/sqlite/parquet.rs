@@ -0,0 +1,119 @@
+use std::{fs::File, path::Path};
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use crate::persistence_adapter::{PersistenceError, PersistenceSpec, Query};
+use super::{arrow, ConflictPolicy, SqlitePersistence};
+
+const PARQUET_BATCH_SIZE: usize = 10_000;
+
+impl SqlitePersistence {
+    // Writes the rows matching filter, or all rows, to a Parquet file at path with the columns scan_arrow
+    // gives them, one row group per 10000 rows. Like scan_arrow, rows that fail to read are left out and
+    // recorded as the last error. Returns the number of rows written
+    pub fn export_parquet<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, path: impl AsRef<Path>, filter: Option<Query>) -> Result<u64, PersistenceError> {
+        let file = File::create(path).map_err(|e|self.backend_error(e))?;
+        let mut writer = ArrowWriter::try_new(file, arrow::arrow_schema(Spec::fields()), None).map_err(|e|self.backend_error(e))?;
+        let mut last_key = None;
+        let mut written = 0;
+        loop {
+            let (batch, next_key, read) = self.read_arrow_batch::<Key, Data, Spec>(filter.as_ref(), last_key.as_ref(), PARQUET_BATCH_SIZE)?;
+            if batch.num_rows() > 0 {
+                writer.write(&batch).map_err(|e|self.backend_error(e))?;
+                writer.flush().map_err(|e|self.backend_error(e))?;
+                written += batch.num_rows() as u64;
+            }
+            if read < PARQUET_BATCH_SIZE {
+                break;
+            }
+            last_key = next_key;
+        }
+        writer.close().map_err(|e|self.backend_error(e))?;
+        Ok(written)
+    }
+
+    // Inserts the rows of a Parquet file written by export_parquet, or any file with a column of the
+    // matching Arrow type for every spec field. Other columns are ignored. Keys already in the table are
+    // handled like import_file does, each of the file's record batches is inserted in a savepoint of its
+    // own, committed on its own outside a transaction, and on an error earlier batches stay in the table.
+    // Returns the number of inserted rows
+    pub fn import_parquet<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, path: impl AsRef<Path>, conflict: ConflictPolicy) -> Result<u64, PersistenceError> {
+        let file = File::open(path).map_err(|e|self.backend_error(e))?;
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file).map_err(|e|self.backend_error(e))?
+            .with_batch_size(PARQUET_BATCH_SIZE)
+            .build().map_err(|e|self.backend_error(e))?;
+        let command = self.import_command::<Key, Data, Spec>(conflict);
+
+        let mut inserted = 0;
+        for batch in reader {
+            let batch = batch.map_err(|e|self.backend_error(e))?;
+            let rows = arrow::from_record_batch(Spec::fields(), &batch).map_err(|e|self.backend_error(e))?;
+            let savepoint = self.savepoint("import_parquet")?;
+            let mut statement = self.connection.prepare(&command).map_err(|e|self.backend_error(e))?;
+            for row in rows {
+                inserted += self.import_row::<Key, Data, Spec>(&mut statement, &command, row)?;
+            }
+            drop(statement);
+            savepoint.release()?;
+        }
+        Ok(inserted)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, Query};
+    use crate::persistence_adapter::sqlite::{ConflictPolicy, SqlitePersistence};
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_parquet_round_trip() {
+        let (temp_dir, db_connection) = sqlite_connection();
+
+        let source = SqlitePersistence::new(db_connection.clone(), "source");
+        let target = SqlitePersistence::new(db_connection, "target");
+        let source_adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &source;
+        let target_adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &target;
+        source_adapter.initialize();
+        target_adapter.initialize();
+        for i in 0..3 {
+            let entry = AllSupportedTypes{ string: format!("row {i}"), bytes: vec![i as u8; 3], integer: -i, unsigned_integer: i as u64, float: 0.5, double: 1.5 };
+            source_adapter.store(&format!("key{i}"), &entry).expect("Failed to store");
+        }
+
+        let path = temp_dir.path().join("export.parquet");
+        let exported = source.export_parquet::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&path, Some(Query::LessThan("integer".to_string(), PersistenceData::Integer(0))));
+        assert_eq!(exported.ok(), Some(2));
+
+        let import = |conflict|target.import_parquet::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&path, conflict);
+        assert_eq!(import(ConflictPolicy::Abort).ok(), Some(2));
+        assert_eq!(target_adapter.scan(0, None), source_adapter.scan(1, None));
+        assert!(matches!(import(ConflictPolicy::Abort), Err(PersistenceError::UniqueViolation { .. })));
+        assert_eq!(import(ConflictPolicy::Skip).ok(), Some(0));
+        assert_eq!(import(ConflictPolicy::Replace).ok(), Some(2));
+        assert_eq!(target_adapter.scan(0, None).len(), 2);
+
+        assert!(target.import_parquet::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(temp_dir.path().join("missing.parquet"), ConflictPolicy::Abort).is_err());
+    }
+
+    #[test]
+    fn test_import_parquet_in_transaction() {
+        let (temp_dir, db_connection) = sqlite_connection();
+
+        let source = SqlitePersistence::new(db_connection.clone(), "source");
+        let target = SqlitePersistence::new(db_connection, "target");
+        let source_adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &source;
+        let target_adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &target;
+        source_adapter.initialize();
+        target_adapter.initialize();
+        source_adapter.store(&"key0".to_string(), &AllSupportedTypes::with_integer(1)).expect("Failed to store");
+        let path = temp_dir.path().join("export.parquet");
+        assert_eq!(source.export_parquet::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&path, None).ok(), Some(1));
+
+        // the imported rows are part of the transaction and go with it
+        let transaction = target.transaction().expect("Failed to begin");
+        assert_eq!(target.import_parquet::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&path, ConflictPolicy::Abort).ok(), Some(1));
+        assert_eq!(target_adapter.scan(0, None).len(), 1);
+        assert!(transaction.rollback().is_ok());
+        assert!(target_adapter.scan(0, None).is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, PersistenceSpec, SpecError};
+use super::{quote_identifier, SqlitePersistence};
+
+// One of the key ranges scan_partitions splits a table into, [from, to) with None unbounded
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionHandle<Key> {
+    pub from: Option<Key>,
+    pub to: Option<Key>
+}
+
+impl<Key> PartitionHandle<Key> {
+    // The partition's rows in key order, through any adapter on the same table. Give each thread its own
+    // SqlitePersistence on its own connection to scan partitions in parallel
+    pub fn scan<Data, Spec: PersistenceSpec<Key, Data>>(&self, adapter: &impl PersistenceAdapter<Key, Data, Spec>, limit: Option<usize>) -> Vec<(Key, Data)> {
+        adapter.scan_range(self.from.as_ref(), self.to.as_ref(), limit)
+    }
+}
+
+impl SqlitePersistence {
+    // Splits the table's keys into up to n ranges with about the same number of rows, for bulk processing and
+    // exports that scan them concurrently. Fewer ranges come back when there are fewer rows than n. Rows
+    // written after the split fall into whichever range covers their key
+    pub fn scan_partitions<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, n: usize) -> Result<Vec<PartitionHandle<Key>>, PersistenceError> {
+        let command = format!("SELECT count(*) FROM {}{}", quote_identifier(&self.table_name), self.where_row_scope());
+        let _timer = self.time_statement(&command, []);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        statement.next().map_err(|e|self.backend_error(e))?;
+        let rows = statement.read::<i64, usize>(0).map_err(|e|self.backend_error(e))? as usize;
+
+        // the keys at every rows / n offset start a new range
+        let command = format!("SELECT {0} FROM {1}{2} ORDER BY {0} LIMIT 1 OFFSET ?", quote_identifier(Spec::key_field()), quote_identifier(&self.table_name), self.where_row_scope());
+        let mut statement = self.connection.prepare(&command).map_err(|e|self.backend_error(e))?;
+        let key_type = Spec::fields().iter().find(|f|f.get_name() == Spec::key_field()).ok_or_else(||SpecError::missing(Spec::key_field()))?;
+        let mut boundaries = Vec::new();
+        for i in 1..n.clamp(1, rows.max(1)) {
+            let _timer = self.time_statement(&command, []);
+            statement.reset().map_err(|e|self.backend_error(e))?;
+            statement.bind((1, (i * rows / n) as i64)).map_err(|e|self.backend_error(e))?;
+            self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+            if statement.next().map_err(|e|self.backend_error(e))? != Row {
+                break;
+            }
+            boundaries.push(SqlitePersistence::read_field(key_type, &statement, Spec::key_field())?);
+        }
+
+        // each boundary ends one range and starts the next
+        let key = |boundary: Option<&PersistenceData>|boundary.map(|key|Spec::deserialize_key(key).ok_or_else(||SpecError::new(Spec::key_field(), "Invalid key"))).transpose();
+        (0..=boundaries.len()).map(|i|Ok(PartitionHandle {
+            from: key(i.checked_sub(1).and_then(|i|boundaries.get(i)))?,
+            to: key(boundaries.get(i))?
+        })).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{sync::Arc, thread};
+    use sqlite_::Connection;
+    use tempdir::TempDir;
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_scan_partitions() {
+        let temp_dir = TempDir::new("sqlite_test").expect("Failed to create tempdir");
+
+        let path = temp_dir.path().join("test.sqlite");
+        let persistence = SqlitePersistence::new(Arc::new(Connection::open_with_full_mutex(&path).expect("Failed to open temp db")), "test_table");
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        assert_eq!(persistence.scan_partitions::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(4).map(|p|p.len()).ok(), Some(1));
+
+        let row = AllSupportedTypes::with_integer(1);
+        for i in 0..100 {
+            assert!(adapter.store(&format!("{i:03}"), &row).is_ok());
+        }
+        let partitions = persistence.scan_partitions::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(4).expect("Failed to partition");
+        assert_eq!(partitions.iter().map(|p|p.from.clone()).collect::<Vec<_>>(), vec![None, Some("025".to_string()), Some("050".to_string()), Some("075".to_string())]);
+
+        // each partition scanned on its own thread and connection
+        let scanned = thread::scope(|scope|{
+            let threads = partitions.iter().map(|partition|{
+                let path = &path;
+                scope.spawn(move ||{
+                    let own = SqlitePersistence::new(Arc::new(Connection::open_with_full_mutex(path).expect("Failed to open db")), "test_table");
+                    partition.scan::<AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&own, None).into_iter().map(|(key, _)|key).collect::<Vec<_>>()
+                })
+            }).collect::<Vec<_>>();
+            threads.into_iter().map(|t|t.join().expect("Scan panicked")).collect::<Vec<_>>()
+        });
+        assert!(scanned.iter().all(|keys|keys.len() == 25));
+        assert_eq!(scanned.concat(), (0..100).map(|i|format!("{i:03}")).collect::<Vec<_>>());
+
+        assert_eq!(persistence.scan_partitions::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(1000).map(|p|p.len()).ok(), Some(100));
+    }
+}
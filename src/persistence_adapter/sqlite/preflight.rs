@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, PersistenceSpec};
+use super::{checksum, column_type, tenant, ttl, version, DeserializationMode, SchemaDrift, SqlitePersistence};
+
+// Something about the table that will make the adapter fail or misbehave, see SqlitePersistence::preflight
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreflightFinding {
+    MissingTable, // run initialize
+    MissingColumn { column: String }, // the spec gained a field, the table needs the column added
+    ColumnType { column: String, expected: String, actual: String },
+    ExtraColumn { column: String }, // rows fail to load in DeserializationMode::Strict, drop the column or go Lenient
+    KeyNotPrimary { expected: Vec<String>, actual: Vec<String> }, // duplicate keys are possible and lookups scan the table
+    SchemaDrift { stored: String, compiled: String }, // the spec changed since record_schema_hash, the table may need migrating
+    RoundTrip { message: String } // the sentinel row couldn't be written and read back unchanged
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PreflightReport {
+    pub findings: Vec<PreflightFinding>
+}
+
+impl PreflightReport {
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+impl SqlitePersistence {
+    // Checks at startup that the table matches what Spec and this adapter's settings expect: the table
+    // exists, has the spec's columns with their types plus the checksum and tenant columns when enabled, is
+    // keyed on the key field, and the spec hasn't drifted from a recorded schema hash. With a sentinel row it
+    // also stores, loads and deletes that row inside a savepoint that is rolled back, so nothing is left
+    // behind. Errors are only returned when sqlite can't be asked, problems with the table are findings
+    pub fn preflight<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, sentinel: Option<(&Key, &Data)>) -> Result<PreflightReport, PersistenceError> {
+        let mut findings = Vec::new();
+        let command = "SELECT name, type, pk FROM pragma_table_info(?)";
+        let timer = self.time_statement(command, [&PersistenceData::String(self.table_name.clone())]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        statement.bind((1, self.table_name.as_str())).map_err(|e|self.backend_error(e))?;
+        let mut columns = BTreeMap::new();
+        let mut primary_key = Vec::new();
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            let name = statement.read::<String, usize>(0).map_err(|e|self.backend_error(e))?;
+            let pk = statement.read::<i64, usize>(2).map_err(|e|self.backend_error(e))?;
+            if pk > 0 {
+                primary_key.push((pk, name.clone()));
+            }
+            columns.insert(name, statement.read::<String, usize>(1).map_err(|e|self.backend_error(e))?);
+        }
+        drop(timer);
+        if columns.is_empty() {
+            return Ok(PreflightReport { findings: vec![PreflightFinding::MissingTable] });
+        }
+
+        let mut expected = Spec::fields().iter().map(|f|(f.get_name(), column_type(f))).collect::<Vec<_>>();
+        if self.checksums {
+            expected.push((checksum::CHECKSUM_COLUMN, "TEXT"));
+        }
+        if self.versioned {
+            expected.push((version::VERSION_COLUMN, "INTEGER"));
+        }
+        if self.ttl {
+            expected.push((ttl::EXPIRES_COLUMN, "INTEGER"));
+        }
+        if self.tenant.is_some() {
+            expected.push((tenant::TENANT_COLUMN, "TEXT"));
+        }
+        for (column, column_type) in &expected {
+            match columns.get(*column) {
+                None => findings.push(PreflightFinding::MissingColumn { column: column.to_string() }),
+                Some(actual) if !actual.eq_ignore_ascii_case(column_type) => {
+                    findings.push(PreflightFinding::ColumnType { column: column.to_string(), expected: column_type.to_string(), actual: actual.clone() })
+                },
+                Some(_) => {}
+            }
+        }
+        if self.deserialization_mode == DeserializationMode::Strict {
+            // internal columns of features this adapter doesn't use are ignored when reading
+            let internal = [checksum::CHECKSUM_COLUMN, version::VERSION_COLUMN, ttl::EXPIRES_COLUMN, tenant::TENANT_COLUMN];
+            findings.extend(columns.keys().filter(|c|!expected.iter().any(|(e, _)|e == c) && !internal.contains(&c.as_str())).map(|c|PreflightFinding::ExtraColumn { column: c.clone() }));
+        }
+
+        primary_key.sort();
+        let actual_key = primary_key.into_iter().map(|(_, name)|name).collect::<Vec<_>>();
+        let expected_key = match self.tenant {
+            Some(_) => vec![tenant::TENANT_COLUMN.to_string(), Spec::key_field().to_string()],
+            None => vec![Spec::key_field().to_string()]
+        };
+        if actual_key != expected_key {
+            findings.push(PreflightFinding::KeyNotPrimary { expected: expected_key, actual: actual_key });
+        }
+
+        if let SchemaDrift::Drifted { stored, compiled } = self.schema_drift::<Key, Data, Spec>()? {
+            findings.push(PreflightFinding::SchemaDrift { stored, compiled });
+        }
+
+        if let Some((key, data)) = sentinel {
+            if let Err(message) = self.round_trip::<Key, Data, Spec>(key, data) {
+                findings.push(PreflightFinding::RoundTrip { message });
+            }
+        }
+        Ok(PreflightReport { findings })
+    }
+
+    fn round_trip<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &Key, data: &Data) -> Result<(), String> {
+        // Data isn't comparable, so rows are compared by their serialized fields
+        let serialized = |data: &Data|Spec::serialize_data(data).map(|fields|format!("{:?}", fields.into_iter().collect::<BTreeMap<&str, PersistenceData>>())).map_err(|e|format!("{e:?}"));
+        let expected = serialized(data)?;
+
+        // a savepoint rather than a transaction, so preflight also runs inside a caller's transaction
+        let savepoint = self.savepoint("preflight").map_err(|e|e.to_string())?;
+        let adapter: &dyn PersistenceAdapter<Key, Data, Spec> = self;
+        adapter.store(key, data).map_err(|e|format!("store failed: {}", e.message))?;
+        let loaded = adapter.load(key).ok_or_else(||format!("load failed: {}", self.last_error.lock().unwrap_or_else(|e|e.into_inner()).clone().unwrap_or_else(||"row not found".to_string())))?;
+        if serialized(&loaded)? != expected {
+            return Err("the row loaded differs from the row stored".to_string());
+        }
+        if adapter.delete(key).map_err(|e|format!("delete failed: {e}"))? != 1 {
+            return Err("delete didn't find the row".to_string());
+        }
+        // dropping the savepoint without releasing it rolls the sentinel back, an early return does the same
+        drop(savepoint);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::sqlite::{PreflightFinding, SqlitePersistence};
+    use crate::tests::{sqlite_connection, sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_preflight() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let preflight = |persistence: &SqlitePersistence, sentinel: Option<(&String, &AllSupportedTypes)>|persistence.preflight::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(sentinel).expect("Failed to run preflight");
+        assert_eq!(preflight(&persistence, None).findings, vec![PreflightFinding::MissingTable]);
+
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        let entry = AllSupportedTypes::with_integer(1);
+        let report = preflight(&persistence, Some((&"__preflight".to_string(), &entry)));
+        assert!(report.is_ok(), "{report:?}");
+        assert!(!adapter.contains(&"__preflight".to_string()));
+
+        assert!(db_connection.execute("ALTER TABLE test_table ADD COLUMN legacy TEXT").is_ok());
+        let report = preflight(&persistence.clone().with_checksums(), None);
+        assert!(report.findings.contains(&PreflightFinding::MissingColumn { column: "_checksum".to_string() }));
+        assert!(report.findings.contains(&PreflightFinding::ExtraColumn { column: "legacy".to_string() }));
+
+        assert!(db_connection.execute("CREATE TABLE unkeyed (key TEXT, string TEXT, bytes BLOB, integer INTEGER, unsigned_integer INTEGER, float TEXT, double REAL)").is_ok());
+        let report = preflight(&SqlitePersistence::new(db_connection, "unkeyed"), None);
+        assert!(report.findings.contains(&PreflightFinding::KeyNotPrimary { expected: vec!["key".to_string()], actual: Vec::new() }));
+        assert!(report.findings.contains(&PreflightFinding::ColumnType { column: "float".to_string(), expected: "REAL".to_string(), actual: "TEXT".to_string() }));
+    }
+
+    #[test]
+    fn test_preflight_in_transaction() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+
+        // the sentinel round trip only undoes its own writes, not the caller's
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert!(adapter.store(&"a".to_string(), &AllSupportedTypes::with_integer(1)).is_ok());
+        let report = persistence.preflight::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(Some((&"__preflight".to_string(), &AllSupportedTypes::with_integer(2)))).expect("Failed to run preflight");
+        assert!(report.findings.is_empty());
+        assert!(transaction.commit().is_ok());
+        assert_eq!(adapter.scan(0, None).into_iter().map(|(key, _)|key).collect::<Vec<_>>(), vec!["a".to_string()]);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::persistence_adapter::{PersistenceError, PersistenceSpec, Query};
+use super::{intersperse, quote_identifier, SqlitePersistence};
+
+// keys bound per DELETE, well below sqlite's limit on statement parameters
+const DELETE_CHUNK: usize = 500;
+
+impl SqlitePersistence {
+    // Deletes the rows with these keys all at once or not at all, returns the number deleted. Keys that don't
+    // exist are skipped. Runs in a savepoint, so it can be called inside a transaction
+    pub fn delete_many<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, keys: &[Key]) -> Result<u64, PersistenceError> {
+        let savepoint = self.savepoint("delete_many")?;
+        let mut deleted = 0;
+        for chunk in keys.chunks(DELETE_CHUNK) {
+            let placeholders = intersperse(chunk.iter().map(|_|"?"), ", ").collect::<String>();
+            let command = format!("DELETE FROM {} WHERE {} IN ({placeholders}){} RETURNING 1", quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope());
+            let serialized = chunk.iter().map(Spec::serialize_key).collect::<Vec<_>>();
+            let _timer = self.time_statement(&command, &serialized);
+            let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+            for (i, key) in serialized.iter().enumerate() {
+                SqlitePersistence::bind_data(&mut statement, i + 1, key).map_err(|e|self.backend_error(e))?;
+            }
+            self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+            deleted += self.count_returned(&mut statement)?;
+        }
+        savepoint.release()?;
+        Ok(deleted)
+    }
+
+    // Deletes the rows matching filter batch_size at a time, each batch in its own savepoint. Outside a
+    // transaction that commits every batch, so a big cleanup never holds the write lock for long and other
+    // writers get in between batches; inside one the batches become part of it. Calls progress
+    // with the number deleted so far after each batch, returns the total. Stopping halfway leaves the rows
+    // of the remaining batches, run it again to finish
+    pub fn purge<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, filter: Query, batch_size: usize, mut progress: impl FnMut(u64)) -> Result<u64, PersistenceError> {
+        let (filter, _, values) = SqlitePersistence::generate_filter(&filter, 0, Vec::new());
+        let command = format!(
+            "DELETE FROM {0} WHERE {1} IN (SELECT {1} FROM {0} WHERE {filter}{2} LIMIT {3}){2} RETURNING 1",
+            quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope(), batch_size.max(1)
+        );
+        let mut purged = 0;
+        loop {
+            let savepoint = self.savepoint("purge")?;
+            let _timer = self.time_statement(&command, &values);
+            let mut statement = self.connection.prepare(&command).map_err(|e|self.backend_error(e))?;
+            for (i, value) in values.iter().enumerate() {
+                SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+            }
+            self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+            let deleted = self.count_returned(&mut statement)?;
+            drop(statement);
+            savepoint.release()?;
+            if deleted == 0 {
+                return Ok(purged);
+            }
+            purged += deleted;
+            progress(purged);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, Query};
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_delete_many_and_purge() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        let row = AllSupportedTypes::with_integer;
+        for i in 0..1_200 {
+            assert!(adapter.store(&i.to_string(), &row(i % 2)).is_ok());
+        }
+
+        // more keys than fit in one statement, one of them missing
+        let keys = (0..600).map(|i|(i * 2).to_string()).chain(["missing".to_string()]).collect::<Vec<_>>();
+        assert_eq!(persistence.delete_many::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&keys).ok(), Some(600));
+        assert_eq!(adapter.scan(0, None).len(), 600);
+
+        let mut reported = Vec::new();
+        let odd = Query::Equals("integer".to_string(), PersistenceData::Integer(1));
+        let purged = persistence.purge::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(odd, 250, |n|reported.push(n));
+        assert_eq!(purged.ok(), Some(600));
+        assert_eq!(reported, vec![250, 500, 600]);
+        assert!(adapter.scan(0, None).is_empty());
+    }
+
+    #[test]
+    fn test_delete_many_and_purge_in_transaction() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        for i in 0..10 {
+            assert!(adapter.store(&i.to_string(), &AllSupportedTypes::with_integer(i % 2)).is_ok());
+        }
+
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert_eq!(persistence.delete_many::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(&["0".to_string(), "2".to_string()]).ok(), Some(2));
+        let odd = Query::Equals("integer".to_string(), PersistenceData::Integer(1));
+        assert_eq!(persistence.purge::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(odd, 2, |_|{}).ok(), Some(5));
+        assert_eq!(adapter.scan(0, None).len(), 3);
+
+        // rolling back the transaction undoes both
+        assert!(transaction.rollback().is_ok());
+        assert_eq!(adapter.scan(0, None).len(), 10);
+    }
+}
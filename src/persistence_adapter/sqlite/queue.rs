@@ -0,0 +1,257 @@
+use std::{collections::HashMap, marker::PhantomData, sync::Arc, time::Duration};
+use debug_ignore::DebugIgnore;
+use itertools::intersperse;
+use sqlite_::{ConnectionWithFullMutex, Statement};
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceError, PersistenceSpec, SpecError};
+use crate::persistence_adapter::clock::{duration_millis, Clock, SystemClock};
+use super::{column_type, quote_identifier, SqlitePersistence};
+
+// A message claimed from a PersistentQueue. attempts doubles as the receipt: ack and nack only apply
+// while no other worker has claimed the message since. Ids aren't reused, so a receipt can't match a
+// later message either
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueMessage<T> {
+    pub id: u64,
+    pub attempts: u32,
+    pub data: T
+}
+
+// Work queue stored in a sqlite table. It runs its own statements on the connection rather than going through
+// an adapter, so unlike KvStore or PersistentCache it only works with sqlite. Spec describes the payload columns,
+// its key field must be an integer and is used as the message id. Claims are single statements so two workers
+// never get the same message
+#[derive(Debug, Clone)]
+pub struct PersistentQueue<T, Spec: PersistenceSpec<u64, T>> {
+    connection: DebugIgnore<Arc<ConnectionWithFullMutex>>,
+    table_name: String,
+    max_attempts: u32,
+    clock: Arc<dyn Clock>,
+    _marker: PhantomData<(T, Spec)>
+}
+
+impl<T, Spec: PersistenceSpec<u64, T>> PersistentQueue<T, Spec> {
+    pub fn new(connection: Arc<ConnectionWithFullMutex>, table_name: &str) -> Self {
+        PersistentQueue { connection: DebugIgnore(connection), table_name: table_name.to_string(), max_attempts: 5, clock: Arc::new(SystemClock {}), _marker: PhantomData }
+    }
+
+    // messages claimed this many times without an ack are moved to the dead letters
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    // the clock visibility timeouts are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn initialize(&self) -> Result<(), PersistenceError> {
+        let mut command = format!("CREATE TABLE IF NOT EXISTS {} (", quote_identifier(&self.table_name));
+        for field in Spec::fields() {
+            if field.get_name() == Spec::key_field() {
+                command.push_str(&format!("{} INTEGER PRIMARY KEY AUTOINCREMENT, ", quote_identifier(field.get_name())));
+            } else {
+                command.push_str(&format!("{} {}, ", quote_identifier(field.get_name()), column_type(field)));
+            }
+        }
+        command.push_str("_visible_at INTEGER NOT NULL, _attempts INTEGER NOT NULL, _dead INTEGER NOT NULL)");
+        self.connection.execute(command)?;
+        Ok(())
+    }
+
+    pub fn push(&self, data: &T) -> Result<u64, PersistenceError> {
+        let serialized = Spec::serialize_data(data)?;
+        let payload_fields = Spec::fields().iter().map(|f|f.get_name()).filter(|name|*name != Spec::key_field()).collect::<Vec<_>>();
+
+        let mut command = format!("INSERT INTO {} (", quote_identifier(&self.table_name));
+        payload_fields.iter().for_each(|name|command.push_str(&format!("{}, ", quote_identifier(name))));
+        command.push_str("_visible_at, _attempts, _dead) VALUES (");
+        payload_fields.iter().for_each(|_|command.push_str("?, "));
+        command.push_str(&format!("?, 0, 0) RETURNING {}", quote_identifier(Spec::key_field())));
+
+        let mut statement = self.connection.prepare(command)?;
+        for (i, name) in payload_fields.iter().enumerate() {
+            let value = serialized.get(name).ok_or_else(||PersistenceError::Backend{message: format!("Missing serialized field {name}")})?;
+            SqlitePersistence::bind_data(&mut statement, i + 1, value)?;
+        }
+        statement.bind((payload_fields.len() + 1, self.clock.now_millis()))?;
+        statement.next()?;
+        Ok(statement.read::<i64, usize>(0)? as u64)
+    }
+
+    // Claims the oldest visible message, hiding it from other workers for visibility_timeout.
+    // If it is not acked in time it becomes visible again, until max_attempts is reached
+    pub fn pop(&self, visibility_timeout: Duration) -> Result<Option<QueueMessage<T>>, PersistenceError> {
+        let now = self.clock.now_millis();
+
+        let mut dead_letter = self.connection.prepare(format!("UPDATE {} SET _dead = 1 WHERE _dead = 0 AND _visible_at <= ? AND _attempts >= ?", quote_identifier(&self.table_name)))?;
+        dead_letter.bind((1, now))?;
+        dead_letter.bind((2, self.max_attempts as i64))?;
+        dead_letter.next()?;
+
+        let mut claim = self.connection.prepare(format!(
+            "UPDATE {0} SET _visible_at = ?, _attempts = _attempts + 1 WHERE {1} = (SELECT {1} FROM {0} WHERE _dead = 0 AND _visible_at <= ? AND _attempts < ? ORDER BY {1} LIMIT 1) RETURNING {2}, _attempts",
+            quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), Self::field_list()
+        ))?;
+        claim.bind((1, now.saturating_add(duration_millis(visibility_timeout))))?;
+        claim.bind((2, now))?;
+        claim.bind((3, self.max_attempts as i64))?;
+        match claim.next()? {
+            Row => Ok(Some(Self::read_message(&claim)?)),
+            _ => Ok(None)
+        }
+    }
+
+    // Removes a processed message, returns false if it was claimed again by someone else in the meantime
+    pub fn ack(&self, message: &QueueMessage<T>) -> Result<bool, PersistenceError> {
+        self.execute_for_receipt(format!("DELETE FROM {} WHERE {} = ? AND _attempts = ? AND _dead = 0 RETURNING _attempts", quote_identifier(&self.table_name), quote_identifier(Spec::key_field())), message, None)
+    }
+
+    // Makes a claimed message visible again right away instead of waiting for its visibility timeout
+    pub fn nack(&self, message: &QueueMessage<T>) -> Result<bool, PersistenceError> {
+        self.execute_for_receipt(format!("UPDATE {} SET _visible_at = ? WHERE {} = ? AND _attempts = ? AND _dead = 0 RETURNING _attempts", quote_identifier(&self.table_name), quote_identifier(Spec::key_field())), message, Some(self.clock.now_millis()))
+    }
+
+    pub fn dead_letters(&self, limit: Option<usize>) -> Result<Vec<QueueMessage<T>>, PersistenceError> {
+        let mut statement = self.connection.prepare(format!(
+            "SELECT {}, _attempts FROM {} WHERE _dead = 1 ORDER BY {} LIMIT {}",
+            Self::field_list(), quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), limit.map(|l|l as isize).unwrap_or(-1)
+        ))?;
+        let mut messages = Vec::new();
+        while statement.next()? == Row {
+            messages.push(Self::read_message(&statement)?);
+        }
+        Ok(messages)
+    }
+
+    fn field_list() -> String {
+        intersperse(Spec::fields().iter().map(|f|quote_identifier(f.get_name())), ", ".to_string()).collect()
+    }
+
+    // reads a row selected as field_list() followed by _attempts
+    fn read_message(statement: &Statement) -> Result<QueueMessage<T>, PersistenceError> {
+        let fields = Spec::fields().iter().map(|f|Ok((f.get_name(), SqlitePersistence::read_field(f, statement, f.get_name())?))).collect::<Result<HashMap<_, _>, SpecError>>()?;
+        let id = fields.get(Spec::key_field()).and_then(Spec::deserialize_key).ok_or_else(||PersistenceError::Backend{message: "Invalid message id".to_string()})?;
+        let attempts = statement.read::<i64, &str>("_attempts")? as u32;
+        let data = Spec::deserialize_data(fields)?;
+        Ok(QueueMessage { id, attempts, data })
+    }
+
+    fn execute_for_receipt(&self, command: String, message: &QueueMessage<T>, visible_at: Option<i64>) -> Result<bool, PersistenceError> {
+        let mut statement = self.connection.prepare(command)?;
+        let mut index = 1;
+        if let Some(visible_at) = visible_at {
+            statement.bind((index, visible_at))?;
+            index += 1;
+        }
+        SqlitePersistence::bind_data(&mut statement, index, &Spec::serialize_key(&message.id))?;
+        statement.bind((index + 1, message.attempts as i64))?;
+        Ok(statement.next()? == Row)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{collections::HashMap, time::Duration};
+    use crate::persistence_adapter::{PersistenceData, PersistenceSpec, PersistenceType, SpecError};
+    use crate::persistence_adapter::sqlite::PersistentQueue;
+    use crate::tests::sqlite_connection;
+
+    const JOB_FIELDS: [PersistenceType; 2] = [
+        PersistenceType::UnsignedInteger("id"),
+        PersistenceType::String("name")
+    ];
+
+    struct JobSpec {}
+
+    impl PersistenceSpec<u64, String> for JobSpec {
+        fn fields() -> &'static [PersistenceType] {
+            &JOB_FIELDS
+        }
+
+        fn key_field() -> &'static str {
+            "id"
+        }
+
+        fn serialize_key(key: &u64) -> PersistenceData {
+            PersistenceData::UnsignedInteger(*key)
+        }
+
+        fn deserialize_key(key: &PersistenceData) -> Option<u64> {
+            key.to_unsigned_int()
+        }
+
+        fn serialize_data(data: &String) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+            Ok(HashMap::from([("name", PersistenceData::String(data.clone()))]))
+        }
+
+        fn deserialize_data(mut data: HashMap<&'static str, PersistenceData>) -> Result<String, SpecError> {
+            data.remove("name").and_then(PersistenceData::into_string).ok_or_else(||SpecError::missing("name"))
+        }
+    }
+
+    #[test]
+    fn test_queue_claim_ack_dead_letter() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let queue = PersistentQueue::<String, JobSpec>::new(db_connection, "jobs").with_max_attempts(2);
+        assert!(queue.initialize().is_ok());
+
+        let first = queue.push(&"first".to_string()).expect("Failed to push");
+        let second = queue.push(&"second".to_string()).expect("Failed to push");
+
+        let claimed = queue.pop(Duration::from_secs(60)).expect("Failed to pop").expect("Queue should not be empty");
+        assert_eq!((claimed.id, claimed.attempts, claimed.data.as_str()), (first, 1, "first"));
+        assert!(queue.ack(&claimed).is_ok_and(|acked|acked));
+
+        let claimed = queue.pop(Duration::from_secs(60)).expect("Failed to pop").expect("Queue should not be empty");
+        assert_eq!(claimed.id, second);
+        assert!(queue.pop(Duration::from_secs(60)).expect("Failed to pop").is_none());
+        assert!(queue.nack(&claimed).is_ok_and(|nacked|nacked));
+
+        let reclaimed = queue.pop(Duration::ZERO).expect("Failed to pop").expect("Message should be visible again");
+        assert_eq!((reclaimed.id, reclaimed.attempts), (second, 2));
+        assert!(queue.ack(&claimed).is_ok_and(|acked|!acked));
+
+        assert!(queue.pop(Duration::from_secs(60)).expect("Failed to pop").is_none());
+        assert_eq!(queue.dead_letters(None).expect("Failed to list dead letters"), vec![reclaimed]);
+    }
+
+    #[test]
+    fn test_queue_ids_not_reused() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let queue = PersistentQueue::<String, JobSpec>::new(db_connection, "jobs");
+        assert!(queue.initialize().is_ok());
+
+        // a worker whose claim ran out while another one acked the message
+        let first = queue.push(&"first".to_string()).expect("Failed to push");
+        let stale = queue.pop(Duration::ZERO).expect("Failed to pop").expect("Queue should not be empty");
+        let claimed = queue.pop(Duration::from_secs(60)).expect("Failed to pop").expect("Message should be visible again");
+        assert!(queue.ack(&claimed).is_ok_and(|acked|acked));
+
+        // the next message gets a new id even though the highest one was deleted, so the stale receipt misses it
+        let second = queue.push(&"second".to_string()).expect("Failed to push");
+        assert!(second > first);
+        let claimed = queue.pop(Duration::from_secs(60)).expect("Failed to pop").expect("Queue should not be empty");
+        assert_eq!((claimed.id, claimed.attempts), (second, 1));
+        assert!(queue.ack(&stale).is_ok_and(|acked|!acked));
+        assert!(queue.nack(&stale).is_ok_and(|nacked|!nacked));
+        assert!(queue.ack(&claimed).is_ok_and(|acked|acked));
+    }
+
+    #[test]
+    fn test_queue_huge_visibility_timeout() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let queue = PersistentQueue::<String, JobSpec>::new(db_connection, "jobs");
+        assert!(queue.initialize().is_ok());
+
+        // a timeout past the end of time hides the message for ever rather than wrapping into the past
+        assert!(queue.push(&"first".to_string()).is_ok());
+        assert!(queue.pop(Duration::MAX).expect("Failed to pop").is_some());
+        assert!(queue.pop(Duration::from_secs(60)).expect("Failed to pop").is_none());
+    }
+}
@@ -0,0 +1,146 @@
+use std::{sync::Arc, time::Duration};
+use debug_ignore::DebugIgnore;
+use sqlite_::ConnectionWithFullMutex;
+use sqlite_::State::Row;
+use crate::persistence_adapter::PersistenceError;
+use crate::persistence_adapter::clock::{Clock, SystemClock};
+use super::quote_identifier;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed { remaining: u64 }, // whole tokens left in the bucket
+    Limited { retry_after: Duration } // when enough tokens will have refilled for the same cost
+}
+
+// Token buckets per key stored in a table, so limits hold across restarts and across every process sharing
+// the database. Each key's bucket holds up to capacity tokens and refills at refill_per_second, a full bucket
+// is created on first use
+#[derive(Debug, Clone)]
+pub struct PersistentRateLimiter {
+    connection: DebugIgnore<Arc<ConnectionWithFullMutex>>,
+    table_name: String,
+    capacity: f64,
+    refill_per_millisecond: f64,
+    clock: Arc<dyn Clock>
+}
+
+impl PersistentRateLimiter {
+    pub fn new(connection: Arc<ConnectionWithFullMutex>, table_name: &str, capacity: u64, refill_per_second: f64) -> Self {
+        PersistentRateLimiter { connection: DebugIgnore(connection), table_name: table_name.to_string(), capacity: capacity as f64, refill_per_millisecond: refill_per_second / 1000.0, clock: Arc::new(SystemClock {}) }
+    }
+
+    // the clock refills are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn initialize(&self) -> Result<(), PersistenceError> {
+        self.connection.execute(format!("CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, tokens REAL NOT NULL, updated_at INTEGER NOT NULL)", quote_identifier(&self.table_name)))?;
+        Ok(())
+    }
+
+    // Takes cost tokens from key's bucket if it has them. Refill and take are a single statement, so
+    // concurrent callers can never spend the same tokens twice
+    pub fn try_acquire(&self, key: &str, cost: u64) -> Result<RateLimitDecision, PersistenceError> {
+        let cost = cost as f64;
+        let now = self.clock.now_millis();
+        if cost > self.capacity {
+            return Ok(RateLimitDecision::Limited { retry_after: Duration::MAX });
+        }
+        let refilled = format!("min(:capacity, {}.tokens + max(0, :now - {}.updated_at) * :rate)", quote_identifier(&self.table_name), quote_identifier(&self.table_name));
+        let command = format!(
+            "INSERT INTO {0} (key, tokens, updated_at) VALUES (:key, :capacity - :cost, :now) ON CONFLICT(key) DO UPDATE SET tokens = {refilled} - :cost, updated_at = :now WHERE {refilled} >= :cost RETURNING tokens",
+            quote_identifier(&self.table_name)
+        );
+        let mut statement = self.connection.prepare(command)?;
+        statement.bind((":key", key))?;
+        statement.bind((":capacity", self.capacity))?;
+        statement.bind((":cost", cost))?;
+        statement.bind((":now", now))?;
+        statement.bind((":rate", self.refill_per_millisecond))?;
+        if statement.next()? == Row {
+            return Ok(RateLimitDecision::Allowed { remaining: statement.read::<f64, usize>(0)?.max(0.0) as u64 });
+        }
+        drop(statement);
+
+        let tokens = self.tokens_at(key, now)?.unwrap_or(self.capacity);
+        let missing_millis = ((cost - tokens) / self.refill_per_millisecond).ceil();
+        let retry_after = match missing_millis.is_finite() {
+            true => Duration::from_millis(missing_millis.max(0.0) as u64),
+            false => Duration::MAX // never refills
+        };
+        Ok(RateLimitDecision::Limited { retry_after })
+    }
+
+    // the tokens in key's bucket now, capacity for a key that hasn't been used
+    pub fn remaining(&self, key: &str) -> Result<u64, PersistenceError> {
+        Ok(self.tokens_at(key, self.clock.now_millis())?.unwrap_or(self.capacity) as u64)
+    }
+
+    // refills key's bucket, e.g. after a successful login
+    pub fn reset(&self, key: &str) -> Result<(), PersistenceError> {
+        let mut statement = self.connection.prepare(format!("DELETE FROM {} WHERE key = ?", quote_identifier(&self.table_name)))?;
+        statement.bind((1, key))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    // Deletes the buckets that have refilled completely, they behave the same as missing ones. Returns the number deleted
+    pub fn purge_full(&self) -> Result<u64, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("DELETE FROM {} WHERE tokens + max(0, :now - updated_at) * :rate >= :capacity RETURNING 1", quote_identifier(&self.table_name)))?;
+        statement.bind((":now", self.clock.now_millis()))?;
+        statement.bind((":rate", self.refill_per_millisecond))?;
+        statement.bind((":capacity", self.capacity))?;
+        let mut deleted = 0;
+        while statement.next()? == Row {
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    fn tokens_at(&self, key: &str, now: i64) -> Result<Option<f64>, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("SELECT tokens, updated_at FROM {} WHERE key = ?", quote_identifier(&self.table_name)))?;
+        statement.bind((1, key))?;
+        if statement.next()? != Row {
+            return Ok(None);
+        }
+        let (tokens, updated_at) = (statement.read::<f64, usize>(0)?, statement.read::<i64, usize>(1)?);
+        Ok(Some(self.capacity.min(tokens + (now - updated_at).max(0) as f64 * self.refill_per_millisecond)))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::sqlite::{PersistentRateLimiter, RateLimitDecision};
+    use crate::tests::sqlite_connection;
+
+    #[test]
+    fn test_rate_limiter() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let limiter = PersistentRateLimiter::new(db_connection.clone(), "rate_limits", 3, 1.0).with_clock(clock.clone());
+        limiter.initialize().expect("Failed to initialize");
+
+        assert_eq!(limiter.try_acquire("alice", 1).ok(), Some(RateLimitDecision::Allowed { remaining: 2 }));
+        assert_eq!(limiter.try_acquire("alice", 2).ok(), Some(RateLimitDecision::Allowed { remaining: 0 }));
+        assert_eq!(limiter.try_acquire("alice", 1).ok(), Some(RateLimitDecision::Limited { retry_after: Duration::from_secs(1) }));
+        assert_eq!(limiter.try_acquire("bob", 3).ok(), Some(RateLimitDecision::Allowed { remaining: 0 }));
+        assert_eq!(limiter.try_acquire("bob", 4).ok(), Some(RateLimitDecision::Limited { retry_after: Duration::MAX }));
+
+        // the buckets outlive the limiter
+        clock.advance(Duration::from_millis(1_500));
+        let limiter = PersistentRateLimiter::new(db_connection, "rate_limits", 3, 1.0).with_clock(clock.clone());
+        assert_eq!(limiter.remaining("alice").ok(), Some(1));
+        assert_eq!(limiter.try_acquire("alice", 1).ok(), Some(RateLimitDecision::Allowed { remaining: 0 }));
+        assert_eq!(limiter.try_acquire("alice", 1).ok(), Some(RateLimitDecision::Limited { retry_after: Duration::from_millis(500) }));
+
+        assert!(limiter.reset("alice").is_ok());
+        assert_eq!(limiter.remaining("alice").ok(), Some(3));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(limiter.purge_full().ok(), Some(1));
+    }
+}
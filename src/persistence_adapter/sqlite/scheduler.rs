@@ -0,0 +1,396 @@
+use std::{fmt::Display, sync::Arc, time::Duration};
+use debug_ignore::DebugIgnore;
+use sqlite_::{ConnectionWithFullMutex, Statement};
+use sqlite_::State::Row;
+use crate::persistence_adapter::PersistenceError;
+use crate::persistence_adapter::clock::{Clock, SystemClock};
+use super::{quote_identifier, transaction::Savepoint};
+
+const MINUTE_MILLIS: i64 = 60_000;
+const DAY_MINUTES: i64 = 24 * 60;
+// how far ahead next_after looks before deciding a cron schedule never fires, e.g. "0 0 30 2 *"
+const SEARCH_DAYS: i64 = 8 * 366;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleParseError {
+    pub message: String
+}
+
+impl Display for ScheduleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ScheduleParseError {}
+
+fn parse_error(message: String) -> ScheduleParseError {
+    ScheduleParseError { message }
+}
+
+// A five field cron expression in UTC: minute hour day-of-month month day-of-week, each a *, number,
+// range or comma separated list of them with an optional /step. Days of the week are 0-7 with both 0 and
+// 7 Sunday. When both day fields are restricted a day matching either one fires, like cron does
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    source: String,
+    minutes: u64, // bit n set if n is allowed
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    either_day: bool
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, ScheduleParseError> {
+        let expanded = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other
+        };
+        let [minutes, hours, days, months, weekdays] = expanded.split_whitespace().collect::<Vec<_>>()[..] else {
+            return Err(parse_error(format!("{expression:?} doesn't have 5 fields")));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Cron {
+            source: expression.trim().to_string(),
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            either_day: !days.starts_with('*') && !weekdays.starts_with('*')
+        })
+    }
+
+    // the first whole minute strictly after millis (unix milliseconds) that matches, None if there's none
+    pub fn next_after(&self, millis: i64) -> Option<i64> {
+        let first = millis.div_euclid(MINUTE_MILLIS) + 1;
+        let (mut day, mut from_minute) = (first.div_euclid(DAY_MINUTES), first.rem_euclid(DAY_MINUTES));
+        for _ in 0..SEARCH_DAYS {
+            if self.matches_day(day) {
+                let found = (from_minute / 60..24).filter(|hour|self.hours & (1 << hour) != 0).find_map(|hour|{
+                    let first_minute = if hour == from_minute / 60 { from_minute % 60 } else { 0 };
+                    (first_minute..60).find(|minute|self.minutes & (1 << minute) != 0).map(|minute|hour * 60 + minute)
+                });
+                if let Some(minute_of_day) = found {
+                    return (day * DAY_MINUTES + minute_of_day).checked_mul(MINUTE_MILLIS);
+                }
+            }
+            day += 1;
+            from_minute = 0;
+        }
+        None
+    }
+
+    fn matches_day(&self, days_since_epoch: i64) -> bool {
+        let (_, month, day) = civil_from_days(days_since_epoch);
+        let weekday = (days_since_epoch + 4).rem_euclid(7); // 1970-01-01 was a Thursday
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let (day_matches, weekday_matches) = (self.days & (1 << day) != 0, self.weekdays & (1 << weekday) != 0);
+        match self.either_day {
+            true => day_matches || weekday_matches,
+            false => day_matches && weekday_matches
+        }
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, ScheduleParseError> {
+    let number = |s: &str|s.parse::<u32>().ok().filter(|n|(min..=max).contains(n)).ok_or_else(||parse_error(format!("{s:?} isn't a number from {min} to {max}")));
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s|*s > 0).ok_or_else(||parse_error(format!("invalid step in {part:?}")))?),
+            None => (part, 1)
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (number(from)?, number(to)?),
+            None if part.contains('/') => (number(range)?, max),
+            None => (number(range)?, number(range)?)
+        };
+        if from > to {
+            return Err(parse_error(format!("{part:?} is an empty range")));
+        }
+        bits |= (from..=to).step_by(step as usize).fold(0, |bits, n|bits | 1 << n);
+    }
+    Ok(bits)
+}
+
+// year, month (1-12) and day (1-31) of a day counted from 1970-01-01, from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    (year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Every(Duration), // written "@every 90s", with ms, s, m or h
+    Cron(Cron)
+}
+
+impl Schedule {
+    pub fn parse(schedule: &str) -> Result<Self, ScheduleParseError> {
+        let Some(interval) = schedule.trim().strip_prefix("@every ") else {
+            return Ok(Schedule::Cron(Cron::parse(schedule)?));
+        };
+        let interval = interval.trim();
+        let split = interval.find(|c: char|!c.is_ascii_digit()).unwrap_or(interval.len());
+        let amount = interval[..split].parse::<u64>().map_err(|_|parse_error(format!("invalid interval {interval:?}")))?;
+        let interval = match &interval[split..] {
+            "ms" => Duration::from_millis(amount),
+            "s" => Duration::from_secs(amount),
+            "m" => Duration::from_secs(amount.checked_mul(60).ok_or_else(||parse_error(format!("interval {interval:?} is too long")))?),
+            "h" => Duration::from_secs(amount.checked_mul(3600).ok_or_else(||parse_error(format!("interval {interval:?} is too long")))?),
+            unit => return Err(parse_error(format!("unknown interval unit {unit:?}, use ms, s, m or h")))
+        };
+        if interval.is_zero() {
+            return Err(parse_error("the interval must be longer than 0".to_string()));
+        }
+        Ok(Schedule::Every(interval))
+    }
+
+    // the next run strictly after millis, None if the schedule never fires again, also when the next run
+    // is past what an i64 of milliseconds holds
+    pub fn next_after(&self, millis: i64) -> Option<i64> {
+        match self {
+            Schedule::Every(interval) => i64::try_from(interval.as_millis()).ok().and_then(|interval|millis.checked_add(interval)),
+            Schedule::Cron(cron) => cron.next_after(millis)
+        }
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Every(interval) => write!(f, "@every {}ms", interval.as_millis()),
+            Schedule::Cron(cron) => f.write_str(&cron.source)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledTask {
+    pub name: String,
+    pub schedule: Schedule,
+    pub payload: Vec<u8>,
+    pub next_run: Option<i64> // milliseconds since the unix epoch, None once the schedule won't fire again
+}
+
+// Recurring tasks stored in a table, for workers in any number of processes sharing the database. Each
+// due run is claimed by exactly one worker: due_tasks moves a task's next_run forward only if no other
+// worker did since it was read
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    connection: DebugIgnore<Arc<ConnectionWithFullMutex>>,
+    table_name: String,
+    clock: Arc<dyn Clock>
+}
+
+impl Scheduler {
+    pub fn new(connection: Arc<ConnectionWithFullMutex>, table_name: &str) -> Self {
+        Scheduler { connection: DebugIgnore(connection), table_name: table_name.to_string(), clock: Arc::new(SystemClock {}) }
+    }
+
+    // the clock runs are scheduled and found due with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn initialize(&self) -> Result<(), PersistenceError> {
+        self.connection.execute(format!("CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, schedule TEXT NOT NULL, payload BLOB NOT NULL, next_run INTEGER)", quote_identifier(&self.table_name)))?;
+        Ok(())
+    }
+
+    // Adds a task or replaces its schedule and payload. Its next run is kept if the schedule didn't change,
+    // so registering the same tasks again at every startup doesn't move them
+    pub fn schedule(&self, name: &str, schedule: &Schedule, payload: &[u8]) -> Result<(), PersistenceError> {
+        let command = format!(
+            "INSERT INTO {0} (name, schedule, payload, next_run) VALUES (:name, :schedule, :payload, :next_run) ON CONFLICT(name) DO UPDATE SET payload = excluded.payload, schedule = excluded.schedule, next_run = CASE WHEN {0}.schedule = excluded.schedule THEN {0}.next_run ELSE excluded.next_run END",
+            quote_identifier(&self.table_name)
+        );
+        let mut statement = self.connection.prepare(command)?;
+        statement.bind((":name", name))?;
+        statement.bind((":schedule", schedule.to_string().as_str()))?;
+        statement.bind((":payload", payload))?;
+        statement.bind((":next_run", schedule.next_after(self.clock.now_millis())))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    // returns whether there was a task to remove
+    pub fn unschedule(&self, name: &str) -> Result<bool, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("DELETE FROM {} WHERE name = ? RETURNING name", quote_identifier(&self.table_name)))?;
+        statement.bind((1, name))?;
+        Ok(statement.next()? == Row)
+    }
+
+    // every task, by name
+    pub fn tasks(&self) -> Result<Vec<ScheduledTask>, PersistenceError> {
+        let mut statement = self.connection.prepare(format!("SELECT name, schedule, payload, next_run FROM {} ORDER BY name", quote_identifier(&self.table_name)))?;
+        let mut tasks = Vec::new();
+        while statement.next()? == Row {
+            tasks.push(Self::read_task(&statement)?);
+        }
+        Ok(tasks)
+    }
+
+    // Claims up to limit tasks whose next run has come, oldest first, in one savepoint, and moves each
+    // one's next run to its first scheduled time after now. Runs missed while no worker was polling are
+    // collapsed into one. The returned tasks carry their new next_run
+    pub fn due_tasks(&self, limit: usize) -> Result<Vec<ScheduledTask>, PersistenceError> {
+        let savepoint = Savepoint::on(&self.connection, "due_tasks")?;
+        let tasks = self.claim_due(limit)?;
+        savepoint.release()?;
+        Ok(tasks)
+    }
+
+    fn claim_due(&self, limit: usize) -> Result<Vec<ScheduledTask>, PersistenceError> {
+        let now = self.clock.now_millis();
+        let mut select = self.connection.prepare(format!("SELECT name, schedule, payload, next_run FROM {} WHERE next_run <= ? ORDER BY next_run, name LIMIT {limit}", quote_identifier(&self.table_name)))?;
+        select.bind((1, now))?;
+        let mut due = Vec::new();
+        while select.next()? == Row {
+            due.push(Self::read_task(&select)?);
+        }
+        drop(select);
+
+        let mut claimed = Vec::new();
+        for mut task in due {
+            let next_run = task.schedule.next_after(now);
+            let mut claim = self.connection.prepare(format!("UPDATE {} SET next_run = ? WHERE name = ? AND next_run = ? RETURNING name", quote_identifier(&self.table_name)))?;
+            claim.bind((1, next_run))?;
+            claim.bind((2, task.name.as_str()))?;
+            claim.bind((3, task.next_run))?;
+            if claim.next()? == Row {
+                task.next_run = next_run;
+                claimed.push(task);
+            }
+        }
+        Ok(claimed)
+    }
+
+    fn read_task(statement: &Statement) -> Result<ScheduledTask, PersistenceError> {
+        let schedule = statement.read::<String, usize>(1)?;
+        Ok(ScheduledTask {
+            name: statement.read::<String, usize>(0)?,
+            schedule: Schedule::parse(&schedule).map_err(|e|PersistenceError::Backend { message: format!("Invalid stored schedule {schedule:?}: {e}") })?,
+            payload: statement.read::<Vec<u8>, usize>(2)?,
+            next_run: statement.read::<Option<i64>, usize>(3)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::sqlite::{Cron, Schedule, Scheduler, SqlitePersistence};
+    use crate::tests::sqlite_connection;
+
+    const MINUTE: i64 = 60_000;
+    // 2024-02-28 23:58 UTC, a Wednesday
+    const START: i64 = 1_709_164_680_000;
+
+    #[test]
+    fn test_cron_next_after() {
+        let next = |expression: &str, after: i64|Cron::parse(expression).expect("Failed to parse").next_after(after);
+        assert_eq!(next("* * * * *", START), Some(START + MINUTE));
+        assert_eq!(next("* * * * *", START + 1), Some(START + MINUTE));
+        assert_eq!(next("*/15 * * * *", START), Some(START + 2 * MINUTE));
+        // leap day, then the first of the month
+        assert_eq!(next("30 12 29 2 *", START), Some(START + 2 * MINUTE + (12 * 60 + 30) * MINUTE));
+        assert_eq!(next("0 0 1 * *", START), Some(START + 2 * MINUTE + 24 * 60 * MINUTE));
+        // restricted day of month and day of week fire on either, Friday comes before the 15th
+        assert_eq!(next("0 0 15 * 5", START), Some(START + 2 * MINUTE + 24 * 60 * MINUTE));
+        assert_eq!(next("0 9 * * 1-5", START), Some(START + 2 * MINUTE + 9 * 60 * MINUTE));
+        assert_eq!(next("0 0 30 2 *", START), None);
+        assert_eq!(next("@weekly", START), Some(START + 2 * MINUTE + 3 * 24 * 60 * MINUTE));
+
+        for invalid in ["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *", "a * * * *"] {
+            assert!(Cron::parse(invalid).is_err(), "{invalid}");
+        }
+        assert_eq!(Schedule::parse("@every 90s").ok(), Some(Schedule::Every(Duration::from_secs(90))));
+        assert!(Schedule::parse("@every 5 days").is_err());
+    }
+
+    #[test]
+    fn test_due_tasks() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_millis(START as u64)));
+        let scheduler = Scheduler::new(db_connection.clone(), "schedules").with_clock(clock.clone());
+        scheduler.initialize().expect("Failed to initialize");
+        let every_minute = Schedule::parse("* * * * *").expect("Failed to parse");
+        assert!(scheduler.schedule("report", &every_minute, b"daily report").is_ok());
+        assert!(scheduler.schedule("sync", &Schedule::Every(Duration::from_secs(300)), b"").is_ok());
+        assert!(scheduler.due_tasks(10).is_ok_and(|tasks|tasks.is_empty()));
+
+        // two workers polling the same due run: only one gets it
+        clock.advance(Duration::from_secs(90));
+        let other_worker = Scheduler::new(db_connection, "schedules").with_clock(clock.clone());
+        let claimed = scheduler.due_tasks(10).expect("Failed to claim");
+        assert_eq!(claimed.iter().map(|t|(t.name.as_str(), t.payload.as_slice(), t.next_run)).collect::<Vec<_>>(), vec![("report", b"daily report".as_slice(), Some(START + 2 * MINUTE))]);
+        assert!(other_worker.due_tasks(10).is_ok_and(|tasks|tasks.is_empty()));
+
+        // registering again at startup keeps the next run, a new schedule replaces it
+        assert!(scheduler.schedule("report", &every_minute, b"daily report").is_ok());
+        assert_eq!(scheduler.tasks().ok().and_then(|tasks|tasks[0].next_run), Some(START + 2 * MINUTE));
+
+        // missed runs are collapsed into one
+        clock.advance(Duration::from_secs(600));
+        let claimed = other_worker.due_tasks(10).expect("Failed to claim");
+        assert_eq!(claimed.iter().map(|t|t.name.as_str()).collect::<Vec<_>>(), vec!["report", "sync"]);
+        assert!(scheduler.due_tasks(10).is_ok_and(|tasks|tasks.is_empty()));
+        assert!(scheduler.unschedule("sync").is_ok_and(|removed|removed));
+        assert_eq!(scheduler.tasks().map(|tasks|tasks.len()).ok(), Some(1));
+    }
+
+    #[test]
+    fn test_schedule_overflow() {
+        assert!(Schedule::parse(&format!("@every {}m", u64::MAX / 59)).is_err());
+        assert!(Schedule::parse(&format!("@every {}h", u64::MAX / 3599)).is_err());
+        assert_eq!(Schedule::parse("@every 2h").ok(), Some(Schedule::Every(Duration::from_secs(7200))));
+
+        let every = Schedule::Every(Duration::from_secs(u64::MAX));
+        assert_eq!(every.next_after(START), None);
+        assert_eq!(Schedule::Every(Duration::from_millis(10)).next_after(i64::MAX - 5), None);
+        assert_eq!(Cron::parse("* * * * *").expect("Failed to parse").next_after(i64::MAX - 5), None);
+    }
+
+    #[test]
+    fn test_due_tasks_in_transaction() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_millis(START as u64)));
+        let scheduler = Scheduler::new(db_connection.clone(), "schedules").with_clock(clock.clone());
+        scheduler.initialize().expect("Failed to initialize");
+        assert!(scheduler.schedule("report", &Schedule::parse("* * * * *").expect("Failed to parse"), b"").is_ok());
+        clock.advance(Duration::from_secs(90));
+
+        // the claim nests in a caller's transaction and is undone with it
+        let persistence = SqlitePersistence::new(db_connection, "test_table");
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert!(scheduler.due_tasks(10).is_ok_and(|tasks|tasks.len() == 1));
+        assert!(transaction.rollback().is_ok());
+        assert!(scheduler.due_tasks(10).is_ok_and(|tasks|tasks.len() == 1));
+        assert!(scheduler.due_tasks(10).is_ok_and(|tasks|tasks.is_empty()));
+    }
+}
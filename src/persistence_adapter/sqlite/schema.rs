@@ -0,0 +1,148 @@
+use sha2::{Digest, Sha256};
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec, PersistenceType};
+use super::{checksum, tenant, to_hex, ttl, version, SqlitePersistence};
+
+// shared by every table on the connection, one row per table
+const SCHEMA_TABLE: &str = "_dmfg_schema";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaDrift {
+    Unrecorded, // no hash stored for the table yet, e.g. before the first record_schema_hash
+    Current,
+    Drifted { stored: String, compiled: String }
+}
+
+fn type_name(field: &PersistenceType) -> &'static str {
+    match field {
+        PersistenceType::String(_) => "string",
+        PersistenceType::Bytes(_) => "bytes",
+        PersistenceType::Integer(_) => "integer",
+        PersistenceType::UnsignedInteger(_) => "unsigned_integer",
+        PersistenceType::Float(_) => "float",
+        PersistenceType::Double(_) => "double",
+        #[cfg(feature = "decimal")]
+        PersistenceType::Decimal(_) => "decimal",
+        #[cfg(feature = "hashed")]
+        PersistenceType::Hashed(_) => "hashed"
+    }
+}
+
+impl SqlitePersistence {
+    // SHA-256 over the key field and every spec field's name and type, sorted by name so reordering the fields
+    // isn't drift, plus the columns this adapter adds for checksums, versions, ttls and tenants. The same
+    // spec and settings hash the same on every build and platform
+    pub fn schema_hash<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> String {
+        let mut fields = Spec::fields().iter().map(|f|(f.get_name(), type_name(f))).collect::<Vec<_>>();
+        if self.checksums {
+            fields.push((checksum::CHECKSUM_COLUMN, "checksum"));
+        }
+        if self.versioned {
+            fields.push((version::VERSION_COLUMN, "version"));
+        }
+        if self.ttl {
+            fields.push((ttl::EXPIRES_COLUMN, "expiry"));
+        }
+        if self.tenant.is_some() {
+            fields.push((tenant::TENANT_COLUMN, "tenant"));
+        }
+        fields.sort();
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"v1\0key\0");
+        hasher.update(Spec::key_field().as_bytes());
+        for (name, type_name) in fields {
+            hasher.update([0]);
+            hasher.update(name.as_bytes());
+            hasher.update([0]);
+            hasher.update(type_name.as_bytes());
+        }
+        to_hex(&hasher.finalize())
+    }
+
+    // the hash last recorded for this table, None if there isn't one
+    pub fn stored_schema_hash(&self) -> Result<Option<String>, PersistenceError> {
+        // checked first so reading doesn't create the metadata table
+        let command = "SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = ?";
+        let timer = self.time_statement(command, [&PersistenceData::String(SCHEMA_TABLE.to_string())]);
+        let mut exists = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        exists.bind((1, SCHEMA_TABLE)).map_err(|e|self.backend_error(e))?;
+        if exists.next().map_err(|e|self.backend_error(e))? != Row {
+            return Ok(None);
+        }
+        drop(timer);
+        let command = format!("SELECT hash FROM \"{SCHEMA_TABLE}\" WHERE table_name = ?");
+        let _timer = self.time_statement(&command, [&PersistenceData::String(self.table_name.clone())]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        statement.bind((1, self.table_name.as_str())).map_err(|e|self.backend_error(e))?;
+        match statement.next().map_err(|e|self.backend_error(e))? {
+            Row => Ok(Some(statement.read::<String, usize>(0).map_err(|e|self.backend_error(e))?)),
+            _ => Ok(None)
+        }
+    }
+
+    // Stores the compiled spec's hash as the table's schema, after initialize or once a migration has brought
+    // the table up to date with the spec
+    pub fn record_schema_hash<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> Result<(), PersistenceError> {
+        self.execute_timed(&format!("CREATE TABLE IF NOT EXISTS \"{SCHEMA_TABLE}\" (table_name TEXT PRIMARY KEY, hash TEXT NOT NULL, recorded_at INTEGER NOT NULL)"))?;
+        let command = format!("INSERT OR REPLACE INTO \"{SCHEMA_TABLE}\" (table_name, hash, recorded_at) VALUES (?, ?, ?)");
+        let values = [PersistenceData::String(self.table_name.clone()), PersistenceData::String(self.schema_hash::<Key, Data, Spec>()), PersistenceData::Integer(self.clock.now_millis())];
+        let _timer = self.time_statement(&command, &values);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        for (i, value) in values.iter().enumerate() {
+            SqlitePersistence::bind_data(&mut statement, i + 1, value).map_err(|e|self.backend_error(e))?;
+        }
+        statement.next().map_err(|e|self.backend_error(e))?;
+        Ok(())
+    }
+
+    // Compares the compiled spec with the recorded schema, for deciding at startup whether to migrate
+    pub fn schema_drift<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> Result<SchemaDrift, PersistenceError> {
+        let compiled = self.schema_hash::<Key, Data, Spec>();
+        Ok(match self.stored_schema_hash()? {
+            None => SchemaDrift::Unrecorded,
+            Some(stored) if stored == compiled => SchemaDrift::Current,
+            Some(stored) => SchemaDrift::Drifted { stored, compiled }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceData, PersistenceSpec, PersistenceType, SpecError};
+    use crate::persistence_adapter::sqlite::{SchemaDrift, SqlitePersistence};
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    // AllSupportedTypesPersistenceSpec with its fields in another order
+    struct ReorderedSpec;
+    impl PersistenceSpec<String, AllSupportedTypes> for ReorderedSpec {
+        fn fields() -> &'static [PersistenceType] {
+            &[PersistenceType::Double("double"), PersistenceType::Float("float"), PersistenceType::UnsignedInteger("unsigned_integer"), PersistenceType::Integer("integer"), PersistenceType::Bytes("bytes"), PersistenceType::String("string"), PersistenceType::String("key")]
+        }
+        fn key_field() -> &'static str { AllSupportedTypesPersistenceSpec::key_field() }
+        fn serialize_key(key: &String) -> PersistenceData { AllSupportedTypesPersistenceSpec::serialize_key(key) }
+        fn deserialize_key(key: &PersistenceData) -> Option<String> { AllSupportedTypesPersistenceSpec::deserialize_key(key) }
+        fn serialize_data(data: &AllSupportedTypes) -> Result<HashMap<&'static str, PersistenceData>, SpecError> { AllSupportedTypesPersistenceSpec::serialize_data(data) }
+        fn deserialize_data(data: HashMap<&'static str, PersistenceData>) -> Result<AllSupportedTypes, SpecError> { AllSupportedTypesPersistenceSpec::deserialize_data(data) }
+    }
+
+    #[test]
+    fn test_schema_drift() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let drift = |persistence: &SqlitePersistence|persistence.schema_drift::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>().expect("Failed to check drift");
+        assert_eq!(drift(&persistence), SchemaDrift::Unrecorded);
+        assert!(persistence.tables().is_ok_and(|tables|tables.is_empty()));
+
+        assert!(persistence.record_schema_hash::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>().is_ok());
+        assert_eq!(drift(&persistence), SchemaDrift::Current);
+        assert_eq!(persistence.schema_hash::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(), persistence.schema_hash::<String, AllSupportedTypes, ReorderedSpec>());
+        assert_eq!(drift(&SqlitePersistence::new(db_connection.clone(), "other_table")), SchemaDrift::Unrecorded);
+
+        let SchemaDrift::Drifted { stored, compiled } = drift(&persistence.clone().with_checksums()) else { panic!("Checksums should change the schema") };
+        assert_eq!(Some(stored), persistence.stored_schema_hash().expect("Failed to read hash"));
+        assert_eq!(compiled, persistence.clone().with_checksums().schema_hash::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>());
+    }
+}
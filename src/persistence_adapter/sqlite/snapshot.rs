@@ -0,0 +1,122 @@
+use std::{collections::VecDeque, ffi::CStr, marker::PhantomData, sync::Arc};
+use debug_ignore::DebugIgnore;
+use sqlite_::Connection;
+use sqlite_::State::Row;
+use sqlite3_sys as ffi;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec};
+use super::{quote_identifier, SqlitePersistence};
+
+// Iterates over a table as it was when SqlitePersistence::scan_snapshot was called. Rows are read a page
+// at a time on a connection of its own that keeps a read transaction open until the iterator is dropped.
+// Like scan, rows that fail to deserialize are skipped and recorded as the adapter's last error
+pub struct ScanSnapshot<Key, Data, Spec> {
+    persistence: SqlitePersistence,
+    page_size: usize,
+    rows: VecDeque<(Key, Data)>,
+    last_key: Option<PersistenceData>,
+    exhausted: bool,
+    _spec: PhantomData<Spec>
+}
+
+impl SqlitePersistence {
+    // Needs the database in WAL mode for writers to keep going while the snapshot is open, with a rollback
+    // journal the open read transaction blocks them until the iterator is dropped. Fails for in-memory databases
+    pub fn scan_snapshot<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, page_size: usize) -> Result<ScanSnapshot<Key, Data, Spec>, PersistenceError> {
+        let filename = unsafe { ffi::sqlite3_db_filename(self.connection.as_raw(), c"main".as_ptr()) };
+        let filename = match filename.is_null() {
+            true => "",
+            false => unsafe { CStr::from_ptr(filename) }.to_str().map_err(|e|self.backend_error(e))?
+        };
+        if filename.is_empty() {
+            return Err(self.backend_error("Snapshots need a database file"));
+        }
+
+        let connection = Connection::open_with_full_mutex(filename).map_err(|e|self.backend_error(e))?;
+        let mut persistence = self.clone();
+        persistence.connection = DebugIgnore(Arc::new(connection));
+        // the snapshot is taken by the first read after BEGIN, not by BEGIN itself
+        persistence.execute_timed("BEGIN")?;
+        persistence.execute_timed("SELECT count(*) FROM sqlite_schema")?;
+        Ok(ScanSnapshot { persistence, page_size: page_size.max(1), rows: VecDeque::new(), last_key: None, exhausted: false, _spec: PhantomData })
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>> ScanSnapshot<Key, Data, Spec> {
+    // reads the next page_size rows after the last key read, in key order
+    fn read_page(&mut self) -> Result<(), PersistenceError> {
+        let persistence = &self.persistence;
+        let key_field = Spec::fields().iter().find(|f|f.get_name() == Spec::key_field())
+            .ok_or_else(||PersistenceError::FieldNotAllowed { field: Spec::key_field().to_string() })?;
+
+        let mut command = format!("SELECT * FROM {}", quote_identifier(&persistence.table_name));
+        match self.last_key.is_some() {
+            true => command.push_str(&format!(" WHERE {} > ?{}", quote_identifier(Spec::key_field()), persistence.and_row_scope())),
+            false => command.push_str(&persistence.where_row_scope())
+        }
+        command.push_str(&format!(" ORDER BY {} LIMIT {}", quote_identifier(Spec::key_field()), self.page_size));
+
+        let _timer = persistence.time_statement(&command, &self.last_key);
+        let mut statement = persistence.connection.prepare(&command).map_err(|e|persistence.backend_error(e))?;
+        if let Some(last_key) = &self.last_key {
+            SqlitePersistence::bind_data(&mut statement, 1, last_key).map_err(|e|persistence.backend_error(e))?;
+        }
+        persistence.bind_tenant(&mut statement).map_err(|e|persistence.backend_error(e))?;
+
+        let mut read = 0;
+        while statement.next().map_err(|e|persistence.backend_error(e))? == Row {
+            read += 1;
+            self.last_key = Some(SqlitePersistence::read_field(key_field, &statement, Spec::key_field())?);
+            match persistence.read_row::<Key, Data, Spec>(&statement) {
+                Ok(row) => self.rows.push_back(row),
+                Err(e) => { persistence.record_error(e); }
+            }
+        }
+        self.exhausted = read < self.page_size;
+        Ok(())
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>> Iterator for ScanSnapshot<Key, Data, Spec> {
+    type Item = (Key, Data);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.rows.is_empty() && !self.exhausted {
+            if let Err(e) = self.read_page() {
+                self.persistence.record_error(e);
+                self.exhausted = true;
+            }
+        }
+        self.rows.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_scan_snapshot() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+        assert!(db_connection.execute("PRAGMA journal_mode=WAL").is_ok());
+
+        let persistence = SqlitePersistence::new(db_connection, "test_table");
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+
+        let entry = AllSupportedTypes::with_integer(-1);
+        for key in ["a", "b", "c"] {
+            assert!(adapter.store(&key.to_string(), &entry).is_ok());
+        }
+
+        let mut snapshot = persistence.scan_snapshot::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>(2).expect("Failed to open snapshot");
+        assert_eq!(snapshot.next().map(|(k, _)|k), Some("a".to_string()));
+
+        // writes after the snapshot was taken aren't visible to it
+        assert!(adapter.store(&"d".to_string(), &entry).is_ok());
+        assert!(adapter.delete(&"c".to_string()).is_ok());
+        assert_eq!(snapshot.map(|(k, _)|k).collect::<Vec<_>>(), vec!["b".to_string(), "c".to_string()]);
+        assert_eq!(adapter.scan(0, None).into_iter().map(|(k, _)|k).collect::<Vec<_>>(), vec!["a".to_string(), "b".to_string(), "d".to_string()]);
+    }
+}
@@ -0,0 +1,127 @@
+use sqlite_::State::Row;
+use sqlite_::Statement;
+use crate::persistence_adapter::{FieldStats, PersistenceAdapterStats, PersistenceData, PersistenceError, TableStats};
+use super::{quote_identifier, SqlitePersistence};
+
+impl SqlitePersistence {
+    // Bytes of the pages holding the table or index, from the dbstat virtual table. None if sqlite was built
+    // without it
+    fn dbstat_size(&self, name: &str) -> Option<u64> {
+        let command = "SELECT coalesce(sum(pgsize), 0) FROM dbstat WHERE name = ?";
+        let _timer = self.time_statement(command, [&PersistenceData::String(name.to_string())]);
+        let mut statement = self.connection.prepare(command).ok()?;
+        statement.bind((1, name)).ok()?;
+        statement.next().ok()?;
+        statement.read::<i64, usize>(0).ok().map(|size|size as u64)
+    }
+}
+
+// The row count is the tenant's with a tenant set, the sizes are always the whole table's. sqlite doesn't
+// record when a table was written, so last_write is None
+impl PersistenceAdapterStats for SqlitePersistence {
+    fn table_stats(&self) -> Result<TableStats, PersistenceError> {
+        let command = format!("SELECT count(*) FROM {}{}", quote_identifier(&self.table_name), self.where_row_scope());
+        let _timer = self.time_statement(&command, []);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        statement.next().map_err(|e|self.backend_error(e))?;
+        let row_count = statement.read::<i64, usize>(0).map_err(|e|self.backend_error(e))? as u64;
+
+        let command = "SELECT name FROM pragma_index_list(?) ORDER BY name";
+        let _timer = self.time_statement(command, [&PersistenceData::String(self.table_name.clone())]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        statement.bind((1, self.table_name.as_str())).map_err(|e|self.backend_error(e))?;
+        let mut index_sizes = Vec::new();
+        while statement.next().map_err(|e|self.backend_error(e))? == Row {
+            let name = statement.read::<String, usize>(0).map_err(|e|self.backend_error(e))?;
+            index_sizes.push((name.clone(), self.dbstat_size(&name)));
+        }
+
+        Ok(TableStats { row_count, approx_size_bytes: self.dbstat_size(&self.table_name), index_sizes, last_write: None })
+    }
+
+    // Exact counts over the tenant's rows with a tenant set. min and max come back as the type sqlite stored
+    // them with, an UnsignedInteger field reads as Integer and a Float as Double, and encrypted fields give
+    // the order of their ciphertext
+    fn analyze_field(&self, field: &str) -> Result<FieldStats, PersistenceError> {
+        // an unknown name in double quotes would be read as a string literal, check it's a column first
+        if !self.table_columns()?.iter().any(|(name, _)|name == field) {
+            return Err(PersistenceError::FieldNotAllowed { field: field.to_string() });
+        }
+        let column = quote_identifier(field);
+        let command = format!("SELECT min({column}), max({column}), count(DISTINCT {column}), count(*) - count({column}) FROM {}{}", quote_identifier(&self.table_name), self.where_row_scope());
+        let _timer = self.time_statement(&command, []);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        statement.next().map_err(|e|self.backend_error(e))?;
+        Ok(FieldStats {
+            min: read_stored(&statement, 0).map_err(|e|self.backend_error(e))?,
+            max: read_stored(&statement, 1).map_err(|e|self.backend_error(e))?,
+            distinct_estimate: statement.read::<i64, usize>(2).map_err(|e|self.backend_error(e))? as u64,
+            null_count: statement.read::<i64, usize>(3).map_err(|e|self.backend_error(e))? as u64
+        })
+    }
+}
+
+fn read_stored(statement: &Statement, column: usize) -> sqlite_::Result<Option<PersistenceData>> {
+    Ok(match statement.column_type(column)? {
+        sqlite_::Type::Integer => Some(PersistenceData::Integer(statement.read(column)?)),
+        sqlite_::Type::Float => Some(PersistenceData::Double(statement.read(column)?)),
+        sqlite_::Type::String => Some(PersistenceData::String(statement.read(column)?)),
+        sqlite_::Type::Binary => Some(PersistenceData::Bytes(statement.read(column)?)),
+        sqlite_::Type::Null => None
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterStats, PersistenceData, PersistenceError};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_table_stats() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        let row = AllSupportedTypes { string: "s".repeat(1_000), bytes: vec![1; 1_000], ..AllSupportedTypes::with_integer(1) };
+        for i in 0..20 {
+            assert!(adapter.store(&i.to_string(), &row).is_ok());
+        }
+
+        let stats = persistence.table_stats().expect("Failed to get stats");
+        assert_eq!(stats.row_count, 20);
+        // the string primary key gets an automatic index
+        assert_eq!(stats.index_sizes.len(), 1);
+        if let Some(size) = stats.approx_size_bytes {
+            assert!(size >= 40_000);
+        }
+        assert!(stats.last_write.is_none());
+    }
+
+    #[test]
+    fn test_analyze_field() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        assert!(persistence.analyze_field("integer").is_ok_and(|stats|stats.min.is_none() && stats.distinct_estimate == 0));
+
+        let row = |integer|AllSupportedTypes { string: format!("s{}", integer % 3), bytes: vec![1], integer, unsigned_integer: 1, float: 1.0, double: 1.0 };
+        for i in -5..5 {
+            assert!(adapter.store(&i.to_string(), &row(i)).is_ok());
+        }
+        assert!(db_connection.execute("UPDATE \"test_table\" SET string = NULL WHERE integer > 2").is_ok());
+
+        let integer = persistence.analyze_field("integer").expect("Failed to analyze");
+        assert_eq!(integer.min.and_then(|m|m.to_int()), Some(-5));
+        assert_eq!(integer.max.and_then(|m|m.to_int()), Some(4));
+        assert_eq!((integer.distinct_estimate, integer.null_count), (10, 0));
+
+        let string = persistence.analyze_field("string").expect("Failed to analyze");
+        assert_eq!(string.min.as_ref().and_then(PersistenceData::to_str), Some("s-1"));
+        assert_eq!((string.distinct_estimate, string.null_count), (5, 2));
+
+        assert!(matches!(persistence.analyze_field("missing"), Err(PersistenceError::FieldNotAllowed { .. })));
+    }
+}
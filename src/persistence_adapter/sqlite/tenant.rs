@@ -0,0 +1,92 @@
+use sqlite_::Statement;
+use super::SqlitePersistence;
+
+pub(super) const TENANT_COLUMN: &str = "_tenant";
+
+impl SqlitePersistence {
+    // Scopes the adapter to one tenant so a single table can serve many of them. initialize adds a _tenant
+    // column to the table and to its primary key, every row is stored with this tenant and every read, update
+    // and delete only sees rows of this tenant. Clone the adapter per request: persistence.clone().with_tenant(&context.tenant).
+    // Tables created without a tenant don't get the column added
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    // The rows a statement may see: the tenant condition, bound by name so it doesn't shift the positional
+    // placeholders before it, and with ttls the condition leaving out expired rows. Always appended after a
+    // statement's other conditions
+    pub(super) fn row_scope(&self) -> Option<String> {
+        match (self.tenant_only_condition(), self.live_condition()) {
+            (Some(tenant), Some(live)) => Some(format!("{tenant} AND {live}")),
+            (tenant, live) => tenant.or(live)
+        }
+    }
+
+    // the tenant condition alone, for statements that also reach expired rows
+    pub(super) fn tenant_only_condition(&self) -> Option<String> {
+        self.tenant.as_ref().map(|_|format!("\"{TENANT_COLUMN}\" = :tenant"))
+    }
+
+    pub(super) fn and_row_scope(&self) -> String {
+        self.row_scope().map(|c|format!(" AND {c}")).unwrap_or_default()
+    }
+
+    pub(super) fn where_row_scope(&self) -> String {
+        self.row_scope().map(|c|format!(" WHERE {c}")).unwrap_or_default()
+    }
+
+    pub(super) fn bind_tenant(&self, statement: &mut Statement) -> sqlite_::Result<()> {
+        match &self.tenant {
+            Some(tenant) => statement.bind((":tenant", tenant.as_str())),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceData, Query};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_tenants() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection, "test_table").with_checksums();
+        let (one, two) = (persistence.clone().with_tenant("one"), persistence.clone().with_tenant("two"));
+        let adapter_one: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &one;
+        let adapter_two: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &two;
+        adapter_one.initialize();
+
+        let entry = AllSupportedTypes { string: "one".to_string(), ..AllSupportedTypes::with_integer(1) };
+        let other = AllSupportedTypes{ string: "two".to_string(), ..entry.clone() };
+
+        // the same key in two tenants is two rows
+        assert!(adapter_one.store(&"a".to_string(), &entry).is_ok());
+        assert!(adapter_one.store(&"b".to_string(), &entry).is_ok());
+        assert!(adapter_two.store(&"a".to_string(), &other).is_ok());
+        assert!(adapter_one.store(&"a".to_string(), &entry).is_err());
+        assert_eq!(adapter_one.load(&"a".to_string()), Some(entry.clone()));
+        assert_eq!(adapter_two.load(&"a".to_string()), Some(other.clone()));
+        assert!(!adapter_two.contains(&"b".to_string()));
+        assert_eq!(adapter_one.scan(0, None).len(), 2);
+        assert_eq!(adapter_two.scan_range(Some(&"a".to_string()), None, None).len(), 1);
+        assert_eq!(PersistenceAdapterQueryable::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::query(&two, Query::GreaterThan("integer".to_string(), PersistenceData::Integer(0)), 0, None).len(), 1);
+
+        assert_eq!(adapter_two.update(&"b".to_string(), &other, None).ok(), Some(0));
+        assert_eq!(adapter_two.patch(&"a".to_string(), HashMap::from([("integer", PersistenceData::Integer(2))])).ok(), Some(1));
+        assert_eq!(adapter_one.load(&"a".to_string()).map(|a|a.integer), Some(1));
+        assert_eq!(adapter_two.delete(&"b".to_string()).ok(), Some(0));
+        assert_eq!(PersistenceAdapterQueryable::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::clear_where(&one, Query::Equals("key".to_string(), PersistenceData::String("a".to_string()))).ok(), Some(1));
+        assert_eq!(adapter_two.clear().ok(), Some(1));
+        assert_eq!(adapter_one.scan(0, None).len(), 1);
+        assert!(one.verify_all::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>().is_ok_and(|corrupted|corrupted.is_empty()));
+    }
+}
@@ -0,0 +1,133 @@
+use sqlite_::ConnectionWithFullMutex;
+use crate::persistence_adapter::PersistenceError;
+use super::{quote_identifier, SqlitePersistence};
+
+// An open transaction on the adapter's connection, rolled back when dropped without commit. Every
+// statement on the connection is part of it, including ones made through other adapters sharing the connection
+pub struct Transaction<'a> {
+    persistence: &'a SqlitePersistence,
+    finished: bool
+}
+
+// A savepoint around the statements of one write that must land together, released by release and rolled
+// back when dropped without it. Unlike a Transaction it nests, inside an open transaction only its own
+// changes are undone
+pub(super) struct Savepoint<'a> {
+    connection: &'a ConnectionWithFullMutex,
+    persistence: Option<&'a SqlitePersistence>, // the adapter whose last error failures are recorded as
+    name: &'static str,
+    released: bool
+}
+
+impl SqlitePersistence {
+    pub fn transaction(&self) -> Result<Transaction<'_>, PersistenceError> {
+        self.execute_timed("BEGIN")?;
+        Ok(Transaction { persistence: self, finished: false })
+    }
+
+    pub(super) fn savepoint(&self, name: &'static str) -> Result<Savepoint<'_>, PersistenceError> {
+        self.execute_timed(&format!("SAVEPOINT {}", quote_identifier(name)))?;
+        Ok(Savepoint { connection: &self.connection, persistence: Some(self), name, released: false })
+    }
+}
+
+impl<'a> Savepoint<'a> {
+    // a savepoint on a connection used without an adapter, e.g. by a Scheduler
+    pub(super) fn on(connection: &'a ConnectionWithFullMutex, name: &'static str) -> Result<Self, PersistenceError> {
+        connection.execute(format!("SAVEPOINT {}", quote_identifier(name)))?;
+        Ok(Savepoint { connection, persistence: None, name, released: false })
+    }
+
+    pub(super) fn release(mut self) -> Result<(), PersistenceError> {
+        self.released = true;
+        let command = format!("RELEASE {}", quote_identifier(self.name));
+        match self.persistence {
+            Some(persistence) => persistence.execute_timed(&command),
+            None => Ok(self.connection.execute(command)?)
+        }
+    }
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            let command = format!("ROLLBACK TO {name}; RELEASE {name}", name = quote_identifier(self.name));
+            let _ = match self.persistence {
+                Some(persistence) => persistence.execute_timed(&command),
+                None => self.connection.execute(command).map_err(PersistenceError::from)
+            };
+        }
+    }
+}
+
+impl Transaction<'_> {
+    pub fn commit(mut self) -> Result<(), PersistenceError> {
+        self.finished = true;
+        self.execute("COMMIT".to_string())
+    }
+
+    pub fn rollback(mut self) -> Result<(), PersistenceError> {
+        self.finished = true;
+        self.execute("ROLLBACK".to_string())
+    }
+
+    // Marks a point rollback_to can return to. Savepoints nest, names may be reused
+    // and refer to the most recent savepoint with that name
+    pub fn savepoint(&self, name: &str) -> Result<(), PersistenceError> {
+        self.execute(format!("SAVEPOINT {}", quote_identifier(name)))
+    }
+
+    // Undoes everything since the savepoint, including later savepoints. The savepoint itself stays, so
+    // the same batch can be retried and rolled back to again
+    pub fn rollback_to(&self, name: &str) -> Result<(), PersistenceError> {
+        self.execute(format!("ROLLBACK TO {}", quote_identifier(name)))
+    }
+
+    // Forgets the savepoint and every one after it, keeping their changes in the transaction
+    pub fn release(&self, name: &str) -> Result<(), PersistenceError> {
+        self.execute(format!("RELEASE {}", quote_identifier(name)))
+    }
+
+    fn execute(&self, command: String) -> Result<(), PersistenceError> {
+        self.persistence.execute_timed(&command)
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.execute("ROLLBACK".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::tests::{sqlite_persistence, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_savepoints() {
+        let (_temp_dir, persistence) = sqlite_persistence();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+
+        let entry = AllSupportedTypes::with_integer(-1);
+        let keys = ||adapter.scan(0, None).into_iter().map(|(k, _)|k).collect::<Vec<_>>();
+
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+        assert!(transaction.savepoint("batch \"1\"").is_ok());
+        assert!(adapter.store(&"b".to_string(), &entry).is_ok());
+        assert!(transaction.rollback_to("batch \"1\"").is_ok());
+        assert!(adapter.store(&"c".to_string(), &entry).is_ok());
+        assert!(transaction.release("batch \"1\"").is_ok());
+        assert!(transaction.rollback_to("batch \"1\"").is_err());
+        assert!(transaction.commit().is_ok());
+        assert_eq!(keys(), vec!["a".to_string(), "c".to_string()]);
+
+        let transaction = persistence.transaction().expect("Failed to begin");
+        assert!(adapter.delete(&"a".to_string()).is_ok());
+        drop(transaction);
+        assert_eq!(keys(), vec!["a".to_string(), "c".to_string()]);
+    }
+}
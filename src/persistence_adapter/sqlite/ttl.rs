@@ -0,0 +1,166 @@
+use std::time::Duration;
+use crate::persistence_adapter::{PersistenceAdapterTtl, PersistenceData, PersistenceError, PersistenceSpec, StoreError};
+use crate::persistence_adapter::clock::duration_millis;
+use super::{quote_identifier, ConflictPolicy, SqlitePersistence};
+
+pub(super) const EXPIRES_COLUMN: &str = "_expires_at";
+
+impl SqlitePersistence {
+    // Lets rows expire, see PersistenceAdapterTtl. initialize adds an _expires_at column in unix milliseconds,
+    // null for rows stored without a ttl. Expired rows are left out of every statement like rows of another
+    // tenant, until purge_expired deletes them. update and patch keep a row's expiry. Expiry is measured by
+    // the adapter's clock, see with_clock
+    pub fn with_ttl(mut self) -> Self {
+        self.ttl = true;
+        self
+    }
+
+    // the condition that leaves out expired rows, now is written inline so nothing has to be bound for it
+    pub(super) fn live_condition(&self) -> Option<String> {
+        self.ttl.then(||format!("(\"{EXPIRES_COLUMN}\" IS NULL OR \"{EXPIRES_COLUMN}\" > {})", self.clock.now_millis()))
+    }
+
+    // `, "_expires_at"` to append to an INSERT's column list, empty without ttls
+    pub(super) fn expires_column(&self) -> String {
+        match self.ttl {
+            true => format!(", \"{EXPIRES_COLUMN}\""),
+            false => String::new()
+        }
+    }
+
+    // the value matching expires_column for a row expiring ttl from now, or never
+    pub(super) fn expires_value(&self, ttl: Option<Duration>) -> String {
+        match (self.ttl, ttl) {
+            (true, Some(ttl)) => format!(", {}", self.clock.now_millis().saturating_add(duration_millis(ttl))),
+            (true, None) => ", NULL".to_string(),
+            (false, _) => String::new()
+        }
+    }
+
+    // Deletes key's row if it expired, so a new row can be stored under the key before the sweeper got to it
+    pub(super) fn remove_expired<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, serialized_key: &PersistenceData) -> Result<(), PersistenceError> {
+        if !self.ttl {
+            return Ok(());
+        }
+        let command = format!("DELETE FROM {} WHERE {} = ? AND \"{EXPIRES_COLUMN}\" <= {}{}", quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.clock.now_millis(), self.tenant_only_condition().map(|c|format!(" AND {c}")).unwrap_or_default());
+        let _timer = self.time_statement(&command, [serialized_key]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        SqlitePersistence::bind_data(&mut statement, 1, serialized_key).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        statement.next().map_err(|e|self.backend_error(e))?;
+        Ok(())
+    }
+}
+
+impl<Key, Data, Spec: PersistenceSpec<Key, Data>> PersistenceAdapterTtl<Key, Data, Spec> for SqlitePersistence {
+    fn store_with_ttl(&self, key: &Key, data: &Data, ttl: Duration) -> Result<(), StoreError> {
+        if !self.ttl {
+            return Err(StoreError { message: "TTLs aren't enabled, see with_ttl".to_string(), kind: None });
+        }
+        self.insert::<Key, Data, Spec>(key, data, Some(ttl), ConflictPolicy::Abort).map_err(StoreError::from)
+    }
+
+    fn purge_expired(&self) -> Result<u64, PersistenceError> {
+        if !self.ttl {
+            return Ok(0);
+        }
+        let command = format!("DELETE FROM {} WHERE \"{EXPIRES_COLUMN}\" <= {}{} RETURNING 1", quote_identifier(&self.table_name), self.clock.now_millis(), self.tenant_only_condition().map(|c|format!(" AND {c}")).unwrap_or_default());
+        let _timer = self.time_statement(&command, []);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        self.count_returned(&mut statement)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{sync::Arc, time::{Duration, UNIX_EPOCH}};
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceAdapterQueryable, PersistenceAdapterTtl, PersistenceData, Query};
+    use crate::persistence_adapter::clock::ManualClock;
+    use crate::persistence_adapter::retention::Retention;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{sqlite_connection, AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_ttl() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let persistence = Arc::new(SqlitePersistence::new(db_connection.clone(), "test_table").with_clock(clock.clone()).with_ttl());
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = persistence.as_ref();
+        let ttl: &dyn PersistenceAdapterTtl<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = persistence.as_ref();
+        adapter.initialize();
+        assert!(adapter.capabilities().supports_ttl);
+        let row = AllSupportedTypes::with_integer;
+        assert!(adapter.store(&"forever".to_string(), &row(1)).is_ok());
+        assert!(ttl.store_with_ttl(&"short".to_string(), &row(2), Duration::from_secs(10)).is_ok());
+        assert!(ttl.store_with_ttl(&"long".to_string(), &row(3), Duration::from_secs(60)).is_ok());
+        assert_eq!(adapter.scan(0, None).len(), 3);
+
+        clock.advance(Duration::from_secs(10));
+        assert!(adapter.load(&"short".to_string()).is_none());
+        assert!(!adapter.contains(&"short".to_string()));
+        assert!(adapter.contains(&"long".to_string()));
+        let queryable: &dyn PersistenceAdapterQueryable<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = persistence.as_ref();
+        assert_eq!(queryable.query(Query::GreaterThan("integer".to_string(), PersistenceData::Integer(1)), 0, None).len(), 1);
+        assert_eq!(adapter.update(&"short".to_string(), &row(9), None).ok(), Some(0));
+        // the expired row no longer holds its key
+        assert!(adapter.store(&"short".to_string(), &row(4)).is_ok());
+        assert_eq!(adapter.load(&"short".to_string()).map(|r|r.integer), Some(4));
+
+        // the sweeper deletes expired rows for good
+        let mut retention = Retention::new();
+        retention.register_expiring::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec, _>(persistence.clone());
+        clock.advance(Duration::from_secs(50));
+        assert!(!adapter.contains(&"long".to_string()));
+        assert_eq!(retention.enforce_retention().ok(), Some(1));
+        assert_eq!(retention.enforce_retention().ok(), Some(0));
+        assert_eq!(adapter.scan(0, None).into_iter().map(|(key, _)|key).collect::<Vec<_>>(), vec!["forever".to_string(), "short".to_string()]);
+
+        // without with_ttl rows can't be given one
+        let plain = SqlitePersistence::new(db_connection, "plain");
+        assert!(PersistenceAdapterTtl::<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec>::store_with_ttl(&plain, &"a".to_string(), &row(1), Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_ttl_existing_table() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let plain = SqlitePersistence::new(db_connection.clone(), "test_table");
+        let unexpiring: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &plain;
+        unexpiring.initialize();
+        assert!(unexpiring.store(&"old".to_string(), &AllSupportedTypes::with_integer(1)).is_ok());
+
+        // initialize adds the _expires_at column to the existing table, rows already stored never expire
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let persistence = SqlitePersistence::new(db_connection, "test_table").with_clock(clock.clone()).with_ttl();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        let ttl: &dyn PersistenceAdapterTtl<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        assert!(adapter.initialize().is_some());
+        assert!(ttl.store_with_ttl(&"new".to_string(), &AllSupportedTypes::with_integer(2), Duration::from_secs(10)).is_ok());
+        assert_eq!(adapter.scan(0, None).len(), 2);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(ttl.purge_expired().ok(), Some(1));
+        assert_eq!(ttl.purge_expired().ok(), Some(0));
+        assert_eq!(adapter.load(&"old".to_string()).map(|r|r.integer), Some(1));
+    }
+
+    #[test]
+    fn test_failed_insert_keeps_expired_row() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let persistence = SqlitePersistence::new(db_connection.clone(), "test_table").with_clock(clock.clone()).with_ttl();
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        let ttl: &dyn PersistenceAdapterTtl<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        assert!(ttl.store_with_ttl(&"a".to_string(), &AllSupportedTypes::with_integer(1), Duration::from_secs(10)).is_ok());
+        clock.advance(Duration::from_secs(10));
+
+        // the expired row is only removed along with an insert that goes through
+        assert!(db_connection.execute("CREATE TRIGGER refuse BEFORE INSERT ON test_table BEGIN SELECT RAISE(ABORT, 'refused'); END").is_ok());
+        assert!(adapter.store(&"a".to_string(), &AllSupportedTypes::with_integer(2)).is_err());
+        assert_eq!(ttl.purge_expired().ok(), Some(1));
+    }
+}
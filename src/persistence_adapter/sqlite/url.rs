@@ -0,0 +1,122 @@
+use std::{path::Path, sync::Arc};
+use sqlite_::Connection;
+use crate::persistence_adapter::PersistenceError;
+use super::SqlitePersistence;
+
+// Pragma values can't be bound, so only names and values that look like plain pragma values are let through
+pub(crate) fn check_pragmas(pragmas: &[(&str, &str)]) -> Result<(), PersistenceError> {
+    let plain = |s: &str|!s.is_empty() && s.chars().all(|c|c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    match pragmas.iter().find(|(name, value)|!plain(name) || !plain(value)) {
+        Some((name, value)) => Err(PersistenceError::FieldNotAllowed { field: format!("{name} = {value}") }),
+        None => Ok(())
+    }
+}
+
+fn url_error(url: &str, reason: &str) -> PersistenceError {
+    PersistenceError::Backend { message: format!("Invalid sqlite url {url}: {reason}") }
+}
+
+// %XX escapes, for paths and values with spaces or '?' in them
+fn percent_decode(url: &str, s: &str) -> Result<String, PersistenceError> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'%' => {
+                let hex = [input.next(), input.next()].into_iter().flatten().map(char::from).collect::<String>();
+                bytes.push(u8::from_str_radix(&hex, 16).map_err(|_|url_error(url, "bad % escape"))?);
+            },
+            b => bytes.push(b)
+        }
+    }
+    String::from_utf8(bytes).map_err(|_|url_error(url, "path isn't UTF-8"))
+}
+
+impl SqlitePersistence {
+    pub(crate) fn apply_pragmas(&self, pragmas: &[(&str, &str)]) -> Result<(), PersistenceError> {
+        check_pragmas(pragmas)?;
+        for (name, value) in pragmas {
+            self.execute_timed(&format!("PRAGMA {name} = {value}"))?;
+        }
+        Ok(())
+    }
+
+    // Opens the database a URL like "sqlite:///var/lib/app.sqlite?mode=rwc&journal=WAL" points to, for
+    // configuration that comes from the environment. "sqlite://data.sqlite" is relative to the working directory,
+    // "sqlite::memory:" is an in-memory database. Parameters:
+    //   mode=rwc (default, creates the file), rw (the file must exist), ro (the file must exist,
+    //   rejects writes) or memory
+    //   journal=<journal_mode>, busy_timeout=<milliseconds>
+    // any other parameter is set as a pragma of that name, e.g. synchronous=NORMAL
+    pub fn from_url(url: &str, table_name: &str) -> Result<Self, PersistenceError> {
+        let rest = url.strip_prefix("sqlite:").ok_or_else(||url_error(url, "expected the sqlite: scheme"))?;
+        let rest = rest.strip_prefix("//").unwrap_or(rest);
+        let (path, parameters) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut path = percent_decode(url, path)?;
+
+        let mut read_only = false;
+        let mut pragmas = Vec::new();
+        for parameter in parameters.split('&').filter(|p|!p.is_empty()) {
+            let (name, value) = parameter.split_once('=').ok_or_else(||url_error(url, "parameters need a value"))?;
+            let value = percent_decode(url, value)?;
+            match (name, value.as_str()) {
+                ("mode", "rwc") => {},
+                ("mode", "rw" | "ro") if !Path::new(&path).is_file() => return Err(url_error(url, &format!("mode={value} needs an existing database file"))),
+                ("mode", "rw") => {},
+                ("mode", "ro") => read_only = true,
+                ("mode", "memory") => path = ":memory:".to_string(),
+                ("mode", _) => return Err(url_error(url, "mode must be rwc, rw, ro or memory")),
+                ("journal", _) => pragmas.push(("journal_mode".to_string(), value)),
+                _ => pragmas.push((name.to_string(), value))
+            }
+        }
+        if path.is_empty() {
+            return Err(url_error(url, "missing path"));
+        }
+        // query_only, as the connection can only be opened read-write. Applied last so the other pragmas still run
+        if read_only {
+            pragmas.push(("query_only".to_string(), "ON".to_string()));
+        }
+
+        let pragmas = pragmas.iter().map(|(name, value)|(name.as_str(), value.as_str())).collect::<Vec<_>>();
+        check_pragmas(&pragmas)?;
+        let persistence = SqlitePersistence::new(Arc::new(Connection::open_with_full_mutex(&path)?), table_name);
+        persistence.apply_pragmas(&pragmas)?;
+        Ok(persistence)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use tempdir::TempDir;
+    use crate::persistence_adapter::PersistenceAdapter;
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use crate::tests::{AllSupportedTypes, AllSupportedTypesPersistenceSpec};
+
+    #[test]
+    fn test_from_url() {
+        let temp_dir = TempDir::new("sqlite test").expect("Failed to create tempdir");
+        let path = temp_dir.path().join("test.sqlite");
+        let url = format!("sqlite://{}", path.to_str().expect("Tempdir isn't UTF-8").replace(' ', "%20"));
+
+        assert!(SqlitePersistence::from_url(&format!("{url}?mode=rw"), "test_table").is_err());
+        // a mistyped path isn't created as an empty database to read nothing from
+        assert!(SqlitePersistence::from_url(&format!("{url}?mode=ro"), "test_table").is_err());
+        assert!(!path.exists());
+        let persistence = SqlitePersistence::from_url(&format!("{url}?mode=rwc&journal=WAL&synchronous=NORMAL"), "test_table").expect("Failed to open");
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &persistence;
+        adapter.initialize();
+        let entry = AllSupportedTypes::with_integer(1);
+        assert!(adapter.store(&"a".to_string(), &entry).is_ok());
+        assert!(path.with_extension("sqlite-wal").exists());
+
+        let read_only = SqlitePersistence::from_url(&format!("{url}?mode=ro"), "test_table").expect("Failed to open");
+        let adapter: &dyn PersistenceAdapter<String, AllSupportedTypes, AllSupportedTypesPersistenceSpec> = &read_only;
+        assert_eq!(adapter.load(&"a".to_string()), Some(entry.clone()));
+        assert!(adapter.store(&"b".to_string(), &entry).is_err());
+
+        assert!(SqlitePersistence::from_url("sqlite::memory:", "test_table").is_ok());
+        assert!(SqlitePersistence::from_url("postgres://localhost/app", "test_table").is_err());
+        assert!(SqlitePersistence::from_url("sqlite::memory:?synchronous=OFF;DROP", "test_table").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use sqlite_::State::Row;
+use crate::persistence_adapter::{PersistenceData, PersistenceError, PersistenceSpec};
+use super::{quote_identifier, SqlitePersistence};
+
+pub(super) const VERSION_COLUMN: &str = "_version";
+
+impl SqlitePersistence {
+    // Stores the spec's version() with every row in a _version column, added by initialize, and passes it to
+    // the spec's upcast when the row is read, so rows written by older code still deserialize. Writes of
+    // whole rows (store, update without only_update, store_if, store_generated, import_file) stamp the
+    // current version. update with only_update and patch would mix the current layout into an older row, so
+    // they refuse rows below the current version with a Spec error on _version, rewrite those whole first.
+    // Rows without a version are read as version 1
+    pub fn with_versioning(mut self) -> Self {
+        self.versioned = true;
+        self
+    }
+
+    // `, "_version"` to append to an INSERT's column list, empty without versioning
+    pub(super) fn version_column(&self) -> String {
+        match self.versioned {
+            true => format!(", \"{VERSION_COLUMN}\""),
+            false => String::new()
+        }
+    }
+
+    // the value matching version_column, written inline so it doesn't shift the positional placeholders
+    pub(super) fn version_value<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> String {
+        match self.versioned {
+            true => format!(", {}", Spec::version()),
+            false => String::new()
+        }
+    }
+
+    // ` AND "_version" >= n` to append to the WHERE of a partial write, so it skips rows of older versions
+    pub(super) fn and_current_version<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> String {
+        match self.versioned {
+            true => format!(" AND coalesce(\"{VERSION_COLUMN}\", 1) >= {}", Spec::version()),
+            false => String::new()
+        }
+    }
+
+    // After a partial write with and_current_version updated nothing, tells a missing row, which is fine,
+    // from one skipped for its older version, which is an error
+    pub(super) fn check_partial_write<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self, key: &PersistenceData) -> Result<(), PersistenceError> {
+        if !self.versioned {
+            return Ok(());
+        }
+        let command = format!("SELECT coalesce(\"{VERSION_COLUMN}\", 1) FROM {} WHERE {} = ?{}", quote_identifier(&self.table_name), quote_identifier(Spec::key_field()), self.and_row_scope());
+        let _timer = self.time_statement(&command, [key]);
+        let mut statement = self.connection.prepare(command).map_err(|e|self.backend_error(e))?;
+        SqlitePersistence::bind_data(&mut statement, 1, key).map_err(|e|self.backend_error(e))?;
+        self.bind_tenant(&mut statement).map_err(|e|self.backend_error(e))?;
+        if statement.next().map_err(|e|self.backend_error(e))? != Row {
+            return Ok(());
+        }
+        let version = statement.read::<i64, _>(0).map_err(|e|self.backend_error(e))?;
+        Err(PersistenceError::Spec { field: VERSION_COLUMN.to_string(), reason: format!("row is from version {version}, older than {}, rewrite it whole before a partial write", Spec::version()) })
+    }
+
+    // `, "_version" = n` to append to the SET list of a write replacing the whole row
+    pub(super) fn set_version<Key, Data, Spec: PersistenceSpec<Key, Data>>(&self) -> String {
+        match self.versioned {
+            true => format!(", \"{VERSION_COLUMN}\" = {}", Spec::version()),
+            false => String::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+    use crate::persistence_adapter::{PersistenceAdapter, PersistenceData, PersistenceError, PersistenceSpec, PersistenceType, SpecError, Upcaster};
+    use crate::persistence_adapter::sqlite::SqlitePersistence;
+    use super::VERSION_COLUMN;
+    use crate::tests::sqlite_connection;
+
+    const NAME_FIELDS: [PersistenceType; 2] = [
+        PersistenceType::Integer("id"),
+        PersistenceType::String("name")
+    ];
+
+    // version 1 stored a full name
+    struct NameV1 {}
+
+    impl PersistenceSpec<i64, String> for NameV1 {
+        fn fields() -> &'static [PersistenceType] {
+            &NAME_FIELDS
+        }
+
+        fn key_field() -> &'static str {
+            "id"
+        }
+
+        fn serialize_key(key: &i64) -> PersistenceData {
+            PersistenceData::Integer(*key)
+        }
+
+        fn deserialize_key(key: &PersistenceData) -> Option<i64> {
+            key.to_int()
+        }
+
+        fn serialize_data(data: &String) -> Result<HashMap<&'static str, PersistenceData>, SpecError> {
+            Ok(HashMap::from([("name", PersistenceData::String(data.clone()))]))
+        }
+
+        fn deserialize_data(mut data: HashMap<&'static str, PersistenceData>) -> Result<String, SpecError> {
+            data.remove("name").and_then(PersistenceData::into_string).ok_or_else(||SpecError::missing("name"))
+        }
+    }
+
+    // version 2 stores "last, first" in the same column
+    struct NameV2 {}
+
+    fn last_name_first(data: &mut HashMap<&'static str, PersistenceData>) -> Result<(), SpecError> {
+        let name = data.remove("name").and_then(PersistenceData::into_string).ok_or_else(||SpecError::missing("name"))?;
+        let (first, last) = name.split_once(' ').ok_or_else(||SpecError::new("name", "no last name"))?;
+        data.insert("name", PersistenceData::String(format!("{last}, {first}")));
+        Ok(())
+    }
+
+    impl PersistenceSpec<i64, String> for NameV2 {
+        fn fields() -> &'static [PersistenceType] { NameV1::fields() }
+        fn key_field() -> &'static str { NameV1::key_field() }
+        fn serialize_key(key: &i64) -> PersistenceData { NameV1::serialize_key(key) }
+        fn deserialize_key(key: &PersistenceData) -> Option<i64> { NameV1::deserialize_key(key) }
+        fn serialize_data(data: &String) -> Result<HashMap<&'static str, PersistenceData>, SpecError> { NameV1::serialize_data(data) }
+        fn deserialize_data(data: HashMap<&'static str, PersistenceData>) -> Result<String, SpecError> { NameV1::deserialize_data(data) }
+
+        fn version() -> u32 {
+            2
+        }
+
+        fn upcasters() -> &'static [Upcaster] {
+            &[last_name_first]
+        }
+    }
+
+    #[test]
+    fn test_versioning() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection, "names").with_versioning();
+        let old: &dyn PersistenceAdapter<i64, String, NameV1> = &persistence;
+        let new: &dyn PersistenceAdapter<i64, String, NameV2> = &persistence;
+        old.initialize();
+        assert!(old.store(&1, &"Ada Lovelace".to_string()).is_ok());
+        assert!(old.store(&2, &"Plato".to_string()).is_ok());
+        assert!(new.store(&3, &"Hopper, Grace".to_string()).is_ok());
+
+        // version 1 rows are upcast when read, version 2 rows aren't
+        assert_eq!(new.load(&1).as_deref(), Some("Lovelace, Ada"));
+        assert_eq!(new.load(&3).as_deref(), Some("Hopper, Grace"));
+        // a row the upcaster rejects is skipped like any other unreadable row
+        assert!(new.load(&2).is_none());
+        assert_eq!(new.scan(0, None).len(), 2);
+
+        // rewriting the row stamps the new version
+        assert_eq!(new.update(&1, &"Lovelace, Ada".to_string(), None).ok(), Some(1));
+        assert_eq!(new.load(&1).as_deref(), Some("Lovelace, Ada"));
+        // rows of a newer version than the code can't be read
+        assert!(old.load(&3).is_none());
+    }
+
+    #[test]
+    fn test_partial_writes_to_old_rows() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let persistence = SqlitePersistence::new(db_connection, "names").with_versioning();
+        let old: &dyn PersistenceAdapter<i64, String, NameV1> = &persistence;
+        let new: &dyn PersistenceAdapter<i64, String, NameV2> = &persistence;
+        old.initialize();
+        assert!(old.store(&1, &"Ada Lovelace".to_string()).is_ok());
+        assert!(new.store(&2, &"Hopper, Grace".to_string()).is_ok());
+
+        // a partial write to a version 1 row is refused instead of being upcast again on the next read
+        let renamed = "Byron, Ada".to_string();
+        assert!(new.update(&1, &renamed, Some(&["name"])).is_err_and(|e|matches!(e.kind, Some(PersistenceError::Spec { ref field, .. }) if field == VERSION_COLUMN)));
+        assert!(matches!(new.patch(&1, HashMap::from([("name", PersistenceData::String(renamed.clone()))])), Err(PersistenceError::Spec { .. })));
+        assert_eq!(new.load(&1).as_deref(), Some("Lovelace, Ada"));
+
+        // rows at the current version take partial writes, missing rows still just update nothing
+        assert_eq!(new.update(&2, &renamed, Some(&["name"])).ok(), Some(1));
+        assert_eq!(new.load(&2).as_deref(), Some("Byron, Ada"));
+        assert_eq!(new.patch(&3, HashMap::from([("name", PersistenceData::String(renamed.clone()))])).ok(), Some(0));
+
+        // once rewritten whole the old row takes them too
+        assert_eq!(new.update(&1, &"Lovelace, Ada".to_string(), None).ok(), Some(1));
+        assert_eq!(new.patch(&1, HashMap::from([("name", PersistenceData::String(renamed))])).ok(), Some(1));
+        assert_eq!(new.load(&1).as_deref(), Some("Byron, Ada"));
+    }
+
+    #[test]
+    fn test_versioning_existing_table() {
+        let (_temp_dir, db_connection) = sqlite_connection();
+
+        let plain = SqlitePersistence::new(db_connection.clone(), "names");
+        let unversioned: &dyn PersistenceAdapter<i64, String, NameV1> = &plain;
+        unversioned.initialize();
+        assert!(unversioned.store(&1, &"Ada Lovelace".to_string()).is_ok());
+
+        // initialize adds the _version column to the existing table
+        let persistence = SqlitePersistence::new(db_connection, "names").with_versioning();
+        let new: &dyn PersistenceAdapter<i64, String, NameV2> = &persistence;
+        assert!(new.initialize().is_some());
+        assert!(new.initialize().is_some());
+        assert!(new.store(&2, &"Hopper, Grace".to_string()).is_ok());
+
+        // rows written before versioning was enabled are read as version 1
+        assert_eq!(new.load(&1).as_deref(), Some("Lovelace, Ada"));
+        assert_eq!(new.load(&2).as_deref(), Some("Hopper, Grace"));
+    }
+}
@@ -0,0 +1,185 @@
+use std::{panic::{self, AssertUnwindSafe}, sync::{Arc, Mutex, mpsc::{self, RecvTimeoutError, Sender}}, thread::{self, JoinHandle}, time::Duration};
+use crate::persistence_adapter::PersistenceError;
+
+// What a supervised task does after its job panics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    Never, // the task stops, see TaskStatus::running
+    Restart { max_restarts: Option<u32> } // keeps running from the next interval, up to max_restarts times in total
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskStatus {
+    pub name: String,
+    pub running: bool,
+    pub runs: u64, // completed runs, successful or not
+    pub restarts: u32,
+    pub last_error: Option<String> // the error or panic of the latest run, None if it succeeded
+}
+
+type Job = Arc<dyn Fn() -> Result<(), PersistenceError> + Send + Sync>;
+
+struct Task {
+    interval: Duration,
+    policy: RestartPolicy,
+    job: Job,
+    status: Arc<Mutex<TaskStatus>>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>
+}
+
+// Owns periodic background work, e.g. retention and TTL sweeps, WAL checkpoints and cache cleanup, so it's
+// started, stopped and watched in one place:
+// supervisor.register("retention", Duration::from_secs(60), RestartPolicy::Never, move ||retention.enforce_retention().map(|_|()))
+// Each task runs its job every interval on its own thread, right away when started. A job that returns an
+// error runs again at the next interval, one that panics is handled by its RestartPolicy. Tasks stop when
+// the supervisor is stopped or dropped, waiting for runs in progress to finish
+pub struct TaskSupervisor {
+    tasks: Vec<Task>
+}
+
+impl TaskSupervisor {
+    pub fn new() -> Self {
+        TaskSupervisor { tasks: Vec::new() }
+    }
+
+    // Adds a task, started with the others by start. Started right away if the supervisor is running
+    pub fn register(&mut self, name: &str, interval: Duration, policy: RestartPolicy, job: impl Fn() -> Result<(), PersistenceError> + Send + Sync + 'static) {
+        let running = self.tasks.iter().any(|task|task.thread.is_some());
+        let status = TaskStatus { name: name.to_string(), running: false, runs: 0, restarts: 0, last_error: None };
+        self.tasks.push(Task { interval, policy, job: Arc::new(job), status: Arc::new(Mutex::new(status)), stop: None, thread: None });
+        if running {
+            self.start();
+        }
+    }
+
+    // Starts every task that isn't running, including ones stopped by a panic
+    pub fn start(&mut self) {
+        for task in self.tasks.iter_mut().filter(|task|!task.status.lock().unwrap_or_else(|e|e.into_inner()).running) {
+            if let Some(thread) = task.thread.take() {
+                let _ = thread.join();
+            }
+            let (stop, stopped) = mpsc::channel::<()>();
+            let (interval, policy, job, status) = (task.interval, task.policy, task.job.clone(), task.status.clone());
+            status.lock().unwrap_or_else(|e|e.into_inner()).running = true;
+            task.stop = Some(stop);
+            task.thread = Some(thread::spawn(move ||{
+                loop {
+                    let result = panic::catch_unwind(AssertUnwindSafe(||job()));
+                    let mut status = status.lock().unwrap_or_else(|e|e.into_inner());
+                    status.runs += 1;
+                    match result {
+                        Ok(result) => status.last_error = result.err().map(|e|e.to_string()),
+                        Err(panic) => {
+                            let message = panic.downcast_ref::<&str>().map(|s|s.to_string()).or_else(||panic.downcast_ref::<String>().cloned()).unwrap_or_default();
+                            status.last_error = Some(format!("panicked: {message}"));
+                            match policy {
+                                RestartPolicy::Restart { max_restarts } if max_restarts.is_none_or(|max|status.restarts < max) => status.restarts += 1,
+                                _ => break
+                            }
+                        }
+                    }
+                    drop(status);
+                    match stopped.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => {},
+                        _ => break
+                    }
+                }
+                status.lock().unwrap_or_else(|e|e.into_inner()).running = false;
+            }));
+        }
+    }
+
+    // Stops every task, waiting for runs in progress to finish
+    pub fn stop(&mut self) {
+        for task in &mut self.tasks {
+            drop(task.stop.take());
+        }
+        for task in &mut self.tasks {
+            if let Some(thread) = task.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    // every task in the order registered
+    pub fn status(&self) -> Vec<TaskStatus> {
+        self.tasks.iter().map(|task|task.status.lock().unwrap_or_else(|e|e.into_inner()).clone()).collect()
+    }
+}
+
+impl Default for TaskSupervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TaskSupervisor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{sync::{Arc, atomic::{AtomicU32, Ordering}}, thread, time::{Duration, Instant}};
+    use crate::persistence_adapter::PersistenceError;
+    use crate::persistence_adapter::supervisor::{RestartPolicy, TaskSupervisor};
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(condition());
+    }
+
+    #[test]
+    fn test_task_supervisor() {
+        let mut supervisor = TaskSupervisor::new();
+        let (ticks, panics, failures) = (Arc::new(AtomicU32::new(0)), Arc::new(AtomicU32::new(0)), Arc::new(AtomicU32::new(0)));
+        {
+            let ticks = ticks.clone();
+            supervisor.register("tick", Duration::from_millis(5), RestartPolicy::Never, move ||{
+                ticks.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+        }
+        {
+            let panics = panics.clone();
+            supervisor.register("flaky", Duration::from_millis(5), RestartPolicy::Restart { max_restarts: Some(2) }, move ||{
+                panics.fetch_add(1, Ordering::SeqCst);
+                panic!("flaky job");
+            });
+        }
+        {
+            let failures = failures.clone();
+            supervisor.register("failing", Duration::from_millis(5), RestartPolicy::Never, move ||{
+                failures.fetch_add(1, Ordering::SeqCst);
+                Err(PersistenceError::Backend { message: "unreachable".to_string() })
+            });
+        }
+        assert_eq!(supervisor.status().into_iter().map(|status|status.name).collect::<Vec<_>>(), vec!["tick", "flaky", "failing"]);
+        assert!(supervisor.status().iter().all(|status|!status.running));
+
+        supervisor.start();
+        wait_until(||ticks.load(Ordering::SeqCst) >= 3 && failures.load(Ordering::SeqCst) >= 3 && !supervisor.status()[1].running);
+        let status = supervisor.status();
+        // the panicking job ran once and was restarted twice before giving up
+        assert_eq!((panics.load(Ordering::SeqCst), status[1].restarts), (3, 2));
+        assert_eq!(status[1].last_error.as_deref(), Some("panicked: flaky job"));
+        // errors don't stop a task
+        assert!(status[2].running && status[2].last_error.is_some());
+        assert!(status[0].running && status[0].last_error.is_none());
+
+        supervisor.stop();
+        assert!(supervisor.status().iter().all(|status|!status.running));
+        let stopped_at = ticks.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(ticks.load(Ordering::SeqCst), stopped_at);
+
+        // start brings back every stopped task, the panicked one included
+        supervisor.start();
+        wait_until(||panics.load(Ordering::SeqCst) > 3);
+    }
+}